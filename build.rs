@@ -0,0 +1,31 @@
+use std::process::Command;
+
+// Recording the commit imogen was built from, for the /version endpoint
+fn main() {
+    let commit = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|commit| commit.trim().to_string())
+        .unwrap_or_default();
+
+    println!("cargo:rustc-env=IMOGEN_GIT_COMMIT={}", commit);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+
+    #[cfg(feature = "grpc")]
+    compile_protos();
+}
+
+// Generating the gRPC service from its definition, without needing protoc
+#[cfg(feature = "grpc")]
+fn compile_protos() {
+    let descriptors = protox::compile(["proto/imogen.proto"], ["proto"]).expect("proto/imogen.proto should compile");
+    tonic_build::configure()
+        .build_client(false)
+        .compile_fds(descriptors)
+        .expect("the gRPC service should be generated");
+    println!("cargo:rerun-if-changed=proto/imogen.proto");
+}
@@ -0,0 +1,180 @@
+use std::fs::OpenOptions;
+use std::io::{LineWriter, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use actix_web::dev::{BodySize, MessageBody, ServiceResponse};
+
+use crate::cache::Lookup;
+use crate::clock::UtcTime;
+use crate::request_id::RequestId;
+
+
+// "common" and "combined" are the formats of Apache and nginx, followed by
+// how long the response took in milliseconds and how the cache served it.
+// "json" writes each request as an object of its own
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessLogFormat {
+    Common,
+    Combined,
+    Json,
+}
+
+impl AccessLogFormat {
+    // "combined" -> Some(AccessLogFormat::Combined)
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "common" => Some(Self::Common),
+            "combined" => Some(Self::Combined),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+// Writing a line for every request, to stdout unless given a file
+#[derive(Clone, Debug)]
+pub struct AccessLogConfig {
+    pub format: AccessLogFormat,
+    pub path: Option<String>,
+}
+
+impl AccessLogConfig {
+    pub fn new(format: AccessLogFormat) -> Self {
+        Self { format, path: None }
+    }
+
+    // Appending to this file, which is created if it doesn't exist
+    pub fn with_path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+}
+
+// Where access log lines are written, shared by every worker
+#[derive(Clone)]
+pub struct AccessLog {
+    format: AccessLogFormat,
+    writer: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+// What's written about each request
+struct Entry {
+    remote: String,
+    forwarded_for: Option<String>,
+    method: String,
+    target: String,
+    version: String,
+    status: u16,
+    bytes: Option<u64>,
+    duration: Duration,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    cache: Option<&'static str>,
+    request_id: Option<String>,
+    received: SystemTime,
+}
+
+impl AccessLog {
+    pub fn open(config: &AccessLogConfig) -> std::io::Result<Self> {
+        let writer: Box<dyn Write + Send> = match &config.path {
+            Some(path) => Box::new(LineWriter::new(OpenOptions::new().create(true).append(true).open(path)?)),
+            None => Box::new(std::io::stdout()),
+        };
+        Ok(Self { format: config.format, writer: Arc::new(Mutex::new(writer)) })
+    }
+
+    // Recording a response, and the request it answered, which was
+    // received at `received` and took `duration` to answer
+    pub fn record<B: MessageBody>(&self, res: &ServiceResponse<B>, received: SystemTime, duration: Duration) {
+        let req = res.request();
+        let header = |name: &str| req.headers().get(name).and_then(|value| value.to_str().ok()).map(|value| value.to_string());
+
+        let target = match req.query_string() {
+            "" => req.path().to_string(),
+            query => format!("{}?{}", req.path(), query),
+        };
+        let bytes = match res.response().body().size() {
+            BodySize::Sized(bytes) => Some(bytes),
+            BodySize::Empty | BodySize::None => Some(0),
+            BodySize::Stream => None,
+        };
+
+        let entry = Entry {
+            remote: req.peer_addr().map(|address| address.ip().to_string()).unwrap_or_else(|| "-".to_string()),
+            forwarded_for: header("x-forwarded-for"),
+            method: req.method().to_string(),
+            target,
+            version: format!("{:?}", req.version()),
+            status: res.status().as_u16(),
+            bytes,
+            duration,
+            referer: header("referer"),
+            user_agent: header("user-agent"),
+            cache: req.extensions().get::<Lookup>().map(|lookup| lookup.name()),
+            request_id: req.extensions().get::<RequestId>().map(|id| id.0.clone()),
+            received,
+        };
+
+        let line = match self.format {
+            AccessLogFormat::Common => common_line(&entry, false),
+            AccessLogFormat::Combined => common_line(&entry, true),
+            AccessLogFormat::Json => json_line(&entry),
+        };
+
+        // Failing to log isn't a reason to fail the request
+        let mut writer = self.writer.lock().unwrap();
+        let _ = writeln!(writer, "{}", line);
+    }
+}
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+// Quoting a header's value, with any quotes inside it escaped
+// Some("Mozilla/5.0") -> "\"Mozilla/5.0\""
+// None -> "\"-\""
+fn quoted(value: &Option<String>) -> String {
+    format!("\"{}\"", value.as_deref().unwrap_or("-").replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// 127.0.0.1 - - [14/Oct/2026:13:08:20 +0000] "GET /cat.png?w=40 HTTP/1.1" 200 1834 27 miss
+// Combined lines add the referer and user agent before the duration
+fn common_line(entry: &Entry, combined: bool) -> String {
+    let t = UtcTime::from_system_time(entry.received);
+    let mut line = format!(
+        "{} - - [{:02}/{}/{}:{:02}:{:02}:{:02} +0000] \"{} {} {}\" {} {}",
+        entry.remote,
+        t.day, MONTHS[(t.month - 1) as usize], t.year, t.hour, t.minute, t.second,
+        entry.method,
+        entry.target,
+        entry.version,
+        entry.status,
+        entry.bytes.map(|bytes| bytes.to_string()).unwrap_or_else(|| "-".to_string()),
+    );
+
+    if combined {
+        line.push_str(&format!(" {} {}", quoted(&entry.referer), quoted(&entry.user_agent)));
+    }
+    line.push_str(&format!(" {} {}", entry.duration.as_millis(), entry.cache.unwrap_or("-")));
+    line
+}
+
+fn json_line(entry: &Entry) -> String {
+    let t = UtcTime::from_system_time(entry.received);
+    let time = format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", t.year, t.month, t.day, t.hour, t.minute, t.second);
+
+    serde_json::json!({
+        "time": time,
+        "remote": entry.remote,
+        "forwarded_for": entry.forwarded_for,
+        "method": entry.method,
+        "path": entry.target,
+        "version": entry.version,
+        "status": entry.status,
+        "bytes": entry.bytes,
+        "duration_ms": entry.duration.as_millis() as u64,
+        "referer": entry.referer,
+        "user_agent": entry.user_agent,
+        "cache": entry.cache,
+        "request_id": entry.request_id,
+    }).to_string()
+}
@@ -0,0 +1,557 @@
+use std::collections::HashMap;
+use image::{DynamicImage, GenericImageView};
+use image::imageops::FilterType;
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use actix_web::{web, HttpRequest, HttpResponse};
+
+use crate::{
+    auth,
+    Caches,
+    ServerConfig,
+    ImageServiceFailure,
+    build_path_to_master,
+    decode_master,
+    downscale,
+    fit_within,
+    master_dimensions,
+    origin,
+    run_blocking,
+    try_loading_unprocessed_image,
+};
+
+
+// Summaries computed from an image's master (hashes, colors, previews)
+// are small, so they're cached as JSON rather than as image variants
+
+// "cat", "blurhash-4x3" -> "./images/.analysis/cat.blurhash-4x3.json"
+fn build_path_to_analysis(config: &ServerConfig, filename: &str, kind: &str) -> String {
+    let dir = config.build_path_to_cache("analysis");
+    format!("{}/{}{}.{}.json", dir, config.shard(filename), filename, kind)
+}
+
+fn try_loading_cached_analysis<T: DeserializeOwned>(config: &ServerConfig, filepath: &str) -> Option<T> {
+    let contents = config.storage.get(filepath).ok()?;
+    serde_json::from_slice(&contents).ok()
+}
+
+// Loading a previously computed analysis of an image, or computing it
+// and persisting it for next time
+fn cached<T, F>(
+    config: &ServerConfig,
+    filename: &str,
+    kind: &str,
+    compute: F,
+) -> Result<T, ImageServiceFailure>
+where
+    T: Serialize + DeserializeOwned,
+    F: FnOnce() -> Result<T, ImageServiceFailure>,
+{
+    let filepath = build_path_to_analysis(config, filename, kind);
+    if let Some(analysis) = try_loading_cached_analysis(config, &filepath) {
+        return Ok(analysis);
+    }
+
+    let analysis = compute()?;
+
+    if let Ok(contents) = serde_json::to_vec(&analysis) {
+        let _ = config.storage.put(&filepath, &contents);
+    }
+
+    Ok(analysis)
+}
+
+// The largest side of the copy analyses are computed from. Each of them
+// looks at far fewer pixels than this
+const SAMPLE_SIZE: u32 = 256;
+
+// Decoding the master straight to a copy that fits within the sample
+// size, so that analyses never hold its full size bitmap
+fn try_loading_sample(config: &ServerConfig, filename: &str) -> Result<DynamicImage, ImageServiceFailure> {
+    if let Some(origin) = &config.origin {
+        origin::refresh_master(config, origin, filename)?;
+    }
+    let buffer = try_loading_unprocessed_image(config, &build_path_to_master(config, filename))?;
+    let original = master_dimensions(&buffer, config.max_pixels)?;
+    if original.0.max(original.1) <= SAMPLE_SIZE {
+        return decode_master(&buffer, config.max_pixels);
+    }
+
+    let (width, height) = fit_within(original, (SAMPLE_SIZE, SAMPLE_SIZE));
+    downscale::decode_scaled(&buffer, width, height).ok_or(ImageServiceFailure::CouldNotDecode { source: None })
+}
+
+// As `cached`, for analyses computed from a sample of the master
+fn cached_analysis<T, F>(
+    config: &ServerConfig,
+    filename: &str,
+    kind: &str,
+    compute: F,
+) -> Result<T, ImageServiceFailure>
+where
+    T: Serialize + DeserializeOwned,
+    F: FnOnce(DynamicImage) -> T,
+{
+    cached(config, filename, kind, || Ok(compute(try_loading_sample(config, filename)?)))
+}
+
+// As `cached_analysis`, for requests: the master is only decoded once a
+// processing slot is free and the memory budget has room for the sample,
+// as when processing a variant
+fn limited_analysis<T, F>(
+    config: &ServerConfig,
+    caches: &Caches,
+    filename: &str,
+    kind: &str,
+    compute: F,
+) -> Result<T, ImageServiceFailure>
+where
+    T: Serialize + DeserializeOwned,
+    F: FnOnce(DynamicImage) -> T,
+{
+    cached(config, filename, kind, || {
+        let _slot = caches.processing.acquire().ok_or(ImageServiceFailure::Overloaded)?;
+        let sample = SAMPLE_SIZE as usize * SAMPLE_SIZE as usize * 4;
+        let _reservation = caches.budget.reserve(sample).ok_or(ImageServiceFailure::Overloaded)?;
+        Ok(compute(try_loading_sample(config, filename)?))
+    })
+}
+
+#[derive(Serialize, Deserialize)]
+struct Dimensions {
+    width: u32,
+    height: u32,
+}
+
+// The size of an upload's master, read from its header once and kept
+// with its analyses, so that a large master isn't read again to find it
+pub fn cached_dimensions(config: &ServerConfig, filename: &str) -> Result<(u32, u32), ImageServiceFailure> {
+    let compute = || {
+        let master = try_loading_unprocessed_image(config, &build_path_to_master(config, filename))?;
+        let (width, height) = master_dimensions(&master, config.max_pixels)?;
+        Ok(Dimensions { width, height })
+    };
+    cached(config, filename, "dimensions", compute).map(|dimensions| (dimensions.width, dimensions.height))
+}
+
+#[derive(Serialize, Deserialize)]
+struct DifferenceHash {
+    dhash: String,
+}
+
+// A 64 bit difference hash: shrinking the image to 9x8 grey pixels, and
+// setting a bit wherever a pixel is brighter than the one to its right.
+// Resized, recompressed or slightly edited copies of an image hash to
+// the same bits, or nearly so
+fn compute_dhash(image: DynamicImage) -> DifferenceHash {
+    let grey = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+
+    let mut hash: u64 = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let brighter = grey.get_pixel(x, y)[0] > grey.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | brighter as u64;
+        }
+    }
+    DifferenceHash { dhash: format!("{:016x}", hash) }
+}
+
+// A 64 bit average hash: shrinking the image to 8x8 grey pixels, and
+// setting a bit wherever a pixel is brighter than their average
+fn average_hash(image: &DynamicImage) -> u64 {
+    let grey = image.resize_exact(8, 8, FilterType::Triangle).to_luma8();
+    let mean = grey.pixels().map(|pixel| pixel[0] as u32).sum::<u32>() / 64;
+    grey.pixels().fold(0, |hash, pixel| (hash << 1) | (pixel[0] as u32 > mean) as u64)
+}
+
+// A 64 bit perceptual hash: the discrete cosine transform of the image
+// shrunk to 32x32 grey pixels, setting a bit wherever one of its 8x8
+// lowest frequencies is above their median. It's the most robust of the
+// three to changes in brightness, contrast and compression
+fn perceptual_hash(image: &DynamicImage) -> u64 {
+    const SIZE: usize = 32;
+    let grey = image.resize_exact(SIZE as u32, SIZE as u32, FilterType::Triangle).to_luma8();
+    let pixels: Vec<f64> = grey.pixels().map(|pixel| pixel[0] as f64).collect();
+
+    // cos((2x + 1)uπ / 2N), for every frequency u and position x
+    let cosines: Vec<f64> = (0..8 * SIZE)
+        .map(|i| {
+            let (u, x) = ((i / SIZE) as f64, (i % SIZE) as f64);
+            ((2.0 * x + 1.0) * u * std::f64::consts::PI / (2.0 * SIZE as f64)).cos()
+        })
+        .collect();
+
+    let mut frequencies = [0.0; 64];
+    for v in 0..8 {
+        for u in 0..8 {
+            let mut sum = 0.0;
+            for y in 0..SIZE {
+                for x in 0..SIZE {
+                    sum += pixels[y * SIZE + x] * cosines[u * SIZE + x] * cosines[v * SIZE + y];
+                }
+            }
+            frequencies[v * 8 + u] = sum;
+        }
+    }
+
+    // Leaving out the average brightness, which says nothing of the image's
+    // structure and would outweigh the rest
+    let mut sorted: Vec<f64> = frequencies[1..].to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let median = sorted[sorted.len() / 2];
+    frequencies.iter().fold(0, |hash, &frequency| (hash << 1) | (frequency > median) as u64)
+}
+
+#[derive(Serialize, Deserialize)]
+struct PerceptualHashes {
+    phash: String,
+    ahash: String,
+    dhash: String,
+}
+
+fn compute_hashes(image: DynamicImage) -> PerceptualHashes {
+    PerceptualHashes {
+        phash: format!("{:016x}", perceptual_hash(&image)),
+        ahash: format!("{:016x}", average_hash(&image)),
+        dhash: compute_dhash(image).dhash,
+    }
+}
+
+// Respond to a request for the perceptual, average and difference hashes
+// of an image, each 64 bits in hex
+pub async fn serve_phash_via_http(
+    req: HttpRequest,
+    name: web::Path<ImageName>,
+    config: web::Data<ServerConfig>,
+    caches: web::Data<Caches>,
+) -> HttpResponse {
+    if let Err(failure) = auth::authorize_upload(&req, &config, auth::Access::Read, &name.filename) {
+        return failure.as_http_response();
+    }
+
+    let filename = name.into_inner().filename;
+    match run_blocking(move || limited_analysis(&config, &caches, &filename, "hashes", compute_hashes)).await {
+        Ok(hashes) => HttpResponse::Ok().json(hashes),
+        Err(failure) => failure.as_http_response(&req),
+    }
+}
+
+// The perceptual hash of an upload's master, kept with its other hashes
+pub fn cached_phash(config: &ServerConfig, filename: &str) -> Result<u64, ImageServiceFailure> {
+    let hashes = cached_analysis(config, filename, "hashes", compute_hashes)?;
+    u64::from_str_radix(&hashes.phash, 16).map_err(|_| ImageServiceFailure::CouldNotDecode { source: None })
+}
+
+// The difference hash of an upload's master, computed once and kept with
+// its analyses
+pub fn cached_dhash(config: &ServerConfig, filename: &str) -> Result<u64, ImageServiceFailure> {
+    let hash = cached_analysis(config, filename, "dhash", compute_dhash)?;
+    u64::from_str_radix(&hash.dhash, 16).map_err(|_| ImageServiceFailure::CouldNotDecode { source: None })
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ImageName {
+    pub filename: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BlurHashInstructions {
+    x: Option<u32>,
+    y: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BlurHash {
+    blurhash: String,
+    width: u32,
+    height: u32,
+}
+
+// `original` is the size the master was stored at, rather than that of
+// the sample
+fn compute_blurhash(image: DynamicImage, original: (u32, u32), components_x: u32, components_y: u32) -> BlurHash {
+    let (width, height) = original;
+
+    // The hash only captures a handful of frequencies, so a small
+    // thumbnail produces the same result far more quickly
+    let thumbnail = image.thumbnail(64, 64).to_rgba8();
+    let blurhash = blurhash::encode(
+        components_x,
+        components_y,
+        thumbnail.width(),
+        thumbnail.height(),
+        thumbnail.as_raw(),
+    ).unwrap_or_default();
+
+    BlurHash { blurhash, width, height }
+}
+
+// Respond to a request for the BlurHash of an image
+pub async fn serve_blurhash_via_http(
+    req: HttpRequest,
+    name: web::Path<ImageName>,
+    instructions: web::Query<BlurHashInstructions>,
+    config: web::Data<ServerConfig>,
+    caches: web::Data<Caches>,
+) -> HttpResponse {
+    if let Err(failure) = auth::authorize_upload(&req, &config, auth::Access::Read, &name.filename) {
+        return failure.as_http_response();
+    }
+
+    let components_x = instructions.x.unwrap_or(4);
+    let components_y = instructions.y.unwrap_or(3);
+    if !(1..=9).contains(&components_x) || !(1..=9).contains(&components_y) {
+        let parameter = if (1..=9).contains(&components_x) { "y" } else { "x" };
+        let message = "BlurHash components must be between 1 and 9";
+        return ImageServiceFailure::invalid_parameter(parameter, message).as_http_response(&req);
+    }
+
+    let kind = format!("blurhash-{}x{}", components_x, components_y);
+
+    let filename = name.into_inner().filename;
+    let computed = run_blocking(move || {
+        let original = cached_dimensions(&config, &filename)?;
+        let compute = move |image| compute_blurhash(image, original, components_x, components_y);
+        limited_analysis(&config, &caches, &filename, &kind, compute)
+    }).await;
+    match computed {
+        Ok(blurhash) => HttpResponse::Ok().json(blurhash),
+        Err(failure) => failure.as_http_response(&req),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LqipInstructions {
+    w: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Lqip {
+    lqip: String,
+    width: u32,
+    height: u32,
+}
+
+// Shrinking and blurring an image into a preview small enough to
+// inline into HTML as a data URI
+fn compute_lqip(image: DynamicImage, width: u32) -> Lqip {
+    let preview = image.thumbnail(width, width).blur(1.0);
+
+    let webp = webp::Encoder::from_image(&preview).encode(50.0);
+
+    Lqip {
+        lqip: format!("data:image/webp;base64,{}", base64::encode(&*webp)),
+        width: preview.width(),
+        height: preview.height(),
+    }
+}
+
+// Respond to a request for a low quality inline preview of an image
+pub async fn serve_lqip_via_http(
+    req: HttpRequest,
+    name: web::Path<ImageName>,
+    instructions: web::Query<LqipInstructions>,
+    config: web::Data<ServerConfig>,
+    caches: web::Data<Caches>,
+) -> HttpResponse {
+    if let Err(failure) = auth::authorize_upload(&req, &config, auth::Access::Read, &name.filename) {
+        return failure.as_http_response();
+    }
+
+    let width = instructions.w.unwrap_or(20);
+    if !(1..=64).contains(&width) {
+        return ImageServiceFailure::invalid_parameter("w", "Preview width must be between 1 and 64").as_http_response(&req);
+    }
+
+    let kind = format!("lqip-{}", width);
+    let compute = move |image| compute_lqip(image, width);
+
+    let filename = name.into_inner().filename;
+    match run_blocking(move || limited_analysis(&config, &caches, &filename, &kind, compute)).await {
+        Ok(lqip) => HttpResponse::Ok().json(lqip),
+        Err(failure) => failure.as_http_response(&req),
+    }
+}
+
+// [255, 136, 0] -> "#ff8800"
+fn to_hex_color(rgb: [u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", rgb[0], rgb[1], rgb[2])
+}
+
+// The opaque pixels of a small thumbnail, which are representative
+// enough of the whole image's colors
+fn sample_pixels(image: &DynamicImage) -> Vec<[u8; 3]> {
+    image
+        .thumbnail(100, 100)
+        .to_rgba8()
+        .pixels()
+        .filter(|pixel| pixel[3] >= 128)
+        .map(|pixel| [pixel[0], pixel[1], pixel[2]])
+        .collect()
+}
+
+fn average_color(pixels: &[[u8; 3]]) -> [u8; 3] {
+    let mut sums = [0u64; 3];
+    for pixel in pixels {
+        for channel in 0..3 { sums[channel] += pixel[channel] as u64; }
+    }
+
+    let count = pixels.len().max(1) as u64;
+    [(sums[0] / count) as u8, (sums[1] / count) as u8, (sums[2] / count) as u8]
+}
+
+#[derive(Serialize, Deserialize)]
+struct Colors {
+    dominant: String,
+    average: String,
+}
+
+// Grouping similar pixels into coarse buckets, and choosing the average
+// color of the most populated bucket as the dominant one
+fn compute_colors(image: DynamicImage) -> Colors {
+    let pixels = sample_pixels(&image);
+
+    let mut buckets: HashMap<[u8; 3], Vec<[u8; 3]>> = HashMap::new();
+    for pixel in &pixels {
+        let bucket = [pixel[0] >> 4, pixel[1] >> 4, pixel[2] >> 4];
+        buckets.entry(bucket).or_default().push(*pixel);
+    }
+
+    let dominant = buckets
+        .values()
+        .max_by_key(|members| members.len())
+        .map(|members| average_color(members))
+        .unwrap_or([0, 0, 0]);
+
+    Colors {
+        dominant: to_hex_color(dominant),
+        average: to_hex_color(average_color(&pixels)),
+    }
+}
+
+// Respond to a request for the dominant and average colors of an image
+pub async fn serve_color_via_http(
+    req: HttpRequest,
+    name: web::Path<ImageName>,
+    config: web::Data<ServerConfig>,
+    caches: web::Data<Caches>,
+) -> HttpResponse {
+    if let Err(failure) = auth::authorize_upload(&req, &config, auth::Access::Read, &name.filename) {
+        return failure.as_http_response();
+    }
+
+    let filename = name.into_inner().filename;
+    match run_blocking(move || limited_analysis(&config, &caches, &filename, "color", compute_colors)).await {
+        Ok(colors) => HttpResponse::Ok().json(colors),
+        Err(failure) => failure.as_http_response(&req),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PaletteInstructions {
+    count: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PaletteColor {
+    color: String,
+    // The percentage of the image's pixels closest to this color, 0 to 100
+    population: f32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Palette {
+    palette: Vec<PaletteColor>,
+}
+
+// The channel with the widest spread of values, and that spread
+fn widest_channel(pixels: &[[u8; 3]]) -> (usize, u8) {
+    (0..3)
+        .map(|channel| {
+            let values = pixels.iter().map(|pixel| pixel[channel]);
+            let spread = values.clone().max().unwrap_or(0) - values.min().unwrap_or(0);
+            (channel, spread)
+        })
+        .max_by_key(|&(_, spread)| spread)
+        .unwrap_or((0, 0))
+}
+
+// Dividing pixels into `count` groups of similar colors using median cut,
+// repeatedly splitting the group whose colors are most spread out
+fn median_cut(pixels: Vec<[u8; 3]>, count: usize) -> Vec<Vec<[u8; 3]>> {
+    let mut groups = vec![pixels];
+
+    while groups.len() < count {
+        let candidate = groups
+            .iter()
+            .enumerate()
+            .filter(|(_, group)| group.len() > 1)
+            .max_by_key(|(_, group)| widest_channel(group).1 as usize * group.len())
+            .map(|(index, _)| index);
+
+        let index = match candidate {
+            Some(index) => index,
+            None => break,
+        };
+
+        let mut group = groups.swap_remove(index);
+        let (channel, spread) = widest_channel(&group);
+        if spread == 0 {
+            groups.push(group);
+            break;
+        }
+
+        group.sort_unstable_by_key(|pixel| pixel[channel]);
+        let upper = group.split_off(group.len() / 2);
+        groups.push(group);
+        groups.push(upper);
+    }
+
+    groups
+}
+
+fn compute_palette(image: DynamicImage, count: usize) -> Palette {
+    let pixels = sample_pixels(&image);
+    let total = pixels.len().max(1) as f32;
+
+    let mut groups = median_cut(pixels, count);
+    groups.retain(|group| !group.is_empty());
+    groups.sort_by_key(|group| std::cmp::Reverse(group.len()));
+
+    let palette = groups
+        .iter()
+        .map(|group| PaletteColor {
+            color: to_hex_color(average_color(group)),
+            population: group.len() as f32 / total * 100.0,
+        })
+        .collect();
+
+    Palette { palette }
+}
+
+// Respond to a request for the most prominent colors of an image
+pub async fn serve_palette_via_http(
+    req: HttpRequest,
+    name: web::Path<ImageName>,
+    instructions: web::Query<PaletteInstructions>,
+    config: web::Data<ServerConfig>,
+    caches: web::Data<Caches>,
+) -> HttpResponse {
+    if let Err(failure) = auth::authorize_upload(&req, &config, auth::Access::Read, &name.filename) {
+        return failure.as_http_response();
+    }
+
+    let count = instructions.count.unwrap_or(5);
+    if !(1..=16).contains(&count) {
+        return ImageServiceFailure::invalid_parameter("count", "Palette count must be between 1 and 16").as_http_response(&req);
+    }
+
+    let kind = format!("palette-{}", count);
+    let compute = move |image| compute_palette(image, count);
+
+    let filename = name.into_inner().filename;
+    match run_blocking(move || limited_analysis(&config, &caches, &filename, &kind, compute)).await {
+        Ok(palette) => HttpResponse::Ok().json(palette),
+        Err(failure) => failure.as_http_response(&req),
+    }
+}
@@ -0,0 +1,428 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use actix_web::{HttpRequest, HttpResponse};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use serde::{Serialize, Deserialize};
+
+use crate::ServerConfig;
+
+
+// A credential that is allowed to upload files, along with the
+// metadata that will be recorded against anything uploaded with it
+#[derive(Clone, Debug)]
+pub struct ApiKey {
+    pub key: String,
+    pub name: String,
+    pub metadata: HashMap<String, String>,
+}
+
+impl ApiKey {
+    pub fn new(key: &str, name: &str) -> Self {
+        Self {
+            key: key.to_string(),
+            name: name.to_string(),
+            metadata: HashMap::new(),
+        }
+    }
+
+    pub fn with_metadata(mut self, key: &str, value: &str) -> Self {
+        self.metadata.insert(key.to_string(), value.to_string());
+        self
+    }
+}
+
+// Settings for accepting JSON Web Tokens issued by an external
+// identity provider, as an alternative to API keys
+#[derive(Clone)]
+pub struct JwtConfig {
+    algorithm: Algorithm,
+    key: DecodingKey,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+}
+
+impl JwtConfig {
+    // Validating tokens signed with a shared secret
+    pub fn hs256(secret: &str) -> Self {
+        Self::with_key(Algorithm::HS256, DecodingKey::from_secret(secret.as_bytes()))
+    }
+
+    // Validating tokens signed by the holder of an RSA private key
+    pub fn rs256(public_key_pem: &str) -> Result<Self, String> {
+        match DecodingKey::from_rsa_pem(public_key_pem.as_bytes()) {
+            Ok(key) => Ok(Self::with_key(Algorithm::RS256, key)),
+            Err(e) => Err(format!("Invalid RSA public key: {}", e)),
+        }
+    }
+
+    fn with_key(algorithm: Algorithm, key: DecodingKey) -> Self {
+        Self {
+            algorithm,
+            key,
+            issuer: None,
+            audience: None,
+        }
+    }
+
+    pub fn with_issuer(mut self, issuer: &str) -> Self {
+        self.issuer = Some(issuer.to_string());
+        self
+    }
+
+    pub fn with_audience(mut self, audience: &str) -> Self {
+        self.audience = Some(audience.to_string());
+        self
+    }
+
+    fn validation(&self) -> Validation {
+        let mut validation = Validation::new(self.algorithm);
+        validation.validate_aud = self.audience.is_some();
+        if let Some(issuer) = &self.issuer { validation.set_issuer(&[issuer]); }
+        if let Some(audience) = &self.audience { validation.set_audience(&[audience]); }
+        validation
+    }
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: Option<String>,
+    iss: Option<String>,
+}
+
+// How a group of routes decides who may use them
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessPolicy {
+    // Anyone may use the route
+    Public,
+    // Requests must present an API key or a valid token
+    Token,
+    // Requests must carry an unexpired signature made with the URL signing secret
+    SignedUrl,
+}
+
+// An access policy applying to every path beginning with `prefix`
+#[derive(Clone, Debug)]
+pub struct PathPolicy {
+    pub prefix: String,
+    pub policy: AccessPolicy,
+}
+
+pub enum Access {
+    Read,
+    Write,
+}
+
+pub enum AuthFailure {
+    MissingCredentials,
+    InvalidCredentials,
+    MissingSignature,
+    InvalidSignature,
+    ExpiredSignature,
+}
+
+impl AuthFailure {
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::MissingCredentials => "This route requires an API key or token",
+            Self::InvalidCredentials => "The provided API key or token is not valid",
+            Self::MissingSignature => "This route requires a signed URL",
+            Self::InvalidSignature => "The URL signature is not valid",
+            Self::ExpiredSignature => "The URL signature has expired",
+        }
+    }
+
+    pub fn as_http_response(&self) -> HttpResponse {
+        let message = self.message();
+        match self {
+            Self::MissingCredentials | Self::InvalidCredentials => {
+                HttpResponse::Unauthorized()
+                    .header("www-authenticate", "Bearer")
+                    .body(message)
+            }
+            _ => HttpResponse::Forbidden().body(message),
+        }
+    }
+}
+
+// The identity of whoever made an authenticated request
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Identity {
+    pub name: String,
+    pub metadata: HashMap<String, String>,
+}
+
+impl From<&ApiKey> for Identity {
+    fn from(api_key: &ApiKey) -> Self {
+        Self {
+            name: api_key.name.clone(),
+            metadata: api_key.metadata.clone(),
+        }
+    }
+}
+
+impl From<Claims> for Identity {
+    fn from(claims: Claims) -> Self {
+        let mut metadata = HashMap::new();
+        if let Some(issuer) = claims.iss { metadata.insert("issuer".to_string(), issuer); }
+
+        Self {
+            name: claims.sub.unwrap_or_default(),
+            metadata,
+        }
+    }
+}
+
+// Comparing secrets in constant time, so that response timing
+// doesn't reveal how much of a guessed key was correct
+fn secrets_match(a: &str, b: &str) -> bool {
+    let a = a.as_bytes();
+    let b = b.as_bytes();
+    if a.len() != b.len() { return false; }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// "Authorization: Bearer abc" or "X-Api-Key: abc" -> "abc"
+fn presented_credential(req: &HttpRequest) -> Option<&str> {
+    let headers = req.headers();
+    let authorization = match headers.get("authorization") {
+        Some(value) => Some(value.to_str().ok()?),
+        None => None,
+    };
+    credential_in(authorization, headers.get("x-api-key").and_then(|value| value.to_str().ok()))
+}
+
+// The credential carried by an authorization header, or an API key
+// header, by whatever protocol they arrived
+pub fn credential_in<'a>(authorization: Option<&'a str>, api_key: Option<&'a str>) -> Option<&'a str> {
+    if let Some(token) = authorization.and_then(|value| value.strip_prefix("Bearer ")) {
+        return Some(token.trim());
+    }
+    api_key.map(|key| key.trim())
+}
+
+fn identify(presented: &str, config: &ServerConfig) -> Result<Identity, AuthFailure> {
+    if let Some(api_key) = config.api_keys.iter().find(|k| secrets_match(&k.key, presented)) {
+        return Ok(Identity::from(api_key));
+    }
+
+    let jwt = match &config.jwt {
+        Some(jwt) => jwt,
+        None => return Err(AuthFailure::InvalidCredentials),
+    };
+
+    match jsonwebtoken::decode::<Claims>(presented, &jwt.key, &jwt.validation()) {
+        Ok(token) => Ok(Identity::from(token.claims)),
+        Err(_) => Err(AuthFailure::InvalidCredentials),
+    }
+}
+
+fn authenticate(req: &HttpRequest, config: &ServerConfig) -> Result<Identity, AuthFailure> {
+    match presented_credential(req) {
+        Some(presented) => identify(presented, config),
+        None => Err(AuthFailure::MissingCredentials),
+    }
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Query string parameters that carry a URL signature, rather than
+// describing the image being requested
+pub const SIGNATURE_PARAMS: [&str; 2] = ["expires", "sig"];
+
+fn now_in_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn url_mac(secret: &str, message: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(message.as_bytes());
+    mac
+}
+
+// "secret", "message" -> the HMAC-SHA256 of "message", in hex
+#[cfg(feature = "webhooks")]
+pub fn hmac_hex(secret: &str, message: &str) -> String {
+    to_hex(&url_mac(secret, message).finalize().into_bytes())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) { return None; }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// "/cat.png?w=200", "secret", 1700000000 -> "/cat.png?w=200&expires=1700000000&sig=..."
+pub fn sign_url(path_and_query: &str, secret: &str, expires_at: u64) -> String {
+    let separator = if path_and_query.contains('?') { "&" } else { "?" };
+    let unsigned = format!("{}{}expires={}", path_and_query, separator, expires_at);
+    let signature = to_hex(&url_mac(secret, &unsigned).finalize().into_bytes());
+    format!("{}&sig={}", unsigned, signature)
+}
+
+// Checking a URL produced by `sign_url`. The signature covers the path
+// and every query parameter that precedes it, including the expiry
+pub fn verify_signed_url(req: &HttpRequest, config: &ServerConfig) -> Result<(), AuthFailure> {
+    let secret = match &config.url_signing_secret {
+        Some(secret) => secret,
+        None => return Err(AuthFailure::InvalidSignature),
+    };
+
+    let query = req.query_string();
+    let (unsigned_query, signature) = match query.rfind("sig=") {
+        Some(0) => ("", &query[4..]),
+        Some(i) if query[..i].ends_with('&') => (&query[..i - 1], &query[i + 4..]),
+        _ => return Err(AuthFailure::MissingSignature),
+    };
+
+    let signature = match from_hex(signature) {
+        Some(bytes) => bytes,
+        None => return Err(AuthFailure::InvalidSignature),
+    };
+
+    let unsigned = match unsigned_query.is_empty() {
+        true => req.path().to_string(),
+        false => format!("{}?{}", req.path(), unsigned_query),
+    };
+
+    if url_mac(secret, &unsigned).verify_slice(&signature).is_err() {
+        return Err(AuthFailure::InvalidSignature);
+    }
+
+    let expires_at = unsigned_query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("expires="))
+        .and_then(|value| value.parse::<u64>().ok());
+
+    match expires_at {
+        Some(expires_at) if expires_at >= now_in_seconds() => Ok(()),
+        Some(_) => Err(AuthFailure::ExpiredSignature),
+        None => Err(AuthFailure::InvalidSignature),
+    }
+}
+
+// Whether a request carries a valid, unexpired URL signature, whatever
+// its path's policy
+pub fn has_valid_signature(req: &HttpRequest, config: &ServerConfig) -> bool {
+    verify_signed_url(req, config).is_ok()
+}
+
+// "/cat.png" -> "/cat"
+// Policies may name an upload by one of its URLs, which governs every
+// view of it, since those are authorized against its upload path
+fn without_format(prefix: &str) -> &str {
+    match prefix.rsplit_once('.') {
+        Some((name, extension)) if crate::OUTPUT_FORMATS.contains(&extension) || extension == "jpg" => name,
+        _ => prefix,
+    }
+}
+
+// Whether `prefix` covers `path` in whole segments, so that "/admin"
+// governs "/admin/uploads" but not "/administrator.png"
+fn covers(prefix: &str, path: &str) -> bool {
+    if prefix.ends_with('/') {
+        return path.starts_with(prefix);
+    }
+    let prefix = without_format(prefix);
+    path == prefix || path.strip_prefix(prefix).is_some_and(|rest| rest.starts_with('/'))
+}
+
+// Choosing the policy of the longest matching path prefix, where
+// paths are relative to the configured base path
+fn policy_for_path<'a>(path: &str, policies: &'a [PathPolicy]) -> Option<&'a PathPolicy> {
+    policies
+        .iter()
+        .filter(|p| covers(&p.prefix, path))
+        .max_by_key(|p| p.prefix.len())
+}
+
+fn effective_policy(path: &str, config: &ServerConfig, access: Access) -> AccessPolicy {
+    let (policies, fallback) = match access {
+        Access::Read => (&config.read_policies, AccessPolicy::Public),
+        Access::Write => {
+            // Writes stay open only when no credentials are configured at all
+            let has_credentials = !config.api_keys.is_empty() || config.jwt.is_some();
+            let fallback = if has_credentials { AccessPolicy::Token } else { AccessPolicy::Public };
+            (&config.write_policies, fallback)
+        }
+    };
+
+    policy_for_path(path, policies)
+        .map(|p| p.policy)
+        .unwrap_or(fallback)
+}
+
+// Determining whether a request may proceed, and who made it if known.
+// Unless configured otherwise, images can be viewed by anyone, and
+// uploads are open until API keys or token settings are configured
+pub fn authorize(
+    req: &HttpRequest,
+    config: &ServerConfig,
+    access: Access,
+) -> Result<Option<Identity>, AuthFailure> {
+    authorize_as(req, config, access, config.route_path(req.path()))
+}
+
+// "/cat.webp"
+// Deciding as `authorize` does, for a request that's answered with the
+// image at `path` though it was made to another, like a IIIF one
+pub fn authorize_as(
+    req: &HttpRequest,
+    config: &ServerConfig,
+    access: Access,
+    path: &str,
+) -> Result<Option<Identity>, AuthFailure> {
+    match effective_policy(path, config, access) {
+        AccessPolicy::Public => Ok(None),
+        AccessPolicy::Token => authenticate(req, config).map(Some),
+        AccessPolicy::SignedUrl => verify_signed_url(req, config).map(|_| None),
+    }
+}
+
+// "cat" -> "/cat"
+// The path every read of an upload is authorized against, whichever of
+// its routes it arrives by, so that its variants, IIIF and Deep Zoom
+// views, and analyses are all governed by the same policy
+pub fn upload_path(filename: &str) -> String {
+    format!("/{}", filename)
+}
+
+// Deciding as `authorize_as` does, for a request that reads or writes
+// the upload named `filename`
+pub fn authorize_upload(
+    req: &HttpRequest,
+    config: &ServerConfig,
+    access: Access,
+    filename: &str,
+) -> Result<Option<Identity>, AuthFailure> {
+    authorize_as(req, config, access, &upload_path(filename))
+}
+
+// Deciding as `authorize_as` does, for a call that didn't arrive over
+// HTTP, like a gRPC one, given the credential it presented. Signatures
+// only sign URLs, so paths that need them refuse such calls
+#[cfg(feature = "grpc")]
+pub fn authorize_credential(
+    presented: Option<&str>,
+    config: &ServerConfig,
+    access: Access,
+    path: &str,
+) -> Result<Option<Identity>, AuthFailure> {
+    match (effective_policy(path, config, access), presented) {
+        (AccessPolicy::Public, _) => Ok(None),
+        (AccessPolicy::Token, Some(presented)) => identify(presented, config).map(Some),
+        (AccessPolicy::Token, None) => Err(AuthFailure::MissingCredentials),
+        (AccessPolicy::SignedUrl, _) => Err(AuthFailure::MissingSignature),
+    }
+}
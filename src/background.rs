@@ -0,0 +1,43 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+
+// Work that carries on after a response was sent, like caching the
+// variant it contained, counted so that shutting down can wait for it
+#[derive(Clone, Default)]
+pub struct BackgroundWork {
+    pending: Arc<AtomicUsize>,
+}
+
+impl BackgroundWork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn spawn<F: Future<Output = ()> + 'static>(&self, task: F) {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        let pending = self.pending.clone();
+
+        actix_web::rt::spawn(async move {
+            task.await;
+            pending.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    // Waiting up to `timeout` for whatever is pending to finish,
+    // returning how much didn't
+    pub async fn finish(&self, timeout: Duration) -> usize {
+        let started = Instant::now();
+
+        while self.pending() > 0 && started.elapsed() < timeout {
+            actix_web::rt::time::delay_for(Duration::from_millis(25)).await;
+        }
+        self.pending()
+    }
+}
@@ -0,0 +1,632 @@
+use std::path::PathBuf;
+use std::path::Path;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+
+use crate::{ServerConfig, ImageServiceFailure, Caches, auth, build_path_to_master};
+use crate::analysis::ImageName;
+use crate::storage::is_partial_write;
+
+
+// Expired variants are looked for at least this often, but no more
+// often than the minimum, even when they expire sooner
+const MIN_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+// Partial writes older than this are taken to have been abandoned, rather
+// than still being written
+const ABANDONED_WRITE_MIN_AGE: Duration = Duration::from_secs(60 * 60);
+
+// Request counts are kept for at most this many variants
+const MAX_TRACKED_VARIANTS: usize = 10_000;
+
+// The variants reported as hottest by default, and at most
+const DEFAULT_HOTTEST: usize = 10;
+const MAX_HOTTEST: usize = 100;
+
+// "cat", "w=200", "png" -> "cat?w=200.png"
+// The name of a variant, which identifies its upload, the instructions
+// it was processed with, and the format it was encoded in
+pub fn variant_name(filename: &str, cache_key: &str, extension: &str) -> String {
+    format!("{}?{}.{}", filename, cache_key, extension)
+}
+
+// "cat?w=200.png" -> "4a9c1e0b7f3d52a8e6b1c0d9f2a37e58"
+// Cached files are named after a hash of the variant name, and so of its
+// upload and query, since query strings aren't safe to use in filenames
+// on every platform
+pub fn hash_of(name: &str) -> String {
+    let digest = format!("{:x}", Sha256::digest(name.as_bytes()));
+    digest[..32].to_string()
+}
+
+fn build_path_to_variants_dir(config: &ServerConfig) -> String {
+    config.build_path_to_cache("variants")
+}
+
+// "cat?w=200.png", "png" -> "./images/.variants/4a9c…7e58.png"
+pub fn build_path_to_variant(config: &ServerConfig, variant_name: &str, extension: &str) -> String {
+    let dir = build_path_to_variants_dir(config);
+    format!("{}/{}{}.{}", dir, config.shard(variant_name), hash_of(variant_name), extension)
+}
+
+// What a hashed variant was derived from, stored beside it
+#[derive(Serialize, Deserialize, Debug)]
+struct IndexEntry {
+    filename: String,
+    params: String,
+    extension: String,
+    // The variant's length in bytes, to recognize incomplete copies
+    #[serde(default)]
+    size: Option<u64>,
+}
+
+// "./images/.variants/4a9c…7e58.png" -> "./images/.variants/4a9c…7e58.json"
+fn build_path_to_index_entry(variant: &Path) -> PathBuf {
+    variant.with_extension("json")
+}
+
+// Saving a newly processed variant, and recording which upload it
+// was derived from, so that it can be purged along with its upload
+pub fn store_variant(
+    config: &ServerConfig,
+    filename: &str,
+    cache_key: &str,
+    extension: &str,
+    buffer: &[u8],
+) -> std::io::Result<()> {
+    let variant_name = variant_name(filename, cache_key, extension);
+    let filepath = build_path_to_variant(config, &variant_name, extension);
+
+    let entry = IndexEntry {
+        filename: filename.to_string(),
+        params: cache_key.to_string(),
+        extension: extension.to_string(),
+        size: Some(buffer.len() as u64),
+    };
+    let contents = serde_json::to_vec(&entry)?;
+    let index_entry = build_path_to_index_entry(Path::new(&filepath));
+    config.storage.put(&index_entry.to_string_lossy(), &contents)?;
+
+    config.storage.put(&filepath, buffer)
+}
+
+// "cat", "w=200", "png" for every cached rendition, as recorded when it
+// was saved. Renditions saved before index entries existed are left out
+pub fn recorded_variants(config: &ServerConfig) -> Vec<(String, String, String)> {
+    cached_renditions(config)
+        .iter()
+        .filter_map(|path| load_index_entry(config, path))
+        .map(|entry| (entry.filename, entry.params, entry.extension))
+        .collect()
+}
+
+// Analyses are JSON themselves, and don't have index entries
+fn has_index_entry(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension != "json")
+}
+
+fn load_index_entry(config: &ServerConfig, variant: &Path) -> Option<IndexEntry> {
+    if !has_index_entry(variant) { return None; }
+    let contents = config.storage.get(&build_path_to_index_entry(variant).to_string_lossy()).ok()?;
+    serde_json::from_slice(&contents).ok()
+}
+
+// Discarding cached files that were left empty, or that don't have the
+// length recorded when they were saved, so that they're produced again
+pub fn discard_if_damaged(config: &ServerConfig, path: &str) {
+    let size = match config.storage.metadata(path) {
+        Some(metadata) => metadata.size,
+        None => return,
+    };
+
+    let path = Path::new(path);
+    let expected = load_index_entry(config, path).and_then(|entry| entry.size);
+    if size == 0 || expected.is_some_and(|expected| expected != size) {
+        remove_variant(config, path);
+    }
+}
+
+// How long ago a master was replaced, if it was replaced after the
+// variant at `path` was produced from it
+fn staleness(config: &ServerConfig, master: SystemTime, path: &str) -> Option<Duration> {
+    let variant = config.storage.metadata(path)?.modified?;
+    match master > variant {
+        true => Some(SystemTime::now().duration_since(master).unwrap_or_default()),
+        false => None,
+    }
+}
+
+fn master_modified(config: &ServerConfig, filename: &str) -> Option<SystemTime> {
+    config.storage.metadata(&build_path_to_master(config, filename))?.modified
+}
+
+pub fn is_stale(config: &ServerConfig, filename: &str, path: &str) -> bool {
+    master_modified(config, filename).and_then(|master| staleness(config, master, path)).is_some()
+}
+
+pub enum Freshness {
+    Fresh,
+    // Produced before its master was replaced, but still fit to serve
+    // while a fresh one is produced
+    Stale,
+    // Produced from a master that has since been replaced or deleted,
+    // and deleted along with everything else derived from it
+    Discarded,
+}
+
+// Discarding the cached variants and analyses of uploads that have been
+// deleted or replaced since, so that they aren't served any more. Those
+// whose master was replaced within `window` may still be served while
+// they're revalidated
+pub fn check_freshness(config: &ServerConfig, filename: &str, path: &str, window: Option<Duration>) -> Freshness {
+    let master = match master_modified(config, filename) {
+        Some(master) => master,
+        None => {
+            // Searching for the rest only once, when the first is found
+            if config.storage.exists(path) {
+                remove_variants_of(config, filename);
+            }
+            return Freshness::Discarded;
+        }
+    };
+
+    let age = match staleness(config, master, path) {
+        Some(age) => age,
+        None => return Freshness::Fresh,
+    };
+
+    if window.is_some_and(|window| age <= window) {
+        return Freshness::Stale;
+    }
+
+    remove_variants_of(config, filename);
+    Freshness::Discarded
+}
+
+// "cat?w=200.png" -> true, "cat.webp" -> false
+// Variants used to be stored beside their masters, named with their
+// query string. Those are still recognized so they can be cleaned up
+fn is_legacy_variant(filename: &str) -> bool {
+    filename.contains('?')
+}
+
+// Every complete file within `dir`, leaving out those still being written
+fn list_files(config: &ServerConfig, dir: &str) -> Vec<PathBuf> {
+    config.storage.list(dir)
+        .into_iter()
+        .filter(|path| !is_partial_write(path))
+        .map(PathBuf::from)
+        .collect()
+}
+
+fn file_name(path: &Path) -> &str {
+    path.file_name().and_then(|name| name.to_str()).unwrap_or("")
+}
+
+// Every cached rendition of an upload on disk
+fn cached_renditions(config: &ServerConfig) -> Vec<PathBuf> {
+    // Masters are never considered when the cache has a directory of its own
+    let legacy = match &config.cache_dir {
+        Some(_) => vec![],
+        None => list_files(config, &config.uploads_dir)
+            .into_iter()
+            .filter(|path| is_legacy_variant(file_name(path)))
+            .collect(),
+    };
+
+    list_files(config, &build_path_to_variants_dir(config))
+        .into_iter()
+        .filter(|path| path.extension().is_some_and(|extension| extension != "json"))
+        .chain(legacy)
+        .collect()
+}
+
+// Every cached rendition on disk, including synthesized placeholders
+pub fn cached_variants(config: &ServerConfig) -> Vec<PathBuf> {
+    let mut variants = cached_renditions(config);
+    variants.extend(list_files(config, &config.build_path_to_cache("placeholder")));
+    variants
+}
+
+// Every cached analysis on disk
+pub fn cached_analyses(config: &ServerConfig) -> Vec<PathBuf> {
+    list_files(config, &config.build_path_to_cache("analysis"))
+}
+
+// Deleting a cached file, along with its index entry if it has one
+fn remove_variant(config: &ServerConfig, path: &Path) -> bool {
+    if has_index_entry(path) {
+        let _ = config.storage.delete(&build_path_to_index_entry(path).to_string_lossy());
+    }
+    config.storage.delete(&path.to_string_lossy()).is_ok()
+}
+
+// "cat", "cat?w=200.png" -> true, "cat", "cat.blurhash-4x3.json" -> true
+// Whether a named variant or analysis was derived from an upload
+pub fn is_derived_from(filename: &str, cached: &str) -> bool {
+    if let Some(query_and_extension) = cached.strip_prefix(&format!("{}?", filename)) {
+        return !query_and_extension.is_empty();
+    }
+
+    // Analyses are named after their upload, the kind of analysis, and ".json"
+    match cached.strip_prefix(&format!("{}.", filename)) {
+        Some(kind_and_extension) => kind_and_extension.matches('.').count() == 1,
+        None => false,
+    }
+}
+
+// Deleting every cached rendition and analysis of one upload from disk,
+// returning how many were removed
+pub fn remove_variants_of(config: &ServerConfig, filename: &str) -> usize {
+    let analyses = cached_analyses(config);
+
+    cached_renditions(config)
+        .into_iter()
+        .chain(analyses)
+        .filter(|path| match load_index_entry(config, path) {
+            Some(entry) => entry.filename == filename,
+            None => is_derived_from(filename, file_name(path)),
+        })
+        .filter(|path| remove_variant(config, path))
+        .count()
+}
+
+// "./images/.variants/4a9c…7e58.png" -> "cat", "cat.blurhash-4x3.json" -> "cat"
+// The upload a cached rendition or analysis was derived from
+fn derived_from(config: &ServerConfig, path: &Path) -> Option<String> {
+    if let Some(entry) = load_index_entry(config, path) {
+        return Some(entry.filename);
+    }
+
+    let name = file_name(path);
+    if is_legacy_variant(name) {
+        return name.split('?').next().map(|filename| filename.to_string());
+    }
+
+    match path.extension().is_some_and(|extension| extension == "json") {
+        true => name.rsplitn(3, '.').nth(2).map(|filename| filename.to_string()),
+        false => None,
+    }
+}
+
+// Deleting cached renditions and analyses whose upload no longer exists,
+// returning how many were removed
+pub fn remove_orphaned_variants(config: &ServerConfig) -> usize {
+    let analyses = cached_analyses(config);
+
+    cached_renditions(config)
+        .into_iter()
+        .chain(analyses)
+        .filter(|path| match derived_from(config, path) {
+            Some(filename) => !config.storage.exists(&build_path_to_master(config, &filename)),
+            None => false,
+        })
+        .filter(|path| remove_variant(config, path))
+        .count()
+}
+
+// Deleting every cached rendition, placeholder and analysis on disk,
+// returning how many were removed. Masters are left intact
+pub fn remove_all_variants(config: &ServerConfig) -> usize {
+    cached_variants(config)
+        .into_iter()
+        .chain(cached_analyses(config))
+        .filter(|path| remove_variant(config, path))
+        .count()
+}
+
+#[derive(Serialize)]
+struct PurgeResult {
+    filename: String,
+    purged: usize,
+}
+
+// Respond to a request to delete the cached renditions of an upload,
+// so that they're re-derived from its master when next requested
+pub async fn serve_purge_via_http(
+    req: HttpRequest,
+    name: web::Path<ImageName>,
+    config: web::Data<ServerConfig>,
+    caches: web::Data<Caches>,
+) -> HttpResponse {
+    if let Err(failure) = auth::authorize(&req, &config, auth::Access::Write) {
+        return failure.as_http_response();
+    }
+
+    let filename = name.into_inner().filename;
+    caches.memory.remove_where(|variant_name| is_derived_from(&filename, variant_name));
+
+    let target = filename.clone();
+    let purged = web::block(move || match config.storage.exists(&build_path_to_master(&config, &target)) {
+        true => Ok(Some(remove_variants_of(&config, &target))),
+        false => Ok::<_, ()>(None),
+    }).await;
+    match purged {
+        Ok(Some(purged)) => HttpResponse::Ok().json(PurgeResult { filename, purged }),
+        Ok(None) => ImageServiceFailure::ImageDoesNotExist.as_http_response(&req),
+        Err(_) => HttpResponse::InternalServerError().body("Cached variants could not be purged"),
+    }
+}
+
+#[derive(Serialize)]
+struct FlushResult {
+    purged: usize,
+}
+
+// Respond to a request to delete the entire cache, like after upgrading
+// the encoder, so that every variant is re-derived from its master
+pub async fn serve_flush_via_http(
+    req: HttpRequest,
+    config: web::Data<ServerConfig>,
+    caches: web::Data<Caches>,
+) -> HttpResponse {
+    if let Err(failure) = auth::authorize(&req, &config, auth::Access::Write) {
+        return failure.as_http_response();
+    }
+
+    caches.memory.clear();
+
+    match web::block(move || Ok::<_, ()>(remove_all_variants(&config))).await {
+        Ok(purged) => HttpResponse::Ok().json(FlushResult { purged }),
+        Err(_) => HttpResponse::InternalServerError().body("The cache could not be flushed"),
+    }
+}
+
+// The last time a file was read or written, as far as the filesystem knows
+fn last_used(config: &ServerConfig, path: &Path) -> Option<SystemTime> {
+    let metadata = config.storage.metadata(&path.to_string_lossy())?;
+    let modified = metadata.modified?;
+    match metadata.accessed {
+        Some(accessed) => Some(accessed.max(modified)),
+        None => Some(modified),
+    }
+}
+
+// Deleting variants that haven't been used within `max_age`,
+// returning how many were removed
+pub fn remove_expired_variants(config: &ServerConfig, max_age: Duration) -> usize {
+    let now = SystemTime::now();
+    cached_variants(config)
+        .into_iter()
+        .filter(|path| {
+            let age = last_used(config, path).and_then(|time| now.duration_since(time).ok());
+            age.is_some_and(|age| age > max_age)
+        })
+        .filter(|path| remove_variant(config, path))
+        .count()
+}
+
+// Periodically deleting expired variants, for as long as the server runs
+pub async fn sweep_expired_variants(
+    config: web::Data<ServerConfig>,
+    caches: web::Data<Caches>,
+    max_age: Duration,
+) {
+    let mut interval = actix_web::rt::time::interval(max_age.clamp(MIN_SWEEP_INTERVAL, MAX_SWEEP_INTERVAL));
+    loop {
+        interval.tick().await;
+        let config = config.clone();
+        match web::block(move || Ok::<_, ()>(remove_expired_variants(&config, max_age))).await {
+            Ok(0) => {},
+            Ok(removed) => {
+                caches.stats.expired.fetch_add(removed as u64, Ordering::Relaxed);
+                tracing::info!(removed, "removed expired variants");
+            }
+            Err(_) => tracing::error!("failed to remove expired variants"),
+        }
+    }
+}
+
+// Deleting files that writes were abandoned partway through, like when
+// the server crashed, returning how many were removed
+pub fn remove_abandoned_writes(config: &ServerConfig) -> usize {
+    let mut dirs = vec![config.uploads_dir.as_str()];
+    dirs.extend(config.cache_dir.as_deref());
+
+    dirs.into_iter()
+        .flat_map(|dir| config.storage.list(dir))
+        .filter(|path| is_partial_write(path))
+        .filter(|path| {
+            let age = config.storage.metadata(path)
+                .and_then(|metadata| metadata.modified)
+                .and_then(|modified| modified.elapsed().ok());
+            age.is_some_and(|age| age >= ABANDONED_WRITE_MIN_AGE)
+        })
+        .filter(|path| config.storage.delete(path).is_ok())
+        .count()
+}
+
+// Periodically deleting what was derived from deleted uploads, even if
+// it's never requested again, and what abandoned writes left behind
+pub async fn sweep_orphaned_variants(config: web::Data<ServerConfig>) {
+    let mut interval = actix_web::rt::time::interval(MAX_SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let orphans = config.clone();
+        match web::block(move || Ok::<_, ()>(remove_orphaned_variants(&orphans))).await {
+            Ok(0) => {},
+            Ok(removed) => tracing::info!(removed, "removed variants of deleted uploads"),
+            Err(_) => tracing::error!("failed to remove variants of deleted uploads"),
+        }
+
+        let abandoned = config.clone();
+        match web::block(move || Ok::<_, ()>(remove_abandoned_writes(&abandoned))).await {
+            Ok(0) => {},
+            Ok(removed) => tracing::info!(removed, "removed abandoned partial writes"),
+            Err(_) => tracing::error!("failed to remove abandoned partial writes"),
+        }
+    }
+}
+
+// Where a requested variant was found. Bypassing the cache means it
+// was processed again on purpose, like with `?nocache=1`
+#[derive(Clone, Copy)]
+pub enum Lookup {
+    Memory,
+    Disk,
+    Miss,
+    Bypass,
+}
+
+impl Lookup {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Lookup::Memory => "memory",
+            Lookup::Disk => "disk",
+            Lookup::Miss => "miss",
+            Lookup::Bypass => "bypass",
+        }
+    }
+
+    // The `X-Cache` header: "HIT", "MISS" or "BYPASS"
+    pub fn x_cache(&self) -> &'static str {
+        match self {
+            Lookup::Memory | Lookup::Disk => "HIT",
+            Lookup::Miss => "MISS",
+            Lookup::Bypass => "BYPASS",
+        }
+    }
+}
+
+// Counting how variant requests are served, to help size the cache
+pub struct CacheStats {
+    memory_hits: AtomicU64,
+    disk_hits: AtomicU64,
+    misses: AtomicU64,
+    bypasses: AtomicU64,
+    expired: AtomicU64,
+    requests: Mutex<HashMap<String, u64>>,
+}
+
+impl CacheStats {
+    pub fn new() -> Self {
+        Self {
+            memory_hits: AtomicU64::new(0),
+            disk_hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            bypasses: AtomicU64::new(0),
+            expired: AtomicU64::new(0),
+            requests: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn record(&self, variant_name: &str, lookup: Lookup) {
+        let counter = match lookup {
+            Lookup::Memory => &self.memory_hits,
+            Lookup::Disk => &self.disk_hits,
+            Lookup::Miss => &self.misses,
+            Lookup::Bypass => &self.bypasses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        let mut requests = self.requests.lock().unwrap();
+        if let Some(count) = requests.get_mut(variant_name) {
+            *count += 1;
+        } else if requests.len() < MAX_TRACKED_VARIANTS {
+            requests.insert(variant_name.to_string(), 1);
+        }
+    }
+
+    // How many lookups found variants each way, so far
+    pub fn lookups(&self) -> Vec<(Lookup, u64)> {
+        vec![
+            (Lookup::Memory, self.memory_hits.load(Ordering::Relaxed)),
+            (Lookup::Disk, self.disk_hits.load(Ordering::Relaxed)),
+            (Lookup::Miss, self.misses.load(Ordering::Relaxed)),
+            (Lookup::Bypass, self.bypasses.load(Ordering::Relaxed)),
+        ]
+    }
+
+    // The most requested variants, most requested first
+    fn hottest(&self, count: usize) -> Vec<HotVariant> {
+        let requests = self.requests.lock().unwrap();
+        let mut hottest: Vec<HotVariant> = requests
+            .iter()
+            .map(|(variant, requests)| HotVariant { variant: variant.clone(), requests: *requests })
+            .collect();
+
+        hottest.sort_by(|a, b| b.requests.cmp(&a.requests).then_with(|| a.variant.cmp(&b.variant)));
+        hottest.truncate(count);
+        hottest
+    }
+}
+
+#[derive(Serialize)]
+struct HotVariant {
+    variant: String,
+    requests: u64,
+}
+
+#[derive(Serialize)]
+struct DiskUsage {
+    entries: usize,
+    bytes: u64,
+    expired: u64,
+}
+
+#[derive(Serialize)]
+struct Lookups {
+    memory_hits: u64,
+    disk_hits: u64,
+    misses: u64,
+    bypasses: u64,
+}
+
+#[derive(Serialize)]
+struct CacheReport {
+    disk: DiskUsage,
+    memory: crate::hot_cache::Usage,
+    decoded: crate::decoded_cache::Usage,
+    lookups: Lookups,
+    hottest: Vec<HotVariant>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StatsInstructions {
+    top: Option<usize>,
+}
+
+// Respond to a request for how full the cache is and how well it's working
+pub async fn serve_stats_via_http(
+    req: HttpRequest,
+    instructions: web::Query<StatsInstructions>,
+    config: web::Data<ServerConfig>,
+    caches: web::Data<Caches>,
+) -> HttpResponse {
+    if let Err(failure) = auth::authorize(&req, &config, auth::Access::Write) {
+        return failure.as_http_response();
+    }
+
+    let disk = web::block(move || {
+        let variants = cached_variants(&config);
+        let bytes = variants.iter().filter_map(|path| config.storage.metadata(&path.to_string_lossy())).map(|m| m.size).sum();
+        Ok::<_, ()>((variants.len(), bytes))
+    });
+
+    let (entries, bytes) = match disk.await {
+        Ok(usage) => usage,
+        Err(_) => return HttpResponse::InternalServerError().body("The cache could not be measured"),
+    };
+
+    let stats = &caches.stats;
+    let report = CacheReport {
+        disk: DiskUsage { entries, bytes, expired: stats.expired.load(Ordering::Relaxed) },
+        memory: caches.memory.usage(),
+        decoded: caches.decoded.usage(),
+        lookups: Lookups {
+            memory_hits: stats.memory_hits.load(Ordering::Relaxed),
+            disk_hits: stats.disk_hits.load(Ordering::Relaxed),
+            misses: stats.misses.load(Ordering::Relaxed),
+            bypasses: stats.bypasses.load(Ordering::Relaxed),
+        },
+        hottest: stats.hottest(instructions.top.unwrap_or(DEFAULT_HOTTEST).min(MAX_HOTTEST)),
+    };
+
+    HttpResponse::Ok().json(report)
+}
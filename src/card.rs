@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use actix_web::{web, HttpRequest, HttpResponse};
+use image::{DynamicImage, Rgba, RgbaImage};
+use image::imageops::FilterType;
+use serde::Deserialize;
+
+use crate::{
+    auth,
+    cache,
+    Bytes,
+    font,
+    ImageServiceFailure,
+    ServerConfig,
+    encode_image,
+    image_buffer_as_http_response,
+    processing_query_string,
+    run_blocking,
+    try_loading_master,
+    try_streaming_preprocessed_file_from_disk,
+};
+use crate::placeholder::mix;
+
+
+// Social preview cards, like those linked by `og:image`: an upload cropped
+// to fill 1200x630, darkened by a gradient, with text drawn on top. What
+// goes where is decided by templates in the configuration, and the text
+// of each field is given in the query string, like
+// `GET /card/blog.png?image=harbor&title=Hello`
+
+pub const CARD_WIDTH: u32 = 1200;
+pub const CARD_HEIGHT: u32 = 630;
+
+// Longer text is refused, since it couldn't fit on a card anyway
+const MAX_FIELD_LENGTH: usize = 300;
+
+// A text field of a card template, drawn with its top-left corner at
+// (x, y), wrapped to `max_width` pixels and cut short after `max_lines`
+#[derive(Clone, Debug)]
+pub struct CardField {
+    // "title", the query parameter its text is given by
+    pub name: String,
+    pub x: u32,
+    pub y: u32,
+    // How many pixels each pixel of the built-in font is drawn as
+    pub size: u32,
+    pub color: Rgba<u8>,
+    pub max_width: u32,
+    pub max_lines: u32,
+}
+
+impl CardField {
+    pub fn new(name: &str, x: u32, y: u32) -> Self {
+        Self {
+            name: name.to_string(),
+            x,
+            y,
+            size: 6,
+            color: Rgba([255, 255, 255, 255]),
+            max_width: CARD_WIDTH.saturating_sub(2 * x),
+            max_lines: 2,
+        }
+    }
+
+    pub fn with_size(mut self, size: u32) -> Self {
+        self.size = size.max(1);
+        self
+    }
+
+    pub fn with_color(mut self, color: Rgba<u8>) -> Self {
+        self.color = color;
+        self
+    }
+
+    pub fn with_max_width(mut self, width: u32) -> Self {
+        self.max_width = width;
+        self
+    }
+
+    pub fn with_max_lines(mut self, lines: u32) -> Self {
+        self.max_lines = lines.max(1);
+        self
+    }
+}
+
+// A layout for cards, requested with `/card/{name}.{extension}`
+#[derive(Clone, Debug)]
+pub struct CardTemplate {
+    pub name: String,
+    // The upload drawn when a request doesn't name one with `?image=`
+    pub image: Option<String>,
+    // Drawn beneath the image, where it's transparent
+    pub background: Rgba<u8>,
+    // Drawn over the image, from the top of the card to the bottom
+    pub gradient: (Rgba<u8>, Rgba<u8>),
+    pub fields: Vec<CardField>,
+}
+
+impl CardTemplate {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            image: None,
+            background: Rgba([0, 0, 0, 255]),
+            gradient: (Rgba([0, 0, 0, 0]), Rgba([0, 0, 0, 192])),
+            fields: vec![],
+        }
+    }
+
+    pub fn with_image(mut self, filename: &str) -> Self {
+        self.image = Some(filename.to_string());
+        self
+    }
+
+    pub fn with_background(mut self, color: Rgba<u8>) -> Self {
+        self.background = color;
+        self
+    }
+
+    pub fn with_gradient(mut self, top: Rgba<u8>, bottom: Rgba<u8>) -> Self {
+        self.gradient = (top, bottom);
+        self
+    }
+
+    pub fn with_field(mut self, field: CardField) -> Self {
+        self.fields.push(field);
+        self
+    }
+}
+
+// "card=blog&title=Hello" -> true
+// Whether a cached variant is a card, rather than a processed image
+pub fn is_card(params: &str) -> bool {
+    params.starts_with("card=")
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CardDescription {
+    template: String,
+    extension: String,
+}
+
+// Drawing `color` over `pixel`, as far as its alpha lets it
+fn blend(pixel: Rgba<u8>, color: Rgba<u8>) -> Rgba<u8> {
+    let opaque = Rgba([color[0], color[1], color[2], pixel[3].max(color[3])]);
+    mix(pixel, opaque, color[3] as f32 / 255.0)
+}
+
+// "the quick brown fox", 10 characters a line, at most 2 lines
+// -> ["the quick", "brown fox"]
+// Breaking text between words, where it can, and ending it with an
+// ellipsis when there's more than fits
+fn wrap(text: &str, columns: usize, max_lines: usize) -> Vec<String> {
+    let columns = columns.max(1);
+    let mut lines: Vec<String> = vec![];
+    let mut current = String::new();
+    for word in text.split_whitespace() {
+        let mut word = word.to_string();
+        loop {
+            let needed = match current.is_empty() {
+                true => word.chars().count(),
+                false => current.chars().count() + 1 + word.chars().count(),
+            };
+            if needed <= columns {
+                if !current.is_empty() { current.push(' '); }
+                current.push_str(&word);
+                break;
+            }
+            if !current.is_empty() {
+                lines.push(std::mem::take(&mut current));
+                continue;
+            }
+            // Words longer than a whole line are broken wherever they must be
+            let rest = word.chars().skip(columns).collect();
+            lines.push(word.chars().take(columns).collect());
+            word = rest;
+        }
+    }
+    if !current.is_empty() {
+        lines.push(current);
+    }
+
+    if lines.len() > max_lines {
+        lines.truncate(max_lines);
+        let last = lines.last_mut().unwrap();
+        let kept: String = last.chars().take(columns.saturating_sub(3)).collect();
+        *last = format!("{}...", kept.trim_end());
+    }
+    lines
+}
+
+fn render_card(template: &CardTemplate, image: DynamicImage, text: &HashMap<String, String>) -> DynamicImage {
+    let mut canvas = RgbaImage::from_pixel(CARD_WIDTH, CARD_HEIGHT, template.background);
+
+    let image = image.resize_to_fill(CARD_WIDTH, CARD_HEIGHT, FilterType::Lanczos3).to_rgba8();
+    for (pixel, drawn) in canvas.pixels_mut().zip(image.pixels()) {
+        *pixel = blend(*pixel, *drawn);
+    }
+
+    let (top, bottom) = template.gradient;
+    for (_, y, pixel) in canvas.enumerate_pixels_mut() {
+        let t = y as f32 / (CARD_HEIGHT - 1) as f32;
+        *pixel = blend(*pixel, mix(top, bottom, t));
+    }
+
+    for field in &template.fields {
+        let text = match text.get(&field.name) {
+            Some(text) => text,
+            None => continue,
+        };
+        let columns = (field.max_width + field.size) / ((font::GLYPH_WIDTH + 1) * field.size);
+        let line_height = (font::GLYPH_HEIGHT + 3) * field.size;
+        for (i, line) in wrap(text, columns as usize, field.max_lines as usize).iter().enumerate() {
+            let y = field.y as i64 + (i as u32 * line_height) as i64;
+            font::draw_text(&mut canvas, line, field.x as i64, y, field.size, field.color);
+        }
+    }
+
+    DynamicImage::ImageRgba8(canvas)
+}
+
+// Rendering a card of the upload `filename`, and caching it beside its
+// variants, so that it's purged along with them
+fn try_rendering_card(
+    config: &ServerConfig,
+    template: &CardTemplate,
+    filename: &str,
+    cache_key: &str,
+    extension: &str,
+    text: &HashMap<String, String>,
+) -> Result<Bytes, ImageServiceFailure> {
+    let card = render_card(template, try_loading_master(config, filename)?, text);
+    let buffer = encode_image(&card, extension)?;
+    let _ = cache::store_variant(config, filename, cache_key, extension, &buffer);
+    Ok(buffer)
+}
+
+// Respond to a request for a social preview card, like
+// `GET /card/blog.png?image=harbor&title=Hello&author=Ada`
+pub async fn serve_card_via_http(
+    req: HttpRequest,
+    desc: web::Path<CardDescription>,
+    query: web::Query<HashMap<String, String>>,
+    config: web::Data<ServerConfig>,
+) -> HttpResponse {
+    if let Err(failure) = auth::authorize(&req, &config, auth::Access::Read) {
+        return failure.as_http_response();
+    }
+
+    let CardDescription { template, extension } = desc.into_inner();
+    let mut text = query.into_inner();
+
+    if !config.serves_format(&extension) {
+        return config.unserved_format(&extension).as_http_response(&req);
+    }
+
+    let template = match config.card_templates.iter().find(|candidate| candidate.name == template) {
+        Some(template) => template.clone(),
+        None => return HttpResponse::NotFound().body(format!("There's no card template named {:?}", template)),
+    };
+
+    let filename = match text.remove("image").or_else(|| template.image.clone()) {
+        Some(filename) => filename,
+        None => return HttpResponse::BadRequest().body("Name the upload to draw with `image`"),
+    };
+    if let Err(failure) = auth::authorize_upload(&req, &config, auth::Access::Read, &filename) {
+        return failure.as_http_response();
+    }
+
+    if let Some((name, _)) = text.iter().find(|(_, value)| value.chars().count() > MAX_FIELD_LENGTH) {
+        let message = format!("`{}` may be at most {} characters long", name, MAX_FIELD_LENGTH);
+        return HttpResponse::BadRequest().body(message);
+    }
+
+    let cache_key = format!("card={}&{}", template.name, processing_query_string(&req));
+    let filepath = cache::build_path_to_variant(&config, &cache::variant_name(&filename, &cache_key, &extension), &extension);
+    let (checked_config, checked_path, checked_filename) = (config.clone(), filepath.clone(), filename.clone());
+    let stale = run_blocking(move || {
+        cache::discard_if_damaged(&checked_config, &checked_path);
+        Ok(cache::is_stale(&checked_config, &checked_filename, &checked_path))
+    }).await;
+    if let Ok(false) = stale {
+        if let Some(response) = try_streaming_preprocessed_file_from_disk(&config, &filepath, &req).await {
+            return response;
+        }
+    }
+
+    let format = extension.clone();
+    let rendered = run_blocking(move || try_rendering_card(&config, &template, &filename, &cache_key, &format, &text)).await;
+    match rendered {
+        Ok(buffer) => image_buffer_as_http_response(buffer, &extension),
+        Err(failure) => failure.as_http_response(&req),
+    }
+}
@@ -0,0 +1,61 @@
+use actix_web::HttpRequest;
+
+use crate::ProcessingInstructions;
+use crate::negotiation::Negotiation;
+
+
+// The hints a client is invited to send, via `Accept-CH`
+pub const ACCEPTED_CLIENT_HINTS: &str = "Sec-CH-DPR, Sec-CH-Width, DPR, Width";
+
+// Device pixel ratios beyond this aren't worth the extra bytes
+const MAX_DPR: f32 = 4.0;
+
+fn consult_number(req: &HttpRequest, negotiation: &mut Negotiation, headers: &[&'static str]) -> Option<f32> {
+    let mut value = None;
+    for header in headers {
+        let parsed = negotiation
+            .consult(req, header)
+            .and_then(|v| v.trim().parse::<f32>().ok())
+            .filter(|v| v.is_finite() && *v > 0.0);
+        value = value.or(parsed);
+    }
+    value
+}
+
+fn scale(dimension: u32, dpr: f32) -> u32 {
+    ((dimension as f32) * dpr).round().max(1.0) as u32
+}
+
+// Treating client hints as implicit processing instructions. Explicit
+// dimensions are multiplied by the device pixel ratio, and the layout
+// width (which already accounts for it) fills in a missing width.
+// Clients asking to save data are served at a ratio of 1. Variants
+// produced from hints are identified by their effective dimensions,
+// so they share cached variants with explicit requests
+pub fn apply_client_hints(
+    req: &HttpRequest,
+    negotiation: &mut Negotiation,
+    optional: &mut ProcessingInstructions,
+) {
+    let dpr = consult_number(req, negotiation, &["Sec-CH-DPR", "DPR"]);
+    let width = consult_number(req, negotiation, &["Sec-CH-Width", "Width"]);
+    let save_data = negotiation
+        .consult(req, "Save-Data")
+        .map(|v| v.trim().eq_ignore_ascii_case("on"))
+        .unwrap_or(false);
+
+    let dpr = match save_data {
+        true => 1.0,
+        false => dpr.unwrap_or(1.0).min(MAX_DPR),
+    };
+
+    if let Some(w) = optional.w {
+        optional.w = Some(scale(w, dpr));
+    } else if let Some(width) = width {
+        optional.w = Some(width.round().max(1.0) as u32);
+    }
+
+    if let Some(h) = optional.h {
+        optional.h = Some(scale(h, dpr));
+    }
+}
@@ -0,0 +1,1208 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+use actix_web::http::StatusCode;
+use image::Rgba;
+use serde::{Deserialize, Serialize};
+
+use crate::{ServerConfig, CardField, CardTemplate, ApiKey, JwtConfig, AccessPolicy, OriginConfig, EncodingDefaults, PngCompression, LogFormat, LoggingConfig, AccessLogConfig, AccessLogFormat, OUTPUT_FORMATS};
+
+
+// Settings for the server, read from a file like `imogen.toml`. Every
+// section is optional, and unrecognized keys are refused, so that typos
+// are found before they're silently ignored
+#[derive(Deserialize, Serialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub port: Option<u64>,
+    pub bind_addresses: Vec<String>,
+    pub uploads_dir: Option<String>,
+    pub cache_dir: Option<String>,
+    pub base_path: Option<String>,
+    pub unix_socket: Option<String>,
+    // "0.0.0.0:50051", for serving gRPC too, with the `grpc` feature
+    pub grpc_address: Option<String>,
+    // How long stopping waits for unfinished work, in seconds
+    pub shutdown_timeout: Option<u64>,
+    // Threads answering HTTP requests, and those doing work kept off them
+    pub workers: Option<usize>,
+    pub blocking_threads: Option<usize>,
+    // Megabytes that images being processed at once may take, and how
+    // many seconds images wait for room, across every site
+    pub memory_budget: Option<usize>,
+    pub memory_budget_wait: Option<u64>,
+    // How many variants are processed at once across every site, how many
+    // may queue for a turn, and for how many seconds
+    pub processing_limit: Option<usize>,
+    pub processing_queue: Option<usize>,
+    pub processing_queue_wait: Option<u64>,
+    pub sharded_layout: bool,
+    pub content_addressed_layout: bool,
+    // Adding a `Server-Timing` header to every image served
+    pub server_timing: bool,
+    // Serving uploads as the IIIF Image API describes, beneath /iiif
+    pub iiif: bool,
+    // Answering GraphQL requests at /graphql, with the `graphql` feature
+    pub graphql: bool,
+    // "/var/lib/imogen/index.sqlite", for listing uploads without going
+    // through every master, with the `sqlite` feature
+    pub metadata_index: Option<String>,
+    pub tls: Option<TlsSection>,
+    pub limits: LimitsSection,
+    pub formats: FormatsSection,
+    pub encoding: EncodingSection,
+    pub logging: LoggingSection,
+    pub access_log: Option<AccessLogSection>,
+    pub cache: CacheSection,
+    pub auth: AuthSection,
+    // "thumbnail" = "w=200&h=200&sampling=triangle"
+    pub presets: BTreeMap<String, String>,
+    // Layouts of social preview cards, served at /card/{name}.{extension}
+    pub cards: BTreeMap<String, CardSection>,
+    pub fallback_image: Option<FallbackImageSection>,
+    pub origin: Option<OriginSection>,
+    pub s3: Option<S3Section>,
+    pub webhooks: Option<WebhooksSection>,
+    pub background_removal: Option<BackgroundRemovalSection>,
+    pub upscaling: Option<UpscalingSection>,
+    // Only answering requests for this Host
+    pub host: Option<String>,
+    // Other sites served alongside this one, each configured like a file
+    // of its own, except for how the server listens
+    pub sites: Vec<ConfigFile>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSection {
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+#[derive(Deserialize, Serialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsSection {
+    pub max_upload_size: Option<usize>,
+    // The most pixels an upload or master may decode to
+    pub max_pixels: Option<u64>,
+    // Only serving presets, unless the URL is signed
+    pub presets_only: bool,
+    // Logging a warning about images that take longer than this many
+    // milliseconds to process, or produce more than this many bytes
+    pub warn_processing_ms: Option<u64>,
+    pub warn_output_bytes: Option<usize>,
+    // Streaming variants larger than this many bytes from disk
+    pub stream_threshold: Option<usize>,
+}
+
+#[derive(Deserialize, Serialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct FormatsSection {
+    pub negotiation: bool,
+    pub client_hints: bool,
+    // ["webp", "jpeg"], or every format when empty
+    pub output: Vec<String>,
+}
+
+// Qualities are from 1 to 100, and PNG compression is "fast", "default"
+// or "best". WebPs are lossless unless given a quality
+#[derive(Deserialize, Serialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncodingSection {
+    pub jpeg_quality: Option<u8>,
+    pub webp_quality: Option<u8>,
+    // From 0, the fastest, to 6, the smallest
+    pub webp_method: Option<u8>,
+    pub webp_multithreaded: bool,
+    pub png_compression: Option<String>,
+}
+
+// Levels are "trace", "debug", "info", "warn", "error" or "off", and the
+// format is "pretty" or "json"
+#[derive(Deserialize, Serialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingSection {
+    pub level: Option<String>,
+    pub format: Option<String>,
+    // "imogen::cache" = "debug"
+    pub modules: BTreeMap<String, String>,
+    pub otlp: Option<OtlpSection>,
+}
+
+// Sending traces to an OpenTelemetry collector over OTLP/HTTP, which
+// requires building imogen with the `otlp` feature
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct OtlpSection {
+    pub endpoint: String,
+    pub service_name: Option<String>,
+    // "authorization" = "Bearer …"
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+// The format is "common", "combined" or "json", and lines are written to
+// stdout unless given a path
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AccessLogSection {
+    #[serde(default = "default_access_log_format")]
+    pub format: String,
+    pub path: Option<String>,
+}
+
+fn default_access_log_format() -> String {
+    "combined".to_string()
+}
+
+// Durations are given in seconds
+#[derive(Deserialize, Serialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheSection {
+    pub variant_max_age: Option<u64>,
+    pub hot_cache_megabytes: Option<usize>,
+    pub decoded_cache_megapixels: Option<usize>,
+    pub negative_cache_ttl: Option<u64>,
+    pub stale_while_revalidate: Option<u64>,
+    pub canonical_redirects: bool,
+}
+
+#[derive(Deserialize, Serialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSection {
+    pub api_keys: Vec<ApiKeySection>,
+    pub jwt: Option<JwtSection>,
+    pub url_signing_secret: Option<String>,
+    // "/private" = "token", where policies are "public", "token" or "signed-url"
+    pub read_policies: BTreeMap<String, String>,
+    pub write_policies: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeySection {
+    pub key: String,
+    pub name: String,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+}
+
+// Tokens are verified with either a shared secret, or an RSA public key
+#[derive(Deserialize, Serialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct JwtSection {
+    pub hs256_secret: Option<String>,
+    pub rs256_public_key_path: Option<String>,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct FallbackImageSection {
+    pub filename: String,
+    #[serde(default = "default_fallback_status")]
+    pub status: u16,
+}
+
+// An upload cropped to fill a card, a gradient drawn over it from top to
+// bottom, and text fields on top, like ["00000000", "000000c0"]
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CardSection {
+    pub image: Option<String>,
+    pub background: Option<String>,
+    pub gradient: Option<Vec<String>>,
+    #[serde(default)]
+    pub fields: Vec<CardFieldSection>,
+}
+
+// A line of text from the query parameter `name`, drawn at (x, y), where
+// `size` is how large the built-in font is drawn
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CardFieldSection {
+    pub name: String,
+    pub x: u32,
+    pub y: u32,
+    pub size: Option<u32>,
+    pub color: Option<String>,
+    pub max_width: Option<u32>,
+    pub max_lines: Option<u32>,
+}
+
+fn default_fallback_status() -> u16 {
+    200
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct OriginSection {
+    pub base_url: String,
+    #[serde(default)]
+    pub allowed_prefixes: Vec<String>,
+    pub max_age: Option<u64>,
+}
+
+// Keeping uploads and cached files in a bucket, which requires the `s3`
+// feature. With `local_cache_dir`, up to `local_cache_megabytes` of them
+// are also kept on local disk
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct S3Section {
+    pub bucket: String,
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    pub endpoint: Option<String>,
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    pub local_cache_dir: Option<String>,
+    #[serde(default = "default_local_cache_megabytes")]
+    pub local_cache_megabytes: u64,
+}
+
+// POSTing uploads, replacements and deletions to `urls`, which requires
+// the `webhooks` feature. The retry delay is in seconds, and doubles
+// after every failed attempt
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct WebhooksSection {
+    pub urls: Vec<String>,
+    pub secret: Option<String>,
+    pub max_attempts: Option<u32>,
+    pub retry_delay: Option<u64>,
+    pub dead_letter_log: Option<String>,
+}
+
+// Cutting images out of their backgrounds with "?removebg=true", by
+// POSTing them to `url`, which requires the `removebg` feature. The
+// timeout is in seconds
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct BackgroundRemovalSection {
+    pub url: String,
+    pub api_key: Option<String>,
+    pub timeout: Option<u64>,
+}
+
+// Enlarging images with "?upscale=2x", by POSTing them to `url`, which
+// requires the `upscale` feature. The timeout is in seconds, and
+// `max_pixels` is the most an image can have before it's upscaled
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct UpscalingSection {
+    pub url: String,
+    pub api_key: Option<String>,
+    pub timeout: Option<u64>,
+    pub max_factor: Option<u32>,
+    pub max_pixels: Option<u64>,
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_local_cache_megabytes() -> u64 {
+    1024
+}
+
+// "ccc" -> Rgba([204, 204, 204, 255])
+fn color(value: &str) -> Result<Rgba<u8>, String> {
+    crate::placeholder::parse_hex_color(value)
+        .ok_or_else(|| format!("{:?} is not a color. Try a hex value like \"ccc\" or \"000000c0\"", value))
+}
+
+fn card_template(name: &str, card: &CardSection) -> Result<CardTemplate, String> {
+    let mut template = CardTemplate::new(name);
+    if let Some(image) = &card.image {
+        template = template.with_image(image);
+    }
+    if let Some(background) = &card.background {
+        template = template.with_background(color(background)?);
+    }
+    if let Some(gradient) = &card.gradient {
+        template = match &gradient[..] {
+            [top, bottom] => template.with_gradient(color(top)?, color(bottom)?),
+            _ => return Err(format!("The gradient of the card {:?} needs a top and a bottom color", name)),
+        };
+    }
+    for field in &card.fields {
+        let mut card_field = CardField::new(&field.name, field.x, field.y);
+        if let Some(size) = field.size {
+            card_field = card_field.with_size(size);
+        }
+        if let Some(value) = &field.color {
+            card_field = card_field.with_color(color(value)?);
+        }
+        if let Some(width) = field.max_width {
+            card_field = card_field.with_max_width(width);
+        }
+        if let Some(lines) = field.max_lines {
+            card_field = card_field.with_max_lines(lines);
+        }
+        template = template.with_field(card_field);
+    }
+    Ok(template)
+}
+
+// "signed-url" -> AccessPolicy::SignedUrl
+fn access_policy(name: &str) -> Result<AccessPolicy, String> {
+    match name {
+        "public" => Ok(AccessPolicy::Public),
+        "token" => Ok(AccessPolicy::Token),
+        "signed-url" => Ok(AccessPolicy::SignedUrl),
+        _ => Err(format!("Unknown access policy {:?}. Try \"public\", \"token\" or \"signed-url\"", name)),
+    }
+}
+
+fn encoding_defaults(encoding: &EncodingSection) -> Result<EncodingDefaults, String> {
+    let mut defaults = EncodingDefaults::new();
+    if let Some(quality) = encoding.jpeg_quality {
+        defaults = defaults.with_jpeg_quality(quality);
+    }
+    if let Some(quality) = encoding.webp_quality {
+        defaults = defaults.with_webp_quality(quality);
+    }
+    if let Some(method) = encoding.webp_method {
+        if method > 6 {
+            return Err(format!("The WebP method must be from 0 to 6, not {}", method));
+        }
+        defaults = defaults.with_webp_method(method);
+    }
+    if encoding.webp_multithreaded {
+        defaults = defaults.with_webp_multithreading();
+    }
+    if let Some(name) = &encoding.png_compression {
+        let compression = PngCompression::from_name(name)
+            .ok_or_else(|| format!("Unknown PNG compression {:?}. Try \"fast\", \"default\" or \"best\"", name))?;
+        defaults = defaults.with_png_compression(compression);
+    }
+    Ok(defaults)
+}
+
+fn jwt_config(jwt: &JwtSection) -> Result<JwtConfig, String> {
+    let config = match (&jwt.hs256_secret, &jwt.rs256_public_key_path) {
+        (Some(secret), None) => JwtConfig::hs256(secret),
+        (None, Some(path)) => {
+            let pem = std::fs::read_to_string(path)
+                .map_err(|e| format!("Could not read {}: {}", path, e))?;
+            JwtConfig::rs256(&pem)?
+        }
+        _ => return Err("[auth.jwt] needs exactly one of hs256_secret or rs256_public_key_path".to_string()),
+    };
+
+    let config = match &jwt.issuer {
+        Some(issuer) => config.with_issuer(issuer),
+        None => config,
+    };
+    Ok(match &jwt.audience {
+        Some(audience) => config.with_audience(audience),
+        None => config,
+    })
+}
+
+#[cfg(feature = "s3")]
+fn with_s3_storage(config: ServerConfig, s3: &S3Section) -> Result<ServerConfig, String> {
+    use crate::{S3Config, S3Storage, TieredStorage};
+
+    let access_key = s3.access_key_id.as_deref()
+        .ok_or("[s3] needs an access_key_id, or IMOGEN_S3_ACCESS_KEY_ID")?;
+    let secret_key = s3.secret_access_key.as_deref()
+        .ok_or("[s3] needs a secret_access_key, or IMOGEN_S3_SECRET_ACCESS_KEY")?;
+
+    let mut bucket = S3Config::new(&s3.bucket, &s3.region, access_key, secret_key);
+    if let Some(endpoint) = &s3.endpoint {
+        bucket = bucket.with_endpoint(endpoint);
+    }
+
+    let remote = S3Storage::new(bucket);
+    Ok(match &s3.local_cache_dir {
+        Some(dir) => config.with_storage(TieredStorage::new(remote, dir, s3.local_cache_megabytes)),
+        None => config.with_storage(remote),
+    })
+}
+
+#[cfg(not(feature = "s3"))]
+fn with_s3_storage(_config: ServerConfig, _s3: &S3Section) -> Result<ServerConfig, String> {
+    Err("Keeping uploads in S3 requires building imogen with the `s3` feature".to_string())
+}
+
+#[cfg(feature = "sqlite")]
+fn with_metadata_index(config: ServerConfig, path: &str) -> Result<ServerConfig, String> {
+    let index = crate::MetadataIndex::open(path).map_err(|e| format!("Could not open the metadata index {}: {}", path, e))?;
+    Ok(config.with_metadata_index(index))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn with_metadata_index(_config: ServerConfig, _path: &str) -> Result<ServerConfig, String> {
+    Err("A metadata index requires building imogen with the `sqlite` feature".to_string())
+}
+
+#[cfg(feature = "webhooks")]
+fn with_webhooks(config: ServerConfig, webhooks: &WebhooksSection) -> Result<ServerConfig, String> {
+    let (first, others) = webhooks.urls.split_first().ok_or("[webhooks] needs at least one url")?;
+    let mut delivery = others.iter().fold(crate::WebhookConfig::new(first), |delivery, url| delivery.with_url(url));
+    if let Some(secret) = &webhooks.secret {
+        delivery = delivery.with_secret(secret);
+    }
+    if let Some(attempts) = webhooks.max_attempts {
+        if attempts == 0 {
+            return Err("Webhooks need at least one attempt".to_string());
+        }
+        delivery = delivery.with_max_attempts(attempts);
+    }
+    if let Some(seconds) = webhooks.retry_delay {
+        delivery = delivery.with_retry_delay(Duration::from_secs(seconds));
+    }
+    if let Some(path) = &webhooks.dead_letter_log {
+        delivery = delivery.with_dead_letter_log(path);
+    }
+    Ok(config.with_webhooks(delivery))
+}
+
+#[cfg(not(feature = "webhooks"))]
+fn with_webhooks(_config: ServerConfig, _webhooks: &WebhooksSection) -> Result<ServerConfig, String> {
+    Err("Webhooks require building imogen with the `webhooks` feature".to_string())
+}
+
+#[cfg(feature = "removebg")]
+fn with_background_removal(config: ServerConfig, removal: &BackgroundRemovalSection) -> Result<ServerConfig, String> {
+    let mut service = crate::BackgroundRemovalConfig::new(&removal.url);
+    if let Some(key) = &removal.api_key {
+        service = service.with_api_key(key);
+    }
+    if let Some(seconds) = removal.timeout {
+        service = service.with_timeout(Duration::from_secs(seconds));
+    }
+    Ok(config.with_background_removal(service))
+}
+
+#[cfg(not(feature = "removebg"))]
+fn with_background_removal(_config: ServerConfig, _removal: &BackgroundRemovalSection) -> Result<ServerConfig, String> {
+    Err("Background removal requires building imogen with the `removebg` feature".to_string())
+}
+
+#[cfg(feature = "upscale")]
+fn with_upscaling(config: ServerConfig, upscaling: &UpscalingSection) -> Result<ServerConfig, String> {
+    let mut service = crate::UpscalingConfig::new(&upscaling.url);
+    if let Some(key) = &upscaling.api_key {
+        service = service.with_api_key(key);
+    }
+    if let Some(seconds) = upscaling.timeout {
+        service = service.with_timeout(Duration::from_secs(seconds));
+    }
+    if let Some(factor) = upscaling.max_factor {
+        service = service.with_max_factor(factor);
+    }
+    if let Some(pixels) = upscaling.max_pixels {
+        service = service.with_max_pixels(pixels);
+    }
+    Ok(config.with_upscaling(service))
+}
+
+#[cfg(not(feature = "upscale"))]
+fn with_upscaling(_config: ServerConfig, _upscaling: &UpscalingSection) -> Result<ServerConfig, String> {
+    Err("Upscaling requires building imogen with the `upscale` feature".to_string())
+}
+
+#[cfg(feature = "otlp")]
+fn with_otlp(logging: LoggingConfig, otlp: &OtlpSection) -> Result<LoggingConfig, String> {
+    let mut exporter = crate::OtlpConfig::new(&otlp.endpoint);
+    if let Some(name) = &otlp.service_name {
+        exporter = exporter.with_service_name(name);
+    }
+    for (name, value) in &otlp.headers {
+        exporter = exporter.with_header(name, value);
+    }
+    Ok(logging.with_otlp(exporter))
+}
+
+#[cfg(not(feature = "otlp"))]
+fn with_otlp(_logging: LoggingConfig, _otlp: &OtlpSection) -> Result<LoggingConfig, String> {
+    Err("Exporting traces requires building imogen with the `otlp` feature".to_string())
+}
+
+// The keys whose values are secret, wherever they appear
+const SECRET_KEYS: [&str; 7] = ["key", "hs256_secret", "url_signing_secret", "secret_access_key", "headers", "secret", "api_key"];
+
+fn redact(value: &mut toml::Value) {
+    match value {
+        toml::Value::Table(table) => {
+            for (key, value) in table.iter_mut() {
+                match SECRET_KEYS.contains(&key.as_str()) {
+                    true => *value = toml::Value::String("<redacted>".to_string()),
+                    false => redact(value),
+                }
+            }
+        }
+        toml::Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => (),
+    }
+}
+
+fn prune(value: &mut toml::Value) {
+    match value {
+        toml::Value::Table(table) => {
+            table.iter_mut().for_each(|(_, value)| prune(value));
+            table.retain(|_, value| match value {
+                toml::Value::Table(table) => !table.is_empty(),
+                toml::Value::Array(values) => !values.is_empty(),
+                _ => true,
+            });
+        }
+        toml::Value::Array(values) => values.iter_mut().for_each(prune),
+        _ => (),
+    }
+}
+
+// "IMOGEN_PORT", "8080" -> Ok(8080)
+fn parse<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value.trim().parse().map_err(|_| format!("{}={:?} is not valid", name, value))
+}
+
+// "IMOGEN_SHARDED_LAYOUT", "yes" -> Ok(true)
+fn parse_switch(name: &str, value: &str) -> Result<bool, String> {
+    match value.trim().to_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" | "" => Ok(false),
+        _ => Err(format!("{}={:?} is not valid. Try \"true\" or \"false\"", name, value)),
+    }
+}
+
+// "a, b,,c" -> ["a", "b", "c"]
+fn parse_list(value: &str) -> Vec<String> {
+    value.split(',')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .map(|item| item.to_string())
+        .collect()
+}
+
+// "/private=token,/drafts=signed-url" -> {"/private": "token", "/drafts": "signed-url"}
+fn parse_pairs(name: &str, value: &str, separator: char) -> Result<BTreeMap<String, String>, String> {
+    parse_list(value).iter()
+        .map(|pair| match pair.split_once(separator) {
+            Some((key, value)) => Ok((key.trim().to_string(), value.trim().to_string())),
+            None => Err(format!("{} expects entries like \"a{}b\", not {:?}", name, separator, pair)),
+        })
+        .collect()
+}
+
+// The section that an environment variable changes, which has to be
+// configured by another one first
+fn section<'a, T>(section: &'a mut Option<T>, name: &str, needs: &str) -> Result<&'a mut T, String> {
+    section.as_mut().ok_or_else(|| format!("{} is set without {}", name, needs))
+}
+
+impl ConfigFile {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Could not read {}: {}", path, e))?;
+        Self::parse(&contents).map_err(|message| format!("{}: {}", path, message))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        toml::from_str(contents).map_err(|e| e.to_string())
+    }
+
+    // These settings as TOML, with secrets like API keys replaced, so that
+    // they can be printed without leaking them. Empty sections and lists
+    // are left out
+    pub fn to_redacted_toml(&self) -> String {
+        let mut value = toml::Value::try_from(self).expect("Settings can always be written as TOML");
+        redact(&mut value);
+        prune(&mut value);
+        toml::to_string(&value).expect("Settings can always be written as TOML")
+    }
+
+    // Overriding these settings with `IMOGEN_*` environment variables, like
+    // IMOGEN_PORT=8080 or IMOGEN_S3_BUCKET=my-images. Lists are separated
+    // by commas, and presets are named after their variable, so that
+    // IMOGEN_PRESET_THUMBNAIL="w=200" configures "thumbnail".
+    // Unrecognized variables are ignored, since container platforms often
+    // add their own, like IMOGEN_SERVICE_HOST
+    pub fn apply_environment<I>(&mut self, vars: I) -> Result<(), String>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let vars: HashMap<String, String> = vars.into_iter().collect();
+        let var = |name: &str| vars.get(&format!("IMOGEN_{}", name)).cloned();
+
+        if let Some(port) = var("PORT") {
+            self.port = Some(parse("IMOGEN_PORT", &port)?);
+        }
+        if let Some(hosts) = var("BIND_ADDRESSES") {
+            self.bind_addresses = parse_list(&hosts);
+        }
+        if let Some(dir) = var("UPLOADS_DIR") {
+            self.uploads_dir = Some(dir);
+        }
+        if let Some(dir) = var("CACHE_DIR") {
+            self.cache_dir = Some(dir);
+        }
+        if let Some(base_path) = var("BASE_PATH") {
+            self.base_path = Some(base_path);
+        }
+        if let Some(path) = var("UNIX_SOCKET") {
+            self.unix_socket = Some(path);
+        }
+        if let Some(address) = var("GRPC_ADDRESS") {
+            self.grpc_address = Some(address);
+        }
+        if let Some(seconds) = var("SHUTDOWN_TIMEOUT") {
+            self.shutdown_timeout = Some(parse("IMOGEN_SHUTDOWN_TIMEOUT", &seconds)?);
+        }
+        if let Some(workers) = var("WORKERS") {
+            self.workers = Some(parse("IMOGEN_WORKERS", &workers)?);
+        }
+        if let Some(threads) = var("BLOCKING_THREADS") {
+            self.blocking_threads = Some(parse("IMOGEN_BLOCKING_THREADS", &threads)?);
+        }
+        if let Some(megabytes) = var("MEMORY_BUDGET") {
+            self.memory_budget = Some(parse("IMOGEN_MEMORY_BUDGET", &megabytes)?);
+        }
+        if let Some(seconds) = var("MEMORY_BUDGET_WAIT") {
+            self.memory_budget_wait = Some(parse("IMOGEN_MEMORY_BUDGET_WAIT", &seconds)?);
+        }
+        if let Some(jobs) = var("PROCESSING_LIMIT") {
+            self.processing_limit = Some(parse("IMOGEN_PROCESSING_LIMIT", &jobs)?);
+        }
+        if let Some(length) = var("PROCESSING_QUEUE") {
+            self.processing_queue = Some(parse("IMOGEN_PROCESSING_QUEUE", &length)?);
+        }
+        if let Some(seconds) = var("PROCESSING_QUEUE_WAIT") {
+            self.processing_queue_wait = Some(parse("IMOGEN_PROCESSING_QUEUE_WAIT", &seconds)?);
+        }
+        if let Some(value) = var("SHARDED_LAYOUT") {
+            self.sharded_layout = parse_switch("IMOGEN_SHARDED_LAYOUT", &value)?;
+        }
+        if let Some(value) = var("CONTENT_ADDRESSED_LAYOUT") {
+            self.content_addressed_layout = parse_switch("IMOGEN_CONTENT_ADDRESSED_LAYOUT", &value)?;
+        }
+        if let Some(value) = var("SERVER_TIMING") {
+            self.server_timing = parse_switch("IMOGEN_SERVER_TIMING", &value)?;
+        }
+        if let Some(value) = var("IIIF") {
+            self.iiif = parse_switch("IMOGEN_IIIF", &value)?;
+        }
+        if let Some(value) = var("GRAPHQL") {
+            self.graphql = parse_switch("IMOGEN_GRAPHQL", &value)?;
+        }
+        if let Some(path) = var("METADATA_INDEX") {
+            self.metadata_index = Some(path);
+        }
+
+        let cert_path = var("TLS_CERT_PATH").or(self.tls.as_ref().map(|tls| tls.cert_path.clone()));
+        let key_path = var("TLS_KEY_PATH").or(self.tls.as_ref().map(|tls| tls.key_path.clone()));
+        match (cert_path, key_path) {
+            (Some(cert_path), Some(key_path)) => self.tls = Some(TlsSection { cert_path, key_path }),
+            (None, None) => (),
+            _ => return Err("IMOGEN_TLS_CERT_PATH and IMOGEN_TLS_KEY_PATH must be set together".to_string()),
+        }
+
+        if let Some(bytes) = var("MAX_UPLOAD_SIZE") {
+            self.limits.max_upload_size = Some(parse("IMOGEN_MAX_UPLOAD_SIZE", &bytes)?);
+        }
+        if let Some(pixels) = var("MAX_PIXELS") {
+            self.limits.max_pixels = Some(parse("IMOGEN_MAX_PIXELS", &pixels)?);
+        }
+        if let Some(value) = var("PRESETS_ONLY") {
+            self.limits.presets_only = parse_switch("IMOGEN_PRESETS_ONLY", &value)?;
+        }
+        if let Some(milliseconds) = var("WARN_PROCESSING_MS") {
+            self.limits.warn_processing_ms = Some(parse("IMOGEN_WARN_PROCESSING_MS", &milliseconds)?);
+        }
+        if let Some(bytes) = var("WARN_OUTPUT_BYTES") {
+            self.limits.warn_output_bytes = Some(parse("IMOGEN_WARN_OUTPUT_BYTES", &bytes)?);
+        }
+        if let Some(bytes) = var("STREAM_THRESHOLD") {
+            self.limits.stream_threshold = Some(parse("IMOGEN_STREAM_THRESHOLD", &bytes)?);
+        }
+
+        if let Some(value) = var("FORMAT_NEGOTIATION") {
+            self.formats.negotiation = parse_switch("IMOGEN_FORMAT_NEGOTIATION", &value)?;
+        }
+        if let Some(value) = var("CLIENT_HINTS") {
+            self.formats.client_hints = parse_switch("IMOGEN_CLIENT_HINTS", &value)?;
+        }
+        if let Some(formats) = var("OUTPUT_FORMATS") {
+            self.formats.output = parse_list(&formats);
+        }
+
+        if let Some(quality) = var("JPEG_QUALITY") {
+            self.encoding.jpeg_quality = Some(parse("IMOGEN_JPEG_QUALITY", &quality)?);
+        }
+        if let Some(quality) = var("WEBP_QUALITY") {
+            self.encoding.webp_quality = Some(parse("IMOGEN_WEBP_QUALITY", &quality)?);
+        }
+        if let Some(method) = var("WEBP_METHOD") {
+            self.encoding.webp_method = Some(parse("IMOGEN_WEBP_METHOD", &method)?);
+        }
+        if let Some(value) = var("WEBP_MULTITHREADED") {
+            self.encoding.webp_multithreaded = parse_switch("IMOGEN_WEBP_MULTITHREADED", &value)?;
+        }
+        if let Some(compression) = var("PNG_COMPRESSION") {
+            self.encoding.png_compression = Some(compression);
+        }
+        if let Some(level) = var("LOG_LEVEL") {
+            self.logging.level = Some(level);
+        }
+        if let Some(format) = var("LOG_FORMAT") {
+            self.logging.format = Some(format);
+        }
+        if let Some(modules) = var("LOG_MODULES") {
+            self.logging.modules = parse_pairs("IMOGEN_LOG_MODULES", &modules, '=')?;
+        }
+        // The names every OpenTelemetry SDK reads, too
+        let otlp_endpoint = var("OTLP_ENDPOINT").or(vars.get("OTEL_EXPORTER_OTLP_ENDPOINT").cloned());
+        if let Some(endpoint) = otlp_endpoint {
+            match &mut self.logging.otlp {
+                Some(otlp) => otlp.endpoint = endpoint,
+                None => self.logging.otlp = Some(OtlpSection { endpoint, service_name: None, headers: BTreeMap::new() }),
+            }
+        }
+        if let Some(otlp) = &mut self.logging.otlp {
+            let service_name = var("OTLP_SERVICE_NAME").or(vars.get("OTEL_SERVICE_NAME").cloned());
+            otlp.service_name = service_name.or(otlp.service_name.take());
+        }
+        let new_access_log = || AccessLogSection { format: default_access_log_format(), path: None };
+        if let Some(format) = var("ACCESS_LOG_FORMAT") {
+            self.access_log.get_or_insert_with(new_access_log).format = format;
+        }
+        if let Some(path) = var("ACCESS_LOG_PATH") {
+            self.access_log.get_or_insert_with(new_access_log).path = Some(path);
+        }
+
+        if let Some(seconds) = var("VARIANT_MAX_AGE") {
+            self.cache.variant_max_age = Some(parse("IMOGEN_VARIANT_MAX_AGE", &seconds)?);
+        }
+        if let Some(megabytes) = var("HOT_CACHE_MEGABYTES") {
+            self.cache.hot_cache_megabytes = Some(parse("IMOGEN_HOT_CACHE_MEGABYTES", &megabytes)?);
+        }
+        if let Some(megapixels) = var("DECODED_CACHE_MEGAPIXELS") {
+            self.cache.decoded_cache_megapixels = Some(parse("IMOGEN_DECODED_CACHE_MEGAPIXELS", &megapixels)?);
+        }
+        if let Some(seconds) = var("NEGATIVE_CACHE_TTL") {
+            self.cache.negative_cache_ttl = Some(parse("IMOGEN_NEGATIVE_CACHE_TTL", &seconds)?);
+        }
+        if let Some(seconds) = var("STALE_WHILE_REVALIDATE") {
+            self.cache.stale_while_revalidate = Some(parse("IMOGEN_STALE_WHILE_REVALIDATE", &seconds)?);
+        }
+        if let Some(value) = var("CANONICAL_REDIRECTS") {
+            self.cache.canonical_redirects = parse_switch("IMOGEN_CANONICAL_REDIRECTS", &value)?;
+        }
+
+        // IMOGEN_API_KEYS="ci:secret,deploy:other"
+        if let Some(keys) = var("API_KEYS") {
+            self.auth.api_keys = parse_pairs("IMOGEN_API_KEYS", &keys, ':')?
+                .into_iter()
+                .map(|(name, key)| ApiKeySection { key, name, metadata: HashMap::new() })
+                .collect();
+        }
+        if let Some(secret) = var("JWT_HS256_SECRET") {
+            self.auth.jwt.get_or_insert_with(JwtSection::default).hs256_secret = Some(secret);
+        }
+        if let Some(path) = var("JWT_RS256_PUBLIC_KEY_PATH") {
+            self.auth.jwt.get_or_insert_with(JwtSection::default).rs256_public_key_path = Some(path);
+        }
+        if let Some(issuer) = var("JWT_ISSUER") {
+            section(&mut self.auth.jwt, "IMOGEN_JWT_ISSUER", "a JWT secret or public key")?.issuer = Some(issuer);
+        }
+        if let Some(audience) = var("JWT_AUDIENCE") {
+            section(&mut self.auth.jwt, "IMOGEN_JWT_AUDIENCE", "a JWT secret or public key")?.audience = Some(audience);
+        }
+        if let Some(secret) = var("URL_SIGNING_SECRET") {
+            self.auth.url_signing_secret = Some(secret);
+        }
+        if let Some(policies) = var("READ_POLICIES") {
+            self.auth.read_policies = parse_pairs("IMOGEN_READ_POLICIES", &policies, '=')?;
+        }
+        if let Some(policies) = var("WRITE_POLICIES") {
+            self.auth.write_policies = parse_pairs("IMOGEN_WRITE_POLICIES", &policies, '=')?;
+        }
+
+        for (name, query) in &vars {
+            if let Some(preset) = name.strip_prefix("IMOGEN_PRESET_") {
+                self.presets.insert(preset.to_lowercase(), query.clone());
+            }
+        }
+
+        if let Some(filename) = var("FALLBACK_IMAGE") {
+            match &mut self.fallback_image {
+                Some(fallback) => fallback.filename = filename,
+                None => self.fallback_image = Some(FallbackImageSection { filename, status: default_fallback_status() }),
+            }
+        }
+        if let Some(status) = var("FALLBACK_IMAGE_STATUS") {
+            section(&mut self.fallback_image, "IMOGEN_FALLBACK_IMAGE_STATUS", "IMOGEN_FALLBACK_IMAGE")?
+                .status = parse("IMOGEN_FALLBACK_IMAGE_STATUS", &status)?;
+        }
+
+        if let Some(base_url) = var("ORIGIN_URL") {
+            match &mut self.origin {
+                Some(origin) => origin.base_url = base_url,
+                None => self.origin = Some(OriginSection { base_url, allowed_prefixes: vec![], max_age: None }),
+            }
+        }
+        if let Some(prefixes) = var("ORIGIN_ALLOWED_PREFIXES") {
+            section(&mut self.origin, "IMOGEN_ORIGIN_ALLOWED_PREFIXES", "IMOGEN_ORIGIN_URL")?
+                .allowed_prefixes = parse_list(&prefixes);
+        }
+        if let Some(seconds) = var("ORIGIN_MAX_AGE") {
+            section(&mut self.origin, "IMOGEN_ORIGIN_MAX_AGE", "IMOGEN_ORIGIN_URL")?
+                .max_age = Some(parse("IMOGEN_ORIGIN_MAX_AGE", &seconds)?);
+        }
+
+        if let Some(bucket) = var("S3_BUCKET") {
+            match &mut self.s3 {
+                Some(s3) => s3.bucket = bucket,
+                None => self.s3 = Some(S3Section {
+                    bucket,
+                    region: default_s3_region(),
+                    endpoint: None,
+                    access_key_id: None,
+                    secret_access_key: None,
+                    local_cache_dir: None,
+                    local_cache_megabytes: default_local_cache_megabytes(),
+                }),
+            }
+        }
+        if let Some(s3) = &mut self.s3 {
+            // The names every AWS tool reads credentials from, too
+            let access_key_id = var("S3_ACCESS_KEY_ID").or(vars.get("AWS_ACCESS_KEY_ID").cloned());
+            let secret_access_key = var("S3_SECRET_ACCESS_KEY").or(vars.get("AWS_SECRET_ACCESS_KEY").cloned());
+
+            s3.access_key_id = access_key_id.or(s3.access_key_id.take());
+            s3.secret_access_key = secret_access_key.or(s3.secret_access_key.take());
+            s3.region = var("S3_REGION").unwrap_or(std::mem::take(&mut s3.region));
+            s3.endpoint = var("S3_ENDPOINT").or(s3.endpoint.take());
+            s3.local_cache_dir = var("S3_LOCAL_CACHE_DIR").or(s3.local_cache_dir.take());
+            if let Some(megabytes) = var("S3_LOCAL_CACHE_MEGABYTES") {
+                s3.local_cache_megabytes = parse("IMOGEN_S3_LOCAL_CACHE_MEGABYTES", &megabytes)?;
+            }
+        }
+
+        if let Some(urls) = var("WEBHOOK_URLS") {
+            match &mut self.webhooks {
+                Some(webhooks) => webhooks.urls = parse_list(&urls),
+                None => self.webhooks = Some(WebhooksSection {
+                    urls: parse_list(&urls),
+                    secret: None,
+                    max_attempts: None,
+                    retry_delay: None,
+                    dead_letter_log: None,
+                }),
+            }
+        }
+        if let Some(secret) = var("WEBHOOK_SECRET") {
+            section(&mut self.webhooks, "IMOGEN_WEBHOOK_SECRET", "IMOGEN_WEBHOOK_URLS")?.secret = Some(secret);
+        }
+        if let Some(attempts) = var("WEBHOOK_MAX_ATTEMPTS") {
+            section(&mut self.webhooks, "IMOGEN_WEBHOOK_MAX_ATTEMPTS", "IMOGEN_WEBHOOK_URLS")?
+                .max_attempts = Some(parse("IMOGEN_WEBHOOK_MAX_ATTEMPTS", &attempts)?);
+        }
+        if let Some(seconds) = var("WEBHOOK_RETRY_DELAY") {
+            section(&mut self.webhooks, "IMOGEN_WEBHOOK_RETRY_DELAY", "IMOGEN_WEBHOOK_URLS")?
+                .retry_delay = Some(parse("IMOGEN_WEBHOOK_RETRY_DELAY", &seconds)?);
+        }
+        if let Some(path) = var("WEBHOOK_DEAD_LETTER_LOG") {
+            section(&mut self.webhooks, "IMOGEN_WEBHOOK_DEAD_LETTER_LOG", "IMOGEN_WEBHOOK_URLS")?.dead_letter_log = Some(path);
+        }
+
+        if let Some(url) = var("REMOVEBG_URL") {
+            match &mut self.background_removal {
+                Some(removal) => removal.url = url,
+                None => self.background_removal = Some(BackgroundRemovalSection { url, api_key: None, timeout: None }),
+            }
+        }
+        if let Some(key) = var("REMOVEBG_API_KEY") {
+            section(&mut self.background_removal, "IMOGEN_REMOVEBG_API_KEY", "IMOGEN_REMOVEBG_URL")?.api_key = Some(key);
+        }
+        if let Some(seconds) = var("REMOVEBG_TIMEOUT") {
+            section(&mut self.background_removal, "IMOGEN_REMOVEBG_TIMEOUT", "IMOGEN_REMOVEBG_URL")?
+                .timeout = Some(parse("IMOGEN_REMOVEBG_TIMEOUT", &seconds)?);
+        }
+
+        if let Some(url) = var("UPSCALE_URL") {
+            match &mut self.upscaling {
+                Some(upscaling) => upscaling.url = url,
+                None => self.upscaling = Some(UpscalingSection { url, api_key: None, timeout: None, max_factor: None, max_pixels: None }),
+            }
+        }
+        if let Some(key) = var("UPSCALE_API_KEY") {
+            section(&mut self.upscaling, "IMOGEN_UPSCALE_API_KEY", "IMOGEN_UPSCALE_URL")?.api_key = Some(key);
+        }
+        if let Some(seconds) = var("UPSCALE_TIMEOUT") {
+            section(&mut self.upscaling, "IMOGEN_UPSCALE_TIMEOUT", "IMOGEN_UPSCALE_URL")?
+                .timeout = Some(parse("IMOGEN_UPSCALE_TIMEOUT", &seconds)?);
+        }
+        if let Some(factor) = var("UPSCALE_MAX_FACTOR") {
+            section(&mut self.upscaling, "IMOGEN_UPSCALE_MAX_FACTOR", "IMOGEN_UPSCALE_URL")?
+                .max_factor = Some(parse("IMOGEN_UPSCALE_MAX_FACTOR", &factor)?);
+        }
+        if let Some(pixels) = var("UPSCALE_MAX_PIXELS") {
+            section(&mut self.upscaling, "IMOGEN_UPSCALE_MAX_PIXELS", "IMOGEN_UPSCALE_URL")?
+                .max_pixels = Some(parse("IMOGEN_UPSCALE_MAX_PIXELS", &pixels)?);
+        }
+
+        Ok(())
+    }
+
+    // Settings that only apply to the whole server, like how it listens
+    // and logs, which sites can't choose for themselves
+    fn listening_settings(&self) -> Vec<&'static str> {
+        let mut settings = vec![];
+        if self.port.is_some() { settings.push("port"); }
+        if !self.bind_addresses.is_empty() { settings.push("bind_addresses"); }
+        if self.unix_socket.is_some() { settings.push("unix_socket"); }
+        if self.grpc_address.is_some() { settings.push("grpc_address"); }
+        if self.shutdown_timeout.is_some() { settings.push("shutdown_timeout"); }
+        if self.workers.is_some() { settings.push("workers"); }
+        if self.blocking_threads.is_some() { settings.push("blocking_threads"); }
+        if self.memory_budget.is_some() { settings.push("memory_budget"); }
+        if self.memory_budget_wait.is_some() { settings.push("memory_budget_wait"); }
+        if self.processing_limit.is_some() { settings.push("processing_limit"); }
+        if self.processing_queue.is_some() { settings.push("processing_queue"); }
+        if self.processing_queue_wait.is_some() { settings.push("processing_queue_wait"); }
+        if self.tls.is_some() { settings.push("tls"); }
+        if !self.sites.is_empty() { settings.push("sites"); }
+        let logging = &self.logging;
+        if logging.level.is_some() || logging.format.is_some() || !logging.modules.is_empty() || logging.otlp.is_some() {
+            settings.push("logging");
+        }
+        if self.access_log.is_some() { settings.push("access_log"); }
+        settings
+    }
+
+    // How the process logs, which applies to every site
+    pub fn logging_config(&self) -> Result<LoggingConfig, String> {
+        let mut logging = LoggingConfig::new();
+        if let Some(level) = &self.logging.level {
+            logging = logging.with_level(level);
+        }
+        if let Some(name) = &self.logging.format {
+            let format = LogFormat::from_name(name)
+                .ok_or_else(|| format!("Unknown log format {:?}. Try \"pretty\" or \"json\"", name))?;
+            logging = logging.with_format(format);
+        }
+        for (module, level) in &self.logging.modules {
+            logging = logging.with_module_level(module, level);
+        }
+        match &self.logging.otlp {
+            Some(otlp) => with_otlp(logging, otlp),
+            None => Ok(logging),
+        }
+    }
+
+    // Building the server's configuration from these settings, with the
+    // uploads directory defaulting to "./images"
+    pub fn server_config(&self) -> Result<ServerConfig, String> {
+        let uploads_dir = self.uploads_dir.as_deref().unwrap_or("./images");
+        let mut config = ServerConfig::new(uploads_dir);
+
+        for site in &self.sites {
+            let name = site.host.as_deref().or(site.base_path.as_deref()).unwrap_or("");
+            if name.is_empty() {
+                return Err("Every [[sites]] entry needs a host or a base_path".to_string());
+            }
+            if site.uploads_dir.is_none() {
+                return Err(format!("The site {} needs its own uploads_dir", name));
+            }
+            if let Some(setting) = site.listening_settings().first() {
+                return Err(format!("The site {} can't set {}, which only applies to the whole server", name, setting));
+            }
+
+            let site_config = site.server_config().map_err(|message| format!("The site {}: {}", name, message))?;
+            config = config.with_site(site_config);
+        }
+        if let Some(host) = &self.host {
+            config = config.with_host(host);
+        }
+
+        for host in &self.bind_addresses {
+            config = config.with_bind_address(host);
+        }
+        if let Some(cache_dir) = &self.cache_dir {
+            config = config.with_cache_dir(cache_dir);
+        }
+        if let Some(base_path) = &self.base_path {
+            config = config.with_base_path(base_path);
+        }
+        if let Some(path) = &self.unix_socket {
+            config = config.with_unix_socket(path);
+        }
+        if let Some(address) = &self.grpc_address {
+            config = config.with_grpc(address);
+        }
+        if let Some(access_log) = &self.access_log {
+            let format = AccessLogFormat::from_name(&access_log.format).ok_or_else(|| {
+                format!("Unknown access log format {:?}. Try \"common\", \"combined\" or \"json\"", access_log.format)
+            })?;
+            let mut access_log_config = AccessLogConfig::new(format);
+            if let Some(path) = &access_log.path {
+                access_log_config = access_log_config.with_path(path);
+            }
+            config = config.with_access_log(access_log_config);
+        }
+        if let Some(seconds) = self.shutdown_timeout {
+            config = config.with_shutdown_timeout(Duration::from_secs(seconds));
+        }
+        if let Some(workers) = self.workers {
+            config = config.with_workers(workers);
+        }
+        if let Some(threads) = self.blocking_threads {
+            config = config.with_blocking_threads(threads);
+        }
+        if let Some(megabytes) = self.memory_budget {
+            config = config.with_memory_budget(megabytes);
+        }
+        if let Some(seconds) = self.memory_budget_wait {
+            config = config.with_memory_budget_wait(Duration::from_secs(seconds));
+        }
+        if let Some(jobs) = self.processing_limit {
+            if jobs == 0 {
+                return Err("The processing limit must allow at least one variant at once".to_string());
+            }
+            config = config.with_processing_limit(jobs);
+        }
+        if let Some(length) = self.processing_queue {
+            config = config.with_processing_queue(length);
+        }
+        if let Some(seconds) = self.processing_queue_wait {
+            config = config.with_processing_queue_wait(Duration::from_secs(seconds));
+        }
+        if self.sharded_layout {
+            config = config.with_sharded_layout();
+        }
+        if self.content_addressed_layout {
+            config = config.with_content_addressed_layout();
+        }
+        if self.server_timing {
+            config = config.with_server_timing();
+        }
+        if self.iiif {
+            config = config.with_iiif();
+        }
+        if self.graphql {
+            config = config.with_graphql();
+        }
+        if let Some(path) = &self.metadata_index {
+            config = with_metadata_index(config, path)?;
+        }
+        if let Some(tls) = &self.tls {
+            config = config.with_tls(&tls.cert_path, &tls.key_path);
+        }
+
+        if let Some(bytes) = self.limits.max_upload_size {
+            config = config.with_max_upload_size(bytes);
+        }
+        if let Some(pixels) = self.limits.max_pixels {
+            config = config.with_max_pixels(pixels);
+        }
+        if self.limits.presets_only {
+            config = config.with_presets_only();
+        }
+        if let Some(milliseconds) = self.limits.warn_processing_ms {
+            config = config.with_slow_processing_warning(Duration::from_millis(milliseconds));
+        }
+        if let Some(bytes) = self.limits.warn_output_bytes {
+            config = config.with_large_output_warning(bytes);
+        }
+        if let Some(bytes) = self.limits.stream_threshold {
+            config = config.with_stream_threshold(bytes);
+        }
+
+        if self.formats.negotiation {
+            config = config.with_format_negotiation();
+        }
+        if self.formats.client_hints {
+            config = config.with_client_hints();
+        }
+        if let Some(format) = self.formats.output.iter().find(|format| !OUTPUT_FORMATS.contains(&format.as_str())) {
+            return Err(format!("Unknown output format {:?}. Try one of: {}", format, OUTPUT_FORMATS.join(", ")));
+        }
+        if !self.formats.output.is_empty() {
+            let formats: Vec<&str> = self.formats.output.iter().map(|format| format.as_str()).collect();
+            config = config.with_output_formats(&formats);
+        }
+
+        config = config.with_encoding_defaults(encoding_defaults(&self.encoding)?);
+
+        if let Some(seconds) = self.cache.variant_max_age {
+            config = config.with_variant_max_age(Duration::from_secs(seconds));
+        }
+        if let Some(megabytes) = self.cache.hot_cache_megabytes {
+            config = config.with_hot_cache(megabytes);
+        }
+        if let Some(megapixels) = self.cache.decoded_cache_megapixels {
+            config = config.with_decoded_cache(megapixels);
+        }
+        if let Some(seconds) = self.cache.negative_cache_ttl {
+            config = config.with_negative_cache(Duration::from_secs(seconds));
+        }
+        if let Some(seconds) = self.cache.stale_while_revalidate {
+            config = config.with_stale_while_revalidate(Duration::from_secs(seconds));
+        }
+        if self.cache.canonical_redirects {
+            config = config.with_canonical_redirects();
+        }
+
+        for api_key in &self.auth.api_keys {
+            let key = api_key.metadata.iter().fold(
+                ApiKey::new(&api_key.key, &api_key.name),
+                |key, (name, value)| key.with_metadata(name, value),
+            );
+            config = config.with_api_key(key);
+        }
+        if let Some(jwt) = &self.auth.jwt {
+            config = config.with_jwt(jwt_config(jwt)?);
+        }
+        if let Some(secret) = &self.auth.url_signing_secret {
+            config = config.with_url_signing_secret(secret);
+        }
+        for (prefix, policy) in &self.auth.read_policies {
+            config = config.with_read_policy(prefix, access_policy(policy)?);
+        }
+        for (prefix, policy) in &self.auth.write_policies {
+            config = config.with_write_policy(prefix, access_policy(policy)?);
+        }
+
+        for (name, query) in &self.presets {
+            config = config.with_preset(name, query);
+        }
+        for (name, card) in &self.cards {
+            config = config.with_card_template(card_template(name, card)?);
+        }
+
+        if let Some(fallback) = &self.fallback_image {
+            let status = StatusCode::from_u16(fallback.status)
+                .map_err(|_| format!("{} is not an HTTP status code", fallback.status))?;
+            config = config.with_fallback_image(&fallback.filename, status);
+        }
+
+        if let Some(origin) = &self.origin {
+            let mut origin_config = OriginConfig::new(&origin.base_url);
+            for prefix in &origin.allowed_prefixes {
+                origin_config = origin_config.with_allowed_prefix(prefix);
+            }
+            if let Some(seconds) = origin.max_age {
+                origin_config = origin_config.with_max_age(Duration::from_secs(seconds));
+            }
+            config = config.with_origin(origin_config);
+        }
+
+        if let Some(s3) = &self.s3 {
+            config = with_s3_storage(config, s3)?;
+        }
+        if let Some(webhooks) = &self.webhooks {
+            config = with_webhooks(config, webhooks)?;
+        }
+        if let Some(removal) = &self.background_removal {
+            config = with_background_removal(config, removal)?;
+        }
+        if let Some(upscaling) = &self.upscaling {
+            config = with_upscaling(config, upscaling)?;
+        }
+
+        Ok(config)
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::Duration;
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+
+use crate::ServerConfig;
+
+
+// Unreferenced blobs are only removed once they're this old, since an
+// upload saves its blob a moment before the name that refers to it
+const UNREFERENCED_BLOB_MIN_AGE: Duration = Duration::from_secs(60 * 60);
+
+// Which blob an upload's name refers to
+#[derive(Serialize, Deserialize)]
+struct NameRecord {
+    hash: String,
+}
+
+// What verifying every master found
+#[derive(Serialize, Debug, Default)]
+pub struct IntegrityReport {
+    pub verified: usize,
+    // Uploads whose blob is missing, or no longer matches its hash
+    pub corrupted: Vec<String>,
+    // Blobs that no upload referred to anymore
+    pub removed: usize,
+}
+
+// "cat" -> "./images/.names/cat.json"
+fn build_path_to_name_record(config: &ServerConfig, filename: &str) -> String {
+    format!("{}/.names/{}{}.json", config.uploads_dir, config.shard(filename), filename)
+}
+
+fn build_path_to_blobs_dir(config: &ServerConfig) -> String {
+    format!("{}/.blobs", config.uploads_dir)
+}
+
+// "9f86…0f08" -> "./images/.blobs/9f/86/9f86…0f08.webp"
+fn build_path_to_blob(config: &ServerConfig, hash: &str) -> String {
+    format!("{}/{}/{}/{}.webp", build_path_to_blobs_dir(config), &hash[..2], &hash[2..4], hash)
+}
+
+fn hash_of(contents: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(contents))
+}
+
+fn load_name_record(config: &ServerConfig, filename: &str) -> Option<NameRecord> {
+    let contents = config.storage.get(&build_path_to_name_record(config, filename)).ok()?;
+    let record: NameRecord = serde_json::from_slice(&contents).ok()?;
+
+    // Recognizing damaged records, rather than building paths from them
+    match record.hash.len() == 64 && record.hash.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        true => Some(record),
+        false => None,
+    }
+}
+
+fn save_name_record(config: &ServerConfig, filename: &str, hash: &str) -> std::io::Result<()> {
+    let contents = serde_json::to_vec(&NameRecord { hash: hash.to_string() })?;
+    config.storage.put(&build_path_to_name_record(config, filename), &contents)
+}
+
+// "cat" -> Some("./images/.blobs/9f/86/9f86…0f08.webp")
+// The blob holding the master of an upload, if it has one
+pub fn resolve(config: &ServerConfig, filename: &str) -> Option<String> {
+    load_name_record(config, filename).map(|record| build_path_to_blob(config, &record.hash))
+}
+
+// Saving a master under the hash of its contents, which costs nothing
+// more than a name record when another upload has the same contents
+pub fn save(config: &ServerConfig, filename: &str, contents: &[u8]) -> std::io::Result<()> {
+    let hash = hash_of(contents);
+    let blob = build_path_to_blob(config, &hash);
+
+    // Saving the blob again even if it exists, so that it's dated later
+    // than any variant produced from a master this name referred to before
+    config.storage.put(&blob, contents)?;
+    save_name_record(config, filename, &hash)
+}
+
+// Forgetting an upload's name. Its blob is left for `verify` to remove,
+// since other uploads may refer to it too
+pub fn forget(config: &ServerConfig, filename: &str) -> std::io::Result<()> {
+    config.storage.delete(&build_path_to_name_record(config, filename))
+}
+
+// Referring to an upload's blob by another name, without copying it
+pub fn rename(config: &ServerConfig, from: &str, to: &str) -> std::io::Result<()> {
+    let record = load_name_record(config, from)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "No such upload"))?;
+
+    save_name_record(config, to, &record.hash)?;
+    forget(config, from)
+}
+
+// "./images/.names/4a/9c/cat.json" -> "cat"
+fn name_of(path: &str) -> Option<String> {
+    Path::new(path).file_name()?.to_str()?.strip_suffix(".json").map(|name| name.to_string())
+}
+
+// Every upload with a name record
+pub fn list_names(config: &ServerConfig) -> Vec<String> {
+    config.storage.list(&format!("{}/.names", config.uploads_dir))
+        .iter()
+        .filter_map(|path| name_of(path))
+        .collect()
+}
+
+// Hashing every master again to find any that were damaged, and removing
+// blobs that no upload refers to anymore
+pub fn verify(config: &ServerConfig) -> IntegrityReport {
+    let mut report = IntegrityReport::default();
+    let mut referenced = HashSet::new();
+
+    for filename in list_names(config) {
+        let record = match load_name_record(config, &filename) {
+            Some(record) => record,
+            None => {
+                report.corrupted.push(filename);
+                continue;
+            }
+        };
+
+        let blob = build_path_to_blob(config, &record.hash);
+        match config.storage.get(&blob) {
+            Ok(contents) if hash_of(&contents) == record.hash => report.verified += 1,
+            _ => report.corrupted.push(filename),
+        }
+        referenced.insert(blob);
+    }
+
+    for blob in config.storage.list(&build_path_to_blobs_dir(config)) {
+        if referenced.contains(&blob) { continue; }
+
+        let age = config.storage.metadata(&blob)
+            .and_then(|metadata| metadata.modified)
+            .and_then(|modified| modified.elapsed().ok());
+
+        if age.is_some_and(|age| age >= UNREFERENCED_BLOB_MIN_AGE) && config.storage.delete(&blob).is_ok() {
+            report.removed += 1;
+        }
+    }
+
+    report.corrupted.sort();
+    report
+}
@@ -0,0 +1,138 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hasher;
+use std::sync::{Arc, Mutex};
+use image::{DynamicImage, GenericImageView};
+#[cfg(feature = "server")]
+use serde::Serialize;
+
+
+// Telling a master apart from whatever replaced it under the same name
+pub fn fingerprint(buffer: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(buffer);
+    hasher.finish()
+}
+
+fn pixels(image: &DynamicImage) -> usize {
+    let (width, height) = image.dimensions();
+    width as usize * height as usize
+}
+
+struct Entry {
+    image: Arc<DynamicImage>,
+    fingerprint: u64,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<String, Entry>,
+    // Keys ordered from least to most recently used
+    recency: BTreeMap<u64, String>,
+    used: usize,
+    clock: u64,
+    evictions: u64,
+}
+
+impl State {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.last_used);
+            self.used -= pixels(&entry.image);
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+#[derive(Serialize)]
+pub struct Usage {
+    pub entries: usize,
+    pub pixels: usize,
+    pub budget: usize,
+    pub evictions: u64,
+}
+
+// Masters recently decoded, one per master at the largest size it was
+// decoded at, so that renditions of the same image requested one after
+// another don't decode it again each time. Once the budget of pixels is
+// used up, the least recently used are evicted to make room
+pub struct DecodedCache {
+    budget: usize,
+    state: Mutex<State>,
+}
+
+impl DecodedCache {
+    pub fn new(budget: usize) -> Self {
+        Self { budget, state: Mutex::new(State::default()) }
+    }
+
+    pub fn disabled() -> Self {
+        Self::new(0)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.budget > 0
+    }
+
+    // A decoded copy of the master with this `fingerprint`, if there's one
+    // at least `width` pixels wide
+    pub fn get(&self, key: &str, fingerprint: u64, width: u32) -> Option<Arc<DynamicImage>> {
+        if !self.is_enabled() { return None; }
+
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+        let entry = state.entries.get_mut(key)?;
+        if entry.fingerprint != fingerprint || entry.image.width() < width {
+            return None;
+        }
+
+        let previous = std::mem::replace(&mut entry.last_used, clock);
+        let image = entry.image.clone();
+        state.recency.remove(&previous);
+        state.recency.insert(clock, key.to_string());
+        Some(image)
+    }
+
+    // Holding a decoded master, unless a larger copy of it is already held
+    // or it wouldn't fit in the budget on its own
+    pub fn insert(&self, key: &str, fingerprint: u64, image: Arc<DynamicImage>) {
+        let size = pixels(&image);
+        if size > self.budget { return; }
+
+        let mut state = self.state.lock().unwrap();
+        if let Some(entry) = state.entries.get(key) {
+            if entry.fingerprint == fingerprint && entry.image.width() >= image.width() {
+                return;
+            }
+        }
+        state.remove(key);
+
+        while state.used + size > self.budget {
+            let oldest = match state.recency.values().next() {
+                Some(oldest) => oldest.clone(),
+                None => break,
+            };
+            state.remove(&oldest);
+            state.evictions += 1;
+        }
+
+        state.clock += 1;
+        let last_used = state.clock;
+        state.used += size;
+        state.recency.insert(last_used, key.to_string());
+        state.entries.insert(key.to_string(), Entry { image, fingerprint, last_used });
+    }
+
+    #[cfg(feature = "server")]
+    pub fn usage(&self) -> Usage {
+        let state = self.state.lock().unwrap();
+        Usage {
+            entries: state.entries.len(),
+            pixels: state.used,
+            budget: self.budget,
+            evictions: state.evictions,
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use image::{DynamicImage, GenericImageView, GrayImage, Rgba, RgbaImage};
+use image::imageops::FilterType;
+use serde::{Serialize, Deserialize};
+
+use crate::{
+    analysis,
+    auth,
+    Bytes,
+    ImageServiceFailure,
+    ServerConfig,
+    encode_image,
+    image_buffer_as_http_response,
+    run_blocking,
+    try_loading_master,
+};
+
+
+// Comparing two uploads, such as screenshots rendered before and after a
+// change, by their structural similarity and how far apart their hashes are
+
+// How much any channel of a pixel may differ before the pixel counts as
+// changed, which leaves out the noise of lossy compression
+const TOLERANCE: u8 = 16;
+
+#[derive(Deserialize, Debug)]
+pub struct DiffInstructions {
+    a: String,
+    b: String,
+    // "json", or "png" for an image of what changed
+    #[serde(rename = "as")]
+    output: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Comparison {
+    a: String,
+    b: String,
+    // The mean structural similarity, which is 1 for identical images
+    ssim: f64,
+    // The share of pixels that changed, from 0 to 1
+    changed_pixels: f64,
+    phash_distance: u32,
+    dhash_distance: u32,
+    // Images of different sizes are compared with `b` resized to `a`
+    same_dimensions: bool,
+}
+
+enum Outcome {
+    Compared(Comparison),
+    Rendered(Bytes),
+}
+
+// The mean structural similarity of two greyscale images of the same size,
+// over 8x8 windows, from 1 when they're identical, down to 0 when they
+// share no structure at all
+fn ssim(a: &GrayImage, b: &GrayImage) -> f64 {
+    const WINDOW: u32 = 8;
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let (width, height) = a.dimensions();
+    let (window_width, window_height) = (WINDOW.min(width), WINDOW.min(height));
+    let n = (window_width * window_height) as f64;
+
+    let mut total = 0.0;
+    let mut windows = 0;
+    for top in (0..=height - window_height).step_by(window_height as usize) {
+        for left in (0..=width - window_width).step_by(window_width as usize) {
+            let (mut sum_a, mut sum_b, mut squares_a, mut squares_b, mut products) = (0.0, 0.0, 0.0, 0.0, 0.0);
+            for y in top..top + window_height {
+                for x in left..left + window_width {
+                    let (pa, pb) = (a.get_pixel(x, y)[0] as f64, b.get_pixel(x, y)[0] as f64);
+                    sum_a += pa;
+                    sum_b += pb;
+                    squares_a += pa * pa;
+                    squares_b += pb * pb;
+                    products += pa * pb;
+                }
+            }
+
+            let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+            let variance_a = squares_a / n - mean_a * mean_a;
+            let variance_b = squares_b / n - mean_b * mean_b;
+            let covariance = products / n - mean_a * mean_b;
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (variance_a + variance_b + C2));
+            windows += 1;
+        }
+    }
+    total / windows as f64
+}
+
+fn changed(a: &Rgba<u8>, b: &Rgba<u8>) -> bool {
+    a.0.iter().zip(b.0.iter()).any(|(a, b)| a.abs_diff(*b) > TOLERANCE)
+}
+
+fn count_changed(a: &RgbaImage, b: &RgbaImage) -> usize {
+    a.pixels().zip(b.pixels()).filter(|(a, b)| changed(a, b)).count()
+}
+
+// Drawing `a` in faded grey, with every pixel that changed in `b` in red
+fn render_diff(a: &RgbaImage, b: &RgbaImage) -> RgbaImage {
+    let mut canvas = RgbaImage::new(a.width(), a.height());
+    for ((pixel, pa), pb) in canvas.pixels_mut().zip(a.pixels()).zip(b.pixels()) {
+        *pixel = match changed(pa, pb) {
+            true => Rgba([255, 0, 0, 255]),
+            false => {
+                let [r, g, b, _] = pa.0;
+                let luma = (r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000;
+                let faded = (170 + luma / 3) as u8;
+                Rgba([faded, faded, faded, 255])
+            }
+        };
+    }
+    canvas
+}
+
+fn compare(config: &ServerConfig, a: String, b: String, render: bool) -> Result<Outcome, ImageServiceFailure> {
+    let first = try_loading_master(config, &a)?;
+    let second = try_loading_master(config, &b)?;
+
+    let (width, height) = first.dimensions();
+    let same_dimensions = second.dimensions() == (width, height);
+    let second = match same_dimensions {
+        true => second,
+        false => second.resize_exact(width, height, FilterType::Triangle),
+    };
+    let (first_pixels, second_pixels) = (first.to_rgba8(), second.to_rgba8());
+
+    if render {
+        let diff = DynamicImage::ImageRgba8(render_diff(&first_pixels, &second_pixels));
+        return encode_image(&diff, "png").map(Outcome::Rendered);
+    }
+
+    let changed_pixels = count_changed(&first_pixels, &second_pixels) as f64 / (width as f64 * height as f64);
+    let phash_distance = (analysis::cached_phash(config, &a)? ^ analysis::cached_phash(config, &b)?).count_ones();
+    let dhash_distance = (analysis::cached_dhash(config, &a)? ^ analysis::cached_dhash(config, &b)?).count_ones();
+
+    Ok(Outcome::Compared(Comparison {
+        ssim: ssim(&first.to_luma8(), &second.to_luma8()),
+        a,
+        b,
+        changed_pixels,
+        phash_distance,
+        dhash_distance,
+        same_dimensions,
+    }))
+}
+
+// Respond to a request to compare two uploads, like
+// `GET /diff?a=home-before&b=home-after&as=png`
+pub async fn serve_diff_via_http(
+    req: HttpRequest,
+    instructions: web::Query<DiffInstructions>,
+    config: web::Data<ServerConfig>,
+) -> HttpResponse {
+    if let Err(failure) = auth::authorize(&req, &config, auth::Access::Read) {
+        return failure.as_http_response();
+    }
+
+    let DiffInstructions { a, b, output } = instructions.into_inner();
+    for filename in [&a, &b] {
+        if let Err(failure) = auth::authorize_upload(&req, &config, auth::Access::Read, filename) {
+            return failure.as_http_response();
+        }
+    }
+    let render = match output.as_deref() {
+        None | Some("json") => false,
+        Some("png") => true,
+        Some(_) => return HttpResponse::BadRequest().body("`as` must be `json` or `png`"),
+    };
+
+    match run_blocking(move || compare(&config, a, b, render)).await {
+        Ok(Outcome::Compared(comparison)) => HttpResponse::Ok().json(comparison),
+        Ok(Outcome::Rendered(buffer)) => image_buffer_as_http_response(buffer, "png"),
+        Err(failure) => failure.as_http_response(&req),
+    }
+}
@@ -0,0 +1,50 @@
+use std::os::raw::c_int;
+use image::{DynamicImage, RgbImage, RgbaImage};
+use libwebp_sys::{
+    VP8StatusCode, WEBP_CSP_MODE, WEBP_DECODER_ABI_VERSION, WebPDecode, WebPDecoderConfig,
+    WebPFreeDecBuffer, WebPInitDecoderConfigInternal,
+};
+
+
+// Decoding a webp master straight to `width` by `height`, which libwebp
+// does a row at a time as it goes, so the full size image is never held
+// in memory. The webp crate doesn't expose this, so libwebp is called
+// directly
+pub fn decode_scaled(buffer: &[u8], width: u32, height: u32) -> Option<DynamicImage> {
+    let _span = tracing::info_span!("decode", bytes = buffer.len(), scaled_width = width, scaled_height = height).entered();
+
+    let mut config: WebPDecoderConfig = unsafe { std::mem::zeroed() };
+    if unsafe { WebPInitDecoderConfigInternal(&mut config, WEBP_DECODER_ABI_VERSION as c_int) } == 0 {
+        return None;
+    }
+
+    let has_alpha = webp::BitstreamFeatures::new(buffer)?.has_alpha();
+    let channels = if has_alpha { 4 } else { 3 };
+    config.output.colorspace = if has_alpha { WEBP_CSP_MODE::MODE_RGBA } else { WEBP_CSP_MODE::MODE_RGB };
+    config.options.use_scaling = 1;
+    config.options.scaled_width = width as c_int;
+    config.options.scaled_height = height as c_int;
+
+    let status = unsafe { WebPDecode(buffer.as_ptr(), buffer.len(), &mut config) };
+    let pixels = match status {
+        VP8StatusCode::VP8_STATUS_OK => {
+            // Rows may be padded, so they're copied one at a time
+            let output = unsafe { config.output.u.RGBA };
+            let contents = unsafe { std::slice::from_raw_parts(output.rgba, output.size) };
+            let row = width as usize * channels;
+            let mut pixels = Vec::with_capacity(row * height as usize);
+            for y in 0..height as usize {
+                let start = y * output.stride as usize;
+                pixels.extend_from_slice(&contents[start..start + row]);
+            }
+            Some(pixels)
+        }
+        _ => None,
+    };
+    unsafe { WebPFreeDecBuffer(&mut config.output) };
+
+    match has_alpha {
+        true => RgbaImage::from_raw(width, height, pixels?).map(DynamicImage::ImageRgba8),
+        false => RgbImage::from_raw(width, height, pixels?).map(DynamicImage::ImageRgb8),
+    }
+}
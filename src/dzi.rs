@@ -0,0 +1,163 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+
+use crate::{
+    analysis,
+    auth,
+    run_blocking,
+    serve_variant,
+    Caches,
+    FileDescription,
+    ImageServiceFailure,
+    ProcessingInstructions,
+    ServerConfig,
+};
+use crate::analysis::ImageName;
+use crate::iiif::{self, ImageRequest};
+
+
+// Uploads as Deep Zoom pyramids, for pan and zoom viewers of very large
+// images. A descriptor at /{filename}.dzi tells viewers how large the
+// image is, and they request the tiles they show from
+// /{filename}_files/{level}/{column}_{row}.{format}. Tiles are processed
+// the first time they're requested, and cached like any other variant
+
+const TILE_SIZE: u32 = 254;
+// How many pixels each tile shares with its neighbours
+const OVERLAP: u32 = 1;
+
+// The formats descriptors offer tiles in, from most preferred
+const TILE_FORMATS: [&str; 3] = ["jpeg", "webp", "png"];
+
+// (4000, 3000) -> 12
+// The deepest level, at which the image is at full size. Each level
+// above it is half as large, down to a single pixel at level 0
+fn deepest_level((width, height): (u32, u32)) -> u32 {
+    32 - width.max(height).saturating_sub(1).leading_zeros()
+}
+
+// (4000, 3000) at level 10 -> (1000, 750)
+fn size_at_level((width, height): (u32, u32), level: u32) -> (u32, u32) {
+    let scale = 1u64 << (deepest_level((width, height)) - level);
+    (
+        (width as u64).div_ceil(scale) as u32,
+        (height as u64).div_ceil(scale) as u32,
+    )
+}
+
+// (x, y, width, height)
+type Region = (u32, u32, u32, u32);
+
+// The region of the master a tile covers, and the size of the tile, if
+// there's a tile at this level, column and row
+fn tile((width, height): (u32, u32), level: u32, column: u32, row: u32) -> Option<(Region, (u32, u32))> {
+    if level > deepest_level((width, height)) {
+        return None;
+    }
+    let (level_width, level_height) = size_at_level((width, height), level);
+
+    // Tiles overlap those beside them, except at the image's edges
+    let span = |index: u32, length: u32| {
+        let start = index as u64 * TILE_SIZE as u64;
+        if start >= length as u64 {
+            return None;
+        }
+        let from = if index > 0 { start - OVERLAP as u64 } else { start };
+        let to = (start + TILE_SIZE as u64 + OVERLAP as u64).min(length as u64);
+        Some((from as u32, (to - from) as u32))
+    };
+    let (x, tile_width) = span(column, level_width)?;
+    let (y, tile_height) = span(row, level_height)?;
+
+    // Where the tile is in the master, cut short at its edges
+    let scale = 1u64 << (deepest_level((width, height)) - level);
+    let (x, y) = (x as u64 * scale, y as u64 * scale);
+    let region = (
+        x as u32,
+        y as u32,
+        (tile_width as u64 * scale).min(width as u64 - x) as u32,
+        (tile_height as u64 * scale).min(height as u64 - y) as u32,
+    );
+    Some((region, (tile_width, tile_height)))
+}
+
+// Respond to a request for the Deep Zoom descriptor of an image
+pub async fn serve_dzi_via_http(
+    req: HttpRequest,
+    name: web::Path<ImageName>,
+    config: web::Data<ServerConfig>,
+) -> HttpResponse {
+    if let Err(failure) = auth::authorize_upload(&req, &config, auth::Access::Read, &name.filename) {
+        return failure.as_http_response();
+    }
+
+    let dimensions = {
+        let (config, filename) = (config.clone(), name.filename.clone());
+        run_blocking(move || analysis::cached_dimensions(&config, &filename)).await
+    };
+    let (width, height) = match dimensions {
+        Ok(dimensions) => dimensions,
+        Err(failure) => return failure.as_http_response(&req),
+    };
+
+    let format = TILE_FORMATS
+        .iter()
+        .find(|format| config.output_formats.iter().any(|output| output == *format))
+        .unwrap_or(&TILE_FORMATS[0]);
+    let descriptor = format!(
+        concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<Image xmlns=\"http://schemas.microsoft.com/deepzoom/2008\" Format=\"{}\" Overlap=\"{}\" TileSize=\"{}\">",
+            "<Size Width=\"{}\" Height=\"{}\"/></Image>\n",
+        ),
+        format, OVERLAP, TILE_SIZE, width, height,
+    );
+
+    HttpResponse::Ok().content_type("application/xml").body(descriptor)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TilePath {
+    filename: String,
+    level: u32,
+    column: u32,
+    row: u32,
+    format: String,
+}
+
+// Respond to a request for one tile of an image's pyramid
+pub async fn serve_dzi_tile_via_http(
+    req: HttpRequest,
+    path: web::Path<TilePath>,
+    config: web::Data<ServerConfig>,
+    caches: web::Data<Caches>,
+) -> HttpResponse {
+    if let Err(failure) = auth::authorize_upload(&req, &config, auth::Access::Read, &path.filename) {
+        return failure.as_http_response();
+    }
+
+    let extension = match iiif::extension_of(&path.format, &config) {
+        Some(extension) => extension,
+        None => {
+            let message = format!("{:?} isn't a format that's served. Try jpeg, png or webp", path.format);
+            return ImageServiceFailure::invalid_parameter("format", &message).as_http_response(&req);
+        }
+    };
+
+    let dimensions = {
+        let (config, filename) = (config.clone(), path.filename.clone());
+        run_blocking(move || analysis::cached_dimensions(&config, &filename)).await
+    };
+    let (region, size) = match dimensions.map(|dimensions| tile(dimensions, path.level, path.column, path.row)) {
+        Ok(Some(tile)) => tile,
+        Ok(None) => return HttpResponse::NotFound().body("There is no tile at this level, column and row"),
+        Err(failure) => return failure.as_http_response(&req),
+    };
+
+    let required = FileDescription { filename: path.filename.clone(), extension: extension.to_string() };
+    let optional = ProcessingInstructions::of_iiif(ImageRequest::of_region(region, size));
+    match serve_variant(&req, &required, &optional, &config, &caches, false).await {
+        Ok(response) => response,
+        Err(failure) => failure.as_http_response(&req),
+    }
+}
@@ -0,0 +1,191 @@
+use std::os::raw::{c_int, c_void};
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::{DynamicImage, GenericImageView, ImageOutputFormat};
+use libwebp_sys::{
+    WEBP_ENCODER_ABI_VERSION, WebPConfig, WebPConfigInitInternal, WebPEncode, WebPPicture,
+    WebPPictureFree, WebPPictureImportRGB, WebPPictureImportRGBA, WebPPictureInitInternal, WebPPreset,
+    WebPValidateConfig,
+};
+
+use crate::{Bytes, ImageServiceFailure, ImageServiceResult, cause};
+
+
+// How much effort encoding PNGs spends on making them smaller
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PngCompression {
+    Fast,
+    Default,
+    Best,
+}
+
+impl PngCompression {
+    // "best" -> Some(PngCompression::Best)
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "fast" => Some(Self::Fast),
+            "default" => Some(Self::Default),
+            "best" => Some(Self::Best),
+            _ => None,
+        }
+    }
+}
+
+// How each format is encoded when a request doesn't choose a quality
+// with `?q=`. By default, JPEGs are encoded at full quality, WebPs are
+// lossless, and PNGs are compressed quickly
+#[derive(Clone, Debug)]
+pub struct EncodingDefaults {
+    pub jpeg_quality: u8,
+    // Lossless, unless given a quality
+    pub webp_quality: Option<u8>,
+    // From 0, the fastest, to 6, the smallest, for masters too
+    pub webp_method: u8,
+    pub webp_multithreaded: bool,
+    pub png_compression: PngCompression,
+}
+
+// What libwebp uses when it isn't told otherwise
+const DEFAULT_WEBP_METHOD: u8 = 4;
+const LOSSLESS_WEBP_QUALITY: f32 = 70.0;
+
+impl Default for EncodingDefaults {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EncodingDefaults {
+    pub fn new() -> Self {
+        Self {
+            jpeg_quality: 100,
+            webp_quality: None,
+            webp_method: DEFAULT_WEBP_METHOD,
+            webp_multithreaded: false,
+            png_compression: PngCompression::Fast,
+        }
+    }
+
+    // From 1 to 100
+    pub fn with_jpeg_quality(mut self, quality: u8) -> Self {
+        self.jpeg_quality = quality.clamp(1, 100);
+        self
+    }
+
+    // Encoding WebPs lossily, from 1 to 100
+    pub fn with_webp_quality(mut self, quality: u8) -> Self {
+        self.webp_quality = Some(quality.clamp(1, 100));
+        self
+    }
+
+    // Trading how small WebPs are for how quickly they're encoded, from 0
+    // to 6. Lossless encoding of large images can take seconds at 4
+    pub fn with_webp_method(mut self, method: u8) -> Self {
+        self.webp_method = method.min(6);
+        self
+    }
+
+    // Letting libwebp use a second thread for the parts of encoding it
+    // can split, leaving fewer threads for other requests
+    pub fn with_webp_multithreading(mut self) -> Self {
+        self.webp_multithreaded = true;
+        self
+    }
+
+    pub fn with_png_compression(mut self, compression: PngCompression) -> Self {
+        self.png_compression = compression;
+        self
+    }
+}
+
+fn encode_png(dynamic_image: &DynamicImage, compression: PngCompression) -> ImageServiceResult {
+    let compression = match compression {
+        PngCompression::Fast => CompressionType::Fast,
+        PngCompression::Default => CompressionType::Default,
+        PngCompression::Best => CompressionType::Best,
+    };
+
+    let mut buffer: Bytes = Vec::new();
+    let (width, height) = dynamic_image.dimensions();
+    PngEncoder::new_with_quality(&mut buffer, compression, FilterType::Sub)
+        .encode(dynamic_image.as_bytes(), width, height, dynamic_image.color())
+        .map_err(|e| ImageServiceFailure::CouldNotEncode { source: cause(e) })?;
+    Ok(buffer)
+}
+
+// Appending what libwebp encodes to the buffer behind `custom_ptr`, so
+// that it's written where it'll be kept rather than copied there after
+unsafe extern "C" fn write_to_buffer(data: *const u8, size: usize, picture: *const WebPPicture) -> c_int {
+    let buffer = &mut *((*picture).custom_ptr as *mut Bytes);
+    buffer.extend_from_slice(std::slice::from_raw_parts(data, size));
+    1
+}
+
+// Encoding a WebP lossily at `quality`, or losslessly without one, with
+// the method and threading of `defaults`. The webp crate only encodes
+// with libwebp's defaults, so libwebp is called directly
+pub fn encode_webp(dynamic_image: &DynamicImage, quality: Option<u8>, defaults: &EncodingDefaults) -> ImageServiceResult {
+    let (width, height) = dynamic_image.dimensions();
+    let has_alpha = dynamic_image.color().has_alpha();
+    let pixels = match (dynamic_image, has_alpha) {
+        (DynamicImage::ImageRgba8(image), _) => std::borrow::Cow::Borrowed(image.as_raw()),
+        (DynamicImage::ImageRgb8(image), _) => std::borrow::Cow::Borrowed(image.as_raw()),
+        (_, true) => std::borrow::Cow::Owned(dynamic_image.to_rgba8().into_raw()),
+        (_, false) => std::borrow::Cow::Owned(dynamic_image.to_rgb8().into_raw()),
+    };
+    let stride = width as c_int * if has_alpha { 4 } else { 3 };
+
+    let mut config: WebPConfig = unsafe { std::mem::zeroed() };
+    let mut picture: WebPPicture = unsafe { std::mem::zeroed() };
+    let mut buffer: Bytes = Vec::new();
+    let abi = WEBP_ENCODER_ABI_VERSION as c_int;
+    let initialized = unsafe {
+        WebPConfigInitInternal(&mut config, WebPPreset::WEBP_PRESET_DEFAULT, quality.map(f32::from).unwrap_or(LOSSLESS_WEBP_QUALITY), abi) != 0
+            && WebPPictureInitInternal(&mut picture, abi) != 0
+    };
+    if !initialized {
+        return Err(ImageServiceFailure::CouldNotEncode { source: None });
+    }
+
+    config.lossless = quality.is_none() as c_int;
+    config.method = defaults.webp_method as c_int;
+    config.thread_level = defaults.webp_multithreaded as c_int;
+    picture.use_argb = config.lossless;
+    picture.width = width as c_int;
+    picture.height = height as c_int;
+    picture.writer = Some(write_to_buffer);
+    picture.custom_ptr = &mut buffer as *mut Bytes as *mut c_void;
+
+    let encoded = unsafe {
+        let imported = match has_alpha {
+            true => WebPPictureImportRGBA(&mut picture, pixels.as_ptr(), stride),
+            false => WebPPictureImportRGB(&mut picture, pixels.as_ptr(), stride),
+        };
+        let ok = WebPValidateConfig(&config) != 0 && imported != 0 && WebPEncode(&config, &mut picture) != 0;
+        WebPPictureFree(&mut picture);
+        ok
+    };
+    match encoded {
+        true => Ok(buffer),
+        false => Err(ImageServiceFailure::CouldNotEncode { source: None }),
+    }
+}
+
+// Encoding an image in the format named by `extension`, at `quality`
+// (from 1 to 100) if one was requested. PNGs are always lossless, so
+// they ignore it
+pub fn encode(dynamic_image: &DynamicImage, extension: &str, quality: Option<u8>, defaults: &EncodingDefaults) -> ImageServiceResult {
+    let _span = tracing::info_span!("encode", extension, q = quality).entered();
+    match extension {
+        "webp" => encode_webp(dynamic_image, quality.or(defaults.webp_quality), defaults),
+        "png" => encode_png(dynamic_image, defaults.png_compression),
+        "jpeg" => {
+            let mut buffer: Bytes = Vec::new();
+            let quality = quality.unwrap_or(defaults.jpeg_quality);
+            dynamic_image
+                .write_to(&mut buffer, ImageOutputFormat::Jpeg(quality))
+                .map_err(|e| ImageServiceFailure::CouldNotEncode { source: cause(e) })?;
+            Ok(buffer)
+        },
+        _ => Result::Err(ImageServiceFailure::UnsupportedFormat)
+    }
+}
@@ -0,0 +1,257 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use async_graphql::{Context, EmptySubscription, Error, Object, Result, Schema};
+
+use crate::{
+    analysis,
+    auth,
+    build_path_to_master,
+    cache,
+    cause,
+    delete_upload,
+    listing,
+    metadata,
+    rename_upload,
+    run_blocking,
+    valid_new_name,
+    writable,
+    Caches,
+    DeleteEvent,
+    ImageServiceFailure,
+    ServerConfig,
+};
+use crate::listing::{Search, DEFAULT_PAGE, MAX_PAGE};
+
+
+// Uploads as a GraphQL API at /graphql, for content management systems
+// that would rather ask for exactly the fields they need. Queries need
+// the same access as reading images, and mutations the same access as
+// uploading them
+
+const MAX_TAGS: usize = 32;
+const MAX_TAG_LENGTH: usize = 64;
+
+pub type ImogenSchema = Schema<Query, Mutation, EmptySubscription>;
+
+pub fn schema() -> ImogenSchema {
+    Schema::build(Query, Mutation, EmptySubscription).limit_depth(8).finish()
+}
+
+// Whether the request may change uploads, or why it may not
+struct WriteAccess(std::result::Result<(), &'static str>);
+
+fn config<'a>(ctx: &Context<'a>) -> &'a web::Data<ServerConfig> {
+    ctx.data_unchecked::<web::Data<ServerConfig>>()
+}
+
+fn check_write(ctx: &Context<'_>) -> Result<()> {
+    ctx.data_unchecked::<WriteAccess>().0.map_err(Error::new)
+}
+
+fn check_exists(config: &ServerConfig, filename: &str) -> std::result::Result<(), ImageServiceFailure> {
+    match config.storage.exists(&build_path_to_master(config, filename)) {
+        true => Ok(()),
+        false => Err(ImageServiceFailure::ImageDoesNotExist),
+    }
+}
+
+// ["  Cats", "dogs", "cats"] -> ["cats", "dogs"]
+fn clean_tags(tags: Vec<String>) -> Result<Vec<String>> {
+    let mut cleaned: Vec<String> = tags.iter().map(|tag| tag.trim().to_lowercase()).collect();
+    if let Some(tag) = cleaned.iter().find(|tag| tag.is_empty() || tag.chars().count() > MAX_TAG_LENGTH) {
+        return Err(Error::new(format!("{:?} isn't a tag. Tags have between 1 and {} characters", tag, MAX_TAG_LENGTH)));
+    }
+
+    cleaned.sort();
+    cleaned.dedup();
+    match cleaned.len() > MAX_TAGS {
+        true => Err(Error::new(format!("An upload can have at most {} tags", MAX_TAGS))),
+        false => Ok(cleaned),
+    }
+}
+
+// One upload, whose fields are only looked up when they're asked for
+pub struct Upload {
+    filename: String,
+}
+
+#[Object]
+impl Upload {
+    async fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    // Where the upload is served, as a webp at its own size
+    async fn url(&self, ctx: &Context<'_>) -> String {
+        format!("{}/{}.webp", config(ctx).base_path, self.filename)
+    }
+
+    async fn width(&self, ctx: &Context<'_>) -> Result<u32> {
+        let (config, filename) = (config(ctx).clone(), self.filename.clone());
+        Ok(run_blocking(move || analysis::cached_dimensions(&config, &filename)).await?.0)
+    }
+
+    async fn height(&self, ctx: &Context<'_>) -> Result<u32> {
+        let (config, filename) = (config(ctx).clone(), self.filename.clone());
+        Ok(run_blocking(move || analysis::cached_dimensions(&config, &filename)).await?.1)
+    }
+
+    // The size of the stored master, in bytes
+    async fn bytes(&self, ctx: &Context<'_>) -> Result<Option<u64>> {
+        let (config, filename) = (config(ctx).clone(), self.filename.clone());
+        let stored = run_blocking(move || Ok(config.storage.metadata(&build_path_to_master(&config, &filename)))).await?;
+        Ok(stored.map(|stored| stored.size))
+    }
+
+    // Seconds since the unix epoch, for uploads made with an API key
+    async fn uploaded_at(&self, ctx: &Context<'_>) -> Result<Option<u64>> {
+        let (config, filename) = (config(ctx).clone(), self.filename.clone());
+        Ok(run_blocking(move || Ok(metadata::read(&config, &filename))).await?.uploaded_at)
+    }
+
+    // The name of the API key the upload was made with
+    async fn uploaded_by(&self, ctx: &Context<'_>) -> Result<Option<String>> {
+        let (config, filename) = (config(ctx).clone(), self.filename.clone());
+        let record = run_blocking(move || Ok(metadata::read(&config, &filename))).await?;
+        Ok(record.uploaded_by.map(|identity| identity.name))
+    }
+
+    async fn tags(&self, ctx: &Context<'_>) -> Result<Vec<String>> {
+        let (config, filename) = (config(ctx).clone(), self.filename.clone());
+        Ok(run_blocking(move || Ok(metadata::read(&config, &filename))).await?.tags)
+    }
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    // Uploads in order of their names, beginning after `after`, and only
+    // those tagged with `tag` when it's given
+    async fn uploads(
+        &self,
+        ctx: &Context<'_>,
+        tag: Option<String>,
+        first: Option<usize>,
+        after: Option<String>,
+    ) -> Result<Vec<Upload>> {
+        let first = first.unwrap_or(DEFAULT_PAGE);
+        if first > MAX_PAGE {
+            return Err(Error::new(format!("At most {} uploads can be listed at once", MAX_PAGE)));
+        }
+
+        let config = config(ctx).clone();
+        let search = Search { tag, after, limit: Some(first), ..Search::default() };
+        let filenames = run_blocking(move || Ok(listing::search_names(&config, &search))).await?;
+
+        Ok(filenames.into_iter().map(|filename| Upload { filename }).collect())
+    }
+
+    // The upload named `filename`, like "cat", if there is one
+    async fn upload(&self, ctx: &Context<'_>, filename: String) -> Result<Option<Upload>> {
+        let config = config(ctx).clone();
+        let target = filename.clone();
+        let exists = run_blocking(move || Ok(check_exists(&config, &target).is_ok())).await?;
+        Ok(match exists {
+            true => Some(Upload { filename }),
+            false => None,
+        })
+    }
+}
+
+pub struct Mutation;
+
+#[Object]
+impl Mutation {
+    // Removing an upload, along with its metadata and cached variants
+    async fn delete_upload(&self, ctx: &Context<'_>, filename: String) -> Result<bool> {
+        check_write(ctx)?;
+        let caches = ctx.data_unchecked::<web::Data<Caches>>();
+        let hooks = config(ctx).clone();
+
+        let (config, target) = (config(ctx).clone(), filename.clone());
+        run_blocking(move || {
+            check_exists(&config, &target)?;
+            delete_upload(&config, &target).map_err(|e| ImageServiceFailure::CouldNotWrite { source: cause(e) })
+        }).await?;
+
+        caches.memory.remove_where(|variant_name| cache::is_derived_from(&filename, variant_name));
+        hooks.hooks.delete(DeleteEvent { filename, renamed_to: None });
+        Ok(true)
+    }
+
+    async fn rename_upload(&self, ctx: &Context<'_>, filename: String, to: String) -> Result<Upload> {
+        check_write(ctx)?;
+        if !valid_new_name(&to) {
+            return Err(Error::new("The new name is not a valid filename"));
+        }
+        let caches = ctx.data_unchecked::<web::Data<Caches>>();
+        let hooks = config(ctx).clone();
+
+        let (config, from, target) = (config(ctx).clone(), filename.clone(), to.clone());
+        run_blocking(move || {
+            check_exists(&config, &from)?;
+            if config.storage.exists(&build_path_to_master(&config, &target)) {
+                return Err(ImageServiceFailure::invalid_parameter("to", "Another file with this name already exists."));
+            }
+            rename_upload(&config, &from, &target).map_err(|e| ImageServiceFailure::CouldNotWrite { source: cause(e) })
+        }).await?;
+
+        caches.memory.remove_where(|variant_name| cache::is_derived_from(&filename, variant_name));
+        caches.missing.forget(&to);
+        hooks.hooks.delete(DeleteEvent { filename, renamed_to: Some(to.clone()) });
+        Ok(Upload { filename: to })
+    }
+
+    // Removing the cached variants of an upload, so that they're processed
+    // again from its master. This is how many were removed
+    async fn purge_upload(&self, ctx: &Context<'_>, filename: String) -> Result<usize> {
+        check_write(ctx)?;
+        let caches = ctx.data_unchecked::<web::Data<Caches>>();
+        caches.memory.remove_where(|variant_name| cache::is_derived_from(&filename, variant_name));
+
+        let config = config(ctx).clone();
+        Ok(run_blocking(move || {
+            check_exists(&config, &filename)?;
+            Ok(cache::remove_variants_of(&config, &filename))
+        }).await?)
+    }
+
+    // Replacing the tags of an upload, like ["cats", "homepage"]
+    async fn set_tags(&self, ctx: &Context<'_>, filename: String, tags: Vec<String>) -> Result<Upload> {
+        check_write(ctx)?;
+        let tags = clean_tags(tags)?;
+
+        let (config, target) = (config(ctx).clone(), filename.clone());
+        run_blocking(move || {
+            check_exists(&config, &target)?;
+            metadata::save_tags(&config, &target, tags).map_err(|e| ImageServiceFailure::CouldNotWrite { source: cause(e) })
+        }).await?;
+        Ok(Upload { filename })
+    }
+}
+
+// Respond to a GraphQL request, POSTed as JSON
+pub async fn serve_graphql_via_http(
+    req: HttpRequest,
+    request: web::Json<async_graphql::Request>,
+    schema: web::Data<ImogenSchema>,
+    config: web::Data<ServerConfig>,
+    caches: web::Data<Caches>,
+) -> HttpResponse {
+    if let Err(failure) = auth::authorize(&req, &config, auth::Access::Read) {
+        return failure.as_http_response();
+    }
+    let write = auth::authorize(&req, &config, auth::Access::Write).map(|_| ()).map_err(|failure| failure.message());
+
+    let request = request.into_inner()
+        .data(config)
+        .data(caches)
+        .data(WriteAccess(write));
+    HttpResponse::Ok().json(schema.execute(request).await)
+}
+
+pub fn configure_routes(cfg: &mut web::ServiceConfig) {
+    cfg
+        .data(schema())
+        .service(writable("/graphql", serve_graphql_via_http));
+}
@@ -0,0 +1,322 @@
+use std::net::SocketAddr;
+use actix_web::web;
+use tonic::metadata::MetadataMap;
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::{
+    analysis,
+    auth,
+    build_path_to_master,
+    cache,
+    cause,
+    encode_master,
+    listing,
+    master_dimensions,
+    metadata,
+    pipeline,
+    record_processing,
+    refresh_master_from_origin,
+    run_blocking,
+    save_master,
+    strip_extension,
+    try_loading_unprocessed_image,
+    try_processing_image,
+    Caches,
+    FileDescription,
+    ImageServiceFailure,
+    ProcessingInstructions,
+    ServerConfig,
+    UploadEvent,
+};
+use crate::cache::Freshness;
+
+#[allow(clippy::all)]
+mod proto {
+    tonic::include_proto!("imogen");
+}
+
+use proto::imogen_server::{Imogen, ImogenServer};
+use proto::{ImageInfo, InfoRequest, ProcessReply, ProcessRequest, UploadChunk, UploadReply};
+
+
+// Uploads and processing as a gRPC service, defined by proto/imogen.proto,
+// for other services to call without multipart HTTP. It shares the
+// caches of the server's own site, and the blocking threadpool that
+// processes images, but tonic needs tokio 1, so it's served by a runtime
+// of its own on another thread
+
+impl From<ImageServiceFailure> for Status {
+    fn from(failure: ImageServiceFailure) -> Self {
+        let message = failure.to_string();
+        match failure {
+            ImageServiceFailure::ImageDoesNotExist => Status::not_found(message),
+            ImageServiceFailure::UnsupportedFormat
+            | ImageServiceFailure::InvalidParameter { .. }
+            | ImageServiceFailure::TooManyPixels { .. } => Status::invalid_argument(message),
+            ImageServiceFailure::Overloaded => Status::resource_exhausted(message),
+            ImageServiceFailure::TransformFailed { .. } => Status::unavailable(message),
+            _ => Status::internal(message),
+        }
+    }
+}
+
+// Deciding whether a call may proceed as the HTTP route at `path` would
+fn authorize(
+    metadata: &MetadataMap,
+    config: &ServerConfig,
+    access: auth::Access,
+    path: &str,
+) -> Result<Option<auth::Identity>, auth::AuthFailure> {
+    let header = |name: &str| metadata.get(name).and_then(|value| value.to_str().ok());
+    let presented = auth::credential_in(header("authorization"), header("x-api-key"));
+    auth::authorize_credential(presented, config, access, path)
+}
+
+fn refused(failure: auth::AuthFailure) -> Status {
+    match failure {
+        auth::AuthFailure::MissingCredentials | auth::AuthFailure::InvalidCredentials => Status::unauthenticated(failure.message()),
+        _ => Status::permission_denied(failure.message()),
+    }
+}
+
+// A variant of an upload, and whether it had been processed before. It's
+// found and produced as it is for HTTP requests, so that variants
+// processed for either are cached for both
+fn process_variant(
+    config: &ServerConfig,
+    caches: &Caches,
+    required: &FileDescription,
+    optional: &ProcessingInstructions,
+) -> Result<(web::Bytes, bool), ImageServiceFailure> {
+    if let Some(origin) = &config.origin {
+        refresh_master_from_origin(config, caches, origin, &required.filename)?;
+    }
+    if caches.missing.is_missing(&required.filename) {
+        return Err(ImageServiceFailure::ImageDoesNotExist);
+    }
+
+    let cache_key = optional.cache_key();
+    let master = build_path_to_master(config, &required.filename);
+    if cache_key.is_empty() && required.extension == "webp" {
+        return Ok((web::Bytes::from(try_loading_unprocessed_image(config, &master)?), true));
+    }
+
+    let variant_name = cache::variant_name(&required.filename, &cache_key, &required.extension);
+    let filepath = cache::build_path_to_variant(config, &variant_name, &required.extension);
+    cache::discard_if_damaged(config, &filepath);
+    match cache::check_freshness(config, &required.filename, &filepath, None) {
+        Freshness::Fresh => {
+            if let Some(buffer) = caches.memory.get(&variant_name) {
+                return Ok((buffer, true));
+            }
+            if let Ok(buffer) = try_loading_unprocessed_image(config, &filepath) {
+                return Ok((web::Bytes::from(buffer), true));
+            }
+        }
+        Freshness::Stale => {}
+        Freshness::Discarded => caches.memory.remove_where(|name| cache::is_derived_from(&required.filename, name)),
+    }
+
+    let processed = caches.flights.run(&variant_name, || {
+        let _slot = caches.processing.acquire().ok_or(ImageServiceFailure::Overloaded)?;
+        let unprocessed_image = match try_loading_unprocessed_image(config, &master) {
+            Err(ImageServiceFailure::ImageDoesNotExist) => {
+                caches.missing.record_missing(&required.filename);
+                return Err(ImageServiceFailure::ImageDoesNotExist);
+            }
+            loaded => loaded?,
+        };
+
+        let (processed_image, timings) = try_processing_image(unprocessed_image, optional, required, config, &caches.budget, &caches.decoded)?;
+        let processed_image = web::Bytes::from(processed_image);
+        record_processing(config, caches, &variant_name, optional, required, &timings);
+
+        if let Err(e) = cache::store_variant(config, &required.filename, &cache_key, &required.extension, &processed_image) {
+            tracing::warn!(variant = %variant_name, error = %e, "variant could not be cached");
+        }
+        caches.memory.insert(&variant_name, processed_image.clone());
+        Ok(processed_image)
+    })?;
+    Ok((processed, false))
+}
+
+pub struct ImogenService {
+    config: web::Data<ServerConfig>,
+    caches: web::Data<Caches>,
+}
+
+#[tonic::async_trait]
+impl Imogen for ImogenService {
+    async fn upload_image(&self, request: Request<Streaming<UploadChunk>>) -> Result<Response<UploadReply>, Status> {
+        if self.config.origin.is_some() {
+            return Err(Status::failed_precondition("Uploads are disabled while proxying an origin"));
+        }
+        let metadata = request.metadata().clone();
+        let mut chunks = request.into_inner();
+        let mut filename = String::new();
+        let mut uploader = None;
+        let mut contents = vec![];
+        while let Some(chunk) = chunks.message().await? {
+            // Authorizing against where the upload would be saved, once
+            // the first chunk names it
+            if filename.is_empty() {
+                filename = strip_extension(&chunk.filename);
+                if filename.is_empty() {
+                    return Err(Status::invalid_argument("The first chunk must name the file, like cat.png"));
+                }
+                uploader = authorize(&metadata, &self.config, auth::Access::Write, &auth::upload_path(&filename)).map_err(refused)?;
+            }
+            if contents.len() + chunk.data.len() > self.config.max_upload_size {
+                return Err(Status::invalid_argument(format!("Uploads are limited to {} bytes", self.config.max_upload_size)));
+            }
+            contents.extend_from_slice(&chunk.data);
+        }
+
+        if filename.is_empty() {
+            return Err(Status::invalid_argument("The first chunk must name the file, like cat.png"));
+        }
+        if contents.is_empty() {
+            return Err(Status::invalid_argument("No file data was provided."));
+        }
+
+        let (config, clean_filename) = (self.config.clone(), filename.clone());
+        let uploaded_by = uploader.clone();
+        let stored = run_blocking(move || {
+            if config.storage.exists(&build_path_to_master(&config, &clean_filename)) {
+                return Err(ImageServiceFailure::invalid_parameter("filename", "Another file with this name already exists."));
+            }
+            let master = encode_master(contents, &pipeline::Settings::from(config.get_ref()))?;
+            save_master(&config, &clean_filename, &master).map_err(|e| ImageServiceFailure::CouldNotWrite { source: cause(e) })?;
+
+            // Recording which API key was used for the upload
+            if let Some(uploader) = &uploaded_by {
+                if metadata::record_upload(&config, &clean_filename, uploader).is_err() {
+                    tracing::warn!(filename = %clean_filename, "upload metadata could not be recorded");
+                }
+            }
+            listing::update_index(&config, &clean_filename);
+            let (width, height) = master_dimensions(&master, config.max_pixels).unwrap_or_default();
+            Ok(UploadEvent {
+                filename: clean_filename,
+                bytes: master.len(),
+                width,
+                height,
+                uploaded_by: uploaded_by.map(|uploader| uploader.name),
+            })
+        }).await?;
+
+        self.caches.missing.forget(&filename);
+        let reply = UploadReply { filename, bytes: stored.bytes as u64, width: stored.width, height: stored.height };
+        tracing::info!(filename = %reply.filename, bytes = reply.bytes, "upload saved over gRPC");
+        self.config.hooks.upload(stored);
+        Ok(Response::new(reply))
+    }
+
+    async fn process_image(&self, request: Request<ProcessRequest>) -> Result<Response<ProcessReply>, Status> {
+        let path = auth::upload_path(&request.get_ref().filename);
+        authorize(request.metadata(), &self.config, auth::Access::Read, &path).map_err(refused)?;
+        let ProcessRequest { filename, extension, instructions } = request.into_inner();
+
+        let optional = web::Query::<ProcessingInstructions>::from_query(&instructions)
+            .map_err(|_| ImageServiceFailure::invalid_parameter("instructions", &format!("{:?} could not be parsed", instructions)))?
+            .into_inner()
+            .with_transforms(&instructions, &self.config.transforms)?;
+
+        // Signatures only sign URLs, so only presets can be asked for here
+        // when they're all that URLs may ask for without one
+        if self.config.presets_only {
+            if let Some(parameter) = optional.explicit_instructions().first() {
+                return Err(ImageServiceFailure::invalid_parameter(parameter, "Only presets can be requested. Try preset={name}").into());
+            }
+        }
+        let optional = optional
+            .with_preset(&self.config.presets, &self.config.transforms)
+            .map_err(|message| ImageServiceFailure::invalid_parameter("preset", &message))?;
+        optional.check_quality().map_err(|message| ImageServiceFailure::invalid_parameter("q", &message))?;
+
+        if !self.config.serves_format(&extension) {
+            return Err(self.config.unserved_format(&extension).into());
+        }
+
+        let required = FileDescription { filename, extension };
+        let content_type = format!("image/{}", required.extension);
+        let (config, caches) = (self.config.clone(), self.caches.clone());
+        let (image, cached) = run_blocking(move || process_variant(&config, &caches, &required, &optional)).await?;
+        Ok(Response::new(ProcessReply { image: image.to_vec(), content_type, cached }))
+    }
+
+    async fn get_info(&self, request: Request<InfoRequest>) -> Result<Response<ImageInfo>, Status> {
+        let path = auth::upload_path(&request.get_ref().filename);
+        authorize(request.metadata(), &self.config, auth::Access::Read, &path).map_err(refused)?;
+        let filename = request.into_inner().filename;
+
+        let config = self.config.clone();
+        let info = run_blocking(move || {
+            let (width, height) = analysis::cached_dimensions(&config, &filename)?;
+            let bytes = config.storage.metadata(&build_path_to_master(&config, &filename)).map(|stored| stored.size).unwrap_or(0);
+            let record = metadata::read(&config, &filename);
+            Ok(ImageInfo {
+                filename,
+                width,
+                height,
+                bytes,
+                uploaded_by: record.uploaded_by.map(|identity| identity.name).unwrap_or_default(),
+                uploaded_at: record.uploaded_at.unwrap_or(0),
+                tags: record.tags,
+            })
+        }).await?;
+        Ok(Response::new(info))
+    }
+}
+
+// The gRPC service while it's serving, until it's stopped
+pub struct GrpcServer {
+    stop: tokio1::sync::oneshot::Sender<()>,
+    thread: std::thread::JoinHandle<()>,
+}
+
+impl GrpcServer {
+    // Finishing calls in flight, and then stopping
+    pub fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.thread.join();
+    }
+}
+
+// Listening at `address`, like "0.0.0.0:50051", which fails here rather
+// than on the service's own thread when it can't be bound
+pub fn start(address: &str, config: web::Data<ServerConfig>, caches: web::Data<Caches>) -> Result<GrpcServer, String> {
+    let address: SocketAddr = address.parse().map_err(|_| format!("{:?} isn't a gRPC address, like 0.0.0.0:50051", address))?;
+    let listener = std::net::TcpListener::bind(address).map_err(|e| format!("Failed to bind gRPC to {}: {}", address, e))?;
+    listener.set_nonblocking(true).map_err(|e| format!("Failed to bind gRPC to {}: {}", address, e))?;
+
+    let runtime = tokio1::runtime::Builder::new_multi_thread()
+        .thread_name("imogen-grpc")
+        .enable_all()
+        .build()
+        .map_err(|e| format!("Failed to start the gRPC runtime: {}", e))?;
+
+    let (stop, stopped) = tokio1::sync::oneshot::channel::<()>();
+    let service = ImogenServer::new(ImogenService { config, caches });
+    let thread = std::thread::spawn(move || {
+        runtime.block_on(async move {
+            let incoming = tokio1::net::TcpListener::from_std(listener)
+                .map_err(|e| e.to_string())
+                .and_then(|listener| tonic::transport::server::TcpIncoming::from_listener(listener, true, None).map_err(|e| e.to_string()));
+            let served = match incoming {
+                Ok(incoming) => tonic::transport::Server::builder()
+                    .add_service(service)
+                    .serve_with_incoming_shutdown(incoming, async { let _ = stopped.await; })
+                    .await
+                    .map_err(|e| e.to_string()),
+                Err(message) => Err(message),
+            };
+            if let Err(message) = served {
+                tracing::error!(error = %message, "the gRPC service stopped unexpectedly");
+            }
+        });
+    });
+
+    tracing::info!(address = %address, "serving gRPC");
+    Ok(GrpcServer { stop, thread })
+}
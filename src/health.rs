@@ -0,0 +1,145 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+use actix_web::{web, HttpResponse};
+use image::{DynamicImage, Rgba, RgbaImage};
+use serde::Serialize;
+
+use crate::{ServerConfig, encode_image};
+
+
+fn check_uploads_dir(config: &ServerConfig) -> Result<(), String> {
+    config.storage.check_writable(&config.uploads_dir)
+        .map_err(|e| format!("Uploads directory is not writable: {}", e))
+}
+
+fn check_cache_dir(config: &ServerConfig) -> Option<Result<(), String>> {
+    let cache_dir = config.cache_dir.as_ref()?;
+    Some(config.storage.check_writable(cache_dir).map_err(|e| format!("Cache directory is not writable: {}", e)))
+}
+
+// Round-tripping a single pixel through the encoder and decoder
+fn check_encoder() -> Result<(), String> {
+    let pixel = DynamicImage::ImageRgba8(RgbaImage::from_pixel(1, 1, Rgba([255, 0, 0, 255])));
+
+    let encoded = match encode_image(&pixel, "webp") {
+        Ok(buffer) => buffer,
+        Err(failure) => return Err(format!("Encoder failed: {}", failure)),
+    };
+
+    let decoded = webp::Decoder::new(&encoded).decode().map(|image| image.to_image().to_rgba8());
+    match decoded {
+        Some(image) if image.get_pixel(0, 0) == &Rgba([255, 0, 0, 255]) => Ok(()),
+        _ => Err("Encoded image could not be decoded".to_string()),
+    }
+}
+
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+    checks: BTreeMap<&'static str, String>,
+}
+
+// Respond to load balancers checking whether this instance can serve
+pub async fn serve_health_via_http(config: web::Data<ServerConfig>) -> HttpResponse {
+    // Saving files may block, so it's tried on the blocking threadpool
+    let checked = web::block(move || {
+        let mut results = vec![
+            ("uploads_dir", check_uploads_dir(&config)),
+            ("encoder", check_encoder()),
+        ];
+
+        if let Some(result) = check_cache_dir(&config) {
+            results.push(("cache_dir", result));
+        }
+        Ok::<_, ()>(results)
+    }).await;
+    let results = checked.unwrap_or_else(|_| vec![("storage", Err("Storage could not be checked".to_string()))]);
+
+    let healthy = results.iter().all(|(_, result)| result.is_ok());
+    let checks = results
+        .into_iter()
+        .map(|(name, result)| (name, result.err().unwrap_or_else(|| "ok".to_string())))
+        .collect();
+
+    let health = Health {
+        status: if healthy { "ok" } else { "unavailable" },
+        checks,
+    };
+
+    match healthy {
+        true => HttpResponse::Ok().json(health),
+        false => HttpResponse::ServiceUnavailable().json(health),
+    }
+}
+
+// Startup work that must finish before this instance should receive
+// traffic, like loading indexes or connecting to storage backends
+pub struct Readiness {
+    pending: Mutex<BTreeSet<String>>,
+}
+
+impl Readiness {
+    pub fn new() -> Self {
+        Self { pending: Mutex::new(BTreeSet::new()) }
+    }
+
+    // Marking the server as unready until `finish` is called with the same task
+    pub fn begin(&self, task: &str) {
+        self.pending.lock().unwrap().insert(task.to_string());
+    }
+
+    pub fn finish(&self, task: &str) {
+        self.pending.lock().unwrap().remove(task);
+    }
+
+    pub fn pending(&self) -> Vec<String> {
+        self.pending.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+// Respond to orchestrators checking whether this process is alive,
+// which it is as long as it can respond at all
+pub fn serve_liveness_via_http() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+}
+
+#[derive(Serialize)]
+struct ReadinessReport {
+    status: &'static str,
+    pending: Vec<String>,
+    checks: BTreeMap<&'static str, String>,
+}
+
+// Respond to orchestrators checking whether this instance should
+// receive traffic: startup work is done, and storage is reachable
+pub async fn serve_readiness_via_http(
+    config: web::Data<ServerConfig>,
+    readiness: web::Data<Readiness>,
+) -> HttpResponse {
+    let pending = readiness.pending();
+    let checked = web::block(move || {
+        let mut storage = vec![("uploads_dir", check_uploads_dir(&config))];
+        if let Some(result) = check_cache_dir(&config) {
+            storage.push(("cache_dir", result));
+        }
+        Ok::<_, ()>(storage)
+    }).await;
+    let storage = checked.unwrap_or_else(|_| vec![("storage", Err("Storage could not be checked".to_string()))]);
+
+    let ready = pending.is_empty() && storage.iter().all(|(_, result)| result.is_ok());
+    let checks = storage
+        .into_iter()
+        .map(|(name, result)| (name, result.err().unwrap_or_else(|| "ok".to_string())))
+        .collect();
+
+    let report = ReadinessReport {
+        status: if ready { "ok" } else { "unavailable" },
+        pending,
+        checks,
+    };
+
+    match ready {
+        true => HttpResponse::Ok().json(report),
+        false => HttpResponse::ServiceUnavailable().json(report),
+    }
+}
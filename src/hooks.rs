@@ -0,0 +1,182 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::Processing;
+
+
+// An upload that was saved, as its master was stored. Replacements are
+// told of the same way, when a master is replaced by a new image under
+// the same name, like one that changed at the origin it was fetched from
+#[derive(Clone, Debug)]
+pub struct UploadEvent {
+    pub filename: String,
+    pub bytes: usize,
+    pub width: u32,
+    pub height: u32,
+    // The name of the API key or token it was uploaded with, if any
+    pub uploaded_by: Option<String>,
+}
+
+// A variant that was processed from its master, like for a request,
+// while warming the cache, or when revalidating a stale copy
+#[derive(Clone, Debug)]
+pub struct VariantEvent {
+    pub filename: String,
+    // "sampling=lanczos3&w=200", or "" for the master's own size
+    pub instructions: String,
+    pub extension: String,
+    pub bytes: usize,
+    pub width: u32,
+    pub height: u32,
+    pub duration: Duration,
+}
+
+impl VariantEvent {
+    pub(crate) fn of(filename: &str, instructions: &str, extension: &str, processing: &Processing) -> Self {
+        let (width, height) = processing.output_dimensions;
+        Self {
+            filename: filename.to_string(),
+            instructions: instructions.to_string(),
+            extension: extension.to_string(),
+            bytes: processing.output_bytes,
+            width,
+            height,
+            duration: processing.total(),
+        }
+    }
+}
+
+// An upload that's gone, along with its variants. Uploads that were
+// renamed are gone under their old name
+#[derive(Clone, Debug)]
+pub struct DeleteEvent {
+    pub filename: String,
+    pub renamed_to: Option<String>,
+}
+
+// Told of what happens to uploads, so that applications can keep records
+// of their own or start work downstream. Every method does nothing unless
+// it's implemented. They're called after the fact, one event at a time in
+// the order they happened, on a thread of their own, so that taking their
+// time doesn't hold up requests
+pub trait Hooks: Send + Sync {
+    fn on_upload(&self, _upload: &UploadEvent) {}
+    fn on_replace(&self, _replacement: &UploadEvent) {}
+    fn on_variant_generated(&self, _variant: &VariantEvent) {}
+    fn on_delete(&self, _deletion: &DeleteEvent) {}
+}
+
+enum Event {
+    Upload(UploadEvent),
+    Replace(UploadEvent),
+    Variant(VariantEvent),
+    Delete(DeleteEvent),
+}
+
+impl Event {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Upload(_) => "upload",
+            Self::Replace(_) => "replace",
+            Self::Variant(_) => "variant_generated",
+            Self::Delete(_) => "delete",
+        }
+    }
+}
+
+struct Dispatch {
+    hooks: Vec<Arc<dyn Hooks>>,
+    // Started with the first event
+    sender: Mutex<Option<Sender<Event>>>,
+    pending: AtomicUsize,
+}
+
+// The hooks of a config, and the thread that calls them
+#[derive(Clone)]
+pub struct EventHooks {
+    dispatch: Arc<Dispatch>,
+}
+
+impl EventHooks {
+    pub fn new(hooks: Vec<Arc<dyn Hooks>>) -> Self {
+        let dispatch = Dispatch { hooks, sender: Mutex::new(None), pending: AtomicUsize::new(0) };
+        Self { dispatch: Arc::new(dispatch) }
+    }
+
+    pub fn with(&self, hooks: Arc<dyn Hooks>) -> Self {
+        let mut all = self.dispatch.hooks.clone();
+        all.push(hooks);
+        Self::new(all)
+    }
+
+    pub fn upload(&self, upload: UploadEvent) {
+        self.send(Event::Upload(upload));
+    }
+
+    pub fn replace(&self, replacement: UploadEvent) {
+        self.send(Event::Replace(replacement));
+    }
+
+    pub fn variant_generated(&self, variant: VariantEvent) {
+        self.send(Event::Variant(variant));
+    }
+
+    pub fn delete(&self, deletion: DeleteEvent) {
+        self.send(Event::Delete(deletion));
+    }
+
+    fn send(&self, event: Event) {
+        if self.dispatch.hooks.is_empty() { return; }
+
+        let mut sender = self.dispatch.sender.lock().unwrap();
+        let sender = sender.get_or_insert_with(|| self.start());
+        self.dispatch.pending.fetch_add(1, Ordering::SeqCst);
+        if sender.send(event).is_err() {
+            self.dispatch.pending.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    fn start(&self) -> Sender<Event> {
+        let (sender, events) = mpsc::channel();
+        let dispatch = self.dispatch.clone();
+
+        std::thread::spawn(move || {
+            for event in events {
+                for hooks in &dispatch.hooks {
+                    // Carrying on with the next hook, and the next event,
+                    // when one panics
+                    let called = panic::catch_unwind(AssertUnwindSafe(|| match &event {
+                        Event::Upload(upload) => hooks.on_upload(upload),
+                        Event::Replace(replacement) => hooks.on_replace(replacement),
+                        Event::Variant(variant) => hooks.on_variant_generated(variant),
+                        Event::Delete(deletion) => hooks.on_delete(deletion),
+                    }));
+                    if called.is_err() {
+                        tracing::error!(event = event.name(), "a hook panicked");
+                    }
+                }
+                dispatch.pending.fetch_sub(1, Ordering::SeqCst);
+            }
+        });
+        sender
+    }
+
+    // Waiting up to `timeout` for events that haven't been handed to every
+    // hook yet, returning how many are left
+    pub fn finish(&self, timeout: Duration) -> usize {
+        let started = Instant::now();
+        while self.dispatch.pending.load(Ordering::SeqCst) > 0 && started.elapsed() < timeout {
+            std::thread::sleep(Duration::from_millis(25));
+        }
+        self.dispatch.pending.load(Ordering::SeqCst)
+    }
+}
+
+impl Default for EventHooks {
+    fn default() -> Self {
+        Self::new(vec![])
+    }
+}
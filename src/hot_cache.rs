@@ -0,0 +1,118 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use actix_web::web::Bytes;
+use serde::Serialize;
+
+
+struct Entry {
+    buffer: Bytes,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<String, Entry>,
+    // Keys ordered from least to most recently used
+    recency: BTreeMap<u64, String>,
+    used: usize,
+    clock: u64,
+    evictions: u64,
+}
+
+impl State {
+    fn touch(&mut self, key: &str) -> Option<Bytes> {
+        self.clock += 1;
+        let clock = self.clock;
+        let entry = self.entries.get_mut(key)?;
+        self.recency.remove(&entry.last_used);
+        self.recency.insert(clock, key.to_string());
+        entry.last_used = clock;
+        Some(entry.buffer.clone())
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.last_used);
+            self.used -= entry.buffer.len();
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Usage {
+    pub entries: usize,
+    pub bytes: usize,
+    pub budget: usize,
+    pub evictions: u64,
+}
+
+// Processed images held in memory, so that the most requested variants
+// are served without touching the filesystem. Once the budget is used
+// up, the least recently used variants are evicted to make room
+pub struct HotCache {
+    budget: usize,
+    state: Mutex<State>,
+}
+
+impl HotCache {
+    pub fn new(budget: usize) -> Self {
+        Self { budget, state: Mutex::new(State::default()) }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.budget > 0
+    }
+
+    pub fn get(&self, key: &str) -> Option<Bytes> {
+        match self.is_enabled() {
+            true => self.state.lock().unwrap().touch(key),
+            false => None,
+        }
+    }
+
+    // Holding a buffer, unless it wouldn't fit in the budget on its own
+    pub fn insert(&self, key: &str, buffer: Bytes) {
+        if buffer.len() > self.budget { return; }
+
+        let mut state = self.state.lock().unwrap();
+        state.remove(key);
+
+        while state.used + buffer.len() > self.budget {
+            let oldest = match state.recency.values().next() {
+                Some(oldest) => oldest.clone(),
+                None => break,
+            };
+            state.remove(&oldest);
+            state.evictions += 1;
+        }
+
+        state.clock += 1;
+        let last_used = state.clock;
+        state.used += buffer.len();
+        state.recency.insert(last_used, key.to_string());
+        state.entries.insert(key.to_string(), Entry { buffer, last_used });
+    }
+
+    // Evicting every buffer whose key is matched by `predicate`
+    pub fn remove_where<F: Fn(&str) -> bool>(&self, predicate: F) {
+        let mut state = self.state.lock().unwrap();
+        let keys: Vec<String> = state.entries.keys().filter(|key| predicate(key)).cloned().collect();
+        for key in keys {
+            state.remove(&key);
+        }
+    }
+
+    pub fn clear(&self) {
+        self.remove_where(|_| true);
+    }
+
+    pub fn usage(&self) -> Usage {
+        let state = self.state.lock().unwrap();
+        Usage {
+            entries: state.entries.len(),
+            bytes: state.used,
+            budget: self.budget,
+            evictions: state.evictions,
+        }
+    }
+}
@@ -0,0 +1,400 @@
+use std::str::FromStr;
+use actix_web::{web, HttpRequest, HttpResponse};
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    analysis,
+    auth,
+    readable,
+    run_blocking,
+    serve_variant,
+    fit_within,
+    Caches,
+    FileDescription,
+    ImageServiceFailure,
+    ProcessingInstructions,
+    ServerConfig,
+};
+
+
+// Images served the way the IIIF Image API 3.0 describes, for viewers
+// like OpenSeadragon. Identifiers are the names of uploads:
+// /iiif/{identifier}/{region}/{size}/{rotation}/{quality}.{format}
+// https://iiif.io/api/image/3.0/
+
+const CONTEXT: &str = "http://iiif.io/api/image/3/context.json";
+
+// Tiles are offered at this size, at every scale that's needed for the
+// whole image to fit in one
+const TILE_SIZE: u32 = 512;
+
+// The area of the image asked for
+#[derive(Clone, Debug, PartialEq)]
+enum Region {
+    Full,
+    Square,
+    Pixels(u32, u32, u32, u32),
+    Percent(f64, f64, f64, f64),
+}
+
+// The size the region is scaled to
+#[derive(Clone, Debug, PartialEq)]
+enum Size {
+    Max,
+    Width(u32),
+    Height(u32),
+    Percent(f64),
+    Exact(u32, u32),
+    // "!w,h", as large as fits within both
+    Confined(u32, u32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Quality {
+    Color,
+    Gray,
+    Bitonal,
+}
+
+// "full/max/0/default"
+// The region, size, rotation and quality of a request, applied to a
+// master in place of the usual resizing
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImageRequest {
+    region: Region,
+    size: Size,
+    // Whether the size may be larger than the region, as "^" asks
+    upscale: bool,
+    // Flipping the image horizontally before it's rotated, as "!" asks
+    mirror: bool,
+    // Clockwise, in degrees: 0, 90, 180 or 270
+    rotation: u32,
+    quality: Quality,
+    // As it was requested, naming the variant
+    pub path: String,
+}
+
+// "10,20,30" -> Some(vec![10, 20, 30])
+fn numbers<T: FromStr>(list: &str) -> Option<Vec<T>> {
+    list.split(',').map(|number| number.parse().ok()).collect()
+}
+
+// "x,y,w,h", "pct:x,y,w,h", "full" or "square"
+fn parse_region(region: &str) -> Result<Region, String> {
+    let invalid = || format!("{:?} isn't a region. Try full, square, x,y,w,h or pct:x,y,w,h", region);
+    match region {
+        "full" => return Ok(Region::Full),
+        "square" => return Ok(Region::Square),
+        _ => {}
+    }
+
+    match region.strip_prefix("pct:") {
+        Some(percentages) => match numbers::<f64>(percentages).ok_or_else(invalid)?[..] {
+            [x, y, w, h] if x >= 0.0 && y >= 0.0 && w > 0.0 && h > 0.0 => Ok(Region::Percent(x, y, w, h)),
+            _ => Err(invalid()),
+        },
+        None => match numbers::<u32>(region).ok_or_else(invalid)?[..] {
+            [x, y, w, h] if w > 0 && h > 0 => Ok(Region::Pixels(x, y, w, h)),
+            _ => Err(invalid()),
+        },
+    }
+}
+
+// "max", "w,", ",h", "pct:n", "w,h" or "!w,h", any of which may begin
+// with "^" to allow upscaling
+fn parse_size(size: &str) -> Result<(Size, bool), String> {
+    let invalid = || format!("{:?} isn't a size. Try max, w, ,h, pct:n, w,h or !w,h", size);
+    let (upscale, rest) = match size.strip_prefix('^') {
+        Some(rest) => (true, rest),
+        None => (false, size),
+    };
+    let positive = |number: &str| number.parse::<u32>().ok().filter(|number| *number > 0);
+
+    let parsed = match rest {
+        "max" => Some(Size::Max),
+        _ if rest.starts_with("pct:") => rest[4..].parse::<f64>().ok().filter(|p| *p > 0.0).map(Size::Percent),
+        _ if rest.starts_with('!') => match rest[1..].split_once(',') {
+            Some((w, h)) => positive(w).zip(positive(h)).map(|(w, h)| Size::Confined(w, h)),
+            None => None,
+        },
+        _ => match rest.split_once(',') {
+            Some((w, "")) => positive(w).map(Size::Width),
+            Some(("", h)) => positive(h).map(Size::Height),
+            Some((w, h)) => positive(w).zip(positive(h)).map(|(w, h)| Size::Exact(w, h)),
+            None => None,
+        },
+    };
+    parsed.map(|size| (size, upscale)).ok_or_else(invalid)
+}
+
+// "90", or "!90" to mirror the image first
+fn parse_rotation(rotation: &str) -> Result<(u32, bool), String> {
+    let (mirror, degrees) = match rotation.strip_prefix('!') {
+        Some(degrees) => (true, degrees),
+        None => (false, rotation),
+    };
+
+    match degrees.parse::<f64>() {
+        Ok(degrees) if (0.0..=360.0).contains(&degrees) && degrees % 90.0 == 0.0 => Ok((degrees as u32 % 360, mirror)),
+        Ok(degrees) if (0.0..=360.0).contains(&degrees) => Err("Only rotations by multiples of 90 degrees are supported".to_string()),
+        _ => Err(format!("{:?} isn't a rotation. Try a number of degrees from 0 to 360", rotation)),
+    }
+}
+
+fn parse_quality(quality: &str) -> Result<Quality, String> {
+    match quality {
+        "default" | "color" => Ok(Quality::Color),
+        "gray" => Ok(Quality::Gray),
+        "bitonal" => Ok(Quality::Bitonal),
+        _ => Err(format!("{:?} isn't a quality. Try default, color, gray or bitonal", quality)),
+    }
+}
+
+impl ImageRequest {
+    pub fn parse(region: &str, size: &str, rotation: &str, quality: &str) -> Result<Self, ImageServiceFailure> {
+        let path = format!("{}/{}/{}/{}", region, size, rotation, quality);
+        let region = parse_region(region).map_err(|message| ImageServiceFailure::invalid_parameter("region", &message))?;
+        let (size, upscale) = parse_size(size).map_err(|message| ImageServiceFailure::invalid_parameter("size", &message))?;
+        let (rotation, mirror) = parse_rotation(rotation).map_err(|message| ImageServiceFailure::invalid_parameter("rotation", &message))?;
+        let quality = parse_quality(quality).map_err(|message| ImageServiceFailure::invalid_parameter("quality", &message))?;
+        Ok(Self { region, size, upscale, mirror, rotation, quality, path })
+    }
+
+    // (0, 0, 512, 512) scaled to (256, 256) -> "0,0,512,512/256,256/0/default"
+    // A region of an image in pixels, scaled to exactly `size`, named as
+    // the IIIF request for it would be
+    pub fn of_region((x, y, width, height): (u32, u32, u32, u32), (new_width, new_height): (u32, u32)) -> Self {
+        Self {
+            region: Region::Pixels(x, y, width, height),
+            size: Size::Exact(new_width, new_height),
+            upscale: false,
+            mirror: false,
+            rotation: 0,
+            quality: Quality::Color,
+            path: format!("{},{},{},{}/{},{}/0/default", x, y, width, height, new_width, new_height),
+        }
+    }
+
+    // (x, y, width, height) of the region within an image this large,
+    // cut short where it goes past the image's edges
+    fn region_within(&self, (width, height): (u32, u32)) -> Result<(u32, u32, u32, u32), ImageServiceFailure> {
+        let percent = |value: f64, of: u32| (value / 100.0 * of as f64).round() as u32;
+        let (x, y, w, h) = match self.region {
+            Region::Full => (0, 0, width, height),
+            Region::Square => {
+                let side = width.min(height);
+                ((width - side) / 2, (height - side) / 2, side, side)
+            }
+            Region::Pixels(x, y, w, h) => (x, y, w, h),
+            Region::Percent(x, y, w, h) => (percent(x, width), percent(y, height), percent(w, width), percent(h, height)),
+        };
+
+        if x >= width || y >= height || w == 0 || h == 0 {
+            return Err(ImageServiceFailure::invalid_parameter("region", "The region is outside of the image"));
+        }
+        Ok((x, y, w.min(width - x), h.min(height - y)))
+    }
+
+    // The size a region this large is scaled to
+    fn size_of(&self, (width, height): (u32, u32)) -> Result<(u32, u32), ImageServiceFailure> {
+        let proportion = |length: u32, of: u32, by: u32| ((length as f64 * of as f64 / by as f64).round() as u32).max(1);
+        let (new_width, new_height) = match self.size {
+            Size::Max => (width, height),
+            Size::Width(w) => (w, proportion(height, w, width)),
+            Size::Height(h) => (proportion(width, h, height), h),
+            Size::Percent(p) => (
+                ((width as f64 * p / 100.0).round() as u32).max(1),
+                ((height as f64 * p / 100.0).round() as u32).max(1),
+            ),
+            Size::Exact(w, h) => (w, h),
+            // Only as large as the region, unless upscaling
+            Size::Confined(w, h) => match fit_within((width, height), (w, h)) {
+                (w, h) if !self.upscale && (w > width || h > height) => (width, height),
+                fitted => fitted,
+            },
+        };
+
+        if !self.upscale && (new_width > width || new_height > height) {
+            let message = "The size is larger than the region. Begin it with ^ to upscale";
+            return Err(ImageServiceFailure::invalid_parameter("size", message));
+        }
+        Ok((new_width, new_height))
+    }
+
+    // Cropping, scaling, mirroring, rotating and then coloring an image
+    // as asked, refusing results larger than `max_pixels`
+    pub fn apply(&self, image: DynamicImage, max_pixels: u64) -> Result<DynamicImage, ImageServiceFailure> {
+        let (x, y, width, height) = self.region_within(image.dimensions())?;
+        let (new_width, new_height) = self.size_of((width, height))?;
+        if new_width as u64 * new_height as u64 > max_pixels {
+            return Err(ImageServiceFailure::TooManyPixels { width: new_width, height: new_height, limit: max_pixels });
+        }
+
+        let mut image = match (x, y, width, height) == (0, 0, image.width(), image.height()) {
+            true => image,
+            false => image.crop_imm(x, y, width, height),
+        };
+        if (new_width, new_height) != (width, height) {
+            image = image.resize_exact(new_width, new_height, FilterType::Lanczos3);
+        }
+        if self.mirror {
+            image = image.fliph();
+        }
+        image = match self.rotation {
+            90 => image.rotate90(),
+            180 => image.rotate180(),
+            270 => image.rotate270(),
+            _ => image,
+        };
+
+        Ok(match self.quality {
+            Quality::Color => image,
+            Quality::Gray => image.grayscale(),
+            Quality::Bitonal => {
+                let mut luma = image.to_luma8();
+                for pixel in luma.pixels_mut() {
+                    pixel.0[0] = if pixel.0[0] < 128 { 0 } else { 255 };
+                }
+                DynamicImage::ImageLuma8(luma)
+            }
+        })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ImagePath {
+    identifier: String,
+    region: String,
+    size: String,
+    rotation: String,
+    quality: String,
+    format: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Identifier {
+    identifier: String,
+}
+
+// "jpg" -> Some("jpeg"), for formats the server produces
+pub fn extension_of(format: &str, config: &ServerConfig) -> Option<&'static str> {
+    let extension = match format {
+        "jpg" | "jpeg" => "jpeg",
+        "png" => "png",
+        "webp" => "webp",
+        _ => return None,
+    };
+    config.output_formats.iter().any(|format| format == extension).then_some(extension)
+}
+
+// Respond to a request for a region of an image, processed and cached
+// like any other variant of it
+pub async fn serve_iiif_image_via_http(
+    req: HttpRequest,
+    path: web::Path<ImagePath>,
+    config: web::Data<ServerConfig>,
+    caches: web::Data<Caches>,
+) -> HttpResponse {
+    if let Err(failure) = auth::authorize_upload(&req, &config, auth::Access::Read, &path.identifier) {
+        return failure.as_http_response();
+    }
+
+    let request = match ImageRequest::parse(&path.region, &path.size, &path.rotation, &path.quality) {
+        Ok(request) => request,
+        Err(failure) => return failure.as_http_response(&req),
+    };
+    let extension = match extension_of(&path.format, &config) {
+        Some(extension) => extension,
+        None => {
+            let message = format!("{:?} isn't a format that's served. Try jpg, png or webp", path.format);
+            return ImageServiceFailure::invalid_parameter("format", &message).as_http_response(&req);
+        }
+    };
+
+    let required = FileDescription { filename: path.identifier.clone(), extension: extension.to_string() };
+    let optional = ProcessingInstructions::of_iiif(request);
+    match serve_variant(&req, &required, &optional, &config, &caches, false).await {
+        Ok(response) => response,
+        Err(failure) => failure.as_http_response(&req),
+    }
+}
+
+// The scales tiles are offered at, from full size to the first at which
+// the whole image fits in one tile
+// (2000, 1000) -> [1, 2, 4]
+fn scale_factors((width, height): (u32, u32)) -> Vec<u32> {
+    let mut factors = vec![1];
+    while width.max(height) / factors[factors.len() - 1] > TILE_SIZE {
+        factors.push(factors[factors.len() - 1] * 2);
+    }
+    factors
+}
+
+// Respond to a request for what's known of an image: how large it is,
+// and which of the IIIF features can be asked of it
+pub async fn serve_iiif_info_via_http(
+    req: HttpRequest,
+    name: web::Path<Identifier>,
+    config: web::Data<ServerConfig>,
+) -> HttpResponse {
+    if let Err(failure) = auth::authorize_upload(&req, &config, auth::Access::Read, &name.identifier) {
+        return failure.as_http_response();
+    }
+
+    let dimensions = {
+        let (config, identifier) = (config.clone(), name.identifier.clone());
+        run_blocking(move || analysis::cached_dimensions(&config, &identifier)).await
+    };
+    let (width, height) = match dimensions {
+        Ok(dimensions) => dimensions,
+        Err(failure) => return failure.as_http_response(&req),
+    };
+
+    let connection = req.connection_info();
+    let id = format!("{}://{}{}/iiif/{}", connection.scheme(), connection.host(), config.base_path, name.identifier);
+    let extra_formats: Vec<&str> = config.output_formats.iter().filter(|format| *format == "webp").map(|format| format.as_str()).collect();
+    let info = json!({
+        "@context": CONTEXT,
+        "id": id,
+        "type": "ImageService3",
+        "protocol": "http://iiif.io/api/image",
+        "profile": "level2",
+        "width": width,
+        "height": height,
+        "maxArea": config.max_pixels,
+        "tiles": [{ "width": TILE_SIZE, "scaleFactors": scale_factors((width, height)) }],
+        "extraFormats": extra_formats,
+        "extraQualities": ["color", "gray", "bitonal"],
+        "extraFeatures": ["mirroring", "regionByPct", "regionSquare", "rotationBy90s", "sizeByConfinedWh", "sizeByWh", "sizeUpscaling"],
+    });
+
+    // JSON-LD, for clients that ask for it
+    let accepts_json_ld = req
+        .headers()
+        .get("accept")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/ld+json"));
+    let content_type = match accepts_json_ld {
+        true => format!("application/ld+json;profile=\"{}\"", CONTEXT),
+        false => "application/json".to_string(),
+    };
+
+    HttpResponse::Ok().content_type(content_type).body(info.to_string())
+}
+
+// Pointing requests for an image's base URI at what's known of it
+pub async fn serve_iiif_base_via_http(req: HttpRequest) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .header("location", format!("{}/info.json", req.path()))
+        .finish()
+}
+
+pub fn configure_routes(cfg: &mut web::ServiceConfig) {
+    cfg
+        .service(readable("/iiif/{identifier}/{region}/{size}/{rotation}/{quality}.{format}", serve_iiif_image_via_http))
+        .service(readable("/iiif/{identifier}/info.json", serve_iiif_info_via_http))
+        .service(readable("/iiif/{identifier}", serve_iiif_base_via_http));
+}
@@ -0,0 +1,159 @@
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Serialize, Deserialize};
+
+use crate::{ServerConfig, Caches, auth, build_path_to_master};
+use crate::cache;
+use crate::warming::list_masters;
+
+
+// How often what's stored is counted again
+const SCAN_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// How many files there are of one kind, and how much space they take
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct Usage {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+impl Usage {
+    fn of(config: &ServerConfig, paths: &BTreeSet<String>) -> Self {
+        let bytes = paths.iter().filter_map(|path| config.storage.metadata(path)).map(|file| file.size).sum();
+        Self { files: paths.len(), bytes }
+    }
+
+    fn plus(self, other: Usage) -> Self {
+        Self { files: self.files + other.files, bytes: self.bytes + other.bytes }
+    }
+}
+
+// What a site stores: its masters, and everything derived from them
+#[derive(Clone, Serialize)]
+pub struct Inventory {
+    pub masters: Usage,
+    pub variants: Usage,
+    pub analyses: Usage,
+    // Variants, placeholders and analyses together
+    pub derivatives: Usage,
+    // Seconds since the epoch
+    pub scanned_at: u64,
+}
+
+// Counting every master, cached variant and analysis
+pub fn scan(config: &ServerConfig) -> Inventory {
+    // Content addressed masters with the same contents share a file
+    let masters: BTreeSet<String> = list_masters(config)
+        .iter()
+        .map(|filename| build_path_to_master(config, filename))
+        .collect();
+    let variants: BTreeSet<String> = cache::cached_variants(config)
+        .iter()
+        .map(|path| path.to_string_lossy().to_string())
+        .collect();
+    let analyses: BTreeSet<String> = cache::cached_analyses(config)
+        .iter()
+        .map(|path| path.to_string_lossy().to_string())
+        .collect();
+
+    let (variants, analyses) = (Usage::of(config, &variants), Usage::of(config, &analyses));
+    Inventory {
+        masters: Usage::of(config, &masters),
+        variants,
+        analyses,
+        derivatives: variants.plus(analyses),
+        scanned_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+    }
+}
+
+impl Inventory {
+    // The Prometheus text format
+    pub fn write_metrics(&self, out: &mut String) {
+        let kinds = [("masters", self.masters), ("variants", self.variants), ("analyses", self.analyses)];
+
+        out.push_str("# HELP imogen_storage_files How many masters, cached variants and analyses are stored\n");
+        out.push_str("# TYPE imogen_storage_files gauge\n");
+        for (kind, usage) in &kinds {
+            let _ = writeln!(out, "imogen_storage_files{{kind=\"{}\"}} {}", kind, usage.files);
+        }
+
+        out.push_str("# HELP imogen_storage_bytes How much space masters, cached variants and analyses take\n");
+        out.push_str("# TYPE imogen_storage_bytes gauge\n");
+        for (kind, usage) in &kinds {
+            let _ = writeln!(out, "imogen_storage_bytes{{kind=\"{}\"}} {}", kind, usage.bytes);
+        }
+
+        out.push_str("# HELP imogen_storage_scanned_timestamp_seconds When what's stored was last counted\n");
+        out.push_str("# TYPE imogen_storage_scanned_timestamp_seconds gauge\n");
+        let _ = writeln!(out, "imogen_storage_scanned_timestamp_seconds {}", self.scanned_at);
+    }
+}
+
+// The most recent inventory of a site, once there's been one
+#[derive(Default)]
+pub struct LatestInventory {
+    inventory: Mutex<Option<Inventory>>,
+}
+
+impl LatestInventory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self) -> Option<Inventory> {
+        self.inventory.lock().unwrap().clone()
+    }
+
+    fn set(&self, inventory: Inventory) {
+        *self.inventory.lock().unwrap() = Some(inventory);
+    }
+}
+
+async fn rescan(config: &web::Data<ServerConfig>, caches: &web::Data<Caches>) -> Option<Inventory> {
+    let config = config.clone();
+    let inventory = web::block(move || Ok::<_, ()>(scan(&config))).await.ok()?;
+    caches.inventory.set(inventory.clone());
+    Some(inventory)
+}
+
+// Periodically counting what's stored, for as long as the server runs
+pub async fn sweep_inventory(config: web::Data<ServerConfig>, caches: web::Data<Caches>) {
+    let mut interval = actix_web::rt::time::interval(SCAN_INTERVAL);
+    loop {
+        interval.tick().await;
+        if rescan(&config, &caches).await.is_none() {
+            tracing::error!("failed to count stored files");
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InventoryInstructions {
+    refresh: Option<bool>,
+}
+
+// Respond to a request for how much is stored, as of the last count.
+// With `?refresh=true`, everything is counted again first
+pub async fn serve_inventory_via_http(
+    req: HttpRequest,
+    instructions: web::Query<InventoryInstructions>,
+    config: web::Data<ServerConfig>,
+    caches: web::Data<Caches>,
+) -> HttpResponse {
+    if let Err(failure) = auth::authorize(&req, &config, auth::Access::Write) {
+        return failure.as_http_response();
+    }
+
+    let inventory = match (instructions.refresh.unwrap_or(false), caches.inventory.get()) {
+        (false, Some(inventory)) => Some(inventory),
+        _ => rescan(&config, &caches).await,
+    };
+
+    match inventory {
+        Some(inventory) => HttpResponse::Ok().json(inventory),
+        None => HttpResponse::InternalServerError().body("Stored files could not be counted"),
+    }
+}
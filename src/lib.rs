@@ -1,26 +1,30 @@
-use std::fs::File;
-use std::path::Path;
-use std::io::ErrorKind as IOError;
+use std::sync::Arc;
 use image::io::Reader as ImageReader;
 use image::imageops::FilterType;
 use image::{
-    ImageOutputFormat,
     GenericImageView,
     ImageFormat,
+    DynamicImage,
 };
-use webp;
-use serde::{Serialize, Deserialize};
-use serde_json;
+use serde::Deserialize;
+use std::io::Cursor;
+use std::time::Instant;
+
+#[cfg(feature = "server")]
+use std::path::Path;
+#[cfg(feature = "server")]
+use std::io::ErrorKind as IOError;
+#[cfg(feature = "server")]
+use serde::Serialize;
+#[cfg(feature = "server")]
 use futures::{StreamExt, TryStreamExt};
-use std::io::{
-    Cursor,
-    Write,
-    Read,
-};
+#[cfg(feature = "server")]
 use actix_multipart::Multipart;
-use actix_files::NamedFile;
+#[cfg(feature = "server")]
 use actix_cors::{Cors};
+#[cfg(feature = "server")]
 use actix_web::{
+    guard,
     web,
     App,
     HttpRequest,
@@ -28,46 +32,364 @@ use actix_web::{
     HttpServer,
     Error,
 };
-
-
-enum ImageServiceFailure {
+#[cfg(feature = "server")]
+use actix_web::dev::{Factory, Service};
+#[cfg(feature = "server")]
+use actix_web::{FromRequest, Resource, Responder};
+#[cfg(feature = "server")]
+use futures::Future;
+#[cfg(feature = "server")]
+use tracing::Instrument;
+#[cfg(feature = "server")]
+use actix_web::http::StatusCode;
+#[cfg(feature = "server")]
+use actix_web::http::header::{HeaderName, HeaderValue};
+
+#[cfg(feature = "server")]
+mod access_log;
+#[cfg(feature = "server")]
+mod analysis;
+#[cfg(feature = "server")]
+mod auth;
+#[cfg(feature = "server")]
+mod background;
+#[cfg(feature = "server")]
+mod cache;
+#[cfg(feature = "server")]
+mod client_hints;
+#[cfg(feature = "server")]
+mod card;
+#[cfg(feature = "server")]
+mod clock;
+#[cfg(feature = "server")]
+mod config_file;
+#[cfg(feature = "server")]
+mod content_store;
+#[cfg(feature = "server")]
+mod diff;
+mod downscale;
+#[cfg(feature = "server")]
+mod dzi;
+mod encoding;
+#[cfg(feature = "server")]
+mod font;
+#[cfg(feature = "graphql")]
+mod graphql;
+#[cfg(feature = "grpc")]
+mod grpc;
+#[cfg(feature = "server")]
+mod health;
+#[cfg(feature = "server")]
+mod hooks;
+#[cfg(feature = "server")]
+mod hot_cache;
+#[cfg(feature = "server")]
+mod iiif;
+#[cfg(feature = "server")]
+mod inventory;
+#[cfg(feature = "server")]
+mod logging;
+mod decoded_cache;
+mod memory_budget;
+#[cfg(feature = "server")]
+mod mounting;
+#[cfg(feature = "server")]
+mod processing_limit;
+#[cfg(feature = "server")]
+mod listing;
+#[cfg(feature = "server")]
+mod metadata;
+#[cfg(feature = "sqlite")]
+mod metadata_index;
+#[cfg(feature = "server")]
+mod metrics;
+#[cfg(feature = "server")]
+mod migration;
+#[cfg(feature = "server")]
+mod negative_cache;
+#[cfg(feature = "server")]
+mod negotiation;
+#[cfg(feature = "server")]
+mod origin;
+#[cfg(feature = "otlp")]
+mod otlp;
+pub mod pipeline;
+#[cfg(feature = "server")]
+mod placeholder;
+#[cfg(feature = "removebg")]
+mod remove_background;
+#[cfg(feature = "server")]
+mod replication;
+#[cfg(feature = "server")]
+mod request_id;
+#[cfg(feature = "s3")]
+mod s3_storage;
+#[cfg(feature = "server")]
+mod similarity;
+#[cfg(feature = "server")]
+mod single_flight;
+#[cfg(feature = "sqlite")]
+mod sqlite_storage;
+#[cfg(feature = "server")]
+mod srcset;
+#[cfg(feature = "server")]
+mod storage;
+#[cfg(feature = "server")]
+mod systemd;
+#[cfg(feature = "server")]
+pub mod test;
+#[cfg(feature = "server")]
+mod tiered_storage;
+#[cfg(feature = "server")]
+mod tls;
+mod transform;
+#[cfg(feature = "server")]
+mod upload_urls;
+#[cfg(feature = "upscale")]
+mod upscale;
+#[cfg(feature = "server")]
+mod version;
+#[cfg(feature = "server")]
+mod warming;
+#[cfg(feature = "webhooks")]
+mod webhooks;
+#[cfg(feature = "server")]
+pub use auth::{ApiKey, JwtConfig, AccessPolicy, PathPolicy, sign_url};
+#[cfg(feature = "server")]
+pub use tls::TlsConfig;
+#[cfg(feature = "server")]
+pub use config_file::{ConfigFile, OriginSection};
+#[cfg(feature = "server")]
+pub use origin::OriginConfig;
+pub use encoding::{EncodingDefaults, PngCompression};
+pub use transform::Transform;
+#[cfg(feature = "server")]
+pub use logging::{LogFormat, LoggingConfig};
+#[cfg(feature = "otlp")]
+pub use otlp::OtlpConfig;
+#[cfg(feature = "webhooks")]
+pub use webhooks::{WebhookConfig, Webhooks};
+#[cfg(feature = "removebg")]
+pub use remove_background::{BackgroundRemovalConfig, RemoveBackground};
+#[cfg(feature = "upscale")]
+pub use upscale::{Upscale, UpscalingConfig};
+#[cfg(feature = "server")]
+pub use access_log::{AccessLogConfig, AccessLogFormat};
+#[cfg(feature = "server")]
+pub use card::{CardField, CardTemplate};
+#[cfg(feature = "server")]
+pub use warming::{WarmingPlan, WarmingResult};
+#[cfg(feature = "server")]
+pub use hooks::{Hooks, UploadEvent, VariantEvent, DeleteEvent};
+#[cfg(feature = "server")]
+pub use mounting::{routes, ImageRoutes};
+#[cfg(feature = "server")]
+pub use migration::MigrationResult;
+#[cfg(feature = "server")]
+pub use content_store::IntegrityReport;
+#[cfg(feature = "server")]
+pub use storage::{Storage, StoredFile, LocalStorage, OpenedFile};
+#[cfg(feature = "server")]
+pub use tiered_storage::TieredStorage;
+#[cfg(feature = "server")]
+pub use replication::{ReplicatedStorage, ReconcileResult};
+#[cfg(feature = "sqlite")]
+pub use sqlite_storage::SqliteStorage;
+#[cfg(feature = "sqlite")]
+pub use metadata_index::MetadataIndex;
+#[cfg(feature = "server")]
+pub use listing::{ListedUpload, Search};
+#[cfg(feature = "s3")]
+pub use s3_storage::{S3Config, S3Storage};
+use decoded_cache::DecodedCache;
+use memory_budget::MemoryBudget;
+#[cfg(feature = "server")]
+use negotiation::Negotiation;
+#[cfg(feature = "server")]
+use access_log::AccessLog;
+#[cfg(feature = "server")]
+use background::BackgroundWork;
+#[cfg(feature = "server")]
+use health::Readiness;
+#[cfg(feature = "server")]
+use hooks::EventHooks;
+#[cfg(feature = "server")]
+use hot_cache::HotCache;
+#[cfg(feature = "server")]
+use inventory::LatestInventory;
+#[cfg(feature = "server")]
+use processing_limit::ProcessingLimit;
+#[cfg(feature = "server")]
+use cache::{CacheStats, Freshness, Lookup};
+#[cfg(feature = "server")]
+use metrics::Metrics;
+#[cfg(feature = "server")]
+use negative_cache::NegativeCache;
+#[cfg(feature = "server")]
+use single_flight::SingleFlight;
+
+
+// What went wrong serving or processing an image. Failures that came
+// from an error elsewhere, like in storage or the decoder, keep it as
+// their source when there was one
+#[derive(Clone, Debug)]
+pub enum ImageServiceFailure {
     UnsupportedFormat,
     ImageDoesNotExist,
     MemoryOverflow,
-    CouldNotReadToBuffer,
+    CouldNotReadToBuffer { source: Option<FailureSource> },
+    CouldNotDecode { source: Option<FailureSource> },
+    CouldNotWrite { source: Option<FailureSource> },
+    CouldNotEncode { source: Option<FailureSource> },
+    // The width and height an image claims to be, and the most pixels allowed
+    TooManyPixels { width: u32, height: u32, limit: u64 },
+    // Too much memory is already taken by other images being processed
+    Overloaded,
+    // The query parameter that was refused, if it was just one, and why
+    InvalidParameter { parameter: Option<String>, message: String },
+    // A transform that couldn't be applied, such as one calling a service
+    // that's down, and what it said went wrong
+    TransformFailed { transform: String, message: String },
 }
 
-impl ImageServiceFailure {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for ImageServiceFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let message = match self {
+            Self::UnsupportedFormat => "Unsupported file format",
+            Self::ImageDoesNotExist => "Requested image does not exist",
+            Self::MemoryOverflow => "Failed to allocate adequate memory",
+            Self::CouldNotReadToBuffer { .. } => "Could not load image into memory buffer",
+            Self::CouldNotDecode { .. } => "Could not decode image",
+            Self::CouldNotWrite { .. } => "Could not save image",
+            Self::CouldNotEncode { .. } => "Could not encode image",
+            Self::Overloaded => "Too many images are being processed at once",
+            Self::InvalidParameter { message, .. } => message,
+            Self::TooManyPixels { width, height, limit } => {
+                return write!(f, "Image is {}x{} pixels, more than the limit of {}", width, height, limit);
+            }
+            Self::TransformFailed { transform, message } => {
+                return write!(f, "The {} transform failed: {}", transform, message);
+            }
+        };
+        write!(f, "{}", message)
+    }
+}
+
+impl std::error::Error for ImageServiceFailure {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
-            Self::UnsupportedFormat => "Unsupported file format".to_string(),
-            Self::ImageDoesNotExist => "Requested image does not exist".to_string(),
-            Self::MemoryOverflow => "Failed to allocate adequate memory".to_string(),
-            Self::CouldNotReadToBuffer => "Could not load image into memory buffer".to_string(),
+            Self::CouldNotReadToBuffer { source }
+            | Self::CouldNotDecode { source }
+            | Self::CouldNotWrite { source }
+            | Self::CouldNotEncode { source } => source.as_ref().map(|source| source.as_ref() as &(dyn std::error::Error + 'static)),
+            _ => None,
         }
     }
 }
 
+// The error a failure came from. It's shared, since failures are cloned
+// for every request waiting on the same variant
+pub type FailureSource = Arc<dyn std::error::Error + Send + Sync>;
+
+// Some(Arc::new(io_error))
+fn cause<E: std::error::Error + Send + Sync + 'static>(error: E) -> Option<FailureSource> {
+    Some(Arc::new(error))
+}
+
 type Bytes = Vec<u8>;
+
+// Variants being processed, keyed by their name
+#[cfg(feature = "server")]
+type VariantFlights = SingleFlight<Result<web::Bytes, ImageServiceFailure>>;
+
+// What's remembered between requests, so that popular variants (and
+// persistently missing uploads) don't cost the same work every time
+#[cfg(feature = "server")]
+struct Caches {
+    memory: HotCache,
+    flights: VariantFlights,
+    stats: CacheStats,
+    metrics: Metrics,
+    inventory: LatestInventory,
+    missing: NegativeCache,
+    decoded: DecodedCache,
+    background: BackgroundWork,
+    // Shared by every site
+    budget: Arc<MemoryBudget>,
+    processing: Arc<ProcessingLimit>,
+}
+
+#[cfg(feature = "server")]
+impl Caches {
+    fn new(config: &ServerConfig, budget: Arc<MemoryBudget>, processing: Arc<ProcessingLimit>) -> Self {
+        Self {
+            memory: HotCache::new(config.hot_cache_size),
+            flights: SingleFlight::new(),
+            stats: CacheStats::new(),
+            metrics: Metrics::new(),
+            inventory: LatestInventory::new(),
+            missing: NegativeCache::new(config.negative_cache_ttl),
+            decoded: DecodedCache::new(config.decoded_cache_pixels),
+            background: BackgroundWork::new(),
+            budget,
+            processing,
+        }
+    }
+}
+
+// The formats that can be uploaded, and those that images can be served as
+#[cfg(feature = "server")]
+const INPUT_FORMATS: [&str; 3] = ["png", "jpeg", "webp"];
+#[cfg(feature = "server")]
+const OUTPUT_FORMATS: [&str; 3] = ["png", "jpeg", "webp"];
 type ImageServiceResult = Result<Bytes, ImageServiceFailure>;
+// A variant, along with how long each stage of processing it took
+type ProcessingResult = Result<(Bytes, Processing), ImageServiceFailure>;
+
+// How long each stage of processing a variant took, and how large its
+// master and the variant itself were
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Processing {
+    pub decode: std::time::Duration,
+    pub resize: std::time::Duration,
+    pub encode: std::time::Duration,
+    pub input_bytes: usize,
+    pub output_bytes: usize,
+    // The width and height of the master, and of the variant
+    pub input_dimensions: (u32, u32),
+    pub output_dimensions: (u32, u32),
+}
+
+impl Processing {
+    pub fn total(&self) -> std::time::Duration {
+        self.decode + self.resize + self.encode
+    }
+}
 
 
 // "example.png" -> "example"
+#[cfg(feature = "server")]
 fn strip_extension(filename: &str) -> String {
     let mut parts: Vec<&str> = filename.split(".").collect();
     parts.pop();
     parts.join(".")
 }
 
+#[cfg(feature = "server")]
 #[derive(Serialize)]
 struct UploadResult {
     pub filename: Option<String>,
     pub errors: Vec<String>,
+    // Problems that didn't stop the file from being saved, like it
+    // looking like another upload
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
 }
 
+#[cfg(feature = "server")]
 impl UploadResult {
     pub fn new() -> Self {
-        Self { filename: None, errors: vec![] }
+        Self { filename: None, errors: vec![], warnings: vec![] }
     }
 
     pub fn with_error(mut self, message: &str) -> Self {
@@ -76,13 +398,120 @@ impl UploadResult {
     }
 }
 
+// Upload request bodies larger than this are rejected, unless configured
+// otherwise with `ServerConfig::with_max_upload_size`
+#[cfg(feature = "server")]
+const DEFAULT_MAX_UPLOAD_SIZE: usize = 25 * 1024 * 1024;
+
+// 100 megapixels, which decode to 400MB of RGBA. Small files can claim
+// to be far larger than this, and would exhaust memory if decoded
+const DEFAULT_MAX_PIXELS: u64 = 100_000_000;
+
+// How long an image waits for memory to process it in, when there's a
+// budget, before being refused. Clients are told to retry after a second
+#[cfg(feature = "server")]
+const DEFAULT_MEMORY_BUDGET_WAIT: std::time::Duration = std::time::Duration::from_secs(5);
+#[cfg(feature = "server")]
+const RETRY_OVERLOADED_AFTER: u64 = 1;
+
+// How many variants may queue for a turn at processing, when there's a
+// limit on how many are processed at once, and for how long
+#[cfg(feature = "server")]
+const DEFAULT_PROCESSING_QUEUE: usize = 64;
+#[cfg(feature = "server")]
+const DEFAULT_PROCESSING_QUEUE_WAIT: std::time::Duration = std::time::Duration::from_secs(5);
+
+// Variants larger than this are streamed from disk, rather than being
+// held in memory until the client has received them
+#[cfg(feature = "server")]
+const DEFAULT_STREAM_THRESHOLD: usize = 1024 * 1024;
+
+#[cfg(feature = "server")]
+#[derive(Serialize)]
+struct PayloadTooLarge {
+    error: String,
+    limit: usize,
+}
+
+#[cfg(feature = "server")]
+fn payload_too_large(limit: usize) -> HttpResponse {
+    let body = PayloadTooLarge {
+        error: format!("The request body exceeds the upload limit of {} bytes", limit),
+        limit,
+    };
+    HttpResponse::PayloadTooLarge().json(body)
+}
+
+// Letting an upload URL be used again, after a request to it saved nothing
+#[cfg(feature = "server")]
+async fn release_upload_url(config: &web::Data<ServerConfig>, grant: &upload_urls::UploadGrant) {
+    let (releasing, grant) = (config.clone(), grant.clone());
+    if web::block(move || upload_urls::release(&releasing, &grant)).await.is_err() {
+        tracing::warn!("upload URL could not be released");
+    }
+}
+
 // Respond to a request to upload a file contained in a multipart form stream
-async fn upload(mut payload: Multipart, config: web::Data<ServerConfig>) -> Result<HttpResponse, Error> {
+#[cfg(feature = "server")]
+async fn upload(
+    req: HttpRequest,
+    mut payload: Multipart,
+    config: web::Data<ServerConfig>,
+    caches: web::Data<Caches>,
+) -> Result<HttpResponse, Error> {
+    // Images come from the origin when proxying one, never from clients
+    if config.origin.is_some() {
+        return Ok(HttpResponse::NotFound().body("Uploads are disabled while proxying an origin"));
+    }
+
+    // Requests made to upload URLs are authorized by them. Others are
+    // authorized for each file, before any of its data is read
+    let grant = match upload_urls::grant_of(&req, &config) {
+        Some(Ok(grant)) => Some(grant),
+        Some(Err(failure)) => return Ok(failure.as_http_response()),
+        None => None,
+    };
+    let max_file_size = grant.as_ref().map_or(config.max_upload_size, |grant| grant.max_bytes.min(config.max_upload_size));
+
+    // Rejecting uploads that declare an oversized body up front
+    let declared_size = req
+        .headers()
+        .get("content-length")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+
+    if declared_size.is_some_and(|size| size > max_file_size) {
+        return Ok(payload_too_large(max_file_size));
+    }
+
+    // Using up upload URLs before reading anything sent to them, so that
+    // concurrent requests can't both use one. They're given back if
+    // nothing ends up saved
+    if let Some(grant) = &grant {
+        let (claiming, grant) = (config.clone(), grant.clone());
+        match web::block(move || upload_urls::claim(&claiming, &grant)).await {
+            Ok(()) => {}
+            Err(actix_web::error::BlockingError::Error(e)) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                return Ok(HttpResponse::Forbidden().body("This upload URL has already been used"));
+            }
+            Err(_) => return Ok(HttpResponse::InternalServerError().body("This upload URL could not be redeemed")),
+        }
+    }
+    let mut saved = false;
+
     let mut results: Vec<UploadResult> = vec![];
+    let mut received: usize = 0;
 
     // Iterating over each part of the multipart form
     'form_parts: while let Ok(Some(mut field)) = payload.try_next().await {
         let mut result = UploadResult::new();
+        let started = Instant::now();
+
+        // Upload URLs are for a single file
+        if grant.is_some() && !results.is_empty() {
+            results.push(result.with_error("This upload URL is for a single file"));
+            continue 'form_parts;
+        }
 
         let content_type = match field.content_disposition() {
             Some(result) => result,
@@ -106,23 +535,49 @@ async fn upload(mut payload: Multipart, config: web::Data<ServerConfig>) -> Resu
         };
 
         // Determining upload path
+        // Saving files uploaded to an upload URL by the name it was signed
+        // for, whatever they were called
         let filename = filename.to_string();
-        let clean_filename = strip_extension(&filename);
-        let filepath = format!("{}/{}.webp", config.uploads_dir, clean_filename);
-        if filename != "" { result.filename = Some(filename); }
+        let clean_filename = match &grant {
+            Some(grant) => grant.filename.clone(),
+            None => strip_extension(&filename),
+        };
+        let filepath = build_path_to_master(&config, &clean_filename);
+        if !filename.is_empty() { result.filename = Some(filename); }
+
+        // Authorizing against where the upload would be saved
+        let uploader = match &grant {
+            Some(grant) => grant.uploaded_by.clone(),
+            None => match auth::authorize_upload(&req, &config, auth::Access::Write, &clean_filename) {
+                Ok(uploader) => uploader,
+                Err(failure) => return Ok(failure.as_http_response()),
+            },
+        };
 
         // Preventing duplicate filenames
-        if Path::new(&filepath).exists() {
+        let checking = config.clone();
+        if web::block(move || Ok::<_, ()>(checking.storage.exists(&filepath))).await.unwrap_or(true) {
             let message = "Another file with this name already exists.";
             results.push(result.with_error(message));
             continue 'form_parts;
         }
 
         // Reading file data
+        let reading = tracing::info_span!("read_multipart");
         let mut incoming_data: Bytes = Vec::new();
         while let Some(chunk) = field.next().await {
             match chunk {
-                Ok(data) => incoming_data.extend(data),
+                Ok(data) => {
+                    // Rejecting chunked uploads once they outgrow the limit
+                    received += data.len();
+                    if received > max_file_size {
+                        if let Some(grant) = &grant {
+                            release_upload_url(&config, grant).await;
+                        }
+                        return Ok(payload_too_large(max_file_size));
+                    }
+                    incoming_data.extend_from_slice(&data)
+                },
                 Err(_) => {
                     let message = "File failed to re-assemble.";
                     results.push(result.with_error(message));
@@ -130,16 +585,17 @@ async fn upload(mut payload: Multipart, config: web::Data<ServerConfig>) -> Resu
                 }
             };
         }
+        drop(reading);
 
         // Preventing empty file uploads
-        if incoming_data.len() == 0 {
+        if incoming_data.is_empty() {
             results.push(result.with_error("No file data was provided."));
             continue 'form_parts;
         }
 
         // Constructing Image Reader
-        let cursor = Cursor::new(incoming_data);
-        let reader = match ImageReader::new(cursor).with_guessed_format() {
+        let bytes = incoming_data.len();
+        let reader = match ImageReader::new(Cursor::new(&incoming_data)).with_guessed_format() {
             Ok(result) => result, 
             Err(_) => {
                 results.push(result.with_error("File was un-readable."));
@@ -148,58 +604,125 @@ async fn upload(mut payload: Multipart, config: web::Data<ServerConfig>) -> Resu
         };
 
         // Restricting file formats
-        match reader.format() {
-            Some(ImageFormat::Png) => {},
-            Some(ImageFormat::Jpeg) => {},
-            Some(ImageFormat::WebP) => {},
+        let format = match reader.format() {
+            Some(ImageFormat::Png) => ImageFormat::Png,
+            Some(ImageFormat::Jpeg) => ImageFormat::Jpeg,
+            Some(ImageFormat::WebP) => ImageFormat::WebP,
             _ => {
                 let message = "Unsupported file format. Try converting to \
                 .png, .jpeg, or .webp before uploading.";
                 results.push(result.with_error(message));
                 continue 'form_parts;
             }
-        }
+        };
 
-        // Decoding image data
-        let dynamic_image = match reader.decode() {
-            Ok(result) => result,
-            Err(_) => {
-                let message = "File data could not be decoded.";
-                results.push(result.with_error(message));
+        // Keeping WebPs just as they were uploaded, when they can be
+        let usable = match format {
+            ImageFormat::WebP => is_usable_master(&incoming_data, config.max_pixels),
+            _ => Ok(false),
+        };
+        let data_to_store = match usable {
+            Ok(true) => incoming_data,
+            Ok(false) => {
+                // Refusing decompression bombs, going by the dimensions in the
+                // header, before decoding them
+                let dimensions = reader.into_dimensions().map_err(|e| ImageServiceFailure::CouldNotDecode { source: cause(e) });
+                if let Err(failure) = dimensions.and_then(|(width, height)| check_dimensions(width, height, config.max_pixels)) {
+                    results.push(result.with_error(&failure.to_string()));
+                    continue 'form_parts;
+                }
+
+                // Decoding image data
+                let reader = ImageReader::with_format(Cursor::new(&incoming_data), format);
+                let decoded = tracing::info_span!("decode", bytes).in_scope(|| reader.decode());
+                let dynamic_image = match decoded {
+                    Ok(result) => result,
+                    Err(_) => {
+                        let message = "File data could not be decoded.";
+                        results.push(result.with_error(message));
+                        continue 'form_parts;
+                    }
+                };
+
+                // Re-encoding uploaded image as WebP
+                let _encoding = tracing::info_span!("encode", extension = "webp").entered();
+                match encoding::encode_webp(&dynamic_image, None, &config.encoding) {
+                    Ok(webp) => webp,
+                    Err(_) => {
+                        results.push(result.with_error("File could not be re-encoded."));
+                        continue 'form_parts;
+                    }
+                }
+            }
+            Err(failure) => {
+                results.push(result.with_error(&failure.to_string()));
                 continue 'form_parts;
             }
         };
 
-        // Re-encoding uploaded image as WebP
-        let mut data_to_store: Bytes = Vec::new();
-        let webp_encoder = webp::Encoder::from_image(&dynamic_image);
-        let webp = webp_encoder.encode_lossless();
-        for i in 0..webp.len() { data_to_store.push(webp[i]); }
-
-        // Creating new file on a new threadpool
-        let mut f = match web::block(|| File::create(filepath)).await {
+        // Saving the file on a new threadpool, within this request's span
+        let (width, height) = master_dimensions(&data_to_store, config.max_pixels).unwrap_or_default();
+        let stored = UploadEvent {
+            filename: clean_filename.clone(),
+            bytes: data_to_store.len(),
+            width,
+            height,
+            uploaded_by: uploader.as_ref().map(|uploader| uploader.name.clone()),
+        };
+        let saving_config = config.clone();
+        let saving_filename = clean_filename.clone();
+        let span = tracing::Span::current();
+        match web::block(move || span.in_scope(|| save_master(&saving_config, &saving_filename, &data_to_store))).await {
             Ok(result) => result,
             Err(_) => {
-                let message = "New file could not be created.";
+                let message = "File contents could not be saved";
                 results.push(result.with_error(message));
                 continue 'form_parts;
             }
         };
 
-        // Writing contents to file on a new threadpool
-        match web::block(move || f.write_all(&data_to_store).map(|_| f)).await {
-            Ok(result) => result,
-            Err(_) => {
-                let message = "File contents could not be saved";
+        saved = true;
+
+        // Recording which API key was used for the upload
+        if let Some(uploader) = &uploader {
+            if metadata::record_upload(&config, &clean_filename, uploader).is_err() {
+                let message = "File was saved, but its upload metadata could not be recorded";
                 results.push(result.with_error(message));
                 continue 'form_parts;
             }
-        };
+        }
+
+        // Answering requests for the new upload, rather than with a
+        // 404 remembered from before it existed
+        caches.missing.forget(&clean_filename);
+        let (indexing, indexed) = (config.clone(), clean_filename.clone());
+        let duplicates = web::block(move || {
+            listing::update_index(&indexing, &indexed);
+            Ok::<_, ()>(similarity::near_duplicates_of(&indexing, &indexed))
+        }).await.unwrap_or_default();
+        for duplicate in duplicates {
+            result.warnings.push(format!("This image looks like the existing upload {:?}", duplicate.filename));
+        }
+        config.hooks.upload(stored);
 
         // Success!
+        tracing::info!(filename = %clean_filename, bytes, duration_ms = started.elapsed().as_millis() as u64, "upload saved");
         results.push(result);
     }
 
+    if let Some(grant) = grant.as_ref().filter(|_| !saved) {
+        release_upload_url(&config, grant).await;
+    }
+
+    // Logging failed uploads, which are otherwise only reported to the client
+    for result in results.iter().filter(|result| !result.errors.is_empty()) {
+        tracing::warn!(
+            filename = result.filename.as_deref().unwrap_or(""),
+            errors = %result.errors.join(" "),
+            "upload failed",
+        );
+    }
+
     Ok(
         HttpResponse::Ok()
             .header("content-type", "application/json")
@@ -207,292 +730,3032 @@ async fn upload(mut payload: Multipart, config: web::Data<ServerConfig>) -> Resu
     )
 }
 
-fn try_loading_unprocessed_image(filepath: &str) -> ImageServiceResult {
-    let mut file = match File::open(filepath) {
-        Err(_) => return Err(ImageServiceFailure::ImageDoesNotExist),
-        Ok(f) => f,
-    };
+#[cfg(feature = "server")]
+#[derive(Deserialize)]
+struct RenameInstructions {
+    to: String,
+}
 
-    // Reading the contents of the file into a vector of bytes
-    let mut buffer: Bytes = Vec::new();
-    match file.read_to_end(&mut buffer) {
-        Ok(_) => {},
-        Err(io_err) => return Err( match io_err.kind() {
-            IOError::OutOfMemory => ImageServiceFailure::MemoryOverflow,
-            _ => ImageServiceFailure::CouldNotReadToBuffer,
-        }),
-    };
+#[cfg(feature = "server")]
+#[derive(Serialize)]
+struct RenameResult {
+    filename: String,
+    renamed_to: String,
+}
+
+// Giving an upload another name, along with its upload metadata. With the
+// content addressed layout, only the name record changes. Cached variants
+// are removed, and produced again under the new name when requested
+#[cfg(feature = "server")]
+fn rename_upload(config: &ServerConfig, from: &str, to: &str) -> std::io::Result<()> {
+    match config.content_addressed && content_store::resolve(config, from).is_some() {
+        true => content_store::rename(config, from, to)?,
+        false => {
+            let contents = config.storage.get(&build_path_to_master(config, from))?;
+            save_master(config, to, &contents)?;
+            delete_master(config, from)?;
+        }
+    }
+
+    let record = metadata::build_path_to_metadata_file(config, from);
+    if let Ok(contents) = config.storage.get(&record) {
+        config.storage.put(&metadata::build_path_to_metadata_file(config, to), &contents)?;
+        let _ = config.storage.delete(&record);
+    }
 
-    Ok(buffer)
+    cache::remove_variants_of(config, from);
+    listing::update_index(config, from);
+    listing::update_index(config, to);
+    Ok(())
 }
 
+// Whether an upload may be renamed `to`, which can't be hidden, or look
+// like a path or a query
+#[cfg(feature = "server")]
+fn valid_new_name(to: &str) -> bool {
+    !(to.is_empty() || to.starts_with('.') || to.contains('/') || to.contains('?'))
+}
 
-fn try_processing_image(
-    buffer: Bytes,
-    optional: &ProcessingInstructions,
-    required: &FileDescription,
-) -> ImageServiceResult {
-    // Decoding bytes as webp
-    let webp_decoder = webp::Decoder::new(&buffer);
-    let webp_image = webp_decoder.decode().unwrap();
-    let mut dynamic_image = webp_image.to_image();
+// Removing an upload, along with its upload metadata and cached variants
+#[cfg(feature = "graphql")]
+fn delete_upload(config: &ServerConfig, filename: &str) -> std::io::Result<()> {
+    delete_master(config, filename)?;
+    let _ = config.storage.delete(&metadata::build_path_to_metadata_file(config, filename));
+    cache::remove_variants_of(config, filename);
+    listing::update_index(config, filename);
+    Ok(())
+}
 
-    // Choosing resize dimensions
-    let width = dynamic_image.width();
-    let height = dynamic_image.height();
-    let new_width = optional.w.unwrap_or(width);
-    let new_height = optional.h.unwrap_or(height);
+// Respond to a request to rename an upload, like `POST /cat/rename?to=kitten`
+#[cfg(feature = "server")]
+async fn serve_rename_via_http(
+    req: HttpRequest,
+    name: web::Path<analysis::ImageName>,
+    instructions: web::Query<RenameInstructions>,
+    config: web::Data<ServerConfig>,
+    caches: web::Data<Caches>,
+) -> HttpResponse {
+    if let Err(failure) = auth::authorize(&req, &config, auth::Access::Write) {
+        return failure.as_http_response();
+    }
 
-    // Choosing sampling method filter to use for resizing
-    let filter = match &optional.sampling {
-        None => FilterType::Nearest,
-        Some(filter_name) => match &filter_name[..] {
-            "triangle" => FilterType::Triangle,
-            "catmullrom" => FilterType::CatmullRom,
-            "gaussian" => FilterType::Gaussian,
-            "lanczos3" => FilterType::Lanczos3,
-            _ => FilterType::Nearest,
-        }
-    };
+    let from = name.into_inner().filename;
+    let to = instructions.into_inner().to;
+    if !valid_new_name(&to) {
+        return HttpResponse::BadRequest().body("The new name is not a valid filename");
+    }
 
-    // Resizing the image
-    if height != new_height || width != new_width {
-        dynamic_image = match &optional.stretch.unwrap_or(false) {
-            true => dynamic_image.resize_exact(new_width, new_height, filter),
-            false => dynamic_image.resize(new_width, new_height, filter),
-        }
+    let (checking, master_from, master_to) = (config.clone(), build_path_to_master(&config, &from), build_path_to_master(&config, &to));
+    let existing = web::block(move || Ok::<_, ()>((checking.storage.exists(&master_from), checking.storage.exists(&master_to)))).await;
+    match existing {
+        Ok((true, false)) => {}
+        Ok((false, _)) => return ImageServiceFailure::ImageDoesNotExist.as_http_response(&req),
+        Ok((true, true)) => return HttpResponse::Conflict().body("Another file with this name already exists."),
+        Err(_) => return HttpResponse::InternalServerError().body("The upload could not be renamed"),
     }
 
-    // Initializing the output bytes
-    let mut buffer: Bytes = Vec::new();
+    caches.memory.remove_where(|variant_name| cache::is_derived_from(&from, variant_name));
+    caches.missing.forget(&to);
 
-    // Re-encoding the image and writing to the buffer
-    match &required.extension[..] {
-        "webp" => {
-            let webp_encoder = webp::Encoder::from_image(&dynamic_image);
-            let webp = webp_encoder.encode_lossless();
-            for i in 0..webp.len() { buffer.push(webp[i]); }
-            Ok(buffer)
-        },
-        "png" => {
-            dynamic_image.write_to(&mut buffer, ImageOutputFormat::Png).unwrap();
-            Ok(buffer)
-        },
-        "jpeg" => {
-            dynamic_image.write_to(&mut buffer, ImageOutputFormat::Jpeg(255)).unwrap();
-            Ok(buffer)
-        },
-        _ => Result::Err(ImageServiceFailure::UnsupportedFormat)
+    let result = RenameResult { filename: from.clone(), renamed_to: to.clone() };
+    let renamed = DeleteEvent { filename: from.clone(), renamed_to: Some(to.clone()) };
+    let hooks = config.clone();
+    match web::block(move || rename_upload(&config, &from, &to)).await {
+        Ok(_) => {
+            hooks.hooks.delete(renamed);
+            HttpResponse::Ok().json(result)
+        }
+        Err(_) => HttpResponse::InternalServerError().body("The upload could not be renamed"),
     }
 }
 
+#[cfg(feature = "server")]
+fn try_loading_unprocessed_image(config: &ServerConfig, filepath: &str) -> ImageServiceResult {
+    config.storage.get(filepath).map_err(|io_err| match io_err.kind() {
+        IOError::NotFound => ImageServiceFailure::ImageDoesNotExist,
+        IOError::OutOfMemory => ImageServiceFailure::MemoryOverflow,
+        _ => ImageServiceFailure::CouldNotReadToBuffer { source: cause(io_err) },
+    })
+}
 
-#[derive(Deserialize, Debug)]
-struct FileDescription {
-    filename: String,
-    extension: String,
+
+// Refusing an image whose header claims more than `max_pixels`, before
+// any memory is set aside for decoding it
+fn check_dimensions(width: u32, height: u32, max_pixels: u64) -> Result<(), ImageServiceFailure> {
+    match width as u64 * height as u64 > max_pixels {
+        true => Err(ImageServiceFailure::TooManyPixels { width, height, limit: max_pixels }),
+        false => Ok(()),
+    }
 }
 
-#[derive(Deserialize, Debug)]
-struct ProcessingInstructions {
-    stretch: Option<bool>,
-    sampling: Option<String>,
-    w: Option<u32>,
-    h: Option<u32>,
+// The width and height of a stored master, as its header tells them,
+// refusing those with more than `max_pixels`
+fn master_dimensions(buffer: &[u8], max_pixels: u64) -> Result<(u32, u32), ImageServiceFailure> {
+    let features = webp::BitstreamFeatures::new(buffer).ok_or(ImageServiceFailure::CouldNotDecode { source: None })?;
+    check_dimensions(features.width(), features.height(), max_pixels)?;
+    Ok((features.width(), features.height()))
 }
 
-fn potentially_streamable_file(path: &str) -> Option<NamedFile> {
-    match NamedFile::open(path) {
-        Ok(file) => Some(file),
-        Err(_) => None,
-    }
+// Decoding the bytes of a stored master, which are always webp
+fn decode_master(buffer: &[u8], max_pixels: u64) -> Result<DynamicImage, ImageServiceFailure> {
+    let _span = tracing::info_span!("decode", bytes = buffer.len()).entered();
+    master_dimensions(buffer, max_pixels)?;
+
+    let webp_decoder = webp::Decoder::new(buffer);
+    let webp_image = webp_decoder.decode().ok_or(ImageServiceFailure::CouldNotDecode { source: None })?;
+    Ok(webp_image.to_image())
 }
 
-fn try_streaming_preprocessed_file_from_disk(
-    filepath: &str,
-    req: &HttpRequest,
-) -> Option<HttpResponse> {
-    match potentially_streamable_file(&filepath) {
-        None => None,
-        Some(file) => match file.into_response(&req) {
-            Ok(response) => Some(response),
-            Err(_) => None,
-        }
-    }
+// The format and dimensions of an image in any of the formats that
+// uploads accept, reading only its header, refusing those with more
+// than `max_pixels`
+fn upload_header(contents: &[u8], max_pixels: u64) -> Result<(ImageFormat, (u32, u32)), ImageServiceFailure> {
+    let reader = ImageReader::new(Cursor::new(contents))
+        .with_guessed_format()
+        .map_err(|_| ImageServiceFailure::UnsupportedFormat)?;
+
+    let format = match reader.format() {
+        Some(format @ (ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP)) => format,
+        _ => return Err(ImageServiceFailure::UnsupportedFormat),
+    };
+
+    let (width, height) = reader.into_dimensions().map_err(|e| ImageServiceFailure::CouldNotDecode { source: cause(e) })?;
+    check_dimensions(width, height, max_pixels)?;
+    Ok((format, (width, height)))
 }
 
-fn path_to_requested_file_if_exists(
-    req: & HttpRequest,
-    config: &web::Data<ServerConfig>
-) -> String {
-    format!("{}/{}?{}", config.uploads_dir, req.path(), req.query_string())
+// Decoding an image in any of the formats that uploads accept
+fn decode_upload(contents: &[u8], max_pixels: u64) -> Result<DynamicImage, ImageServiceFailure> {
+    let _span = tracing::info_span!("decode", bytes = contents.len()).entered();
+    let (format, _) = upload_header(contents, max_pixels)?;
+
+    ImageReader::with_format(Cursor::new(contents), format)
+        .decode()
+        .map_err(|e| ImageServiceFailure::CouldNotDecode { source: cause(e) })
 }
 
+// Whether an image can be kept as a master just as it is, sparing it a
+// lossy re-encode. Only still WebPs that libwebp fully decodes are, since
+// masters are always WebPs that it must be able to read back. Animations
+// are converted, since only their first frame is ever served
+fn is_usable_master(contents: &[u8], max_pixels: u64) -> Result<bool, ImageServiceFailure> {
+    let features = match webp::BitstreamFeatures::new(contents) {
+        Some(features) if !features.has_animation() => features,
+        _ => return Ok(false),
+    };
+    check_dimensions(features.width(), features.height(), max_pixels)?;
 
-impl ImageServiceFailure {
-    fn as_http_response(&self) -> HttpResponse {
-        match self {
-            ImageServiceFailure::ImageDoesNotExist => {
-                HttpResponse::NotFound().body(self.to_string())
-            }
-            ImageServiceFailure::UnsupportedFormat => {
-                HttpResponse::BadRequest().body(self.to_string())
-            }
-            ImageServiceFailure::MemoryOverflow => {
-                HttpResponse::InternalServerError().body(self.to_string())
-            }
-            ImageServiceFailure::CouldNotReadToBuffer => {
-                HttpResponse::InternalServerError().body(self.to_string())
-            }
-        }
+    let _span = tracing::info_span!("decode", bytes = contents.len()).entered();
+    Ok(webp::Decoder::new(contents).decode().is_some())
+}
+
+// Converting an image to a master, accepting the same formats as uploads.
+// WebPs are kept as they are, when they can be
+fn encode_master(contents: Bytes, settings: &pipeline::Settings) -> Result<Bytes, ImageServiceFailure> {
+    if is_usable_master(&contents, settings.max_pixels)? {
+        return Ok(contents);
     }
+
+    let dynamic_image = decode_upload(&contents, settings.max_pixels)?;
+    encoding::encode_webp(&dynamic_image, None, &settings.encoding)
 }
 
-fn image_buffer_as_http_response(buffer: Bytes, extension: &str) -> HttpResponse {
-    HttpResponse::Ok()
-        .header("content-type", format!("image/{}", extension))
-        .body(buffer)
+// "cat" -> "./images/cat.webp", or "./images/4a/9c/cat.webp" when sharded,
+// or "./images/.blobs/9f/86/9f86…0f08.webp" when content addressed.
+// Masters saved before the content addressed layout was chosen are
+// still found where they were
+#[cfg(feature = "server")]
+fn build_path_to_master(config: &ServerConfig, filename: &str) -> String {
+    if config.content_addressed {
+        if let Some(blob) = content_store::resolve(config, filename) {
+            return blob;
+        }
+    }
+    format!("{}/{}{}.webp", config.uploads_dir, config.shard(filename), filename)
 }
 
+// Saving the master of an upload wherever the layout keeps it
+#[cfg(feature = "server")]
+fn save_master(config: &ServerConfig, filename: &str, contents: &[u8]) -> std::io::Result<()> {
+    match config.content_addressed {
+        true => content_store::save(config, filename, contents),
+        false => config.storage.put(&build_path_to_master(config, filename), contents),
+    }
+}
 
+#[cfg(feature = "server")]
+fn delete_master(config: &ServerConfig, filename: &str) -> std::io::Result<()> {
+    match config.content_addressed && content_store::resolve(config, filename).is_some() {
+        true => content_store::forget(config, filename),
+        false => config.storage.delete(&build_path_to_master(config, filename)),
+    }
+}
 
-fn build_processing_suffix(req: &HttpRequest) -> String {
-    let qs = req.query_string();
-    match qs.len() {
-        0 => "".to_string(),
-        _ => format!("?{}", qs),
+// Reading and decoding the master of an upload
+#[cfg(feature = "server")]
+fn try_loading_master(config: &ServerConfig, filename: &str) -> Result<DynamicImage, ImageServiceFailure> {
+    if let Some(origin) = &config.origin {
+        origin::refresh_master(config, origin, filename)?;
     }
+    let buffer = try_loading_unprocessed_image(config, &build_path_to_master(config, filename))?;
+    decode_master(&buffer, config.max_pixels)
 }
 
-fn build_path_to_preprocessed_file(
-    file_desc: &FileDescription,
-    processing_suffix: &String,
+// Whether processing would only produce the master again, which is true of
+// WebPs encoded losslessly at the master's own size, however they're sampled
+#[cfg(feature = "server")]
+fn leaves_master_unchanged(
+    buffer: &[u8],
+    optional: &ProcessingInstructions,
+    required: &FileDescription,
     config: &ServerConfig,
-) -> String {
-    format!(
-        "{}/{}{}.{}",
-        config.uploads_dir,
-        file_desc.filename,
-        processing_suffix,
-        file_desc.extension,
-    )
+) -> bool {
+    if required.extension != "webp" || optional.q.or(config.encoding.webp_quality).is_some() || !optional.transforms.is_empty() || optional.iiif.is_some() {
+        return false;
+    }
+
+    match master_dimensions(buffer, config.max_pixels) {
+        Ok((width, height)) => optional.w.unwrap_or(width) == width && optional.h.unwrap_or(height) == height,
+        Err(_) => false,
+    }
 }
 
-fn build_path_to_unprocessed_file(
-    file_desc: &FileDescription,
+// Producing a variant from the bytes of its master, along with how long
+// each stage took
+#[cfg(feature = "server")]
+fn try_processing_image(
+    buffer: Bytes,
+    optional: &ProcessingInstructions,
+    required: &FileDescription,
     config: &ServerConfig,
-) -> String {
-    format!(
-        "{}/{}.{}",
-        config.uploads_dir,
-        file_desc.filename,
-        file_desc.extension,
-    )
+    budget: &MemoryBudget,
+    decoded_masters: &DecodedCache,
+) -> ProcessingResult {
+    let rendition = [(optional.clone(), required.clone())];
+    try_processing_renditions(&buffer, &rendition, &config.into(), budget, decoded_masters)?.remove(0)
 }
 
-struct ImageRequest {
-    req: HttpRequest,
-    filepath_if_preprocessed: String,
-    filepath_if_unprocessed: String,
-    processing: ProcessingInstructions,
-}
-
-impl ImageRequest {
-    fn build(
-        req: HttpRequest,
-        file_desc: web::Path<FileDescription>,
-        processing: web::Query<ProcessingInstructions>,
-        config: web::Data<ServerConfig>,
-    ) -> Self {
-        let processing = processing.into_inner();
-        let file_desc = file_desc.into_inner();
-        let processing_suffix = build_processing_suffix(&req);
+// Producing several variants of one master from a single decode of it,
+// each with how long its stages took. The decode is counted towards the
+// first. The master is decoded as small as the largest variant allows,
+// and the memory reserved is what the largest of them takes, since
+// they're resized and encoded one after another. Every variant is of the
+// same master, and a copy of it decoded recently is used when it's large
+// enough. Images that aren't WebPs, like those processed by the library
+// without being uploaded, are decoded at full size
+fn try_processing_renditions(
+    buffer: &[u8],
+    renditions: &[(ProcessingInstructions, FileDescription)],
+    settings: &pipeline::Settings,
+    budget: &MemoryBudget,
+    decoded_masters: &DecodedCache,
+) -> Result<Vec<ProcessingResult>, ImageServiceFailure> {
+    if renditions.is_empty() {
+        return Ok(vec![]);
+    }
 
-        Self {
-            req,
-            processing,
-            filepath_if_preprocessed: build_path_to_preprocessed_file(
-                &file_desc,
-                &processing_suffix,
-                &config,
-            ),
-            filepath_if_unprocessed: build_path_to_unprocessed_file(
-                &file_desc,
-                &config,
-            ),
+    let is_webp = webp::BitstreamFeatures::new(buffer).is_some();
+    let input_dimensions = match is_webp {
+        true => master_dimensions(buffer, settings.max_pixels)?,
+        false => upload_header(buffer, settings.max_pixels)?.1,
+    };
+    let scaled = renditions
+        .iter()
+        .map(|(optional, _)| scaled_decode_size(input_dimensions, optional).filter(|_| is_webp))
+        .try_fold(None, |largest: Option<(u32, u32)>, scaled| scaled.map(|size| largest.max(Some(size))))
+        .flatten();
+    let decoded_dimensions = scaled.unwrap_or(input_dimensions);
+    let memory = renditions.iter().map(|(optional, _)| estimated_memory(decoded_dimensions, optional)).max().unwrap_or(0);
+    let _reservation = budget.reserve(memory).ok_or(ImageServiceFailure::Overloaded)?;
+
+    let started = Instant::now();
+    let (filename, fingerprint) = (&renditions[0].1.filename, decoded_cache::fingerprint(buffer));
+    let decoded = match decoded_masters.get(filename, fingerprint, decoded_dimensions.0) {
+        Some(decoded) => decoded,
+        None => {
+            let decoded = Arc::new(match (scaled, is_webp) {
+                (Some((width, height)), _) => downscale::decode_scaled(buffer, width, height).ok_or(ImageServiceFailure::CouldNotDecode { source: None })?,
+                (None, true) => decode_master(buffer, settings.max_pixels)?,
+                (None, false) => decode_upload(buffer, settings.max_pixels)?,
+            });
+            decoded_masters.insert(filename, fingerprint, decoded.clone());
+            decoded
         }
-    }
+    };
+    let mut decode = started.elapsed();
+
+    let mut decoded = Some(decoded);
+    let processed = renditions.iter().enumerate().map(|(i, (optional, required))| {
+        // The last variant takes the decoded master, rather than a copy,
+        // unless it's also held by the cache
+        let source = match i + 1 < renditions.len() {
+            true => decoded.as_deref().cloned(),
+            false => decoded.take().map(|decoded| Arc::try_unwrap(decoded).unwrap_or_else(|shared| (*shared).clone())),
+        };
+
+        let started = Instant::now();
+        let dynamic_image = reshape(source.ok_or(ImageServiceFailure::CouldNotDecode { source: None })?, optional, input_dimensions, settings)?;
+        let dynamic_image = transform::apply(dynamic_image, &optional.transforms, &settings.transforms)?;
+        let resize = started.elapsed();
+
+        let started = Instant::now();
+        let encoded = encoding::encode(&dynamic_image, &required.extension, optional.q, &settings.encoding)?;
+        let encode = started.elapsed();
+
+        let processing = Processing {
+            decode: std::mem::take(&mut decode),
+            resize,
+            encode,
+            input_bytes: buffer.len(),
+            output_bytes: encoded.len(),
+            input_dimensions,
+            output_dimensions: dynamic_image.dimensions(),
+        };
+        Ok((encoded, processing))
+    });
+    Ok(processed.collect())
 }
 
-fn serve_image_via_http(
-    req: HttpRequest,
-    required: web::Path<FileDescription>,
-    optional: web::Query<ProcessingInstructions>,
-    config: web::Data<ServerConfig>,
-) -> HttpResponse {
-    let required = required.into_inner();
-    let optional = optional.into_inner();
+// (4000, 3000) with w=200 -> Some((400, 300))
+// How small a master can be decoded, for variants much smaller than it.
+// That's twice the size of the variant, leaving the rest of the way to
+// the sampling filter asked for. Masters are decoded at full size when
+// that wouldn't at least halve them
+fn scaled_decode_size((width, height): (u32, u32), optional: &ProcessingInstructions) -> Option<(u32, u32)> {
+    if optional.w.is_none() && optional.h.is_none() {
+        return None;
+    }
 
-    let preprocessed_filename = match req.query_string() != "" || required.extension != "webp" {
-        true => format!("{}/{}?{}.{}",config.uploads_dir, required.filename, req.query_string(), required.extension),
-        false => format!("{}/{}.{}",config.uploads_dir, required.filename, required.extension),
+    let requested = (optional.w.unwrap_or(width), optional.h.unwrap_or(height));
+    let (new_width, new_height) = match optional.stretch.unwrap_or(false) {
+        true => requested,
+        false => fit_within((width, height), requested),
     };
 
-    match try_streaming_preprocessed_file_from_disk(&preprocessed_filename, &req) {
-        Some(response) => return response,
-        None => {},
-    };
+    let scale = 2.0 * (new_width as f64 / width as f64).max(new_height as f64 / height as f64);
+    match scale > 0.5 {
+        true => None,
+        false => Some((
+            ((width as f64 * scale).ceil() as u32).max(1),
+            ((height as f64 * scale).ceil() as u32).max(1),
+        )),
+    }
+}
 
-    let unprocessed_filename = format!("{}/{}.webp", config.uploads_dir, required.filename);
+// (4000, 3000) within (200, 200) -> (200, 150)
+// The largest size with the same aspect ratio that fits, worked out as
+// `DynamicImage::resize` does
+fn fit_within((width, height): (u32, u32), (new_width, new_height): (u32, u32)) -> (u32, u32) {
+    let (width, height, new_width, new_height) = (width as u64, height as u64, new_width as u64, new_height as u64);
+    match new_width * height <= width * new_height {
+        true => (new_width as u32, (height * new_width / width).clamp(1, u32::MAX as u64) as u32),
+        false => ((width * new_height / height).clamp(1, u32::MAX as u64) as u32, new_height as u32),
+    }
+}
 
-    let unprocessed_image = match try_loading_unprocessed_image(&unprocessed_filename) {
-        Err(failure) => return failure.as_http_response(),
-        Ok(bytes) => bytes,
+// (4000, 3000) with w=200 and h=150 -> 48_120_000
+// Roughly the memory processing a master takes: its decoded pixels, and
+// those of the resized copy, at four bytes each. Images are resized to
+// fit within the requested width and height, so that's the most it takes
+fn estimated_memory((width, height): (u32, u32), optional: &ProcessingInstructions) -> usize {
+    let decoded = width as usize * height as usize * 4;
+    let resized = match (optional.w, optional.h) {
+        (None, None) => 0,
+        (w, h) => w.unwrap_or(width) as usize * h.unwrap_or(height) as usize * 4,
     };
+    decoded + resized
+}
 
-    let processed_image = match try_processing_image(unprocessed_image, &optional, &required) {
-        Err(failure) => return failure.as_http_response(),
-        Ok(buffer) => buffer,
+// Counting how long processing a variant took, and warning about those
+// that took too long or came out too large, along with everything they
+// were processed with
+#[cfg(feature = "server")]
+fn record_processing(
+    config: &ServerConfig,
+    caches: &Caches,
+    variant_name: &str,
+    optional: &ProcessingInstructions,
+    required: &FileDescription,
+    processing: &Processing,
+) {
+    caches.metrics.record(processing);
+    config.hooks.variant_generated(VariantEvent::of(&required.filename, &optional.cache_key(), &required.extension, processing));
+
+    let slow = config.slow_processing.is_some_and(|threshold| processing.total() > threshold);
+    let large = config.large_output.is_some_and(|bytes| processing.output_bytes > bytes);
+    let reason = match (slow, large) {
+        (false, false) => return,
+        (true, false) => "slow processing",
+        (false, true) => "large output",
+        (true, true) => "slow processing and large output",
     };
 
-    let mut file = File::create(preprocessed_filename).unwrap();
-    file.write_all(&processed_image);
-    
-    image_buffer_as_http_response(processed_image, &required.extension)
+    let (width, height) = processing.input_dimensions;
+    tracing::warn!(
+        variant = variant_name,
+        w = optional.w,
+        h = optional.h,
+        q = optional.q,
+        sampling = optional.sampling.as_deref(),
+        stretch = optional.stretch,
+        preset = optional.preset.as_deref(),
+        master_width = width,
+        master_height = height,
+        decode_ms = processing.decode.as_millis() as u64,
+        resize_ms = processing.resize.as_millis() as u64,
+        encode_ms = processing.encode.as_millis() as u64,
+        input_bytes = processing.input_bytes,
+        output_bytes = processing.output_bytes,
+        "{}", reason,
+    );
 }
 
-struct ServerConfig {
-    uploads_dir: String,
+// Resizing an image as instructed, or as a IIIF request asks. Sizes are
+// worked out from `original`, the size it was stored at, since it may
+// have been decoded smaller
+fn reshape(
+    dynamic_image: DynamicImage,
+    optional: &ProcessingInstructions,
+    original: (u32, u32),
+    settings: &pipeline::Settings,
+) -> Result<DynamicImage, ImageServiceFailure> {
+    #[cfg(feature = "server")]
+    if let Some(request) = &optional.iiif {
+        let _span = tracing::info_span!("iiif", request = %request.path).entered();
+        return request.apply(dynamic_image, settings.max_pixels);
+    }
+
+    transform_image(dynamic_image, optional, original, settings.max_pixels)
 }
 
+// Sizes that `w` and `h` ask for are refused past `max_pixels`, like
+// the ones uploads are held to
+fn transform_image(
+    mut dynamic_image: DynamicImage,
+    optional: &ProcessingInstructions,
+    original: (u32, u32),
+    max_pixels: u64,
+) -> Result<DynamicImage, ImageServiceFailure> {
+    let _span = tracing::info_span!("resize", w = optional.w, h = optional.h).entered();
+    // Choosing resize dimensions
+    let (width, height) = original;
+    let new_width = optional.w.unwrap_or(width);
+    let new_height = optional.h.unwrap_or(height);
 
-pub struct ImageServer;
+    // Choosing sampling method filter to use for resizing
+    let (_, filter) = optional.sampling_method();
 
-impl ImageServer {
-    pub fn listen(port: u64, uploads_dir: String) {
-        let config = web::Data::new(ServerConfig { uploads_dir });
+    // Resizing the image
+    if height != new_height || width != new_width {
+        let (new_width, new_height) = match optional.stretch.unwrap_or(false) {
+            true => (new_width, new_height),
+            false => fit_within(original, (new_width, new_height)),
+        };
+        check_dimensions(new_width, new_height, max_pixels)?;
+        dynamic_image = dynamic_image.resize_exact(new_width, new_height, filter);
+    }
 
-        // Creating uploads directory if non-existent
-        std::fs::create_dir_all(Path::new(&config.uploads_dir))
-            .expect("Unable to create uploads directory");
+    Ok(dynamic_image)
+}
 
-        let serve_forever = async move {
-            HttpServer::new(move || {
-                App::new()
-                    .app_data(config.clone())
-                    .wrap(Cors::permissive())
-                    .route("/{filename}.{extension}", web::get().to(serve_image_via_http))
-                    .route("/upload", web::post().to(upload))
-            })
-            .bind(format!("0.0.0.0:{}", port))
-            .expect(&format!("Failed to bind to port {}", port))
-            .run()
-            .await
-        };
+// Encoding an image in the format named by `extension`, as formats are
+// encoded by default
+#[cfg(feature = "server")]
+fn encode_image(dynamic_image: &DynamicImage, extension: &str) -> ImageServiceResult {
+    encoding::encode(dynamic_image, extension, None, &EncodingDefaults::new())
+}
 
-        actix_web::rt::System::new("server")
-            .block_on(serve_forever)
-            .expect("Failed to create async runtime")
+
+#[derive(Deserialize, Clone, Debug)]
+struct FileDescription {
+    filename: String,
+    extension: String,
+}
+
+// Treating empty query string parameters, like "h=" in "?w=200&h=", as absent
+fn empty_as_none<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    match Option::<String>::deserialize(deserializer)?.as_deref() {
+        None | Some("") => Ok(None),
+        Some(value) => value.parse().map(Some).map_err(serde::de::Error::custom),
     }
 }
 
+#[derive(Deserialize, Clone, Debug)]
+struct ProcessingInstructions {
+    #[serde(default, deserialize_with = "empty_as_none")]
+    stretch: Option<bool>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    sampling: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    w: Option<u32>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    h: Option<u32>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    q: Option<u8>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    preset: Option<String>,
+    // The registered transforms asked for, like [("sepia", "80")]
+    #[serde(skip)]
+    transforms: Vec<(String, String)>,
+    // A IIIF request, which takes the place of every other instruction
+    #[cfg(feature = "server")]
+    #[serde(skip)]
+    iiif: Option<iiif::ImageRequest>,
+}
+
+impl ProcessingInstructions {
+    #[cfg(feature = "server")]
+    fn of_iiif(request: iiif::ImageRequest) -> Self {
+        Self {
+            stretch: None,
+            sampling: None,
+            w: None,
+            h: None,
+            q: None,
+            preset: None,
+            transforms: vec![],
+            iiif: Some(request),
+        }
+    }
+
+    // Picking out the registered transforms that `query` asks for
+    fn with_transforms(mut self, query: &str, transforms: &[Arc<dyn Transform>]) -> Result<Self, ImageServiceFailure> {
+        self.transforms = transform::requested(query, transforms)
+            .map_err(|(name, message)| ImageServiceFailure::invalid_parameter(&name, &message))?;
+        Ok(self)
+    }
+
+    // Filling in any instructions that weren't given explicitly from the
+    // named preset, if there is one, along with the transforms it asks for
+    fn with_preset(self, presets: &[Preset], transforms: &[Arc<dyn Transform>]) -> Result<Self, String> {
+        let name = match &self.preset {
+            Some(name) => name.clone(),
+            None => return Ok(self),
+        };
+
+        let preset = match presets.iter().find(|preset| preset.name == name) {
+            Some(preset) => preset,
+            None => return Err(format!("There is no preset named {:?}", name)),
+        };
+
+        let defaults = serde_urlencoded::from_str::<ProcessingInstructions>(&preset.query)
+            .map_err(|_| format!("The preset named {:?} is misconfigured", name))?
+            .with_transforms(&preset.query, transforms)
+            .map_err(|_| format!("The preset named {:?} is misconfigured", name))?;
+
+        // Keeping to the order transforms were registered in
+        let given = |name: &str| self.transforms.iter().chain(&defaults.transforms).find(|(given, _)| given == name).cloned();
+        let transforms = transforms.iter().filter_map(|transform| given(transform.name())).collect();
+
+        Ok(Self {
+            stretch: self.stretch.or(defaults.stretch),
+            sampling: self.sampling.or(defaults.sampling),
+            w: self.w.or(defaults.w),
+            h: self.h.or(defaults.h),
+            q: self.q.or(defaults.q),
+            preset: None,
+            transforms,
+            #[cfg(feature = "server")]
+            iiif: self.iiif,
+        })
+    }
+
+    // The instructions given other than by a preset, like ["w", "h"]
+    #[cfg(feature = "server")]
+    fn explicit_instructions(&self) -> Vec<&str> {
+        let given = [
+            ("stretch", self.stretch.is_some()),
+            ("sampling", self.sampling.is_some()),
+            ("w", self.w.is_some()),
+            ("h", self.h.is_some()),
+            ("q", self.q.is_some()),
+        ];
+        given.iter()
+            .filter(|(_, given)| *given)
+            .map(|(name, _)| *name)
+            .chain(self.transforms.iter().map(|(name, _)| name.as_str()))
+            .collect()
+    }
+
+    // Refusing qualities that no encoder accepts
+    fn check_quality(&self) -> Result<(), String> {
+        match self.q {
+            Some(q) if !(1..=100).contains(&q) => Err("q must be between 1 and 100".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    // The name and filter of the requested sampling method, which
+    // defaults to nearest neighbor for unrecognized methods
+    fn sampling_method(&self) -> (&'static str, FilterType) {
+        match self.sampling.as_deref() {
+            Some("triangle") => ("triangle", FilterType::Triangle),
+            Some("catmullrom") => ("catmullrom", FilterType::CatmullRom),
+            Some("gaussian") => ("gaussian", FilterType::Gaussian),
+            Some("lanczos3") => ("lanczos3", FilterType::Lanczos3),
+            _ => ("nearest", FilterType::Nearest),
+        }
+    }
+
+    // Identifying the variant these instructions produce, regardless of
+    // how the query string was written. Defaults are left out, so that
+    // "?stretch=false&sampling=nearest" is cached as the same variant as "?"
+    // w=200, sampling=lanczos3, stretch=false -> "sampling=lanczos3&w=200"
+    #[cfg(feature = "server")]
+    fn cache_key(&self) -> String {
+        let mut pairs = vec![];
+
+        if let Some(h) = self.h { pairs.push(format!("h={}", h)); }
+        if let Some(q) = self.q { pairs.push(format!("q={}", q)); }
+
+        let (sampling, _) = self.sampling_method();
+        if sampling != "nearest" { pairs.push(format!("sampling={}", sampling)); }
+
+        if self.stretch == Some(true) { pairs.push("stretch=true".to_string()); }
+        if let Some(w) = self.w { pairs.push(format!("w={}", w)); }
+
+        // Transforms are sorted in with everything else, by name
+        for (name, value) in &self.transforms {
+            pairs.push(serde_urlencoded::to_string([(name, value)]).unwrap_or_default());
+        }
+        if let Some(request) = &self.iiif {
+            pairs.push(serde_urlencoded::to_string([("iiif", &request.path)]).unwrap_or_default());
+        }
+        pairs.sort_by(|a, b| a.split('=').next().cmp(&b.split('=').next()));
+
+        pairs.join("&")
+    }
+}
+
+// Opening the file on the blocking threadpool, since storage may have
+// to fetch it first, and responding with it here
+#[cfg(feature = "server")]
+async fn try_streaming_preprocessed_file_from_disk(
+    config: &web::Data<ServerConfig>,
+    filepath: &str,
+    req: &HttpRequest,
+) -> Option<HttpResponse> {
+    let (opening, filepath) = (config.clone(), filepath.to_string());
+    let file = run_blocking(move || Ok(opening.storage.open(&filepath))).await.ok()??;
+    file.into_response(req)
+}
+
+impl ImageServiceFailure {
+    fn invalid_parameter(parameter: &str, message: &str) -> Self {
+        Self::InvalidParameter { parameter: Some(parameter.to_string()), message: message.to_string() }
+    }
+
+    // "image_does_not_exist", for clients to tell failures apart by
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::UnsupportedFormat => "unsupported_format",
+            Self::ImageDoesNotExist => "image_does_not_exist",
+            Self::MemoryOverflow => "memory_overflow",
+            Self::CouldNotReadToBuffer { .. } => "could_not_read",
+            Self::CouldNotDecode { .. } => "could_not_decode",
+            Self::CouldNotWrite { .. } => "could_not_write",
+            Self::CouldNotEncode { .. } => "could_not_encode",
+            Self::InvalidParameter { .. } => "invalid_parameter",
+            Self::TooManyPixels { .. } => "too_many_pixels",
+            Self::Overloaded => "overloaded",
+            Self::TransformFailed { .. } => "transform_failed",
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl ImageServiceFailure {
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::ImageDoesNotExist => StatusCode::NOT_FOUND,
+            Self::UnsupportedFormat | Self::InvalidParameter { .. } => StatusCode::BAD_REQUEST,
+            Self::TooManyPixels { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+            Self::TransformFailed { .. } => StatusCode::BAD_GATEWAY,
+            Self::MemoryOverflow
+            | Self::CouldNotReadToBuffer { .. }
+            | Self::CouldNotDecode { .. }
+            | Self::CouldNotWrite { .. }
+            | Self::CouldNotEncode { .. } => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    // {"error": {"code": "invalid_parameter", "message": "q must be between 1 and 100", "request_id": "7c4f…", "parameter": "q"}}
+    fn as_http_response(&self, req: &HttpRequest) -> HttpResponse {
+        let parameter = match self {
+            Self::InvalidParameter { parameter, .. } => parameter.clone(),
+            _ => None,
+        };
+
+        let mut response = HttpResponse::build(self.status());
+        if let Self::Overloaded = self {
+            response.header("retry-after", RETRY_OVERLOADED_AFTER.to_string());
+        }
+
+        response.json(serde_json::json!({
+            "error": {
+                "code": self.code(),
+                "message": self.to_string(),
+                "request_id": request_id::request_id(req).0,
+                "parameter": parameter,
+            }
+        }))
+    }
+}
+
+#[cfg(feature = "server")]
+fn image_buffer_as_http_response<B: Into<actix_web::body::Body>>(buffer: B, extension: &str) -> HttpResponse {
+    HttpResponse::Ok()
+        .header("content-type", format!("image/{}", extension))
+        .body(buffer)
+}
+
+
+
+// Equivalent query strings are reduced to a single canonical form, so
+// that they share one cached variant. Parameters are sorted by name,
+// and empty values and URL signatures are dropped.
+// "w=200&h=&expires=1700000000&sig=ab12&stretch=true" -> "stretch=true&w=200"
+#[cfg(feature = "server")]
+fn processing_query_string(req: &HttpRequest) -> String {
+    let mut pairs: Vec<&str> = req.query_string()
+        .split('&')
+        .filter(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            let name = parts.next().unwrap_or("");
+            let value = parts.next().unwrap_or("");
+            !name.is_empty() && !value.is_empty() && !auth::SIGNATURE_PARAMS.contains(&name)
+        })
+        .collect();
+
+    pairs.sort_by_key(|pair| pair.split('=').next().unwrap_or(""));
+    pairs.join("&")
+}
+
+// Pointing clients at the canonical URL of the variant they asked for,
+// so that downstream caches also store a single copy. Signed URLs are
+// left alone, since rewriting them would invalidate their signature
+#[cfg(feature = "server")]
+fn canonical_redirect(req: &HttpRequest, canonical_query: &str) -> Option<HttpResponse> {
+    let query = req.query_string();
+    if query == canonical_query || query.split('&').any(|pair| pair.starts_with("sig=")) {
+        return None;
+    }
+
+    let location = match canonical_query.is_empty() {
+        true => req.path().to_string(),
+        false => format!("{}?{}", req.path(), canonical_query),
+    };
+
+    Some(HttpResponse::MovedPermanently().header("location", location).finish())
+}
+
+// Responding with a variant of an upload, either streamed from disk
+// if it's been generated before, or freshly processed from the master.
+// With `regenerate`, any cached copy is ignored and replaced
+#[cfg(feature = "server")]
+async fn serve_variant(
+    req: &HttpRequest,
+    required: &FileDescription,
+    optional: &ProcessingInstructions,
+    config: &web::Data<ServerConfig>,
+    caches: &web::Data<Caches>,
+    regenerate: bool,
+) -> Result<HttpResponse, ImageServiceFailure> {
+    let cache_key = optional.cache_key();
+    let variant_name = cache::variant_name(&required.filename, &cache_key, &required.extension);
+    let is_derived = !cache_key.is_empty() || required.extension != "webp";
+    let stream_above = match is_derived && config.storage.streams_incrementally() {
+        true => Some(config.stream_threshold),
+        false => None,
+    };
+    let streamable = move |buffer: &web::Bytes| stream_above.is_some_and(|threshold| buffer.len() > threshold);
+    let mut stale = false;
+
+    // Answering for uploads known not to exist before storage is asked
+    // about them at all
+    if caches.missing.is_missing(&required.filename) {
+        return Err(ImageServiceFailure::ImageDoesNotExist);
+    }
+
+    // Fetching the master before anything is compared with it, when
+    // proxying an origin
+    if config.origin.is_some() {
+        let (config, caches, filename) = (config.clone(), caches.clone(), required.filename.clone());
+        run_blocking(move || match &config.origin {
+            Some(origin) => refresh_master_from_origin(&config, &caches, origin, &filename),
+            None => Ok(()),
+        }).await?;
+    }
+
+    // Skipping cached copies when regenerating, though masters are
+    // always served as they are
+    let use_cache = !(regenerate && is_derived);
+
+    // Serving popular variants straight from memory, without touching storage
+    if use_cache {
+        if let Some(buffer) = caches.memory.get(&variant_name) {
+            record_lookup(req, caches, &variant_name, Lookup::Memory);
+            return Ok(image_buffer_as_http_response(buffer, &required.extension));
+        }
+    }
+
+    let preprocessed_filename = match is_derived {
+        true => {
+            let filepath = cache::build_path_to_variant(config, &variant_name, &required.extension);
+            // Comparing the cached copy with its master, and discarding
+            // every variant of a master that's been replaced, which reads
+            // through the cache, away from the HTTP worker
+            let (checked_config, checked_path, filename) = (config.clone(), filepath.clone(), required.filename.clone());
+            let freshness = run_blocking(move || {
+                cache::discard_if_damaged(&checked_config, &checked_path);
+                Ok(cache::check_freshness(&checked_config, &filename, &checked_path, checked_config.stale_while_revalidate))
+            }).await?;
+            match freshness {
+                Freshness::Fresh => {},
+                Freshness::Stale => stale = true,
+                Freshness::Discarded => {
+                    caches.memory.remove_where(|name| cache::is_derived_from(&required.filename, name))
+                }
+            }
+            filepath
+        }
+        false => build_path_to_master(config, &required.filename),
+    };
+
+    if use_cache {
+        if let Some(mut response) = try_serving_cached_variant(req, config, required, &preprocessed_filename, &variant_name, caches).await {
+            if stale {
+                revalidate_variant_in_background(req, config, caches, required, optional);
+                advertise_stale_while_revalidate(&mut response, config);
+            }
+            return Ok(response);
+        }
+
+        record_lookup(req, caches, &variant_name, Lookup::Miss);
+    } else {
+        record_lookup(req, caches, &variant_name, Lookup::Bypass);
+    }
+
+    // Processing each variant once, no matter how many clients request it at once
+    let (processed_image, processing, unchanged) = {
+        let (config, caches, required, optional) = (config.clone(), caches.clone(), required.clone(), optional.clone());
+        let (name, cache_key) = (variant_name.clone(), cache_key.clone());
+        run_blocking(move || {
+            let (mut processing, mut unchanged) = (None, false);
+            let processed_image = caches.flights.run(&name, || {
+                let _slot = caches.processing.acquire().ok_or(ImageServiceFailure::Overloaded)?;
+                let unprocessed_filename = build_path_to_master(&config, &required.filename);
+                let unprocessed_image = match try_loading_unprocessed_image(&config, &unprocessed_filename) {
+                    Err(ImageServiceFailure::ImageDoesNotExist) => {
+                        caches.missing.record_missing(&required.filename);
+                        return Err(ImageServiceFailure::ImageDoesNotExist);
+                    }
+                    loaded => loaded?,
+                };
+                if leaves_master_unchanged(&unprocessed_image, &optional, &required, &config) {
+                    unchanged = true;
+                    return Ok(web::Bytes::from(unprocessed_image));
+                }
+
+                let (processed_image, timings) = try_processing_image(unprocessed_image, &optional, &required, &config, &caches.budget, &caches.decoded)?;
+                let processed_image = web::Bytes::from(processed_image);
+                record_processing(&config, &caches, &name, &optional, &required, &timings);
+
+                // Saving large variants before anyone is answered, so that
+                // every request for them can stream the saved copy
+                if streamable(&processed_image) {
+                    if let Err(e) = cache::store_variant(&config, &required.filename, &cache_key, &required.extension, &processed_image) {
+                        tracing::warn!(variant = %name, error = %e, "variant could not be cached");
+                    }
+                } else {
+                    caches.memory.insert(&name, processed_image.clone());
+                }
+                processing = Some(timings);
+                Ok(processed_image)
+            })?;
+            Ok((processed_image, processing, unchanged))
+        }).await?
+    };
+
+    // Streaming the master as it's stored, rather than a copy of it
+    if unchanged {
+        let master = build_path_to_master(config, &required.filename);
+        if let Some(response) = try_streaming_preprocessed_file_from_disk(config, &master, req).await {
+            return Ok(response);
+        }
+    }
+
+    // Only the request that processed the variant saves it
+    if let Some(processing) = processing {
+        req.extensions_mut().insert(processing);
+        if !streamable(&processed_image) {
+            persist_variant_in_background(req, config, caches, required, &cache_key, processed_image.clone());
+        }
+    }
+
+    // The processed copy is let go of once streaming begins, falling back
+    // to sending it whole if the saved copy can't be read
+    if streamable(&processed_image) {
+        if let Some(response) = try_streaming_preprocessed_file_from_disk(config, &preprocessed_filename, req).await {
+            return Ok(response);
+        }
+    }
+
+    Ok(image_buffer_as_http_response(processed_image, &required.extension))
+}
+
+// Running work that would hold up the HTTP worker, like decoding,
+// resizing and encoding, on the blocking threadpool instead, within the
+// current span
+#[cfg(feature = "server")]
+async fn run_blocking<T, F>(work: F) -> Result<T, ImageServiceFailure>
+where
+    F: FnOnce() -> Result<T, ImageServiceFailure> + Send + 'static,
+    T: Send + 'static,
+{
+    let span = tracing::Span::current();
+    match web::block(move || span.in_scope(work)).await {
+        Ok(value) => Ok(value),
+        Err(actix_web::error::BlockingError::Error(failure)) => Err(failure),
+        Err(actix_web::error::BlockingError::Canceled) => Err(ImageServiceFailure::CouldNotReadToBuffer { source: None }),
+    }
+}
+
+// Fetching each image from the origin once, no matter how many clients
+// request it at once. Variant names always contain a "?", so they're
+// never confused with the filenames used as keys here
+#[cfg(feature = "server")]
+fn refresh_master_from_origin(
+    config: &ServerConfig,
+    caches: &Caches,
+    origin: &OriginConfig,
+    filename: &str,
+) -> Result<(), ImageServiceFailure> {
+    if caches.missing.is_missing(filename) {
+        return Err(ImageServiceFailure::ImageDoesNotExist);
+    }
+
+    let refreshed = caches.flights.run(filename, || {
+        origin::refresh_master(config, origin, filename).map(|_| web::Bytes::new())
+    });
+
+    match refreshed {
+        Ok(_) => Ok(()),
+        Err(ImageServiceFailure::ImageDoesNotExist) => {
+            caches.memory.remove_where(|name| cache::is_derived_from(filename, name));
+            caches.missing.record_missing(filename);
+            Err(ImageServiceFailure::ImageDoesNotExist)
+        }
+        Err(failure) => Err(failure),
+    }
+}
+
+// Counting how a variant was found, and noting it for the request's logs
+#[cfg(feature = "server")]
+fn record_lookup(req: &HttpRequest, caches: &Caches, variant_name: &str, lookup: Lookup) {
+    tracing::Span::current().record("cache", lookup.name());
+    req.extensions_mut().insert(lookup);
+    caches.stats.record(variant_name, lookup);
+}
+
+// A variant found on disk, either read whole to be kept in memory or
+// opened to be streamed
+#[cfg(feature = "server")]
+enum CachedVariant {
+    Loaded(Vec<u8>),
+    Opened(OpenedFile),
+}
+
+// Looking for a variant on disk, once it wasn't found in memory. Storage
+// is only asked about it on the blocking threadpool
+#[cfg(feature = "server")]
+async fn try_serving_cached_variant(
+    req: &HttpRequest,
+    config: &web::Data<ServerConfig>,
+    required: &FileDescription,
+    filepath: &str,
+    variant_name: &str,
+    caches: &Caches,
+) -> Option<HttpResponse> {
+    // Promoting variants found on disk into memory, when there's room,
+    // unless they're large enough to be streamed instead
+    let promote = caches.memory.is_enabled();
+    let (reading, filepath) = (config.clone(), filepath.to_string());
+    let found = run_blocking(move || {
+        let large = reading.storage.streams_incrementally()
+            && reading.storage.metadata(&filepath).is_some_and(|file| file.size > reading.stream_threshold as u64);
+        Ok(match promote && !large {
+            true => try_loading_unprocessed_image(&reading, &filepath).ok().map(CachedVariant::Loaded),
+            false => reading.storage.open(&filepath).map(CachedVariant::Opened),
+        })
+    }).await.ok()??;
+
+    let response = match found {
+        CachedVariant::Loaded(contents) => {
+            let buffer = web::Bytes::from(contents);
+            caches.memory.insert(variant_name, buffer.clone());
+            image_buffer_as_http_response(buffer, &required.extension)
+        }
+        CachedVariant::Opened(file) => file.into_response(req)?,
+    };
+    record_lookup(req, caches, variant_name, Lookup::Disk);
+    Some(response)
+}
+
+// Saving a newly processed variant to disk without delaying the response,
+// since failing to cache it doesn't stop it being served this time
+#[cfg(feature = "server")]
+fn persist_variant_in_background(
+    req: &HttpRequest,
+    config: &web::Data<ServerConfig>,
+    caches: &Caches,
+    required: &FileDescription,
+    cache_key: &str,
+    buffer: web::Bytes,
+) {
+    let request_id = request_id::request_id(req);
+    let variant_name = cache::variant_name(&required.filename, cache_key, &required.extension);
+    let config = config.clone();
+    let filename = required.filename.clone();
+    let extension = required.extension.clone();
+    let cache_key = cache_key.to_string();
+    let span = tracing::info_span!("persist_variant", variant = %variant_name);
+
+    caches.background.spawn(async move {
+        let stored = web::block(move || span.in_scope(|| {
+            cache::store_variant(&config, &filename, &cache_key, &extension, &buffer)
+        }));
+
+        if let Err(actix_web::error::BlockingError::Error(e)) = stored.await {
+            tracing::warn!(%request_id, variant = %variant_name, error = %e, "variant could not be cached");
+        }
+    });
+}
+
+// Replacing a variant whose master was replaced, while the stale copy
+// is served in the meantime
+#[cfg(feature = "server")]
+fn revalidate_variant_in_background(
+    req: &HttpRequest,
+    config: &web::Data<ServerConfig>,
+    caches: &web::Data<Caches>,
+    required: &FileDescription,
+    optional: &ProcessingInstructions,
+) {
+    let request_id = request_id::request_id(req);
+    let cache_key = optional.cache_key();
+    let variant_name = cache::variant_name(&required.filename, &cache_key, &required.extension);
+    let config = config.clone();
+    let caches = caches.clone();
+    let required = required.clone();
+    let optional = optional.clone();
+    let name = variant_name.clone();
+    let background = caches.background.clone();
+    let span = tracing::info_span!("revalidate_variant", variant = %variant_name);
+
+    background.spawn(async move {
+        let revalidated = web::block(move || span.in_scope(|| {
+            // Skipping variants that another request revalidated first
+            let filepath = cache::build_path_to_variant(&config, &name, &required.extension);
+            if !cache::is_stale(&config, &required.filename, &filepath) {
+                return Ok(());
+            }
+
+            let processed_image = caches.flights.run(&name, || {
+                let _slot = caches.processing.acquire().ok_or(ImageServiceFailure::Overloaded)?;
+                let unprocessed_filename = build_path_to_master(&config, &required.filename);
+                let unprocessed_image = try_loading_unprocessed_image(&config, &unprocessed_filename)?;
+                let (processed_image, processing) = try_processing_image(unprocessed_image, &optional, &required, &config, &caches.budget, &caches.decoded)?;
+                let processed_image = web::Bytes::from(processed_image);
+                record_processing(&config, &caches, &name, &optional, &required, &processing);
+                caches.memory.insert(&name, processed_image.clone());
+                Ok(processed_image)
+            }).map_err(|failure| failure.to_string())?;
+
+            cache::store_variant(&config, &required.filename, &cache_key, &required.extension, &processed_image)
+                .map_err(|e| e.to_string())
+        }));
+
+        if let Err(actix_web::error::BlockingError::Error(e)) = revalidated.await {
+            tracing::warn!(%request_id, variant = %variant_name, error = %e, "variant could not be revalidated");
+        }
+    });
+}
+
+// Letting downstream caches also serve their copy of a stale variant
+// while they fetch the fresh one
+#[cfg(feature = "server")]
+fn advertise_stale_while_revalidate(response: &mut HttpResponse, config: &ServerConfig) {
+    let window = config.stale_while_revalidate.unwrap_or_default().as_secs();
+    let value = format!("max-age=0, stale-while-revalidate={}", window);
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        response.headers_mut().insert(HeaderName::from_static("cache-control"), value);
+    }
+}
+
+// Substituting the fallback image for missing uploads, processed
+// with the same instructions as the original request
+#[cfg(feature = "server")]
+async fn serve_variant_or_fallback(
+    req: &HttpRequest,
+    required: &FileDescription,
+    optional: &ProcessingInstructions,
+    config: &web::Data<ServerConfig>,
+    caches: &web::Data<Caches>,
+    regenerate: bool,
+) -> HttpResponse {
+    let failure = match serve_variant(req, required, optional, config, caches, regenerate).await {
+        Ok(response) => return response,
+        Err(failure) => failure,
+    };
+    tracing::Span::current().record("error", tracing::field::display(&failure));
+
+    match (&failure, &config.fallback_image) {
+        (ImageServiceFailure::ImageDoesNotExist, Some(fallback)) => {
+            let substitute = FileDescription {
+                filename: fallback.filename.clone(),
+                extension: required.extension.clone(),
+            };
+
+            match serve_variant(req, &substitute, optional, config, caches, regenerate).await {
+                Err(failure) => failure.as_http_response(req),
+                Ok(mut response) => {
+                    if response.status() == StatusCode::OK {
+                        *response.status_mut() = fallback.status;
+                    }
+                    response
+                }
+            }
+        }
+        _ => failure.as_http_response(req),
+    }
+}
+
+// "?nocache=1" or "Cache-Control: no-cache" -> true
+// Forcing a variant to be processed again and its cached copy replaced,
+// like when debugging transformations. Doing so requires write access,
+// so `?nocache=1` is refused without it, while the header (which browsers
+// send on every hard refresh) is ignored
+#[cfg(feature = "server")]
+fn regeneration_requested(req: &HttpRequest, config: &ServerConfig) -> Result<bool, auth::AuthFailure> {
+    let param = req.query_string()
+        .split('&')
+        .any(|pair| pair == "nocache=1" || pair == "nocache=true");
+
+    let header = req.headers()
+        .get("cache-control")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.split(',').any(|directive| directive.trim().eq_ignore_ascii_case("no-cache")));
+
+    if !param && !header { return Ok(false); }
+
+    match auth::authorize(req, config, auth::Access::Write) {
+        Ok(_) => Ok(true),
+        Err(failure) if param => Err(failure),
+        Err(_) => Ok(false),
+    }
+}
+
+// "w=200&h=tall" -> Some("h")
+// Every instruction is optional, so each parameter can be parsed on its
+// own to find the one that couldn't be
+#[cfg(feature = "server")]
+fn unparsable_parameter(query: &str) -> Option<String> {
+    query
+        .split('&')
+        .find(|pair| web::Query::<ProcessingInstructions>::from_query(pair).is_err())
+        .map(|pair| pair.split('=').next().unwrap_or(pair).to_string())
+}
+
+// Refusing processing instructions that can't be parsed, like "?w=wide",
+// the same way as any other invalid parameter
+#[cfg(feature = "server")]
+fn instructions_query_config() -> web::QueryConfig {
+    web::QueryConfig::default().error_handler(|err, req| {
+        let failure = ImageServiceFailure::InvalidParameter {
+            parameter: unparsable_parameter(req.query_string()),
+            message: err.to_string(),
+        };
+        actix_web::error::InternalError::from_response(err, failure.as_http_response(req)).into()
+    })
+}
+
+// "ok", "refused" or "failed", to tell at a glance from logs
+#[cfg(feature = "server")]
+fn outcome(status: StatusCode) -> &'static str {
+    match status.as_u16() {
+        500..=599 => "failed",
+        400..=499 => "refused",
+        _ => "ok",
+    }
+}
+
+// Logging each image served, along with how it was found (in memory, on
+// disk, or by processing it) and why it couldn't be served
+#[cfg(feature = "server")]
+async fn serve_image_via_http(
+    req: HttpRequest,
+    required: web::Path<FileDescription>,
+    optional: web::Query<ProcessingInstructions>,
+    config: web::Data<ServerConfig>,
+    caches: web::Data<Caches>,
+) -> HttpResponse {
+    let span = tracing::info_span!(
+        "serve",
+        filename = %required.filename,
+        extension = %required.extension,
+        params = req.query_string(),
+        cache = tracing::field::Empty,
+        error = tracing::field::Empty,
+    );
+    let started = Instant::now();
+
+    let server_timing = config.server_timing;
+    let served = req.clone();
+    let mut response = serve_image(req, required, optional, config, caches).instrument(span.clone()).await;
+    let _entered = span.enter();
+
+    let status = response.status();
+    let duration_ms = started.elapsed().as_millis() as u64;
+    tracing::info!(status = status.as_u16(), duration_ms, outcome = outcome(status), "image served");
+
+    // Telling CDNs and people debugging whether the variant was cached
+    if let Some(lookup) = served.extensions().get::<Lookup>() {
+        response.headers_mut().insert(HeaderName::from_static("x-cache"), HeaderValue::from_static(lookup.x_cache()));
+    }
+    if server_timing {
+        if let Ok(value) = HeaderValue::from_str(&metrics::server_timing(&served, started.elapsed())) {
+            response.headers_mut().insert(HeaderName::from_static("server-timing"), value);
+        }
+    }
+    response
+}
+
+#[cfg(feature = "server")]
+async fn serve_image(
+    req: HttpRequest,
+    required: web::Path<FileDescription>,
+    optional: web::Query<ProcessingInstructions>,
+    config: web::Data<ServerConfig>,
+    caches: web::Data<Caches>,
+) -> HttpResponse {
+    if let Err(failure) = auth::authorize_upload(&req, &config, auth::Access::Read, &required.filename) {
+        return failure.as_http_response();
+    }
+
+    let optional = match optional.into_inner().with_transforms(req.query_string(), &config.transforms) {
+        Ok(optional) => optional,
+        Err(failure) => return failure.as_http_response(&req),
+    };
+
+    // Refusing instructions other than a preset, unless the URL was signed,
+    // so that clients can't produce an unlimited number of variants
+    let locked = config.presets_only && !auth::has_valid_signature(&req, &config);
+    if locked {
+        if let Some(parameter) = optional.explicit_instructions().first() {
+            let message = "Only presets can be requested. Try ?preset={name}, or a signed URL";
+            return ImageServiceFailure::invalid_parameter(parameter, message).as_http_response(&req);
+        }
+    }
+
+    let mut required = required.into_inner();
+    let mut optional = match optional.with_preset(&config.presets, &config.transforms) {
+        Ok(optional) => optional,
+        Err(message) => return ImageServiceFailure::invalid_parameter("preset", &message).as_http_response(&req),
+    };
+    if let Err(message) = optional.check_quality() {
+        return ImageServiceFailure::invalid_parameter("q", &message).as_http_response(&req);
+    }
+
+    let regenerate = match regeneration_requested(&req, &config) {
+        Ok(regenerate) => regenerate,
+        Err(failure) => return failure.as_http_response(),
+    };
+
+    if config.canonical_redirects {
+        if let Some(redirect) = canonical_redirect(&req, &processing_query_string(&req)) {
+            return redirect;
+        }
+    }
+
+    // Resolving `.auto` to a concrete format that the client supports
+    let mut negotiation = Negotiation::new();
+    if config.format_negotiation && required.extension == "auto" {
+        required.extension = negotiation::negotiate_extension(&req, &mut negotiation, &config.output_formats).to_string();
+    }
+
+    if !config.serves_format(&required.extension) {
+        return config.unserved_format(&required.extension).as_http_response(&req);
+    }
+
+    // Adjusting dimensions for the client's display, which is left to
+    // presets alone when only they are allowed
+    let client_hints = config.client_hints && !locked;
+    if client_hints {
+        client_hints::apply_client_hints(&req, &mut negotiation, &mut optional);
+    }
+
+    let mut response = serve_variant_or_fallback(&req, &required, &optional, &config, &caches, regenerate).await;
+    negotiation.apply(&mut response);
+
+    if client_hints {
+        response.headers_mut().insert(
+            HeaderName::from_static("accept-ch"),
+            HeaderValue::from_static(client_hints::ACCEPTED_CLIENT_HINTS),
+        );
+    }
+
+    response
+}
+
+// A named set of processing instructions, requested with `?preset={name}`
+#[derive(Clone, Debug)]
+pub struct Preset {
+    pub name: String,
+    pub query: String,
+}
+
+// An upload to serve in place of images that don't exist
+#[cfg(feature = "server")]
+#[derive(Clone, Debug)]
+pub struct FallbackImage {
+    pub filename: String,
+    pub status: StatusCode,
+}
+
+#[cfg(feature = "server")]
+pub struct ServerConfig {
+    pub uploads_dir: String,
+    pub api_keys: Vec<ApiKey>,
+    pub jwt: Option<JwtConfig>,
+    pub read_policies: Vec<PathPolicy>,
+    pub write_policies: Vec<PathPolicy>,
+    pub url_signing_secret: Option<String>,
+    pub fallback_image: Option<FallbackImage>,
+    pub format_negotiation: bool,
+    pub client_hints: bool,
+    pub tls: Option<TlsConfig>,
+    pub unix_socket: Option<String>,
+    pub bind_addresses: Vec<String>,
+    pub grpc_address: Option<String>,
+    pub base_path: String,
+    pub canonical_redirects: bool,
+    pub server_timing: bool,
+    pub iiif: bool,
+    pub graphql: bool,
+    pub max_upload_size: usize,
+    pub max_pixels: u64,
+    pub memory_budget: Option<usize>,
+    pub memory_budget_wait: std::time::Duration,
+    pub processing_limit: Option<usize>,
+    pub processing_queue: usize,
+    pub processing_queue_wait: std::time::Duration,
+    pub slow_processing: Option<std::time::Duration>,
+    pub large_output: Option<usize>,
+    pub stream_threshold: usize,
+    pub variant_max_age: Option<std::time::Duration>,
+    pub hot_cache_size: usize,
+    pub decoded_cache_pixels: usize,
+    pub presets: Vec<Preset>,
+    pub card_templates: Vec<CardTemplate>,
+    pub cache_dir: Option<String>,
+    pub negative_cache_ttl: Option<std::time::Duration>,
+    pub stale_while_revalidate: Option<std::time::Duration>,
+    pub storage: Arc<dyn Storage>,
+    pub sharded_layout: bool,
+    pub content_addressed: bool,
+    pub origin: Option<OriginConfig>,
+    pub encoding: EncodingDefaults,
+    pub output_formats: Vec<String>,
+    pub presets_only: bool,
+    pub host: Option<String>,
+    pub sites: Vec<ServerConfig>,
+    pub shutdown_timeout: std::time::Duration,
+    pub workers: Option<usize>,
+    pub blocking_threads: Option<usize>,
+    pub access_log: Option<AccessLogConfig>,
+    pub hooks: EventHooks,
+    pub transforms: Vec<Arc<dyn Transform>>,
+    #[cfg(feature = "sqlite")]
+    pub metadata_index: Option<Arc<MetadataIndex>>,
+}
+
+#[cfg(feature = "server")]
+impl ServerConfig {
+    pub fn new(uploads_dir: &str) -> Self {
+        Self {
+            uploads_dir: uploads_dir.to_string(),
+            api_keys: vec![],
+            jwt: None,
+            read_policies: vec![],
+            write_policies: vec![],
+            url_signing_secret: None,
+            fallback_image: None,
+            format_negotiation: false,
+            client_hints: false,
+            tls: None,
+            unix_socket: None,
+            grpc_address: None,
+            bind_addresses: vec![],
+            base_path: String::new(),
+            canonical_redirects: false,
+            server_timing: false,
+            iiif: false,
+            graphql: false,
+            max_upload_size: DEFAULT_MAX_UPLOAD_SIZE,
+            max_pixels: DEFAULT_MAX_PIXELS,
+            memory_budget: None,
+            memory_budget_wait: DEFAULT_MEMORY_BUDGET_WAIT,
+            processing_limit: None,
+            processing_queue: DEFAULT_PROCESSING_QUEUE,
+            processing_queue_wait: DEFAULT_PROCESSING_QUEUE_WAIT,
+            slow_processing: None,
+            large_output: None,
+            stream_threshold: DEFAULT_STREAM_THRESHOLD,
+            variant_max_age: None,
+            hot_cache_size: 0,
+            decoded_cache_pixels: 0,
+            presets: vec![],
+            card_templates: vec![],
+            cache_dir: None,
+            negative_cache_ttl: None,
+            stale_while_revalidate: None,
+            storage: Arc::new(LocalStorage),
+            sharded_layout: false,
+            content_addressed: false,
+            origin: None,
+            encoding: EncodingDefaults::new(),
+            output_formats: OUTPUT_FORMATS.iter().map(|format| format.to_string()).collect(),
+            presets_only: false,
+            host: None,
+            sites: vec![],
+            shutdown_timeout: std::time::Duration::from_secs(30),
+            workers: None,
+            blocking_threads: None,
+            access_log: None,
+            hooks: EventHooks::default(),
+            transforms: vec![],
+            #[cfg(feature = "sqlite")]
+            metadata_index: None,
+        }
+    }
+
+    // Requiring uploads to present this key (or any other configured one)
+    pub fn with_api_key(mut self, api_key: ApiKey) -> Self {
+        self.api_keys.push(api_key);
+        self
+    }
+
+    // Accepting tokens from an external identity provider
+    pub fn with_jwt(mut self, jwt: JwtConfig) -> Self {
+        self.jwt = Some(jwt);
+        self
+    }
+
+    // Deciding who may view images whose path begins with `prefix`
+    pub fn with_read_policy(mut self, prefix: &str, policy: AccessPolicy) -> Self {
+        self.read_policies.push(PathPolicy { prefix: prefix.to_string(), policy });
+        self
+    }
+
+    // Deciding who may upload or modify images under `prefix`
+    pub fn with_write_policy(mut self, prefix: &str, policy: AccessPolicy) -> Self {
+        self.write_policies.push(PathPolicy { prefix: prefix.to_string(), policy });
+        self
+    }
+
+    // The secret used to verify URLs produced by `imogen::sign_url`
+    pub fn with_url_signing_secret(mut self, secret: &str) -> Self {
+        self.url_signing_secret = Some(secret.to_string());
+        self
+    }
+
+    // Serving the upload named `filename` whenever a requested image
+    // doesn't exist, responding with `status` (typically 200 or 404)
+    pub fn with_fallback_image(mut self, filename: &str, status: StatusCode) -> Self {
+        self.fallback_image = Some(FallbackImage { filename: filename.to_string(), status });
+        self
+    }
+
+    // Letting `{filename}.auto` choose the best format named in the
+    // request's `Accept` header
+    pub fn with_format_negotiation(mut self) -> Self {
+        self.format_negotiation = true;
+        self
+    }
+
+    // Honoring the `DPR`, `Width` and `Save-Data` request headers
+    // as if they were processing instructions
+    pub fn with_client_hints(mut self) -> Self {
+        self.client_hints = true;
+        self
+    }
+
+    // Serving HTTPS using a PEM encoded certificate chain and private key.
+    // Requires the `tls` feature
+    pub fn with_tls(mut self, cert_path: &str, key_path: &str) -> Self {
+        self.tls = Some(TlsConfig {
+            cert_path: cert_path.to_string(),
+            key_path: key_path.to_string(),
+        });
+        self
+    }
+
+    // Listening on a unix domain socket at `path` instead of a TCP port
+    pub fn with_unix_socket(mut self, path: &str) -> Self {
+        self.unix_socket = Some(path.to_string());
+        self
+    }
+
+    // Serving uploads and processing over gRPC too, at an address like
+    // "0.0.0.0:50051". This needs imogen to be built with the `grpc` feature
+    pub fn with_grpc(mut self, address: &str) -> Self {
+        self.grpc_address = Some(address.to_string());
+        self
+    }
+
+    // Listening on a specific interface, like "127.0.0.1" or "::1",
+    // instead of every interface. Can be called repeatedly to listen
+    // on several addresses at once
+    pub fn with_bind_address(mut self, host: &str) -> Self {
+        self.bind_addresses.push(host.to_string());
+        self
+    }
+
+    // Serving every route beneath a path like "/media", so that imogen
+    // can share a domain with other services.
+    // "media/" -> "/media"
+    pub fn with_base_path(mut self, base_path: &str) -> Self {
+        let trimmed = base_path.trim_matches('/');
+        self.base_path = match trimmed.is_empty() {
+            true => String::new(),
+            false => format!("/{}", trimmed),
+        };
+        self
+    }
+
+    // Only answering requests whose Host header names `host`, like
+    // "images.example.com". Mostly useful for sites added with `with_site`
+    pub fn with_host(mut self, host: &str) -> Self {
+        self.host = Some(host.to_lowercase());
+        self
+    }
+
+    // Serving another site from the same server, with its own uploads,
+    // limits, auth, presets and caches. Requests are routed to it by its
+    // host or base path, which it needs at least one of, and requests
+    // for neither are served by this config. Listening settings, like
+    // TLS and bind addresses, only come from this config
+    pub fn with_site(mut self, site: ServerConfig) -> Self {
+        self.sites.push(site);
+        self
+    }
+
+    // How long stopping the server (on SIGTERM or SIGINT) waits for
+    // requests in flight, and then for cached variants and replicated
+    // files still being written, before exiting anyway. 30 seconds by default
+    pub fn with_shutdown_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    // How many threads answer HTTP requests, handing anything slow, like
+    // processing variants, to the blocking threadpool. One per CPU by default
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.workers = Some(workers.max(1));
+        self
+    }
+
+    // How many threads handle work kept off the HTTP workers, like
+    // processing variants, decoding and saving uploads, and caching
+    // variants. Five per CPU by default. There's one such pool for the
+    // whole process, sized when it's first used, so `ServerBuilder::build`
+    // sizes it through `ACTIX_THREADPOOL`, for every server in the process,
+    // and it's too late once anything has used `web::block`
+    pub fn with_blocking_threads(mut self, threads: usize) -> Self {
+        self.blocking_threads = Some(threads.max(1));
+        self
+    }
+
+    // Writing a line for every request served, by every site
+    pub fn with_access_log(mut self, access_log: AccessLogConfig) -> Self {
+        self.access_log = Some(access_log);
+        self
+    }
+
+    // Redirecting requests whose query string isn't in canonical form,
+    // like "?w=200&h=100", to the one that is, like "?h=100&w=200"
+    pub fn with_canonical_redirects(mut self) -> Self {
+        self.canonical_redirects = true;
+        self
+    }
+
+    // Telling clients how each image was found, and how long decoding,
+    // resizing and encoding it took, in a `Server-Timing` header
+    pub fn with_server_timing(mut self) -> Self {
+        self.server_timing = true;
+        self
+    }
+
+    // Serving uploads as the IIIF Image API describes, beneath /iiif, for
+    // viewers of museum and library collections
+    pub fn with_iiif(mut self) -> Self {
+        self.iiif = true;
+        self
+    }
+
+    // Answering GraphQL queries and mutations of uploads at /graphql. This
+    // needs imogen to be built with the `graphql` feature
+    pub fn with_graphql(mut self) -> Self {
+        self.graphql = true;
+        self
+    }
+
+    // Logging a warning, with the instructions it was given, whenever
+    // processing an image takes longer than `threshold` altogether
+    pub fn with_slow_processing_warning(mut self, threshold: std::time::Duration) -> Self {
+        self.slow_processing = Some(threshold);
+        self
+    }
+
+    // Logging a warning whenever a variant is larger than this, in bytes
+    pub fn with_large_output_warning(mut self, bytes: usize) -> Self {
+        self.large_output = Some(bytes);
+        self
+    }
+
+    // Streaming variants larger than this many bytes from disk. They're
+    // saved before the response begins, and kept out of the hot cache
+    pub fn with_stream_threshold(mut self, bytes: usize) -> Self {
+        self.stream_threshold = bytes;
+        self
+    }
+
+    // Limiting the size of upload request bodies, in bytes
+    pub fn with_max_upload_size(mut self, bytes: usize) -> Self {
+        self.max_upload_size = bytes;
+        self
+    }
+
+    // Refusing uploads, and masters, whose width times height is more
+    // than this, before decoding them
+    pub fn with_max_pixels(mut self, pixels: u64) -> Self {
+        self.max_pixels = pixels;
+        self
+    }
+
+    // Processing only as many images at once as are estimated to fit in
+    // this many megabytes, across every site. The rest wait their turn,
+    // and are answered with `503 Service Unavailable` if it doesn't come
+    pub fn with_memory_budget(mut self, megabytes: usize) -> Self {
+        self.memory_budget = Some(megabytes * 1024 * 1024);
+        self
+    }
+
+    // How long images wait for room in the memory budget
+    pub fn with_memory_budget_wait(mut self, wait: std::time::Duration) -> Self {
+        self.memory_budget_wait = wait;
+        self
+    }
+
+    // Processing at most this many variants that weren't cached at once,
+    // across every site. The rest queue for a turn, and are answered with
+    // `503 Service Unavailable` if the queue is full or they wait too long
+    pub fn with_processing_limit(mut self, jobs: usize) -> Self {
+        self.processing_limit = Some(jobs);
+        self
+    }
+
+    // How many variants may queue for a turn, and for how long
+    pub fn with_processing_queue(mut self, length: usize) -> Self {
+        self.processing_queue = length;
+        self
+    }
+
+    pub fn with_processing_queue_wait(mut self, wait: std::time::Duration) -> Self {
+        self.processing_queue_wait = wait;
+        self
+    }
+
+    // Deleting cached variants that haven't been used for this long.
+    // They're re-derived from their master when requested again
+    pub fn with_variant_max_age(mut self, max_age: std::time::Duration) -> Self {
+        self.variant_max_age = Some(max_age);
+        self
+    }
+
+    // Holding up to this many megabytes of the most recently requested
+    // variants in memory, to serve them without reading from disk
+    pub fn with_hot_cache(mut self, megabytes: usize) -> Self {
+        self.hot_cache_size = megabytes * 1024 * 1024;
+        self
+    }
+
+    // Holding up to this many megapixels of recently decoded masters in
+    // memory, so that other variants of them are processed without
+    // decoding them again. Each pixel takes three or four bytes
+    pub fn with_decoded_cache(mut self, megapixels: usize) -> Self {
+        self.decoded_cache_pixels = megapixels * 1_000_000;
+        self
+    }
+
+    // "thumbnail", "w=200&h=200&sampling=triangle"
+    // Naming processing instructions, so that `?preset=thumbnail` can be
+    // requested in place of the full query string
+    pub fn with_preset(mut self, name: &str, query: &str) -> Self {
+        self.presets.push(Preset { name: name.to_string(), query: query.to_string() });
+        self
+    }
+
+    // Serving social preview cards laid out by `template` at
+    // `/card/{name}.{extension}`
+    pub fn with_card_template(mut self, template: CardTemplate) -> Self {
+        self.card_templates.push(template);
+        self
+    }
+
+    // Keeping cached variants, placeholders and analyses in a directory
+    // of their own, rather than in hidden directories among the uploads
+    pub fn with_cache_dir(mut self, cache_dir: &str) -> Self {
+        self.cache_dir = Some(cache_dir.trim_end_matches('/').to_string());
+        self
+    }
+
+    // Remembering for this long that a requested upload doesn't exist,
+    // so that bots requesting dead URLs are answered without touching
+    // the filesystem. Uploading an image with that name ends it early
+    pub fn with_negative_cache(mut self, ttl: std::time::Duration) -> Self {
+        self.negative_cache_ttl = Some(ttl);
+        self
+    }
+
+    // Serving variants whose master was replaced within `window` as they
+    // are, while they're produced again in the background. Otherwise
+    // they're produced again before responding
+    pub fn with_stale_while_revalidate(mut self, window: std::time::Duration) -> Self {
+        self.stale_while_revalidate = Some(window);
+        self
+    }
+
+    // Keeping uploads and cached files somewhere other than the local
+    // filesystem. Paths handed to `storage` are still built from the
+    // uploads and cache directories
+    pub fn with_storage<S: Storage + 'static>(mut self, storage: S) -> Self {
+        self.storage = Arc::new(storage);
+        self
+    }
+
+    // Telling `hooks` of uploads, variants and deletions as they happen.
+    // Several can be added, and each is told in the order they were
+    pub fn with_hooks<H: Hooks + 'static>(mut self, hooks: H) -> Self {
+        self.hooks = self.hooks.with(Arc::new(hooks));
+        self
+    }
+
+    // Listing and searching uploads from `index`, which is kept up to date
+    // as they change, rather than by going through every master
+    #[cfg(feature = "sqlite")]
+    pub fn with_metadata_index(mut self, index: MetadataIndex) -> Self {
+        self.metadata_index = Some(Arc::new(index));
+        self
+    }
+
+    // POSTing uploads, replacements and deletions to webhooks, as hooks
+    // that are told of them after any added before
+    #[cfg(feature = "webhooks")]
+    pub fn with_webhooks(self, webhooks: WebhookConfig) -> Self {
+        self.with_hooks(Webhooks::new(webhooks))
+    }
+
+    // Letting requests cut images out of their backgrounds with
+    // "?removebg=true", by way of an outside service, as a transform
+    #[cfg(feature = "removebg")]
+    pub fn with_background_removal(self, removal: BackgroundRemovalConfig) -> Self {
+        self.with_transform(RemoveBackground::new(removal))
+    }
+
+    // Letting requests enlarge images with "?upscale=2x", by way of an
+    // outside super-resolution service, as a transform
+    #[cfg(feature = "upscale")]
+    pub fn with_upscaling(self, upscaling: UpscalingConfig) -> Self {
+        self.with_transform(Upscale::new(upscaling))
+    }
+
+    // Letting requests ask for an effect of the application's own, by
+    // naming `transform` in their query string. Transforms are applied in
+    // the order they were added, whatever order they're asked for in
+    pub fn with_transform<T: Transform + 'static>(mut self, transform: T) -> Self {
+        self.transforms.push(Arc::new(transform));
+        self
+    }
+
+    // Spreading uploads and cached files across subdirectories named
+    // after a hash of each file's name, for directories that would
+    // otherwise hold hundreds of thousands of files. Files saved with
+    // the other layout aren't found, so it's best chosen up front
+    pub fn with_sharded_layout(mut self) -> Self {
+        self.sharded_layout = true;
+        self
+    }
+
+    // Keeping masters under a hash of their contents, with a record of which
+    // one each upload's name refers to. Identical uploads share one copy,
+    // renaming only rewrites the record, and damage can be found by hashing
+    // masters again with `ImageServer::verify_masters`
+    pub fn with_content_addressed_layout(mut self) -> Self {
+        self.content_addressed = true;
+        self
+    }
+
+    // Fetching images from an origin on demand, instead of accepting
+    // uploads. Fetched images are kept in the uploads directory.
+    // Requires the `proxy` feature
+    pub fn with_origin(mut self, origin: OriginConfig) -> Self {
+        self.origin = Some(origin);
+        self
+    }
+
+    // Choosing how each format is encoded when a request doesn't give a
+    // quality with `?q=`
+    pub fn with_encoding_defaults(mut self, defaults: EncodingDefaults) -> Self {
+        self.encoding = defaults;
+        self
+    }
+
+    // Only serving images in these formats, like &["webp", "jpeg"], rather
+    // than every format imogen can encode
+    pub fn with_output_formats(mut self, extensions: &[&str]) -> Self {
+        self.output_formats = extensions.iter().map(|extension| extension.to_string()).collect();
+        self
+    }
+
+    // Refusing requests for anything but a preset, unless their URL is
+    // signed. Client hints are ignored for unsigned requests too
+    pub fn with_presets_only(mut self) -> Self {
+        self.presets_only = true;
+        self
+    }
+
+    // "webp" -> true, unless restricted with `with_output_formats`
+    fn serves_format(&self, extension: &str) -> bool {
+        OUTPUT_FORMATS.contains(&extension) && self.output_formats.iter().any(|format| format == extension)
+    }
+
+    fn unserved_format(&self, extension: &str) -> ImageServiceFailure {
+        let message = format!(
+            "Images aren't served as {:?}. Try one of: {}",
+            extension,
+            self.output_formats.join(", "),
+        );
+        ImageServiceFailure::invalid_parameter("extension", &message)
+    }
+
+    // "variants" -> "./images/.variants", or "/var/cache/imogen/variants"
+    fn build_path_to_cache(&self, kind: &str) -> String {
+        match &self.cache_dir {
+            Some(cache_dir) => format!("{}/{}", cache_dir, kind),
+            None => format!("{}/.{}", self.uploads_dir, kind),
+        }
+    }
+
+    // "cat" -> "4a/9c/" when sharded, or "" otherwise
+    // The subdirectories a file is kept in, so that no directory holds
+    // more than a small share of them
+    fn shard(&self, name: &str) -> String {
+        match self.sharded_layout {
+            true => {
+                let hash = cache::hash_of(name);
+                format!("{}/{}/", &hash[..2], &hash[2..4])
+            }
+            false => String::new(),
+        }
+    }
+
+    // "/media/cat.png" -> "/cat.png"
+    fn route_path<'a>(&self, path: &'a str) -> &'a str {
+        path.strip_prefix(self.base_path.as_str()).unwrap_or(path)
+    }
+}
+
+
+// Rejecting methods that a route doesn't support, while telling the
+// client which ones it does
+#[cfg(feature = "server")]
+fn method_not_allowed(allowed: &'static str) -> HttpResponse {
+    HttpResponse::MethodNotAllowed()
+        .header("allow", allowed)
+        .finish()
+}
+
+// A route answering GET and HEAD requests
+#[cfg(feature = "server")]
+fn readable<F, I, R, U>(path: &str, handler: F) -> Resource
+where
+    F: Factory<I, R, U>,
+    I: FromRequest + 'static,
+    R: Future<Output = U> + 'static,
+    U: Responder + 'static,
+{
+    web::resource(path)
+        .route(web::get().to(handler.clone()))
+        .route(web::head().to(handler))
+        .default_service(web::route().to(|| method_not_allowed("GET, HEAD")))
+}
+
+// A route answering POST requests
+#[cfg(feature = "server")]
+fn writable<F, I, R, U>(path: &str, handler: F) -> Resource
+where
+    F: Factory<I, R, U>,
+    I: FromRequest + 'static,
+    R: Future<Output = U> + 'static,
+    U: Responder + 'static,
+{
+    web::resource(path)
+        .route(web::post().to(handler))
+        .default_service(web::route().to(|| method_not_allowed("POST")))
+}
+
+// Registering every route that imogen serves
+#[cfg(feature = "server")]
+fn configure_routes(cfg: &mut web::ServiceConfig) {
+    cfg
+        .service(readable("/{filename}.dzi", dzi::serve_dzi_via_http))
+        .service(readable(r"/{filename}_files/{level:\d+}/{column:\d+}_{row:\d+}.{format}", dzi::serve_dzi_tile_via_http))
+        .service(readable("/{filename}.{extension}", serve_image_via_http).app_data(instructions_query_config()))
+        .service(readable(
+            r"/placeholder/{width:\d+}x{height:\d+}.{extension}",
+            placeholder::serve_placeholder_via_http,
+        ))
+        .service(readable("/{filename}/blurhash", analysis::serve_blurhash_via_http))
+        .service(readable("/{filename}/lqip", analysis::serve_lqip_via_http))
+        .service(readable("/{filename}/color", analysis::serve_color_via_http))
+        .service(readable("/{filename}/palette", analysis::serve_palette_via_http))
+        .service(readable("/{filename}/phash", analysis::serve_phash_via_http))
+        .service(readable("/{filename}/srcset", srcset::serve_srcset_via_http))
+        .service(readable("/{filename}/similar", similarity::serve_similar_via_http))
+        .service(readable("/diff", diff::serve_diff_via_http))
+        .service(readable("/card/{template}.{extension}", card::serve_card_via_http))
+        .service(writable("/cache/flush", cache::serve_flush_via_http))
+        .service(readable("/admin/cache/stats", cache::serve_stats_via_http))
+        .service(readable("/admin/storage", inventory::serve_inventory_via_http))
+        .service(readable("/admin/uploads", listing::serve_listing_via_http))
+        .service(readable("/metrics", metrics::serve_metrics_via_http))
+        .service(writable("/admin/cache/warm", warming::serve_warming_via_http))
+        .service(writable("/{filename}/purge", cache::serve_purge_via_http))
+        .service(writable("/{filename}/rename", serve_rename_via_http))
+        .service(writable("/upload/sign", upload_urls::serve_upload_url_via_http))
+        .service(writable("/upload", upload))
+        .service(readable("/healthz", health::serve_health_via_http))
+        .service(readable("/livez", health::serve_liveness_via_http))
+        .service(readable("/readyz", health::serve_readiness_via_http))
+        .service(readable("/version", version::serve_version_via_http));
+}
+
+// What's shared by every request to one site
+#[cfg(feature = "server")]
+#[derive(Clone)]
+struct Site {
+    config: web::Data<ServerConfig>,
+    caches: web::Data<Caches>,
+}
+
+#[cfg(feature = "server")]
+impl Site {
+    fn new(config: ServerConfig, budget: Arc<MemoryBudget>, processing: Arc<ProcessingLimit>) -> Self {
+        let caches = web::Data::new(Caches::new(&config, budget, processing));
+        Self { config: web::Data::new(config), caches }
+    }
+
+    // Every route, beneath the site's base path, for requests to its host
+    fn scope(&self) -> actix_web::Scope {
+        let scope = web::scope(&self.config.base_path)
+            .app_data(self.config.clone())
+            .app_data(self.caches.clone())
+            .configure(configure_routes);
+        let scope = match self.config.iiif {
+            true => scope.configure(iiif::configure_routes),
+            false => scope,
+        };
+        #[cfg(feature = "graphql")]
+        let scope = match self.config.graphql {
+            true => scope.configure(graphql::configure_routes),
+            false => scope,
+        };
+
+        match &self.config.host {
+            Some(host) => scope.guard(guard::Host(host)),
+            None => scope,
+        }
+    }
+
+    fn prepare(&self) -> Result<(), String> {
+        if self.config.origin.is_some() && !cfg!(feature = "proxy") {
+            return Err("An origin was configured, but imogen was built without the `proxy` feature".to_string());
+        }
+        if self.config.graphql && !cfg!(feature = "graphql") {
+            return Err("GraphQL was enabled, but imogen was built without the `graphql` feature".to_string());
+        }
+        transform::check_names(&self.config.transforms)?;
+
+        // Creating uploads directory if non-existent
+        std::fs::create_dir_all(Path::new(&self.config.uploads_dir))
+            .map_err(|e| format!("Unable to create uploads directory {}: {}", self.config.uploads_dir, e))?;
+
+        if let Some(cache_dir) = &self.config.cache_dir {
+            std::fs::create_dir_all(Path::new(cache_dir))
+                .map_err(|e| format!("Unable to create cache directory {}: {}", cache_dir, e))?;
+        }
+        Ok(())
+    }
+
+    // Finishing what requests left running in the background, and then
+    // what the storage backend is still writing
+    async fn finish_background_work(&self, timeout: std::time::Duration) {
+        let started = Instant::now();
+
+        let pending = self.caches.background.pending();
+        if pending > 0 {
+            tracing::info!(pending, uploads_dir = %self.config.uploads_dir, "waiting for background writes to finish");
+        }
+        let unfinished = self.caches.background.finish(timeout).await;
+
+        let storage = self.config.storage.clone();
+        let remaining = timeout.saturating_sub(started.elapsed());
+        let unflushed = web::block(move || Ok::<_, ()>(storage.flush(remaining))).await.unwrap_or(0);
+
+        if unfinished + unflushed > 0 {
+            tracing::warn!(unfinished = unfinished + unflushed, uploads_dir = %self.config.uploads_dir, "exiting with writes unfinished");
+        }
+
+        // Then telling hooks of what happened last
+        let hooks = self.config.hooks.clone();
+        let remaining = timeout.saturating_sub(started.elapsed());
+        let untold = web::block(move || Ok::<_, ()>(hooks.finish(remaining))).await.unwrap_or(0);
+        if untold > 0 {
+            tracing::warn!(untold, uploads_dir = %self.config.uploads_dir, "exiting before hooks were told of every event");
+        }
+    }
+
+    fn spawn_sweepers(&self) {
+        // Cleaning up after deleted uploads in the background
+        actix_web::rt::spawn(cache::sweep_orphaned_variants(self.config.clone()));
+
+        // Counting what's stored, for capacity alerts
+        actix_web::rt::spawn(inventory::sweep_inventory(self.config.clone(), self.caches.clone()));
+
+        // Expiring cached variants in the background
+        if let Some(max_age) = self.config.variant_max_age {
+            actix_web::rt::spawn(cache::sweep_expired_variants(self.config.clone(), self.caches.clone(), max_age));
+        }
+
+        // Forgetting used upload URLs once they've expired
+        if self.config.url_signing_secret.is_some() {
+            actix_web::rt::spawn(upload_urls::sweep_expired_redemptions(self.config.clone()));
+        }
+    }
+}
+
+// What `ImageServer::check_config` finds wrong with one site
+#[cfg(feature = "server")]
+fn site_problems(config: &ServerConfig) -> Vec<String> {
+    let mut problems = vec![];
+
+    let mut dirs = vec![("Uploads", &config.uploads_dir)];
+    if let Some(cache_dir) = &config.cache_dir {
+        dirs.push(("Cache", cache_dir));
+    }
+    for (name, dir) in dirs {
+        // Checking where a missing directory would be created instead
+        let existing = Path::new(dir).ancestors()
+            .find(|ancestor| ancestor.is_dir())
+            .map(|ancestor| ancestor.to_string_lossy().to_string());
+        let checked = match config.storage.check_writable(dir) {
+            Err(e) if e.kind() == IOError::NotFound => match existing {
+                Some(ancestor) => config.storage.check_writable(&ancestor),
+                None => Err(e),
+            },
+            checked => checked,
+        };
+        if let Err(e) = checked {
+            problems.push(format!("{} directory {} is not writable: {}", name, dir, e));
+        }
+    }
+
+    if config.max_upload_size == 0 {
+        problems.push("max_upload_size is 0, so every upload would be refused".to_string());
+    }
+    if config.origin.is_some() && !cfg!(feature = "proxy") {
+        problems.push("An origin was configured, but imogen was built without the `proxy` feature".to_string());
+    }
+    if config.presets_only && config.presets.is_empty() {
+        problems.push("Only presets can be requested, but there aren't any".to_string());
+    }
+
+    if let Err(message) = transform::check_names(&config.transforms) {
+        problems.push(message);
+    }
+
+    for preset in &config.presets {
+        let checked = web::Query::<ProcessingInstructions>::from_query(&preset.query)
+            .map_err(|e| e.to_string())
+            .and_then(|instructions| instructions.into_inner().with_transforms(&preset.query, &config.transforms).map_err(|failure| failure.to_string()))
+            .and_then(|instructions| instructions.check_quality());
+        if let Err(message) = checked {
+            problems.push(format!("The preset named {:?} is misconfigured: {}", preset.name, message));
+        }
+    }
+
+    for template in &config.card_templates {
+        if let Some(field) = template.fields.iter().find(|field| field.name == "image") {
+            problems.push(format!("The card template named {:?} has a field named {:?}, which names its upload", template.name, field.name));
+        }
+        if let Some(image) = &template.image {
+            if config.origin.is_none() && !config.storage.exists(&build_path_to_master(config, image)) {
+                problems.push(format!("The image {:?} of the card template named {:?} hasn't been uploaded", image, template.name));
+            }
+        }
+    }
+
+    if let Some(fallback) = &config.fallback_image {
+        if config.origin.is_none() && !config.storage.exists(&build_path_to_master(config, &fallback.filename)) {
+            problems.push(format!("The fallback image {:?} hasn't been uploaded", fallback.filename));
+        }
+    }
+    problems
+}
+
+// "127.0.0.1", 8080 -> "127.0.0.1:8080"
+// "::1", 8080 -> "[::1]:8080"
+#[cfg(feature = "server")]
+fn socket_address(host: &str, port: u64) -> String {
+    match host.contains(':') && !host.starts_with('[') {
+        true => format!("[{}]:{}", host, port),
+        false => format!("{}:{}", host, port),
+    }
+}
+
+#[cfg(feature = "server")]
+pub struct ImageServer;
+
+#[cfg(feature = "server")]
+impl ImageServer {
+    // Deleting every cached variant in an uploads directory, without
+    // starting a server, returning how many were removed
+    pub fn flush_cache(uploads_dir: String) -> usize {
+        Self::flush_cache_with_config(&ServerConfig::new(&uploads_dir))
+    }
+
+    pub fn flush_cache_with_config(config: &ServerConfig) -> usize {
+        cache::remove_all_variants(config)
+    }
+
+    // Producing variants ahead of time, without starting a server
+    pub fn warm_cache(config: &ServerConfig, plan: &WarmingPlan) -> WarmingResult {
+        warming::warm_cache(config, plan, &MemoryBudget::unlimited())
+    }
+
+    // Deleting the cached renditions and analyses of one upload, like
+    // POST /{filename}/purge, returning how many were removed
+    pub fn purge(config: &ServerConfig, filename: &str) -> Result<usize, String> {
+        if !config.storage.exists(&build_path_to_master(config, filename)) {
+            return Err(ImageServiceFailure::ImageDoesNotExist.to_string());
+        }
+        Ok(cache::remove_variants_of(config, filename))
+    }
+
+    // Saving an image as a new upload, like POST /upload, without
+    // starting a server. "cat.png" is saved as "cat"
+    pub fn import(config: &ServerConfig, filename: &str, contents: Vec<u8>) -> Result<(), String> {
+        let filename = strip_extension(filename);
+        if filename.is_empty() {
+            return Err("A filename was not provided".to_string());
+        }
+        if config.storage.exists(&build_path_to_master(config, &filename)) {
+            return Err("Another file with this name already exists.".to_string());
+        }
+        if contents.len() > config.max_upload_size {
+            return Err(format!("Uploads are limited to {} bytes", config.max_upload_size));
+        }
+
+        let master = encode_master(contents, &config.into()).map_err(|failure| failure.to_string())?;
+        save_master(config, &filename, &master).map_err(|e| e.to_string())?;
+        listing::update_index(config, &filename);
+        Ok(())
+    }
+
+    // Processing an image with the instructions in `query`, like
+    // "w=200&sampling=lanczos3" or "preset=thumbnail", and encoding it in
+    // the format named by `extension`, without uploading it
+    pub fn process(config: &ServerConfig, contents: Vec<u8>, query: &str, extension: &str) -> Result<Vec<u8>, ImageServiceFailure> {
+        Self::process_renditions(config, contents, &[(query, extension)]).map(|mut processed| processed.remove(0))
+    }
+
+    // Processing several renditions of an image at once, like
+    // [("w=400", "webp"), ("w=800", "jpeg")], decoding it only once
+    pub fn process_renditions(config: &ServerConfig, contents: Vec<u8>, renditions: &[(&str, &str)]) -> Result<Vec<Vec<u8>>, ImageServiceFailure> {
+        let settings = pipeline::Settings::from(config);
+        let mut instructions = vec![];
+        for (query, extension) in renditions {
+            instructions.push((pipeline::Instructions::from_query(query, &settings)?, *extension));
+        }
+
+        let processed = pipeline::process_many(&contents, &instructions, &settings)?;
+        Ok(processed.into_iter().map(|rendition| rendition.bytes).collect())
+    }
+
+    // Finding what would stop a config from serving as intended, like
+    // directories that can't be written to, unreadable TLS certificates or
+    // misconfigured presets, without starting a server. Directories that
+    // don't exist yet only need to be creatable, since serving creates them
+    pub fn check_config(config: &ServerConfig) -> Vec<String> {
+        let mut problems = site_problems(config);
+        for site in &config.sites {
+            let name = site.host.clone().unwrap_or_else(|| site.base_path.clone());
+            problems.extend(site_problems(site).into_iter().map(|problem| format!("The site {}: {}", name, problem)));
+        }
+
+        match &config.tls {
+            #[cfg(feature = "tls")]
+            Some(tls) => {
+                if let Err(message) = tls::load_rustls_config(tls) {
+                    problems.push(format!("Failed to configure TLS: {}", message));
+                }
+            }
+            #[cfg(not(feature = "tls"))]
+            Some(_) => problems.push("TLS was configured, but imogen was built without the `tls` feature".to_string()),
+            None => (),
+        }
+        if config.unix_socket.is_some() && config.tls.is_some() {
+            problems.push("TLS cannot be used with a unix domain socket".to_string());
+        }
+        if config.grpc_address.is_some() && !cfg!(feature = "grpc") {
+            problems.push("gRPC was configured, but imogen was built without the `grpc` feature".to_string());
+        }
+        problems
+    }
+
+    // Building the metadata index again from storage, like `imogen reindex`,
+    // returning how many uploads it lists
+    pub fn reindex(config: &ServerConfig) -> Result<usize, String> {
+        #[cfg(feature = "sqlite")]
+        if let Some(index) = &config.metadata_index {
+            return index.rebuild(config);
+        }
+        #[cfg(not(feature = "sqlite"))]
+        let _ = config;
+        Err("There's no metadata index to build. Try metadata_index = \"path\", or IMOGEN_METADATA_INDEX".to_string())
+    }
+
+    // Hashing every content addressed master again, returning which
+    // uploads were damaged, and removing masters no upload refers to
+    pub fn verify_masters(config: &ServerConfig) -> IntegrityReport {
+        content_store::verify(config)
+    }
+
+    // Copying every upload from one storage backend (or layout) to
+    // another, without starting a server
+    pub fn migrate_storage<F>(from: &ServerConfig, to: &ServerConfig, derive_variants: bool, progress: F) -> MigrationResult
+    where
+        F: FnMut(usize, usize, &str),
+    {
+        migration::migrate(from, to, derive_variants, progress)
+    }
+
+    // Gathering how to serve before anything is bound, like
+    // `ImageServer::builder().port(8080).uploads_dir("./images").build()?.run()`
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+}
+
+// How a server will listen, and what it'll serve. Serving `./images` on
+// port 8080 by default
+#[cfg(feature = "server")]
+pub struct ServerBuilder {
+    port: u64,
+    config: ServerConfig,
+}
+
+#[cfg(feature = "server")]
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "server")]
+impl ServerBuilder {
+    pub fn new() -> Self {
+        Self { port: 8080, config: ServerConfig::new("./images") }
+    }
+
+    pub fn port(mut self, port: u64) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub fn uploads_dir(mut self, uploads_dir: &str) -> Self {
+        self.config.uploads_dir = uploads_dir.to_string();
+        self
+    }
+
+    pub fn max_upload_bytes(mut self, bytes: usize) -> Self {
+        self.config = self.config.with_max_upload_size(bytes);
+        self
+    }
+
+    // Serving with every setting of `config`, in place of the uploads
+    // directory and upload limit chosen so far
+    pub fn config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    // Checking the settings, and preparing what serving needs, like the
+    // uploads directory, the access log and TLS certificates
+    pub fn build(self) -> Result<PreparedServer, String> {
+        let ServerBuilder { port, config } = self;
+        let access_log = match &config.access_log {
+            Some(access_log) => Some(AccessLog::open(access_log).map_err(|e| format!("Failed to open the access log: {}", e))?),
+            None => None,
+        };
+
+        if config.unix_socket.is_some() && config.tls.is_some() {
+            return Err("TLS cannot be used with a unix domain socket".to_string());
+        }
+        if config.unix_socket.is_some() && !cfg!(unix) {
+            return Err("Unix domain sockets are not supported on this platform".to_string());
+        }
+        if config.grpc_address.is_some() && !cfg!(feature = "grpc") {
+            return Err("gRPC was configured, but imogen was built without the `grpc` feature".to_string());
+        }
+
+        #[cfg(feature = "tls")]
+        let tls = match &config.tls {
+            Some(tls) => Some(tls::load_rustls_config(tls).map_err(|message| format!("Failed to configure TLS: {}", message))?),
+            None => None,
+        };
+        #[cfg(not(feature = "tls"))]
+        let tls = match &config.tls {
+            Some(_) => return Err("TLS was configured, but imogen was built without the `tls` feature".to_string()),
+            None => None,
+        };
+
+        // Sizing the pool behind `web::block` before it's first used, which
+        // is before any runtime starts when serving with `run`
+        if let Some(threads) = config.blocking_threads {
+            std::env::set_var("ACTIX_THREADPOOL", threads.to_string());
+        }
+
+        let bind_addresses = match config.bind_addresses.is_empty() {
+            true => vec!["0.0.0.0".to_string()],
+            false => config.bind_addresses.clone(),
+        };
+        let (handle, stop) = futures::channel::oneshot::channel();
+        Ok(PreparedServer {
+            port,
+            bind_addresses,
+            unix_socket: config.unix_socket.clone(),
+            #[cfg(feature = "grpc")]
+            grpc_address: config.grpc_address.clone(),
+            tls,
+            shutdown_timeout: config.shutdown_timeout,
+            workers: config.workers,
+            access_log,
+            sites: prepare_sites(config)?,
+            stop,
+            handle: ServerHandle { stop: Arc::new(std::sync::Mutex::new(Some(handle))) },
+        })
+    }
+}
+
+// Every site, ending with this config's own, which serves whatever the
+// others don't. They share one memory budget and processing limit, as
+// they share the blocking threadpool
+#[cfg(feature = "server")]
+fn prepare_sites(mut config: ServerConfig) -> Result<Vec<Site>, String> {
+    if let Some(site) = config.sites.iter().find(|site| site.host.is_none() && site.base_path.is_empty()) {
+        return Err(format!("The site serving {} needs a host or a base path, to tell its requests apart", site.uploads_dir));
+    }
+
+    let budget = Arc::new(MemoryBudget::new(config.memory_budget, config.memory_budget_wait));
+    let processing = Arc::new(ProcessingLimit::new(config.processing_limit, config.processing_queue, config.processing_queue_wait));
+    let mut sites: Vec<Site> = std::mem::take(&mut config.sites)
+        .into_iter()
+        .map(|site| Site::new(site, budget.clone(), processing.clone()))
+        .collect();
+
+    sites.push(Site::new(config, budget, processing));
+    for site in &sites {
+        site.prepare()?;
+    }
+    Ok(sites)
+}
+
+// TLS settings once their certificates have been read. Builds without
+// the `tls` feature refuse them before getting this far
+#[cfg(feature = "tls")]
+type LoadedTls = rustls::ServerConfig;
+#[cfg(all(feature = "server", not(feature = "tls")))]
+type LoadedTls = TlsConfig;
+
+// "TLS cannot be used with a unix domain socket"
+// A setting found to be unusable only once listening begins
+#[cfg(feature = "server")]
+fn unusable(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+}
+
+// A server whose settings have been checked, ready to listen
+#[cfg(feature = "server")]
+pub struct PreparedServer {
+    port: u64,
+    bind_addresses: Vec<String>,
+    unix_socket: Option<String>,
+    #[cfg(feature = "grpc")]
+    grpc_address: Option<String>,
+    tls: Option<LoadedTls>,
+    shutdown_timeout: std::time::Duration,
+    workers: Option<usize>,
+    access_log: Option<AccessLog>,
+    sites: Vec<Site>,
+    stop: futures::channel::oneshot::Receiver<()>,
+    handle: ServerHandle,
+}
+
+// Stopping a server from elsewhere in the application serving it. It
+// stops as it does on SIGTERM, finishing requests in flight first
+#[cfg(feature = "server")]
+#[derive(Clone)]
+pub struct ServerHandle {
+    stop: Arc<std::sync::Mutex<Option<futures::channel::oneshot::Sender<()>>>>,
+}
+
+#[cfg(feature = "server")]
+impl ServerHandle {
+    pub fn stop(&self) {
+        if let Some(stop) = self.stop.lock().unwrap().take() {
+            let _ = stop.send(());
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl PreparedServer {
+    // For stopping the server once it's serving
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
+    // Serving until SIGTERM or SIGINT, and then finishing what's left, in
+    // a runtime of its own. Failing to bind is returned rather than
+    // panicking
+    pub fn run(self) -> Result<(), String> {
+        actix_web::rt::System::new("server").block_on(self.serve())
+    }
+
+    // Serving within the caller's actix runtime, like from a function run
+    // by `#[actix_web::main]`, until SIGTERM, SIGINT or `ServerHandle::stop`
+    pub async fn serve(self) -> Result<(), String> {
+        #[cfg(feature = "grpc")]
+        let grpc_address = self.grpc_address.clone();
+        let PreparedServer { port, bind_addresses, unix_socket, tls, shutdown_timeout, workers, access_log, sites, stop, .. } = self;
+        let sweepers = sites.clone();
+
+        let readiness = web::Data::new(Readiness::new());
+        let startup = readiness.clone();
+        startup.begin("bind");
+
+        {
+            let server = HttpServer::new(move || {
+                let access_log = access_log.clone();
+                let app = App::new()
+                    .app_data(readiness.clone())
+                    .wrap(Cors::permissive())
+                    .wrap_fn(move |req, srv| {
+                        let access_log = access_log.clone();
+                        let received = std::time::SystemTime::now();
+                        let started = Instant::now();
+
+                        let request_id = request_id::assign(&req);
+                        let span = tracing::info_span!(
+                            "request",
+                            request_id = %request_id,
+                            method = %req.method(),
+                            path = req.path(),
+                            traceparent = req.headers().get("traceparent").and_then(|value| value.to_str().ok()),
+                        );
+                        let response = span.in_scope(|| srv.call(req)).instrument(span);
+                        async move {
+                            let mut response = response.await?;
+                            request_id::attach(&request_id, &mut response);
+                            if let Some(access_log) = &access_log {
+                                access_log.record(&response, received, started.elapsed());
+                            }
+                            Ok(response)
+                        }
+                    });
+                sites.iter().fold(app, |app, site| app.service(site.scope()))
+            });
+
+            let addresses: Vec<String> = bind_addresses
+                .iter()
+                .map(|host| socket_address(host, port))
+                .collect();
+
+            // Listening on the sockets systemd passed instead, when
+            // started by a .socket unit
+            let activated = systemd::listeners();
+            let from_systemd = !activated.is_empty();
+
+            let server = match workers {
+                Some(workers) => server.workers(workers),
+                None => server,
+            };
+
+            let server = match from_systemd {
+                true => activated.into_iter().try_fold(server, |server, listener| match (listener, &tls) {
+                    (systemd::Listener::Tcp(listener), None) => server.listen(listener),
+                    #[cfg(feature = "tls")]
+                    (systemd::Listener::Tcp(listener), Some(rustls_config)) => server.listen_rustls(listener, rustls_config.clone()),
+                    #[cfg(not(feature = "tls"))]
+                    (systemd::Listener::Tcp(_), Some(_)) => Err(unusable("TLS was configured, but imogen was built without the `tls` feature")),
+                    #[cfg(unix)]
+                    (systemd::Listener::Unix(listener), None) => server.listen_uds(listener),
+                    #[cfg(unix)]
+                    (systemd::Listener::Unix(_), Some(_)) => Err(unusable("TLS cannot be used with a unix domain socket")),
+                }),
+                false => match (&unix_socket, &tls) {
+                    #[cfg(unix)]
+                    (Some(path), None) => {
+                        // Removing any socket left behind by a previous run
+                        let _ = std::fs::remove_file(path);
+                        server.bind_uds(path)
+                    }
+                    #[cfg(not(unix))]
+                    (Some(_), None) => Err(unusable("Unix domain sockets are not supported on this platform")),
+                    (Some(_), Some(_)) => Err(unusable("TLS cannot be used with a unix domain socket")),
+                    (None, None) => {
+                        addresses.iter().try_fold(server, |server, address| server.bind(address))
+                    }
+                    #[cfg(feature = "tls")]
+                    (None, Some(rustls_config)) => {
+                        addresses.iter().try_fold(server, |server, address| {
+                            server.bind_rustls(address, rustls_config.clone())
+                        })
+                    }
+                    #[cfg(not(feature = "tls"))]
+                    (None, Some(_)) => Err(unusable("TLS was configured, but imogen was built without the `tls` feature")),
+                },
+            };
+
+            let description = match (from_systemd, &unix_socket) {
+                (true, _) => "the sockets passed by systemd".to_string(),
+                (false, Some(path)) => format!("socket {}", path),
+                (false, None) => addresses.join(", "),
+            };
+
+            let running = server
+                .map_err(|e| format!("Failed to bind to {}: {}", description, e))?
+                .shutdown_timeout(shutdown_timeout.as_secs())
+                .run();
+
+            // Serving gRPC beside HTTP, with the caches of the server's own
+            // site, which is the last
+            #[cfg(feature = "grpc")]
+            let grpc = match (&grpc_address, sweepers.last()) {
+                (Some(address), Some(site)) => Some(grpc::start(address, site.config.clone(), site.caches.clone())?),
+                _ => None,
+            };
+
+            startup.finish("bind");
+            systemd::notify("READY=1");
+
+            // Stopping when told to by a handle, but not when every handle
+            // is dropped
+            let stopping = running.clone();
+            actix_web::rt::spawn(async move {
+                if stop.await.is_ok() {
+                    stopping.stop(true).await;
+                }
+            });
+
+            for site in &sweepers {
+                site.spawn_sweepers();
+            }
+
+            // Stopping on SIGTERM or SIGINT only once requests in flight
+            // have finished, and then finishing what they left behind
+            let stopped = running.await;
+            systemd::notify("STOPPING=1");
+            #[cfg(feature = "grpc")]
+            if let Some(grpc) = grpc {
+                grpc.stop();
+            }
+            for site in &sweepers {
+                site.finish_background_work(shutdown_timeout).await;
+            }
+            #[cfg(feature = "otlp")]
+            otlp::flush(shutdown_timeout);
+            stopped.map_err(|e| format!("The server stopped unexpectedly: {}", e))
+        }
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use actix_web::http::StatusCode;
+    use image::{DynamicImage, RgbImage};
+
+    use crate::{encode_image, sign_url, AccessPolicy, ApiKey, CardTemplate, JwtConfig, LocalStorage, OpenedFile, ServerConfig, Storage, StoredFile};
+    use crate::test::{upload_request, TestServer};
+
+
+    // Local storage that counts how often it's asked anything
+    struct CountingStorage {
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl CountingStorage {
+        fn count(&self) {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    impl Storage for CountingStorage {
+        fn get(&self, path: &str) -> std::io::Result<Vec<u8>> {
+            self.count();
+            LocalStorage.get(path)
+        }
+
+        fn put(&self, path: &str, contents: &[u8]) -> std::io::Result<()> {
+            self.count();
+            LocalStorage.put(path, contents)
+        }
+
+        fn delete(&self, path: &str) -> std::io::Result<()> {
+            self.count();
+            LocalStorage.delete(path)
+        }
+
+        fn list(&self, dir: &str) -> Vec<String> {
+            self.count();
+            LocalStorage.list(dir)
+        }
+
+        fn metadata(&self, path: &str) -> Option<StoredFile> {
+            self.count();
+            LocalStorage.metadata(path)
+        }
+
+        fn open(&self, path: &str) -> Option<OpenedFile> {
+            self.count();
+            LocalStorage.open(path)
+        }
+
+        fn streams_incrementally(&self) -> bool {
+            true
+        }
+    }
+
+    // A server whose storage calls are counted by the returned counter
+    fn counted_server<F: FnOnce(ServerConfig) -> ServerConfig>(configure: F) -> (TestServer, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let storage = CountingStorage { calls: calls.clone() };
+        let server = TestServer::with_config(|config| configure(config.with_storage(storage))).unwrap();
+        (server, calls)
+    }
+
+    fn png() -> Vec<u8> {
+        encode_image(&DynamicImage::ImageRgb8(RgbImage::new(32, 32)), "png").unwrap()
+    }
+
+    // A token for `sub` signed with `secret`, expiring `expires_in` seconds
+    // from now, or that long ago if negative
+    fn token(secret: &str, sub: &str, expires_in: i64) -> String {
+        let now = std::time::UNIX_EPOCH.elapsed().unwrap().as_secs() as i64;
+        let claims = serde_json::json!({ "sub": sub, "exp": now + expires_in });
+        let key = jsonwebtoken::EncodingKey::from_secret(secret.as_bytes());
+        jsonwebtoken::encode(&jsonwebtoken::Header::default(), &claims, &key).unwrap()
+    }
+
+    // The path of an upload URL for `filename`, signed with an API key
+    async fn upload_url(server: &TestServer, filename: &str, max_bytes: usize) -> String {
+        let uri = format!("/upload/sign?filename={}&max_bytes={}", filename, max_bytes);
+        let signing = actix_web::test::TestRequest::post().uri(&uri).header("x-api-key", "key");
+        let url = server.send(signing).await.json().unwrap()["url"].as_str().unwrap().to_string();
+        let host = url.find("://").unwrap() + 3;
+        url[host + url[host..].find('/').unwrap()..].to_string()
+    }
+
+    fn run<F: std::future::Future + 'static>(future: F) -> F::Output {
+        actix_web::rt::System::new("test").block_on(future)
+    }
+
+    #[test]
+    fn negative_cache_hit_answers_without_storage() {
+        run(async {
+            let (server, calls) = counted_server(|config| config.with_negative_cache(std::time::Duration::from_secs(60)));
+
+            assert_eq!(server.get("/missing.png?w=10").await.status, StatusCode::NOT_FOUND);
+
+            calls.store(0, Ordering::SeqCst);
+            assert_eq!(server.get("/missing.png?w=10").await.status, StatusCode::NOT_FOUND);
+            assert_eq!(calls.load(Ordering::SeqCst), 0);
+        });
+    }
+
+    #[test]
+    fn hot_cache_hit_answers_without_storage() {
+        run(async {
+            let (server, calls) = counted_server(|config| config.with_hot_cache(16));
+            assert_eq!(server.upload("cat.png", &png()).await.status, StatusCode::OK);
+
+            assert_eq!(server.get("/cat.png?w=10").await.header("x-cache"), Some("MISS"));
+            server.finish().await;
+
+            calls.store(0, Ordering::SeqCst);
+            let response = server.get("/cat.png?w=10").await;
+            assert_eq!(response.status, StatusCode::OK);
+            assert_eq!(response.header("x-cache"), Some("HIT"));
+            assert_eq!(calls.load(Ordering::SeqCst), 0);
+        });
+    }
+
+    #[test]
+    fn oversized_variants_are_refused() {
+        run(async {
+            let server = TestServer::with_config(|config| config.with_max_pixels(1_000_000)).unwrap();
+            server.upload("cat.png", &png()).await;
+
+            let response = server.get("/cat.png?w=100000&h=100000&stretch=true").await;
+            assert_eq!(response.status, StatusCode::PAYLOAD_TOO_LARGE);
+        });
+    }
+
+    #[test]
+    fn stale_master_invalidates_only_its_own_variants() {
+        run(async {
+            let server = TestServer::new().unwrap();
+            server.upload("cat.png", &png()).await;
+            server.upload("dog.png", &png()).await;
+            server.get("/cat.png?w=10").await;
+            server.get("/dog.png?w=10").await;
+            server.finish().await;
+
+            // Replacing the master behind the server's back, as another
+            // replica would
+            let later = std::time::SystemTime::now() + std::time::Duration::from_secs(3600);
+            let master = std::fs::File::options().write(true).open(server.dir().join("uploads/cat.webp")).unwrap();
+            master.set_modified(later).unwrap();
+
+            assert_eq!(server.get("/cat.png?w=10").await.header("x-cache"), Some("MISS"));
+            assert_eq!(server.get("/dog.png?w=10").await.header("x-cache"), Some("HIT"));
+        });
+    }
+
+    #[test]
+    fn uploads_need_a_configured_api_key() {
+        run(async {
+            let server = TestServer::with_config(|config| config.with_api_key(ApiKey::new("key", "ci"))).unwrap();
+
+            assert_eq!(server.upload("cat.png", &png()).await.status, StatusCode::UNAUTHORIZED);
+            let wrong = upload_request("cat.png", &png()).header("x-api-key", "wrong");
+            assert_eq!(server.send(wrong).await.status, StatusCode::UNAUTHORIZED);
+
+            let response = server.send(upload_request("cat.png", &png()).header("x-api-key", "key")).await;
+            assert_eq!(response.status, StatusCode::OK);
+            assert_eq!(response.json().unwrap()[0]["errors"], serde_json::json!([]));
+
+            let bearer = upload_request("dog.png", &png()).header("authorization", "Bearer key");
+            assert_eq!(server.send(bearer).await.json().unwrap()[0]["errors"], serde_json::json!([]));
+        });
+    }
+
+    #[test]
+    fn tokens_are_validated() {
+        run(async {
+            let server = TestServer::with_config(|config| config.with_jwt(JwtConfig::hs256("secret"))).unwrap();
+            let upload = |filename: &str, token: String| {
+                upload_request(filename, &png()).header("authorization", format!("Bearer {}", token))
+            };
+
+            let response = server.send(upload("cat.png", token("secret", "ci", 600))).await;
+            assert_eq!(response.status, StatusCode::OK);
+            assert_eq!(response.json().unwrap()[0]["errors"], serde_json::json!([]));
+
+            let expired = server.send(upload("dog.png", token("secret", "ci", -600))).await;
+            assert_eq!(expired.status, StatusCode::UNAUTHORIZED);
+            let forged = server.send(upload("dog.png", token("another secret", "ci", 600))).await;
+            assert_eq!(forged.status, StatusCode::UNAUTHORIZED);
+        });
+    }
+
+    #[test]
+    fn protected_uploads_are_protected_on_every_route() {
+        run(async {
+            let server = TestServer::with_config(|config| {
+                config
+                    .with_api_key(ApiKey::new("key", "ci"))
+                    .with_read_policy("/cat.png", AccessPolicy::Token)
+                    .with_card_template(CardTemplate::new("social"))
+                    .with_iiif()
+            }).unwrap();
+            for filename in ["cat.png", "dog.png"] {
+                server.send(upload_request(filename, &png()).header("x-api-key", "key")).await;
+            }
+
+            let routes = [
+                "/cat.png",
+                "/cat.webp?w=10",
+                "/iiif/cat/info.json",
+                "/iiif/cat/full/max/0/default.jpg",
+                "/cat.dzi",
+                "/cat_files/0/0_0.jpeg",
+                "/cat/blurhash",
+                "/cat/lqip",
+                "/cat/color",
+                "/cat/palette",
+                "/cat/phash",
+                "/cat/srcset?widths=10,20",
+                "/cat/similar",
+                "/card/social.png?image=cat",
+                "/diff?a=dog&b=cat",
+            ];
+            for route in routes {
+                assert_eq!(server.get(route).await.status, StatusCode::UNAUTHORIZED, "{}", route);
+                let response = server.send(actix_web::test::TestRequest::get().uri(route).header("x-api-key", "key")).await;
+                assert!(response.status.is_success(), "{} answered {}", route, response.status);
+            }
+            assert!(server.get("/dog.png").await.status.is_success());
+        });
+    }
+
+    #[test]
+    fn longest_matching_prefix_decides() {
+        run(async {
+            let server = TestServer::with_config(|config| {
+                config
+                    .with_api_key(ApiKey::new("key", "ci"))
+                    .with_read_policy("/", AccessPolicy::Token)
+                    .with_read_policy("/cat", AccessPolicy::Public)
+                    .with_write_policy("/", AccessPolicy::Token)
+                    .with_write_policy("/guest", AccessPolicy::Public)
+            }).unwrap();
+
+            assert_eq!(server.upload("cat.png", &png()).await.status, StatusCode::UNAUTHORIZED);
+            assert_eq!(server.upload("guest.png", &png()).await.status, StatusCode::OK);
+            server.send(upload_request("cat.png", &png()).header("x-api-key", "key")).await;
+
+            assert_eq!(server.get("/cat.png").await.status, StatusCode::OK);
+            assert_eq!(server.get("/guest.png").await.status, StatusCode::UNAUTHORIZED);
+            // Prefixes match whole segments, not the start of a name
+            server.send(upload_request("category.png", &png()).header("x-api-key", "key")).await;
+            assert_eq!(server.get("/category.png").await.status, StatusCode::UNAUTHORIZED);
+        });
+    }
+
+    #[test]
+    fn signed_urls_expire_and_cover_their_query() {
+        run(async {
+            let server = TestServer::with_config(|config| {
+                config
+                    .with_url_signing_secret("secret")
+                    .with_read_policy("/cat", AccessPolicy::SignedUrl)
+            }).unwrap();
+            server.upload("cat.png", &png()).await;
+            let now = std::time::UNIX_EPOCH.elapsed().unwrap().as_secs();
+
+            assert_eq!(server.get("/cat.png?w=10").await.status, StatusCode::FORBIDDEN);
+            let signed = sign_url("/cat.png?w=10", "secret", now + 600);
+            assert_eq!(server.get(&signed).await.status, StatusCode::OK);
+
+            let tampered = signed.replace("w=10", "w=20");
+            assert_eq!(server.get(&tampered).await.status, StatusCode::FORBIDDEN);
+            let forged = sign_url("/cat.png?w=10", "another secret", now + 600);
+            assert_eq!(server.get(&forged).await.status, StatusCode::FORBIDDEN);
+            let expired = sign_url("/cat.png?w=10", "secret", now - 1);
+            let response = server.get(&expired).await;
+            assert_eq!(response.status, StatusCode::FORBIDDEN);
+            assert_eq!(response.body, b"The URL signature has expired");
+        });
+    }
+
+    #[test]
+    fn upload_urls_can_be_used_once() {
+        run(async {
+            let server = TestServer::with_config(|config| {
+                config.with_api_key(ApiKey::new("key", "ci")).with_url_signing_secret("secret")
+            }).unwrap();
+            let url = upload_url(&server, "cat", 10_000).await;
+
+            let response = server.send(upload_request("anything.png", &png()).uri(&url)).await;
+            assert_eq!(response.json().unwrap()[0]["errors"], serde_json::json!([]));
+            assert_eq!(server.get("/cat.png").await.status, StatusCode::OK);
+
+            let reused = server.send(upload_request("anything.png", &png()).uri(&url)).await;
+            assert_eq!(reused.status, StatusCode::FORBIDDEN);
+        });
+    }
+
+    #[test]
+    fn upload_urls_limit_size_and_survive_failed_uploads() {
+        run(async {
+            let server = TestServer::with_config(|config| {
+                config.with_api_key(ApiKey::new("key", "ci")).with_url_signing_secret("secret")
+            }).unwrap();
+            let url = upload_url(&server, "cat", 1_000).await;
+
+            let oversized = server.send(upload_request("cat.png", &[0; 5_000]).uri(&url)).await;
+            assert_eq!(oversized.status, StatusCode::PAYLOAD_TOO_LARGE);
+            let unreadable = server.send(upload_request("cat.png", b"not an image").uri(&url)).await;
+            assert_ne!(unreadable.json().unwrap()[0]["errors"], serde_json::json!([]));
+
+            // Neither saved anything, so the URL can still be used
+            let response = server.send(upload_request("cat.png", &png()).uri(&url)).await;
+            assert_eq!(response.json().unwrap()[0]["errors"], serde_json::json!([]));
+        });
+    }
+
+    #[test]
+    fn abandoned_writes_are_swept_after_a_grace_period() {
+        run(async {
+            let server = TestServer::new().unwrap();
+            server.upload("cat.png", &png()).await;
+            let uploads = server.dir().join("uploads");
+            for name in ["cat.webp.abandoned.tmp", "cat.webp.writing.tmp"] {
+                std::fs::write(uploads.join(name), b"partial").unwrap();
+            }
+            let earlier = std::time::SystemTime::now() - std::time::Duration::from_secs(2 * 60 * 60);
+            let abandoned = std::fs::File::options().write(true).open(uploads.join("cat.webp.abandoned.tmp")).unwrap();
+            abandoned.set_modified(earlier).unwrap();
+
+            let config = ServerConfig::new(&uploads.to_string_lossy());
+            assert_eq!(crate::cache::remove_abandoned_writes(&config), 1);
+            assert!(!uploads.join("cat.webp.abandoned.tmp").exists());
+            assert!(uploads.join("cat.webp.writing.tmp").exists());
+            assert!(uploads.join("cat.webp").exists());
+        });
+    }
+
+    #[test]
+    fn placeholders_are_cached_by_what_they_draw() {
+        run(async {
+            let server = TestServer::new().unwrap();
+            for query in ["bg=ccc", "bg=cccccc&fg=666", "fg=666666ff"] {
+                assert_eq!(server.get(&format!("/placeholder/20x10.png?{}", query)).await.status, StatusCode::OK);
+            }
+            server.finish().await;
+            let config = ServerConfig::new(&server.dir().join("uploads").to_string_lossy());
+            assert_eq!(crate::cache::cached_variants(&config).len(), 1);
+
+            let unknown = server.get("/placeholder/20x10.png?bg=ccc&cachebuster=1").await;
+            assert_eq!(unknown.status, StatusCode::BAD_REQUEST);
+            assert_eq!(unknown.json().unwrap()["error"]["parameter"], "cachebuster");
+            let unreadable = server.get("/placeholder/20x10.png?bg=ccc,ddd,eee").await;
+            assert_eq!(unreadable.json().unwrap()["error"]["parameter"], "bg");
+        });
+    }
+
+    #[test]
+    fn blurhash_components_are_checked() {
+        run(async {
+            let server = TestServer::new().unwrap();
+            server.upload("cat.png", &png()).await;
+
+            for (query, parameter) in [("x=4&y=10", "y"), ("x=0", "x")] {
+                let response = server.get(&format!("/cat/blurhash?{}", query)).await;
+                assert_eq!(response.status, StatusCode::BAD_REQUEST);
+                assert_eq!(response.json().unwrap()["error"]["parameter"], parameter, "{}", query);
+            }
+        });
+    }
+
+    #[test]
+    fn palette_populations_are_percentages() {
+        run(async {
+            let server = TestServer::new().unwrap();
+            server.upload("cat.png", &png()).await;
+
+            let palette = server.get("/cat/palette?count=2").await.json().unwrap();
+            let total: f64 = palette["palette"].as_array().unwrap().iter()
+                .map(|color| color["population"].as_f64().unwrap())
+                .sum();
+            assert!((total - 100.0).abs() < 0.01, "{}", total);
+
+            let refused = server.get("/cat/palette?count=17").await;
+            assert_eq!(refused.json().unwrap()["error"]["parameter"], "count");
+        });
+    }
+}
@@ -0,0 +1,179 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+
+use crate::{analysis, auth, build_path_to_master, master_dimensions, metadata, ServerConfig};
+use crate::warming::list_masters;
+
+
+// Listing and searching uploads, from the metadata index when there is
+// one, and by going through every master otherwise
+
+// How many uploads are listed at once
+pub const MAX_PAGE: usize = 1000;
+pub const DEFAULT_PAGE: usize = 100;
+
+// What's known of an upload, as the metadata index keeps it
+#[derive(Serialize, Clone, Debug)]
+pub struct ListedUpload {
+    pub filename: String,
+    // The SHA-256 of its master, in hex
+    pub hash: String,
+    pub width: u32,
+    pub height: u32,
+    pub bytes: u64,
+    // The format its master is kept in, like "webp"
+    pub format: String,
+    pub tags: Vec<String>,
+    // Seconds since the unix epoch, and the name of the API key, for
+    // uploads made with one
+    pub uploaded_at: Option<u64>,
+    pub uploaded_by: Option<String>,
+    // Its difference hash, in hex, for finding uploads that look alike
+    pub dhash: Option<String>,
+}
+
+// Reading everything that's listed of an upload from storage, or none if
+// it doesn't exist
+pub fn describe(config: &ServerConfig, filename: &str) -> Option<ListedUpload> {
+    let master = config.storage.get(&build_path_to_master(config, filename)).ok()?;
+    let (width, height) = master_dimensions(&master, config.max_pixels).unwrap_or_default();
+    let format = image::guess_format(&master)
+        .ok()
+        .and_then(|format| format.extensions_str().first())
+        .unwrap_or(&"")
+        .to_string();
+    let record = metadata::read(config, filename);
+
+    Some(ListedUpload {
+        filename: filename.to_string(),
+        hash: format!("{:x}", Sha256::digest(&master)),
+        width,
+        height,
+        bytes: master.len() as u64,
+        format,
+        tags: record.tags,
+        uploaded_at: record.uploaded_at,
+        uploaded_by: record.uploaded_by.map(|identity| identity.name),
+        dhash: analysis::cached_dhash(config, filename).ok().map(|hash| format!("{:016x}", hash)),
+    })
+}
+
+// Which uploads to list, in order of their names
+#[derive(Deserialize, Default, Debug)]
+pub struct Search {
+    // Only uploads whose names contain this, ignoring case
+    pub q: Option<String>,
+    pub tag: Option<String>,
+    pub uploaded_by: Option<String>,
+    // Only uploads whose names come after this, for the next page
+    pub after: Option<String>,
+    pub limit: Option<usize>,
+}
+
+impl Search {
+    pub fn limit(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_PAGE)
+    }
+
+    fn matches_name(&self, filename: &str) -> bool {
+        self.after.as_ref().is_none_or(|after| filename > after.as_str())
+            && self.q.as_ref().is_none_or(|q| filename.to_lowercase().contains(&q.to_lowercase()))
+    }
+
+    fn needs_metadata(&self) -> bool {
+        self.tag.is_some() || self.uploaded_by.is_some()
+    }
+
+    fn matches_metadata(&self, record: &metadata::Metadata) -> bool {
+        self.tag.as_ref().is_none_or(|tag| record.tags.contains(&tag.trim().to_lowercase()))
+            && self.uploaded_by.as_ref().is_none_or(|name| {
+                record.uploaded_by.as_ref().is_some_and(|identity| &identity.name == name)
+            })
+    }
+
+    // The names of matching uploads, going through every master, and
+    // reading the metadata of those whose names match when it's needed
+    fn scan(&self, config: &ServerConfig) -> Vec<String> {
+        list_masters(config)
+            .into_iter()
+            .filter(|filename| self.matches_name(filename))
+            .filter(|filename| !self.needs_metadata() || self.matches_metadata(&metadata::read(config, filename)))
+            .take(self.limit())
+            .collect()
+    }
+}
+
+// The uploads matching `search`
+pub fn search(config: &ServerConfig, search: &Search) -> Vec<ListedUpload> {
+    #[cfg(feature = "sqlite")]
+    if let Some(index) = &config.metadata_index {
+        match index.search(search) {
+            Ok(uploads) => return uploads,
+            Err(e) => tracing::warn!(error = %e, "failed to search the metadata index, so every master is listed instead"),
+        }
+    }
+
+    search.scan(config).iter().filter_map(|filename| describe(config, filename)).collect()
+}
+
+// The names of the uploads matching `search`, which only reads the
+// metadata of uploads that needs to be, when there's no index
+#[cfg(feature = "graphql")]
+pub fn search_names(config: &ServerConfig, search: &Search) -> Vec<String> {
+    #[cfg(feature = "sqlite")]
+    if let Some(index) = &config.metadata_index {
+        match index.search(search) {
+            Ok(uploads) => return uploads.into_iter().map(|upload| upload.filename).collect(),
+            Err(e) => tracing::warn!(error = %e, "failed to search the metadata index, so every master is listed instead"),
+        }
+    }
+
+    search.scan(config)
+}
+
+// Bringing the metadata index, when there is one, up to date with an
+// upload that was saved, changed, or removed
+pub fn update_index(config: &ServerConfig, filename: &str) {
+    #[cfg(feature = "sqlite")]
+    if let Some(index) = &config.metadata_index {
+        index.update(config, filename);
+    }
+    #[cfg(not(feature = "sqlite"))]
+    let _ = (config, filename);
+}
+
+#[derive(Serialize)]
+struct Listing {
+    uploads: Vec<ListedUpload>,
+    // What to pass as `after` for the next page, when there may be one
+    next: Option<String>,
+}
+
+// Respond to a request to list uploads, like
+// `GET /admin/uploads?q=cat&tag=homepage&after=cat-12&limit=100`
+pub async fn serve_listing_via_http(
+    req: HttpRequest,
+    instructions: web::Query<Search>,
+    config: web::Data<ServerConfig>,
+) -> HttpResponse {
+    if let Err(failure) = auth::authorize(&req, &config, auth::Access::Write) {
+        return failure.as_http_response();
+    }
+
+    let instructions = instructions.into_inner();
+    let limit = instructions.limit();
+    if limit == 0 || limit > MAX_PAGE {
+        return HttpResponse::BadRequest().body(format!("limit must be between 1 and {}", MAX_PAGE));
+    }
+
+    let uploads = match web::block(move || Ok::<_, ()>(search(&config, &instructions))).await {
+        Ok(uploads) => uploads,
+        Err(_) => return HttpResponse::InternalServerError().body("Uploads could not be listed"),
+    };
+    let next = match uploads.len() == limit {
+        true => uploads.last().map(|upload| upload.filename.clone()),
+        false => None,
+    };
+    HttpResponse::Ok().json(Listing { uploads, next })
+}
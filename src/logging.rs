@@ -0,0 +1,123 @@
+use std::io::IsTerminal;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer, Registry};
+
+#[cfg(feature = "otlp")]
+use crate::otlp::{OtlpConfig, OtlpLayer};
+
+
+// How log lines are written: readable ones for people, or JSON objects
+// for log collectors
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Pretty,
+    Json,
+}
+
+impl LogFormat {
+    // "json" -> Some(LogFormat::Json)
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "pretty" => Some(Self::Pretty),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+// The levels are "trace", "debug", "info", "warn", "error" or "off"
+const LOG_LEVELS: [&str; 6] = ["trace", "debug", "info", "warn", "error", "off"];
+
+// Which log lines are written to stderr, and how. Everything at `info`
+// and above is written by default, as readable lines
+#[derive(Clone, Debug)]
+pub struct LoggingConfig {
+    pub level: String,
+    // ("imogen::cache", "debug")
+    pub module_levels: Vec<(String, String)>,
+    pub format: LogFormat,
+    #[cfg(feature = "otlp")]
+    pub otlp: Option<OtlpConfig>,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LoggingConfig {
+    pub fn new() -> Self {
+        Self {
+            level: "info".to_string(),
+            module_levels: vec![],
+            format: LogFormat::Pretty,
+            #[cfg(feature = "otlp")]
+            otlp: None,
+        }
+    }
+
+    pub fn with_level(mut self, level: &str) -> Self {
+        self.level = level.to_lowercase();
+        self
+    }
+
+    // Choosing the level of one module, and those within it, like
+    // "imogen::cache" or "actix_web"
+    pub fn with_module_level(mut self, module: &str, level: &str) -> Self {
+        self.module_levels.push((module.to_string(), level.to_lowercase()));
+        self
+    }
+
+    pub fn with_format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    // Also sending spans, like those of each request and the decoding,
+    // resizing and encoding within it, to an OpenTelemetry collector
+    #[cfg(feature = "otlp")]
+    pub fn with_otlp(mut self, otlp: OtlpConfig) -> Self {
+        self.otlp = Some(otlp);
+        self
+    }
+
+    // "info,imogen::cache=debug"
+    fn directives(&self) -> Result<String, String> {
+        let mut levels = std::iter::once(&self.level).chain(self.module_levels.iter().map(|(_, level)| level));
+        if let Some(level) = levels.find(|level| !LOG_LEVELS.contains(&level.as_str())) {
+            return Err(format!("Unknown log level {:?}. Try one of: {}", level, LOG_LEVELS.join(", ")));
+        }
+
+        let mut directives = vec![self.level.clone()];
+        for (module, level) in &self.module_levels {
+            directives.push(format!("{}={}", module, level));
+        }
+        Ok(directives.join(","))
+    }
+
+    // Writing log lines this way for the rest of the process. Only the
+    // first call has any effect
+    pub fn init(&self) -> Result<(), String> {
+        let directives = self.directives()?;
+        let filter = || EnvFilter::try_new(&directives).map_err(|e| e.to_string());
+
+        let lines = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+        let lines = match self.format {
+            LogFormat::Pretty => lines.with_ansi(std::io::stderr().is_terminal()).boxed(),
+            LogFormat::Json => lines.json().boxed(),
+        };
+        #[cfg_attr(not(feature = "otlp"), allow(unused_mut))]
+        let mut layers: Vec<Box<dyn Layer<Registry> + Send + Sync>> = vec![lines.with_filter(filter()?).boxed()];
+
+        #[cfg(feature = "otlp")]
+        if let Some(otlp) = &self.otlp {
+            layers.push(OtlpLayer::new(otlp).with_filter(filter()?).boxed());
+        }
+
+        // Leaving whichever subscriber was installed first in place
+        let _ = tracing_subscriber::registry().with(layers).try_init();
+        Ok(())
+    }
+}
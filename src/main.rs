@@ -1,14 +1,487 @@
-
-use imogen::ImageServer;
+use clap::{Args, Parser, Subcommand};
+use imogen::{ConfigFile, ImageServer, OriginSection, ServerConfig, WarmingPlan};
 use std::env;
+use std::path::{Path, PathBuf};
+
+#[derive(Parser)]
+#[command(name = "imogen", version, about = "An HTTP image server, and tools for looking after its uploads")]
+struct Cli {
+    #[command(flatten)]
+    settings: Settings,
+
+    // Serving, when no command is given
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+// Settings shared by every command, which take precedence over those in
+// the config file and environment
+#[derive(Args)]
+struct Settings {
+    #[arg(long, global = true, value_name = "PATH", help = "Read settings from this file, instead of ./imogen.toml")]
+    config: Option<String>,
+
+    #[arg(long, global = true, value_name = "DIR", help = "Where uploads are kept [default: ./images]")]
+    uploads_dir: Option<String>,
+
+    #[arg(long, global = true, value_name = "DIR", help = "Where cached variants are kept, instead of the uploads directory")]
+    cache_dir: Option<String>,
+
+    #[arg(long, global = true, value_name = "URL", help = "Fetch images from this origin, instead of accepting uploads")]
+    origin: Option<String>,
+
+    #[arg(long, global = true, help = "Use the sharded layout")]
+    sharded: bool,
+
+    #[arg(long, global = true, help = "Use the content addressed layout")]
+    content_addressed: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Serve uploads over HTTP")]
+    Serve {
+        #[arg(long, short, help = "The port to listen on [default: 8080]")]
+        port: Option<u64>,
+    },
+
+    #[command(about = "Process a local image file like a request would, without uploading it")]
+    Process {
+        #[arg(help = "A png, jpeg or webp image")]
+        input: PathBuf,
+
+        #[arg(help = "Where to write the result, whose extension chooses its format")]
+        output: PathBuf,
+
+        #[arg(long, short, value_name = "QUERY", help = "Processing instructions, like \"w=200&sampling=lanczos3\"")]
+        transform: Option<String>,
+
+        #[arg(long, value_name = "NAME", help = "Apply a configured preset")]
+        preset: Option<String>,
+    },
+
+    #[command(about = "Delete the cached renditions and analyses of uploads, keeping their masters")]
+    Purge {
+        #[arg(required = true)]
+        filenames: Vec<String>,
+    },
+
+    #[command(about = "Save local image files as uploads, named after each file")]
+    Import {
+        #[arg(required = true, help = "Image files, or directories of them")]
+        paths: Vec<PathBuf>,
+    },
+
+    #[command(about = "Delete every cached rendition, placeholder and analysis, keeping all masters")]
+    FlushCache,
+
+    #[command(about = "Produce variants ahead of time")]
+    WarmCache {
+        #[arg(help = "Processing instructions, like \"w=800&sampling=lanczos3\" [default: every preset]")]
+        transformations: Vec<String>,
+
+        #[arg(long, value_delimiter = ',', help = "Formats to produce, like webp,jpeg [default: webp]")]
+        extensions: Vec<String>,
+
+        #[arg(long = "filename", value_name = "FILENAME", help = "Only warm this upload, which may be repeated [default: every upload]")]
+        filenames: Vec<String>,
+    },
+
+    #[command(about = "Copy every upload to another storage backend or layout")]
+    MigrateStorage {
+        #[arg(long, value_name = "DIR", help = "The destination's uploads directory [default: the source's]")]
+        to_dir: Option<String>,
+
+        #[arg(long, help = "Use the sharded layout at the destination")]
+        to_sharded: bool,
+
+        #[arg(long, help = "Produce every cached variant again at the destination")]
+        derive_variants: bool,
+
+        #[cfg(feature = "s3")]
+        #[command(flatten)]
+        to_s3: BucketArgs,
+    },
+
+    #[command(about = "Hash every content addressed master again, to find damaged ones")]
+    VerifyMasters,
+
+    #[command(about = "Build the metadata index again from every upload")]
+    Reindex,
+
+    #[command(about = "Check the settings, and print them as they'd be used")]
+    CheckConfig,
+
+    #[cfg(feature = "s3")]
+    #[command(about = "Mirror the uploads directory to a bucket")]
+    ReconcileReplica {
+        #[command(flatten)]
+        s3: BucketArgs,
+    },
+}
+
+// A bucket, whose credentials are read from AWS_ACCESS_KEY_ID and
+// AWS_SECRET_ACCESS_KEY
+#[cfg(feature = "s3")]
+#[derive(Args)]
+struct BucketArgs {
+    #[arg(long, value_name = "NAME", help = "A bucket, reading credentials from AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY")]
+    s3_bucket: Option<String>,
+
+    #[arg(long, value_name = "REGION", default_value = "us-east-1")]
+    s3_region: String,
+
+    #[arg(long, value_name = "URL", help = "An S3 compatible service to use instead of AWS")]
+    s3_endpoint: Option<String>,
+}
+
+#[cfg(feature = "s3")]
+impl BucketArgs {
+    fn storage(&self) -> Option<imogen::S3Storage> {
+        use imogen::{S3Config, S3Storage};
+
+        let bucket = self.s3_bucket.as_ref()?;
+        let access_key = env::var("AWS_ACCESS_KEY_ID").unwrap_or_else(|_| exit_with_error("AWS_ACCESS_KEY_ID is not set"));
+        let secret_key = env::var("AWS_SECRET_ACCESS_KEY").unwrap_or_else(|_| exit_with_error("AWS_SECRET_ACCESS_KEY is not set"));
+
+        let mut s3 = S3Config::new(bucket, &self.s3_region, &access_key, &secret_key);
+        if let Some(endpoint) = &self.s3_endpoint {
+            s3 = s3.with_endpoint(endpoint);
+        }
+        Some(S3Storage::new(s3))
+    }
+}
+
+fn exit_with_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1)
+}
+
+impl Settings {
+    // The settings in --config (or IMOGEN_CONFIG), or in ./imogen.toml if
+    // there is one, overridden by any IMOGEN_* environment variables
+    fn file(&self) -> ConfigFile {
+        let path = match self.config.clone().or(env::var("IMOGEN_CONFIG").ok()) {
+            Some(path) => Some(path),
+            None if Path::new("imogen.toml").exists() => Some("imogen.toml".to_string()),
+            None => None,
+        };
+
+        let mut file = match path {
+            Some(path) => ConfigFile::load(&path).unwrap_or_else(|message| exit_with_error(&message)),
+            None => ConfigFile::default(),
+        };
+        file.apply_environment(env::vars()).unwrap_or_else(|message| exit_with_error(&message));
+        file
+    }
+
+    // The config file's settings, overridden by any given on the command line
+    fn merged_file(&self) -> ConfigFile {
+        let mut file = self.file();
+        if let Some(dir) = &self.uploads_dir {
+            file.uploads_dir = Some(dir.clone());
+        }
+        if let Some(cache_dir) = &self.cache_dir {
+            file.cache_dir = Some(cache_dir.clone());
+        }
+        if let Some(origin) = &self.origin {
+            file.origin = Some(OriginSection { base_url: origin.clone(), allowed_prefixes: vec![], max_age: None });
+        }
+        file.sharded_layout |= self.sharded;
+        file.content_addressed_layout |= self.content_addressed;
+        file
+    }
+
+    fn server_config(&self) -> ServerConfig {
+        self.merged_file().server_config().unwrap_or_else(|message| exit_with_error(&message))
+    }
+}
+
+fn serve(settings: &Settings, port: Option<u64>) {
+    let port = port.or(settings.file().port).unwrap_or(8080);
+    let served = ImageServer::builder()
+        .port(port)
+        .config(settings.server_config())
+        .build()
+        .and_then(|server| server.run());
+
+    if let Err(message) = served {
+        exit_with_error(&message);
+    }
+}
+
+// "thumb.jpg" -> "jpeg"
+fn output_extension(path: &Path) -> String {
+    match path.extension().and_then(|extension| extension.to_str()).map(|e| e.to_lowercase()).as_deref() {
+        Some("jpg") | Some("jpeg") => "jpeg".to_string(),
+        Some(extension) => extension.to_string(),
+        None => exit_with_error("The output needs an extension, like .png, .jpeg or .webp"),
+    }
+}
+
+// imogen process cat.png thumb.webp --transform "w=200&h=200"
+fn process(settings: &Settings, input: &Path, output: &Path, transform: Option<&str>, preset: Option<&str>) {
+    let contents = std::fs::read(input)
+        .unwrap_or_else(|e| exit_with_error(&format!("Could not read {}: {}", input.display(), e)));
+
+    let query = match (transform, preset) {
+        (Some(transform), Some(name)) => format!("{}&preset={}", transform, name),
+        (None, Some(name)) => format!("preset={}", name),
+        (transform, None) => transform.unwrap_or("").to_string(),
+    };
+
+    let processed = ImageServer::process(&settings.server_config(), contents, &query, &output_extension(output))
+        .unwrap_or_else(|failure| exit_with_error(&failure.to_string()));
+
+    std::fs::write(output, processed)
+        .unwrap_or_else(|e| exit_with_error(&format!("Could not write {}: {}", output.display(), e)));
+}
+
+// imogen purge cat dog
+fn purge(settings: &Settings, filenames: &[String]) {
+    let config = settings.server_config();
+
+    let mut failed = false;
+    for filename in filenames {
+        match ImageServer::purge(&config, filename) {
+            Ok(purged) => println!("{}: removed {} cached files", filename, purged),
+            Err(message) => {
+                eprintln!("{}: {}", filename, message);
+                failed = true;
+            }
+        }
+    }
+
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+// The files to import from `paths`, with each directory standing for
+// the files directly inside it
+fn files_to_import(paths: &[PathBuf]) -> Vec<PathBuf> {
+    let mut files = vec![];
+    for path in paths {
+        match std::fs::read_dir(path) {
+            Ok(entries) => {
+                let mut entries: Vec<PathBuf> = entries
+                    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                    .filter(|path| path.is_file())
+                    .collect();
+                entries.sort();
+                files.extend(entries);
+            }
+            Err(_) => files.push(path.clone()),
+        }
+    }
+    files
+}
+
+// imogen import ./photos cat.png
+fn import(settings: &Settings, paths: &[PathBuf]) {
+    let config = settings.server_config();
+    let files = files_to_import(paths);
+
+    let mut imported = 0;
+    let mut failed = false;
+    for path in &files {
+        let filename = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+        let result = std::fs::read(path)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| ImageServer::import(&config, &filename, contents));
+
+        match result {
+            Ok(_) => imported += 1,
+            Err(message) => {
+                eprintln!("{}: {}", path.display(), message);
+                failed = true;
+            }
+        }
+    }
+    println!("Imported {} of {} files", imported, files.len());
+
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+fn flush_cache(settings: &Settings) {
+    let removed = ImageServer::flush_cache_with_config(&settings.server_config());
+    println!("Removed {} cached files", removed);
+}
+
+// imogen warm-cache w=200 "w=800&sampling=lanczos3" --extensions=webp,jpeg
+fn warm_cache(settings: &Settings, transformations: &[String], extensions: &[String], filenames: &[String]) {
+    let mut plan = WarmingPlan::new();
+    for transformation in transformations {
+        plan = plan.with_transformation(transformation);
+    }
+    for filename in filenames {
+        plan = plan.with_filename(filename);
+    }
+    if !extensions.is_empty() {
+        plan = plan.with_extensions(&extensions.iter().map(|e| e.as_str()).collect::<Vec<&str>>());
+    }
+
+    let result = ImageServer::warm_cache(&settings.server_config(), &plan);
+    println!("Warmed {} variants, skipped {} already cached", result.warmed, result.skipped);
+    for error in &result.errors {
+        eprintln!("{}", error);
+    }
+
+    if !result.errors.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+// Hashing every content addressed master again, to find damaged ones
+fn verify_masters(settings: &Settings) {
+    let config = settings.server_config().with_content_addressed_layout();
+    let report = ImageServer::verify_masters(&config);
+    println!("Verified {} masters, removed {} unreferenced", report.verified, report.removed);
+    for filename in &report.corrupted {
+        eprintln!("{} is damaged or missing", filename);
+    }
+
+    if !report.corrupted.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+// imogen reindex --config=/etc/imogen.toml
+fn reindex(settings: &Settings) {
+    match ImageServer::reindex(&settings.server_config()) {
+        Ok(indexed) => println!("Indexed {} uploads", indexed),
+        Err(message) => exit_with_error(&message),
+    }
+}
+
+// imogen check-config --config=/etc/imogen.toml
+// Printing the settings after the environment and command line have
+// had their say, and then whatever is wrong with them
+fn check_config(settings: &Settings) {
+    let file = settings.merged_file();
+    print!("{}", file.to_redacted_toml());
+
+    let problems = match file.server_config() {
+        Ok(config) => ImageServer::check_config(&config),
+        Err(message) => vec![message],
+    };
+    for problem in &problems {
+        eprintln!("{}", problem);
+    }
+
+    match problems.is_empty() {
+        true => eprintln!("The configuration is valid"),
+        false => std::process::exit(1),
+    }
+}
+
+// Where `migrate-storage` copies to
+struct Destination<'a> {
+    to_dir: Option<&'a str>,
+    to_sharded: bool,
+    #[cfg(feature = "s3")]
+    to_s3: &'a BucketArgs,
+}
+
+// The storage that `migrate-storage` copies to, in a bucket when built
+// with the `s3` feature and given --s3-bucket, or on local disk
+fn migration_destination(uploads_dir: &str, destination: &Destination) -> ServerConfig {
+    let to_dir = destination.to_dir.unwrap_or(uploads_dir);
+    let mut config = ServerConfig::new(to_dir);
+    if destination.to_sharded {
+        config = config.with_sharded_layout();
+    }
+
+    #[cfg(feature = "s3")]
+    if let Some(s3) = destination.to_s3.storage() {
+        return config.with_storage(s3);
+    }
+
+    if to_dir == uploads_dir && !destination.to_sharded {
+        exit_with_error("The destination is the same as the source. Try --to-dir=dir or --to-sharded");
+    }
+    config
+}
+
+// imogen migrate-storage --to-dir=/mnt/images --to-sharded --derive-variants
+// Running it again after an interruption skips whatever was already copied
+fn migrate_storage(settings: &Settings, destination: &Destination, derive_variants: bool) {
+    let source = settings.server_config();
+    let destination = migration_destination(&source.uploads_dir, destination);
+
+    let result = ImageServer::migrate_storage(&source, &destination, derive_variants, |done, total, filename| {
+        println!("[{}/{}] {}", done, total, filename);
+    });
+
+    println!(
+        "Copied {} masters, skipped {} already copied, derived {} variants",
+        result.copied, result.skipped, result.derived,
+    );
+    for error in &result.errors {
+        eprintln!("{}", error);
+    }
+
+    if !result.errors.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+// imogen reconcile-replica --s3-bucket=name --s3-region=region [--s3-endpoint=url]
+// Mirroring a local uploads directory to a bucket
+#[cfg(feature = "s3")]
+fn reconcile_replica(settings: &Settings, s3: &BucketArgs) {
+    use imogen::{LocalStorage, ReplicatedStorage};
+
+    let uploads_dir = settings.server_config().uploads_dir;
+    let replica = s3.storage().unwrap_or_else(|| exit_with_error("--s3-bucket is required"));
+
+    let storage = ReplicatedStorage::new(LocalStorage, replica, &uploads_dir);
+    let result = storage.reconcile();
+    println!("Copied {} files, removed {}", result.copied, result.removed);
+    for path in &result.failed {
+        eprintln!("Failed to reconcile {}", path);
+    }
+
+    if !result.failed.is_empty() {
+        std::process::exit(1);
+    }
+}
 
 fn main() {
-    let port = env::args().nth(1)
-        .unwrap_or("8080".to_string()).parse::<u64>()
-        .expect("Invalid port provided");
+    let cli = Cli::parse();
+    let settings = &cli.settings;
 
-    let uploads_dir = env::args().nth(2)
-        .unwrap_or("./images".to_string());
+    settings.file().logging_config()
+        .and_then(|logging| logging.init())
+        .unwrap_or_else(|message| exit_with_error(&message));
 
-    ImageServer::listen(port, uploads_dir);
+    match &cli.command {
+        None => serve(settings, None),
+        Some(Command::Serve { port }) => serve(settings, *port),
+        Some(Command::Process { input, output, transform, preset }) => {
+            process(settings, input, output, transform.as_deref(), preset.as_deref())
+        }
+        Some(Command::Purge { filenames }) => purge(settings, filenames),
+        Some(Command::Import { paths }) => import(settings, paths),
+        Some(Command::FlushCache) => flush_cache(settings),
+        Some(Command::WarmCache { transformations, extensions, filenames }) => {
+            warm_cache(settings, transformations, extensions, filenames)
+        }
+        Some(Command::MigrateStorage { to_dir, to_sharded, derive_variants, #[cfg(feature = "s3")] to_s3 }) => {
+            let destination = Destination {
+                to_dir: to_dir.as_deref(),
+                to_sharded: *to_sharded,
+                #[cfg(feature = "s3")]
+                to_s3,
+            };
+            migrate_storage(settings, &destination, *derive_variants)
+        }
+        Some(Command::VerifyMasters) => verify_masters(settings),
+        Some(Command::Reindex) => reindex(settings),
+        Some(Command::CheckConfig) => check_config(settings),
+        #[cfg(feature = "s3")]
+        Some(Command::ReconcileReplica { s3 }) => reconcile_replica(settings, s3),
+    }
 }
@@ -0,0 +1,83 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+
+// Limiting how much memory the images being processed at once may take
+// altogether, by estimating it before each is decoded. Images that
+// wouldn't fit wait for others to finish, for up to `wait`, and are
+// refused after that
+pub struct MemoryBudget {
+    // In bytes, or no limit at all
+    limit: Option<usize>,
+    wait: Duration,
+    reserved: Mutex<usize>,
+    released: Condvar,
+    refusals: AtomicU64,
+}
+
+// Memory set aside for one image, which is given back when dropped
+pub struct Reservation<'a> {
+    budget: &'a MemoryBudget,
+    bytes: usize,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if self.bytes == 0 { return; }
+
+        *self.budget.reserved.lock().unwrap() -= self.bytes;
+        self.budget.released.notify_all();
+    }
+}
+
+impl MemoryBudget {
+    pub fn new(limit: Option<usize>, wait: Duration) -> Self {
+        Self {
+            limit,
+            wait,
+            reserved: Mutex::new(0),
+            released: Condvar::new(),
+            refusals: AtomicU64::new(0),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(None, Duration::from_secs(0))
+    }
+
+    // Setting `bytes` aside once they fit, or giving up after waiting as
+    // long as allowed. An image larger than the whole budget is still
+    // processed, but only while nothing else is
+    pub fn reserve(&self, bytes: usize) -> Option<Reservation<'_>> {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return Some(Reservation { budget: self, bytes: 0 }),
+        };
+
+        let deadline = Instant::now() + self.wait;
+        let mut reserved = self.reserved.lock().unwrap();
+        while *reserved > 0 && *reserved + bytes > limit {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                self.refusals.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+            reserved = self.released.wait_timeout(reserved, remaining).unwrap().0;
+        }
+
+        *reserved += bytes;
+        Some(Reservation { budget: self, bytes })
+    }
+
+    #[cfg(feature = "server")]
+    pub fn reserved(&self) -> usize {
+        *self.reserved.lock().unwrap()
+    }
+
+    // How many images have been refused for lack of memory
+    #[cfg(feature = "server")]
+    pub fn refusals(&self) -> u64 {
+        self.refusals.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,68 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Serialize, Deserialize};
+
+use crate::ServerConfig;
+use crate::auth::Identity;
+
+
+#[derive(Serialize)]
+struct UploadRecord<'a> {
+    uploaded_by: &'a Identity,
+    uploaded_at: u64,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    tags: &'a [String],
+}
+
+// What's known of an upload besides its image, which is nothing at all
+// for uploads made without an API key and never tagged
+#[derive(Serialize, Deserialize, Default)]
+pub struct Metadata {
+    #[serde(default)]
+    pub uploaded_by: Option<Identity>,
+    #[serde(default)]
+    pub uploaded_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+// "example" -> "./images/.meta/example.json"
+pub fn build_path_to_metadata_file(config: &ServerConfig, clean_filename: &str) -> String {
+    format!("{}/.meta/{}{}.json", config.uploads_dir, config.shard(clean_filename), clean_filename)
+}
+
+// Persisting who uploaded a file, and when, alongside the upload itself.
+// Replacing an upload keeps its tags
+pub fn record_upload(
+    config: &ServerConfig,
+    clean_filename: &str,
+    uploader: &Identity,
+) -> std::io::Result<()> {
+    let uploaded_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    let tags = read(config, clean_filename).tags;
+    let record = UploadRecord { uploaded_by: uploader, uploaded_at, tags: &tags };
+    let contents = serde_json::to_vec(&record)?;
+
+    config.storage.put(&build_path_to_metadata_file(config, clean_filename), &contents)
+}
+
+// The metadata of an upload, or none if nothing was recorded
+pub fn read(config: &ServerConfig, clean_filename: &str) -> Metadata {
+    config.storage.get(&build_path_to_metadata_file(config, clean_filename))
+        .ok()
+        .and_then(|contents| serde_json::from_slice(&contents).ok())
+        .unwrap_or_default()
+}
+
+// Replacing the tags of an upload, keeping the rest of its metadata
+#[cfg(feature = "graphql")]
+pub fn save_tags(config: &ServerConfig, clean_filename: &str, tags: Vec<String>) -> std::io::Result<()> {
+    let record = Metadata { tags, ..read(config, clean_filename) };
+    let contents = serde_json::to_vec(&record)?;
+    config.storage.put(&build_path_to_metadata_file(config, clean_filename), &contents)?;
+    crate::listing::update_index(config, clean_filename);
+    Ok(())
+}
@@ -0,0 +1,170 @@
+use std::sync::Mutex;
+use rusqlite::{params, Connection, Row};
+
+use crate::ServerConfig;
+use crate::listing::{describe, ListedUpload, Search};
+use crate::warming::list_masters;
+
+
+// What's known of every upload, kept in a small SQLite database beside
+// them, so that listing and searching uploads doesn't go through every
+// master. It's kept up to date as uploads change, and can be built again
+// from storage with `imogen reindex`
+pub struct MetadataIndex {
+    connection: Mutex<Connection>,
+}
+
+impl MetadataIndex {
+    // "/var/lib/imogen/index.sqlite", created if it doesn't exist
+    pub fn open(path: &str) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            CREATE TABLE IF NOT EXISTS uploads (
+                filename TEXT PRIMARY KEY,
+                hash TEXT NOT NULL,
+                width INTEGER NOT NULL,
+                height INTEGER NOT NULL,
+                bytes INTEGER NOT NULL,
+                format TEXT NOT NULL,
+                uploaded_at INTEGER,
+                uploaded_by TEXT,
+                dhash TEXT
+            );
+            CREATE TABLE IF NOT EXISTS tags (
+                filename TEXT NOT NULL,
+                tag TEXT NOT NULL,
+                PRIMARY KEY (filename, tag)
+            );
+            CREATE INDEX IF NOT EXISTS uploads_by_hash ON uploads (hash);
+            CREATE INDEX IF NOT EXISTS tags_by_tag ON tags (tag);",
+        )?;
+
+        // Indexes made before difference hashes were kept, which are
+        // filled in by `imogen reindex`
+        let columns: Vec<String> = connection
+            .prepare("SELECT name FROM pragma_table_info('uploads')")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        if !columns.iter().any(|column| column == "dhash") {
+            connection.execute_batch("ALTER TABLE uploads ADD COLUMN dhash TEXT;")?;
+        }
+
+        Ok(Self { connection: Mutex::new(connection) })
+    }
+
+    // Indexing an upload as it's stored now, or forgetting it if it's gone
+    pub fn update(&self, config: &ServerConfig, filename: &str) {
+        let upload = describe(config, filename);
+        let mut connection = self.connection.lock().unwrap();
+        let updated = connection.transaction().and_then(|transaction| {
+            forget(&transaction, filename)?;
+            if let Some(upload) = &upload {
+                insert(&transaction, upload)?;
+            }
+            transaction.commit()
+        });
+
+        if let Err(e) = updated {
+            tracing::warn!(filename, error = %e, "failed to update the metadata index");
+        }
+    }
+
+    // Indexing every upload again from storage, returning how many there are
+    pub fn rebuild(&self, config: &ServerConfig) -> Result<usize, String> {
+        let uploads: Vec<ListedUpload> = list_masters(config)
+            .iter()
+            .filter_map(|filename| describe(config, filename))
+            .collect();
+
+        let mut connection = self.connection.lock().unwrap();
+        let rebuilt = connection.transaction().and_then(|transaction| {
+            transaction.execute_batch("DELETE FROM uploads; DELETE FROM tags;")?;
+            for upload in &uploads {
+                insert(&transaction, upload)?;
+            }
+            transaction.commit()
+        });
+
+        rebuilt.map(|_| uploads.len()).map_err(|e| format!("The metadata index could not be rebuilt: {}", e))
+    }
+
+    pub fn search(&self, search: &Search) -> rusqlite::Result<Vec<ListedUpload>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT filename, hash, width, height, bytes, format, uploaded_at, uploaded_by, dhash,
+                (SELECT group_concat(tag, char(10)) FROM (SELECT tag FROM tags WHERE tags.filename = uploads.filename ORDER BY tag))
+            FROM uploads
+            WHERE (?1 IS NULL OR filename > ?1)
+                AND (?2 IS NULL OR instr(lower(filename), lower(?2)) > 0)
+                AND (?3 IS NULL OR EXISTS (SELECT 1 FROM tags WHERE tags.filename = uploads.filename AND tag = ?3))
+                AND (?4 IS NULL OR uploaded_by = ?4)
+            ORDER BY filename
+            LIMIT ?5",
+        )?;
+
+        let tag = search.tag.as_ref().map(|tag| tag.trim().to_lowercase());
+        let uploads = statement.query_map(
+            params![search.after, search.q, tag, search.uploaded_by, search.limit() as i64],
+            as_listed_upload,
+        )?;
+        uploads.collect()
+    }
+
+    // The difference hash of every upload that has one
+    pub fn dhashes(&self) -> rusqlite::Result<Vec<(String, u64)>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT filename, dhash FROM uploads WHERE dhash IS NOT NULL")?;
+        let hashes = statement.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+
+        Ok(hashes
+            .filter_map(|hash| hash.ok())
+            .filter_map(|(filename, hash)| u64::from_str_radix(&hash, 16).ok().map(|hash| (filename, hash)))
+            .collect())
+    }
+}
+
+fn forget(connection: &Connection, filename: &str) -> rusqlite::Result<()> {
+    connection.execute("DELETE FROM uploads WHERE filename = ?1", params![filename])?;
+    connection.execute("DELETE FROM tags WHERE filename = ?1", params![filename])?;
+    Ok(())
+}
+
+fn insert(connection: &Connection, upload: &ListedUpload) -> rusqlite::Result<()> {
+    connection.execute(
+        "INSERT INTO uploads (filename, hash, width, height, bytes, format, uploaded_at, uploaded_by, dhash)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![
+            upload.filename,
+            upload.hash,
+            upload.width,
+            upload.height,
+            upload.bytes as i64,
+            upload.format,
+            upload.uploaded_at.map(|seconds| seconds as i64),
+            upload.uploaded_by,
+            upload.dhash,
+        ],
+    )?;
+    for tag in &upload.tags {
+        connection.execute("INSERT OR IGNORE INTO tags (filename, tag) VALUES (?1, ?2)", params![upload.filename, tag])?;
+    }
+    Ok(())
+}
+
+// Tags are read as one string, separated by newlines
+fn as_listed_upload(row: &Row) -> rusqlite::Result<ListedUpload> {
+    let tags: Option<String> = row.get(9)?;
+    Ok(ListedUpload {
+        filename: row.get(0)?,
+        hash: row.get(1)?,
+        width: row.get(2)?,
+        height: row.get(3)?,
+        bytes: row.get::<_, i64>(4)? as u64,
+        format: row.get(5)?,
+        tags: tags.map(|tags| tags.lines().map(|tag| tag.to_string()).collect()).unwrap_or_default(),
+        uploaded_at: row.get::<_, Option<i64>>(6)?.map(|seconds| seconds as u64),
+        uploaded_by: row.get(7)?,
+        dhash: row.get(8)?,
+    })
+}
@@ -0,0 +1,173 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use actix_web::{web, HttpRequest, HttpResponse};
+
+use crate::{ServerConfig, Caches, Processing, auth};
+use crate::cache::Lookup;
+
+
+// "cache;desc=miss, decode;dur=4.1, resize;dur=12.0, encode;dur=8.3, total;dur=25.2"
+// A `Server-Timing` header for a request that took `total`, telling
+// how its variant was found, and how long processing it took if it was
+pub fn server_timing(req: &HttpRequest, total: Duration) -> String {
+    let milliseconds = |duration: Duration| duration.as_secs_f64() * 1000.0;
+    let mut metrics = vec![];
+
+    if let Some(lookup) = req.extensions().get::<Lookup>() {
+        metrics.push(format!("cache;desc={}", lookup.name()));
+    }
+    if let Some(processing) = req.extensions().get::<Processing>() {
+        metrics.push(format!("decode;dur={:.1}", milliseconds(processing.decode)));
+        metrics.push(format!("resize;dur={:.1}", milliseconds(processing.resize)));
+        metrics.push(format!("encode;dur={:.1}", milliseconds(processing.encode)));
+    }
+    metrics.push(format!("total;dur={:.1}", milliseconds(total)));
+    metrics.join(", ")
+}
+
+// The upper bounds of histogram buckets, in seconds and in bytes
+const SECONDS_BUCKETS: [f64; 12] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0];
+const BYTES_BUCKETS: [f64; 10] = [
+    1_000.0, 10_000.0, 50_000.0, 100_000.0, 250_000.0, 500_000.0,
+    1_000_000.0, 2_500_000.0, 10_000_000.0, 50_000_000.0,
+];
+
+// Counting observations into buckets, as Prometheus histograms do. Sums
+// are kept in whole units of `unit`, like nanoseconds or bytes
+struct Histogram {
+    bounds: &'static [f64],
+    unit: f64,
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64], unit: f64) -> Self {
+        Self {
+            bounds,
+            unit,
+            buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    fn seconds() -> Self {
+        Self::new(&SECONDS_BUCKETS, 1e-9)
+    }
+
+    fn bytes() -> Self {
+        Self::new(&BYTES_BUCKETS, 1.0)
+    }
+
+    fn observe(&self, units: u64) {
+        let value = units as f64 * self.unit;
+        if let Some(i) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(units, Ordering::Relaxed);
+    }
+
+    // Buckets are cumulative, each counting everything at or below its bound
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, cumulative);
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum.load(Ordering::Relaxed) as f64 * self.unit;
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
+
+// What's measured about every variant processed, by requests and by
+// revalidating them in the background
+pub struct Metrics {
+    decode_seconds: Histogram,
+    resize_seconds: Histogram,
+    encode_seconds: Histogram,
+    input_bytes: Histogram,
+    output_bytes: Histogram,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            decode_seconds: Histogram::seconds(),
+            resize_seconds: Histogram::seconds(),
+            encode_seconds: Histogram::seconds(),
+            input_bytes: Histogram::bytes(),
+            output_bytes: Histogram::bytes(),
+        }
+    }
+
+    pub fn record(&self, processing: &Processing) {
+        self.decode_seconds.observe(processing.decode.as_nanos() as u64);
+        self.resize_seconds.observe(processing.resize.as_nanos() as u64);
+        self.encode_seconds.observe(processing.encode.as_nanos() as u64);
+        self.input_bytes.observe(processing.input_bytes as u64);
+        self.output_bytes.observe(processing.output_bytes as u64);
+    }
+
+    // The Prometheus text format
+    fn render(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP imogen_processing_seconds How long each stage of processing a variant took\n");
+        out.push_str("# TYPE imogen_processing_seconds histogram\n");
+        self.decode_seconds.write(&mut out, "imogen_processing_seconds", "stage=\"decode\"");
+        self.resize_seconds.write(&mut out, "imogen_processing_seconds", "stage=\"resize\"");
+        self.encode_seconds.write(&mut out, "imogen_processing_seconds", "stage=\"encode\"");
+
+        out.push_str("# HELP imogen_processing_bytes The size of masters read, and of the variants produced from them\n");
+        out.push_str("# TYPE imogen_processing_bytes histogram\n");
+        self.input_bytes.write(&mut out, "imogen_processing_bytes", "direction=\"input\"");
+        self.output_bytes.write(&mut out, "imogen_processing_bytes", "direction=\"output\"");
+
+        out
+    }
+}
+
+// Respond to a scrape of the server's metrics
+pub async fn serve_metrics_via_http(
+    req: HttpRequest,
+    config: web::Data<ServerConfig>,
+    caches: web::Data<Caches>,
+) -> HttpResponse {
+    if let Err(failure) = auth::authorize(&req, &config, auth::Access::Write) {
+        return failure.as_http_response();
+    }
+
+    let mut out = caches.metrics.render();
+    out.push_str("# HELP imogen_cache_lookups_total How requested variants were found: in memory, on disk, not at all, or bypassed on purpose\n");
+    out.push_str("# TYPE imogen_cache_lookups_total counter\n");
+    for (lookup, count) in caches.stats.lookups() {
+        let _ = writeln!(out, "imogen_cache_lookups_total{{result=\"{}\"}} {}", lookup.name(), count);
+    }
+    out.push_str("# HELP imogen_memory_reserved_bytes Memory set aside for images being processed, shared by every site\n");
+    out.push_str("# TYPE imogen_memory_reserved_bytes gauge\n");
+    let _ = writeln!(out, "imogen_memory_reserved_bytes {}", caches.budget.reserved());
+    out.push_str("# HELP imogen_memory_refusals_total Images refused because the memory budget stayed full\n");
+    out.push_str("# TYPE imogen_memory_refusals_total counter\n");
+    let _ = writeln!(out, "imogen_memory_refusals_total {}", caches.budget.refusals());
+    out.push_str("# HELP imogen_processing_jobs Variants being processed, and those queued for a turn, across every site\n");
+    out.push_str("# TYPE imogen_processing_jobs gauge\n");
+    let _ = writeln!(out, "imogen_processing_jobs{{state=\"running\"}} {}", caches.processing.running());
+    let _ = writeln!(out, "imogen_processing_jobs{{state=\"queued\"}} {}", caches.processing.waiting());
+    out.push_str("# HELP imogen_processing_refusals_total Variants refused because the processing queue was full or too slow\n");
+    out.push_str("# TYPE imogen_processing_refusals_total counter\n");
+    let _ = writeln!(out, "imogen_processing_refusals_total {}", caches.processing.refusals());
+    if let Some(inventory) = caches.inventory.get() {
+        inventory.write_metrics(&mut out);
+    }
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(out)
+}
@@ -0,0 +1,101 @@
+use std::collections::BTreeMap;
+use serde::Serialize;
+
+use crate::{ServerConfig, WarmingPlan, build_path_to_master, save_master, cache, card, metadata, warming};
+use crate::memory_budget::MemoryBudget;
+
+
+// What migrating from one storage backend to another did
+#[derive(Serialize, Debug, Default)]
+pub struct MigrationResult {
+    pub copied: usize,
+    // Masters the destination already had, like after an interrupted run
+    pub skipped: usize,
+    pub derived: usize,
+    pub errors: Vec<String>,
+}
+
+// Copying one file, unless the destination already has it in full.
+// Masters are saved wherever the destination's layout keeps them
+fn copy(from: &ServerConfig, to: &ServerConfig, source: &str, destination: &str, master: Option<&str>) -> Result<bool, String> {
+    let size = match from.storage.metadata(source) {
+        Some(metadata) => metadata.size,
+        None => return Ok(false),
+    };
+
+    if to.storage.metadata(destination).is_some_and(|copy| copy.size == size) {
+        return Ok(false);
+    }
+
+    let contents = from.storage.get(source).map_err(|e| e.to_string())?;
+    match master {
+        Some(filename) => save_master(to, filename, &contents),
+        None => to.storage.put(destination, &contents),
+    }.map_err(|e| e.to_string())?;
+    Ok(true)
+}
+
+// Copying every master, along with its upload metadata, from the storage
+// of `from` to the storage of `to`. Their layouts may differ, like when
+// moving to a sharded layout. With `derive_variants`, every variant
+// cached by `from` is produced again for `to`, rather than copied, so
+// that it's saved wherever `to` keeps variants.
+// Masters the destination already has are skipped, so an interrupted
+// migration picks up where it left off when run again. `progress` is
+// told about each master as it's done, and how many there are
+pub fn migrate<F>(from: &ServerConfig, to: &ServerConfig, derive_variants: bool, mut progress: F) -> MigrationResult
+where
+    F: FnMut(usize, usize, &str),
+{
+    let mut result = MigrationResult::default();
+    let masters = warming::list_masters(from);
+
+    for (done, filename) in masters.iter().enumerate() {
+        let master = (build_path_to_master(from, filename), build_path_to_master(to, filename));
+        let copied = copy(from, to, &master.0, &master.1, Some(filename))
+            .and_then(|copied| {
+                let record = (
+                    metadata::build_path_to_metadata_file(from, filename),
+                    metadata::build_path_to_metadata_file(to, filename),
+                );
+                copy(from, to, &record.0, &record.1, None).map(|_| copied)
+            });
+
+        match copied {
+            Ok(true) => result.copied += 1,
+            Ok(false) => result.skipped += 1,
+            Err(message) => result.errors.push(format!("{}: {}", filename, message)),
+        }
+
+        progress(done + 1, masters.len(), filename);
+    }
+
+    if !derive_variants {
+        return result;
+    }
+
+    // The source's variants are only read for what they were derived
+    // from, and warming skips any the destination already has. Grouping
+    // them means each master is only decoded once per format
+    let mut wanted: BTreeMap<(String, String), Vec<String>> = BTreeMap::new();
+    for (filename, params, extension) in cache::recorded_variants(from) {
+        // Cards are rendered again when they're next asked for
+        if card::is_card(&params) {
+            continue;
+        }
+        wanted.entry((filename, extension)).or_default().push(params);
+    }
+
+    for ((filename, extension), transformations) in wanted {
+        let plan = transformations.iter().fold(
+            WarmingPlan::new().with_filename(&filename).with_extensions(&[&extension]),
+            |plan, params| plan.with_transformation(params),
+        );
+
+        let warmed = warming::warm_cache(to, &plan, &MemoryBudget::unlimited());
+        result.derived += warmed.warmed;
+        result.errors.extend(warmed.errors);
+    }
+
+    result
+}
@@ -0,0 +1,55 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use actix_web::dev::{AppService, HttpServiceFactory};
+use actix_web::web;
+
+use crate::{ServerConfig, Site, prepare_sites};
+use crate::health::Readiness;
+
+
+// The routes of every site, for mounting in an application's own `App`
+// alongside its other services. They're cloned for each worker, and
+// share their caches and limits between them, as a server's workers do
+#[derive(Clone)]
+pub struct ImageRoutes {
+    sites: Vec<Site>,
+    readiness: web::Data<Readiness>,
+    sweeping: Arc<AtomicBool>,
+}
+
+// Preparing the routes of `config` as `ImageServer::builder().build()`
+// would, without a server of their own. Settings for listening, like the
+// port, TLS and the access log, are left to the application
+pub fn routes(config: ServerConfig) -> Result<ImageRoutes, String> {
+    Ok(ImageRoutes {
+        sites: prepare_sites(config)?,
+        readiness: web::Data::new(Readiness::new()),
+        sweeping: Arc::new(AtomicBool::new(false)),
+    })
+}
+
+impl ImageRoutes {
+    // Waiting for cached variants still being saved, and for changes still
+    // being copied to a replica, once the application's server has stopped
+    pub async fn finish(&self) {
+        for site in &self.sites {
+            site.finish_background_work(site.config.shutdown_timeout).await;
+        }
+    }
+}
+
+impl HttpServiceFactory for ImageRoutes {
+    fn register(self, config: &mut AppService) {
+        // Sweeping in the background starts with the first worker, since
+        // there's no runtime to spawn on before then
+        if !self.sweeping.swap(true, Ordering::SeqCst) {
+            for site in &self.sites {
+                site.spawn_sweepers();
+            }
+        }
+
+        for site in &self.sites {
+            site.scope().app_data(self.readiness.clone()).register(config);
+        }
+    }
+}
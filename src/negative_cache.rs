@@ -0,0 +1,56 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+
+// Requests for this many different missing images are remembered
+// at most, so that scanning bots can't exhaust memory
+const MAX_MISSING: usize = 100_000;
+
+// Uploads recently found not to exist, so that repeated requests for
+// them are answered without touching the filesystem. Entries expire
+// after `ttl`, or as soon as an upload with that name is saved
+pub struct NegativeCache {
+    ttl: Option<Duration>,
+    missing: Mutex<HashMap<String, Instant>>,
+}
+
+impl NegativeCache {
+    pub fn new(ttl: Option<Duration>) -> Self {
+        Self { ttl, missing: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn is_missing(&self, filename: &str) -> bool {
+        if self.ttl.is_none() { return false; }
+
+        let mut missing = self.missing.lock().unwrap();
+        match missing.get(filename) {
+            Some(expiry) if *expiry > Instant::now() => true,
+            Some(_) => {
+                missing.remove(filename);
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub fn record_missing(&self, filename: &str) {
+        let ttl = match self.ttl {
+            Some(ttl) => ttl,
+            None => return,
+        };
+
+        let mut missing = self.missing.lock().unwrap();
+        let now = Instant::now();
+        if missing.len() >= MAX_MISSING {
+            missing.retain(|_, expiry| *expiry > now);
+        }
+        if missing.len() < MAX_MISSING {
+            missing.insert(filename.to_string(), now + ttl);
+        }
+    }
+
+    pub fn forget(&self, filename: &str) {
+        self.missing.lock().unwrap().remove(filename);
+    }
+}
@@ -0,0 +1,78 @@
+use actix_web::{HttpRequest, HttpResponse};
+use actix_web::http::header::{HeaderValue, VARY};
+
+
+// Tracking which request headers influenced a response, so that it can
+// declare them in `Vary`. Shared caches use that to avoid serving a
+// variant negotiated for one client to a client that asked differently
+pub struct Negotiation {
+    inputs: Vec<&'static str>,
+}
+
+impl Negotiation {
+    pub fn new() -> Self {
+        Self { inputs: vec![] }
+    }
+
+    // Reading a request header that affects the response, and noting
+    // that the response varies by it, whether or not it was present
+    pub fn consult<'a>(&mut self, req: &'a HttpRequest, header: &'static str) -> Option<&'a str> {
+        if !self.inputs.contains(&header) {
+            self.inputs.push(header);
+        }
+
+        req.headers().get(header)?.to_str().ok()
+    }
+
+    // Declaring every consulted header in the response's `Vary` header,
+    // alongside any that were already declared there
+    pub fn apply(&self, response: &mut HttpResponse) {
+        if self.inputs.is_empty() { return; }
+
+        let mut vary: Vec<String> = response
+            .headers()
+            .get(VARY)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.split(',').map(|v| v.trim().to_string()).collect())
+            .unwrap_or_default();
+
+        for input in &self.inputs {
+            if !vary.iter().any(|v| v.eq_ignore_ascii_case(input)) {
+                vary.push(input.to_string());
+            }
+        }
+
+        if let Ok(value) = HeaderValue::from_str(&vary.join(", ")) {
+            response.headers_mut().insert(VARY, value);
+        }
+    }
+}
+
+// "image/avif,image/webp;q=0.9,*/*;q=0.8", "image/webp" -> true
+fn accepts(accept: &str, media_type: &str) -> bool {
+    accept.split(',').any(|entry| {
+        let mut parts = entry.split(';').map(|part| part.trim());
+        let matches = parts.next() == Some(media_type);
+        let refused = parts.any(|param| {
+            param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0)
+        });
+        matches && !refused
+    })
+}
+
+// Choosing the best output format the client claims to support, among
+// those the server serves. Every client is assumed to support JPEG and PNG
+// ["webp", "jpeg"], "image/webp" -> "webp"
+// ["webp", "jpeg"], "image/png" -> "jpeg"
+pub fn negotiate_extension(req: &HttpRequest, negotiation: &mut Negotiation, served: &[String]) -> &'static str {
+    let serves = |extension: &str| served.iter().any(|served| served == extension);
+
+    if serves("webp") {
+        match negotiation.consult(req, "Accept") {
+            Some(accept) if accepts(accept, "image/webp") => return "webp",
+            _ => (),
+        }
+    }
+
+    ["jpeg", "png", "webp"].iter().copied().find(|extension| serves(extension)).unwrap_or("jpeg")
+}
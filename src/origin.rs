@@ -0,0 +1,207 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::{Serialize, Deserialize};
+
+use crate::{ServerConfig, ImageServiceFailure, Bytes, DeleteEvent, UploadEvent, cause, build_path_to_master, encode_master, save_master, delete_master, master_dimensions, cache, listing};
+
+
+// Origins that don't answer within this long are treated as unavailable
+#[cfg(feature = "proxy")]
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Where images are fetched from when imogen runs as a resizing proxy,
+// rather than serving its own uploads
+#[derive(Clone, Debug)]
+pub struct OriginConfig {
+    pub base_url: String,
+    pub allowed_prefixes: Vec<String>,
+    pub max_age: Option<Duration>,
+}
+
+impl OriginConfig {
+    // "https://example.com/originals"
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            allowed_prefixes: vec![],
+            max_age: None,
+        }
+    }
+
+    // Only fetching images whose name begins with `prefix`, so that
+    // the rest of the origin can't be reached through imogen
+    pub fn with_allowed_prefix(mut self, prefix: &str) -> Self {
+        self.allowed_prefixes.push(prefix.to_string());
+        self
+    }
+
+    // Asking the origin whether an image has changed once the copy
+    // fetched before is this old. Otherwise copies are kept until purged
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    // "cat.jpg" -> Some("https://example.com/originals/cat.jpg")
+    // "../admin" -> None
+    fn source_url(&self, filename: &str) -> Option<String> {
+        if filename.is_empty() || filename == "." || filename == ".." {
+            return None;
+        }
+
+        let allowed = self.allowed_prefixes.is_empty()
+            || self.allowed_prefixes.iter().any(|prefix| filename.starts_with(prefix.as_str()));
+
+        match allowed {
+            true => Some(format!("{}/{}", self.base_url, percent_encode(filename))),
+            false => None,
+        }
+    }
+}
+
+// "a b.jpg" -> "a%20b.jpg"
+// Encoding anything that could be read as part of another path or query
+fn percent_encode(value: &str) -> String {
+    value.bytes().map(|byte| match byte {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+        _ => format!("%{:02X}", byte),
+    }).collect()
+}
+
+// When a master was last fetched, and what the origin said about it,
+// so that it can be asked whether the image has changed since
+#[derive(Serialize, Deserialize, Default)]
+struct FetchRecord {
+    checked_at: u64,
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+// "cat.jpg" -> "./images/.origin/cat.jpg.json"
+fn build_path_to_fetch_record(config: &ServerConfig, filename: &str) -> String {
+    format!("{}/.origin/{}{}.json", config.uploads_dir, config.shard(filename), filename)
+}
+
+fn load_fetch_record(config: &ServerConfig, filename: &str) -> Option<FetchRecord> {
+    let contents = config.storage.get(&build_path_to_fetch_record(config, filename)).ok()?;
+    serde_json::from_slice(&contents).ok()
+}
+
+fn save_fetch_record(config: &ServerConfig, filename: &str, record: &FetchRecord) {
+    if let Ok(contents) = serde_json::to_vec(record) {
+        let _ = config.storage.put(&build_path_to_fetch_record(config, filename), &contents);
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Only the `proxy` feature can fetch anything
+#[cfg_attr(not(feature = "proxy"), allow(dead_code))]
+enum Fetched {
+    Image(Bytes, FetchRecord),
+    Unchanged,
+    Missing,
+    Failed,
+}
+
+// Requesting an image from the origin, conditionally when a copy of
+// it was fetched before. Images larger than `limit` bytes are refused
+#[cfg(feature = "proxy")]
+fn fetch(url: &str, previous: Option<&FetchRecord>, limit: usize) -> Fetched {
+    use std::io::Read;
+
+    let agent = ureq::AgentBuilder::new().timeout(FETCH_TIMEOUT).build();
+    let mut request = agent.get(url);
+    if let Some(previous) = previous {
+        if let Some(etag) = &previous.etag {
+            request = request.set("if-none-match", etag);
+        }
+        if let Some(last_modified) = &previous.last_modified {
+            request = request.set("if-modified-since", last_modified);
+        }
+    }
+
+    match request.call() {
+        Ok(response) if response.status() == 304 => Fetched::Unchanged,
+        Ok(response) => {
+            let record = FetchRecord {
+                checked_at: now(),
+                etag: response.header("etag").map(|etag| etag.to_string()),
+                last_modified: response.header("last-modified").map(|date| date.to_string()),
+            };
+
+            let mut contents = Vec::new();
+            match response.into_reader().take(limit as u64 + 1).read_to_end(&mut contents) {
+                Ok(_) if contents.len() <= limit => Fetched::Image(contents, record),
+                _ => Fetched::Failed,
+            }
+        }
+        Err(ureq::Error::Status(404, _)) | Err(ureq::Error::Status(410, _)) => Fetched::Missing,
+        Err(_) => Fetched::Failed,
+    }
+}
+
+#[cfg(not(feature = "proxy"))]
+fn fetch(_url: &str, _previous: Option<&FetchRecord>, _limit: usize) -> Fetched {
+    Fetched::Failed
+}
+
+// Making sure there's an up to date master for `filename`, fetching it
+// from the origin if there isn't one yet, or if the copy has outlived
+// the origin's max age and the image has changed since
+pub fn refresh_master(config: &ServerConfig, origin: &OriginConfig, filename: &str) -> Result<(), ImageServiceFailure> {
+    let filepath = build_path_to_master(config, filename);
+    let previous = match config.storage.exists(&filepath) {
+        true => Some(load_fetch_record(config, filename).unwrap_or_default()),
+        false => None,
+    };
+
+    let age = previous.as_ref().map(|record| Duration::from_secs(now().saturating_sub(record.checked_at)));
+    match (age, origin.max_age) {
+        (Some(_), None) => return Ok(()),
+        (Some(age), Some(max_age)) if age < max_age => return Ok(()),
+        _ => {},
+    }
+
+    let url = origin.source_url(filename).ok_or(ImageServiceFailure::ImageDoesNotExist)?;
+    match fetch(&url, previous.as_ref(), config.max_upload_size) {
+        Fetched::Image(contents, record) => {
+            let master = encode_master(contents, &config.into())?;
+            save_master(config, filename, &master).map_err(|e| ImageServiceFailure::CouldNotWrite { source: cause(e) })?;
+            save_fetch_record(config, filename, &record);
+            listing::update_index(config, filename);
+
+            // Telling hooks of copies that changed at the origin
+            if previous.is_some() {
+                let (width, height) = master_dimensions(&master, config.max_pixels).unwrap_or_default();
+                let filename = filename.to_string();
+                config.hooks.replace(UploadEvent { filename, bytes: master.len(), width, height, uploaded_by: None });
+            }
+            Ok(())
+        }
+        Fetched::Unchanged => {
+            let record = FetchRecord { checked_at: now(), ..previous.unwrap_or_default() };
+            save_fetch_record(config, filename, &record);
+            Ok(())
+        }
+        // Forgetting images that were removed from the origin, so that
+        // their variants are removed too
+        Fetched::Missing => {
+            if previous.is_some() {
+                let _ = delete_master(config, filename);
+                let _ = config.storage.delete(&build_path_to_fetch_record(config, filename));
+                cache::remove_variants_of(config, filename);
+                listing::update_index(config, filename);
+                config.hooks.delete(DeleteEvent { filename: filename.to_string(), renamed_to: None });
+            }
+            Err(ImageServiceFailure::ImageDoesNotExist)
+        }
+        // Making do with the copy fetched before while the origin is unavailable
+        Fetched::Failed if previous.is_some() => Ok(()),
+        Fetched::Failed => Err(ImageServiceFailure::CouldNotReadToBuffer { source: None }),
+    }
+}
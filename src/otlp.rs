@@ -0,0 +1,281 @@
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use serde_json::{json, Value};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+use uuid::Uuid;
+
+
+// Sending spans to an OpenTelemetry collector, like
+// "http://localhost:4318", over OTLP/HTTP with JSON bodies
+#[derive(Clone, Debug)]
+pub struct OtlpConfig {
+    pub endpoint: String,
+    pub service_name: String,
+    // ("authorization", "Bearer …"), for collectors that need them
+    pub headers: Vec<(String, String)>,
+}
+
+impl OtlpConfig {
+    pub fn new(endpoint: &str) -> Self {
+        Self {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            service_name: "imogen".to_string(),
+            headers: vec![],
+        }
+    }
+
+    pub fn with_service_name(mut self, name: &str) -> Self {
+        self.service_name = name.to_string();
+        self
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+// Spans are sent in batches of up to this many, or every interval,
+// whichever comes first
+const MAX_BATCH: usize = 512;
+const BATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+// A span being recorded, kept alongside it until it closes
+struct OpenSpan {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    start: SystemTime,
+    attributes: Vec<(String, Value)>,
+}
+
+enum Message {
+    Span(Value),
+    // Sending whatever is waiting, and then replying
+    Flush(Sender<()>),
+}
+
+// Only one exporter can be installed, along with the logging
+static EXPORTER: OnceLock<Mutex<Sender<Message>>> = OnceLock::new();
+
+// Recording every span that passes the logging filter, and sending each
+// to the collector once it closes
+pub struct OtlpLayer {
+    spans: Sender<Message>,
+}
+
+impl OtlpLayer {
+    pub fn new(config: &OtlpConfig) -> Self {
+        let (spans, queue) = mpsc::channel::<Message>();
+        let config = config.clone();
+        std::thread::spawn(move || export(&config, queue));
+
+        let _ = EXPORTER.set(Mutex::new(spans.clone()));
+        Self { spans }
+    }
+}
+
+// Waiting up to `timeout` for spans that haven't been sent yet, before
+// the process exits
+pub fn flush(timeout: Duration) {
+    let exporter = match EXPORTER.get() {
+        Some(exporter) => exporter,
+        None => return,
+    };
+
+    let (done, finished) = mpsc::channel();
+    if exporter.lock().unwrap().send(Message::Flush(done)).is_ok() {
+        let _ = finished.recv_timeout(timeout);
+    }
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    bytes.copy_from_slice(&Uuid::new_v4().as_bytes()[..N]);
+    bytes
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+// -> Some((trace ID, parent span ID))
+// Continuing traces begun by a client or proxy, via `traceparent`
+fn parse_traceparent(header: &str) -> Option<([u8; 16], [u8; 8])> {
+    let parts: Vec<&str> = header.trim().split('-').collect();
+    if parts.len() < 4 || parts[1].len() != 32 || parts[2].len() != 16 {
+        return None;
+    }
+
+    let decode = |digits: &str, bytes: &mut [u8]| -> Option<()> {
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(digits.get(i * 2..i * 2 + 2)?, 16).ok()?;
+        }
+        Some(())
+    };
+
+    let (mut trace_id, mut span_id) = ([0; 16], [0; 8]);
+    decode(parts[1], &mut trace_id)?;
+    decode(parts[2], &mut span_id)?;
+    match trace_id == [0; 16] || span_id == [0; 8] {
+        true => None,
+        false => Some((trace_id, span_id)),
+    }
+}
+
+// Collecting a span's fields as OTLP attributes
+struct FieldVisitor<'a>(&'a mut Vec<(String, Value)>);
+
+impl Visit for FieldVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push((field.name().to_string(), json!({ "stringValue": value })));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.push((field.name().to_string(), json!({ "intValue": value.to_string() })));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.push((field.name().to_string(), json!({ "intValue": value.to_string() })));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.push((field.name().to_string(), json!({ "boolValue": value })));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.record_str(field, &format!("{:?}", value));
+    }
+}
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+
+        let mut attributes = vec![];
+        attrs.record(&mut FieldVisitor(&mut attributes));
+
+        let parent = span.parent().and_then(|parent| {
+            parent.extensions().get::<OpenSpan>().map(|open| (open.trace_id, open.span_id))
+        });
+        let remote_parent = attributes.iter()
+            .find(|(key, _)| key == "traceparent")
+            .and_then(|(_, value)| value["stringValue"].as_str().and_then(parse_traceparent));
+
+        let (trace_id, parent_span_id) = match parent.or(remote_parent) {
+            Some((trace_id, span_id)) => (trace_id, Some(span_id)),
+            None => (random_bytes(), None),
+        };
+
+        span.extensions_mut().insert(OpenSpan {
+            trace_id,
+            span_id: random_bytes(),
+            parent_span_id,
+            start: SystemTime::now(),
+            attributes,
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(open) = span.extensions_mut().get_mut::<OpenSpan>() {
+                values.record(&mut FieldVisitor(&mut open.attributes));
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+        let open = match span.extensions_mut().remove::<OpenSpan>() {
+            Some(open) => open,
+            None => return,
+        };
+
+        let nanos = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0).to_string()
+        };
+        // Requests are served, while everything within them is internal
+        let kind = if span.parent().is_none() { 2 } else { 1 };
+
+        let attributes: Vec<Value> = open.attributes
+            .into_iter()
+            .filter(|(key, _)| key != "traceparent")
+            .map(|(key, value)| json!({ "key": key, "value": value }))
+            .collect();
+
+        let _ = self.spans.send(Message::Span(json!({
+            "traceId": hex(&open.trace_id),
+            "spanId": hex(&open.span_id),
+            "parentSpanId": open.parent_span_id.map(|id| hex(&id)).unwrap_or_default(),
+            "name": span.name(),
+            "kind": kind,
+            "startTimeUnixNano": nanos(open.start),
+            "endTimeUnixNano": nanos(SystemTime::now()),
+            "attributes": attributes,
+        })));
+    }
+}
+
+// Sending spans in batches until the process exits. Failing to reach
+// the collector drops the batch, rather than holding on to it
+fn export(config: &OtlpConfig, queue: Receiver<Message>) {
+    let agent = ureq::Agent::new();
+    let url = format!("{}/v1/traces", config.endpoint);
+
+    let mut batch: Vec<Value> = vec![];
+    let mut flushing: Vec<Sender<()>> = vec![];
+    let mut deadline = Instant::now() + BATCH_INTERVAL;
+
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match queue.recv_timeout(timeout) {
+            Ok(Message::Span(span)) => batch.push(span),
+            Ok(Message::Flush(done)) => flushing.push(done),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        let due = Instant::now() >= deadline || batch.len() >= MAX_BATCH || !flushing.is_empty();
+        if !due { continue; }
+
+        if !batch.is_empty() {
+            let body = json!({
+                "resourceSpans": [{
+                    "resource": {
+                        "attributes": [{ "key": "service.name", "value": { "stringValue": config.service_name } }],
+                    },
+                    "scopeSpans": [{ "scope": { "name": "imogen" }, "spans": batch }],
+                }],
+            });
+
+            let mut request = agent.post(&url).set("content-type", "application/json");
+            for (name, value) in &config.headers {
+                request = request.set(name, value);
+            }
+            if let Err(e) = request.send_string(&body.to_string()) {
+                tracing::warn!(%url, error = %e, "failed to export traces");
+            }
+            batch = vec![];
+        }
+
+        for done in flushing.drain(..) {
+            let _ = done.send(());
+        }
+        deadline = Instant::now() + BATCH_INTERVAL;
+    }
+}
@@ -0,0 +1,276 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{DEFAULT_MAX_PIXELS, EncodingDefaults, FileDescription, ImageServiceFailure, Preset, ProcessingInstructions, Transform, try_processing_renditions, encode_master};
+#[cfg(feature = "server")]
+use crate::ServerConfig;
+use crate::decoded_cache::DecodedCache;
+use crate::memory_budget::MemoryBudget;
+
+
+// What processing needs to know: the most pixels an image may claim to
+// have, how each format is encoded, and the presets and transforms
+// instructions may name
+#[derive(Clone)]
+pub struct Settings {
+    pub max_pixels: u64,
+    pub encoding: EncodingDefaults,
+    pub presets: Vec<Preset>,
+    pub transforms: Vec<Arc<dyn Transform>>,
+}
+
+impl std::fmt::Debug for Settings {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Settings")
+            .field("max_pixels", &self.max_pixels)
+            .field("encoding", &self.encoding)
+            .field("presets", &self.presets)
+            .field("transforms", &self.transforms.iter().map(|transform| transform.name()).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Settings {
+    pub fn new() -> Self {
+        Self { max_pixels: DEFAULT_MAX_PIXELS, encoding: EncodingDefaults::new(), presets: vec![], transforms: vec![] }
+    }
+
+    pub fn with_max_pixels(mut self, pixels: u64) -> Self {
+        self.max_pixels = pixels;
+        self
+    }
+
+    pub fn with_encoding_defaults(mut self, defaults: EncodingDefaults) -> Self {
+        self.encoding = defaults;
+        self
+    }
+
+    // ("thumbnail", "w=200&h=200&sampling=lanczos3")
+    pub fn with_preset(mut self, name: &str, query: &str) -> Self {
+        self.presets.push(Preset { name: name.to_string(), query: query.to_string() });
+        self
+    }
+
+    pub fn with_transform<T: Transform + 'static>(mut self, transform: T) -> Self {
+        self.transforms.push(Arc::new(transform));
+        self
+    }
+}
+
+// Processing the way a server with this config would
+#[cfg(feature = "server")]
+impl From<&ServerConfig> for Settings {
+    fn from(config: &ServerConfig) -> Self {
+        Self {
+            max_pixels: config.max_pixels,
+            encoding: config.encoding.clone(),
+            presets: config.presets.clone(),
+            transforms: config.transforms.clone(),
+        }
+    }
+}
+
+// The sampling filters images can be resized with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sampling {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+}
+
+impl Sampling {
+    // "lanczos3" -> Some(Sampling::Lanczos3)
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "nearest" => Some(Self::Nearest),
+            "triangle" => Some(Self::Triangle),
+            "catmullrom" => Some(Self::CatmullRom),
+            "gaussian" => Some(Self::Gaussian),
+            "lanczos3" => Some(Self::Lanczos3),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Nearest => "nearest",
+            Self::Triangle => "triangle",
+            Self::CatmullRom => "catmullrom",
+            Self::Gaussian => "gaussian",
+            Self::Lanczos3 => "lanczos3",
+        }
+    }
+}
+
+// How to process an image, as the query string of a request would say.
+// Images are resized to fit within the width and height, unless they're
+// stretched to them, and encoded as configured without a quality
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instructions {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    // From 1 to 100
+    pub quality: Option<u8>,
+    pub sampling: Sampling,
+    pub stretch: bool,
+    // The transforms of `Settings` to apply, like [("sepia", "80")]
+    pub transforms: Vec<(String, String)>,
+}
+
+impl Default for Instructions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Instructions {
+    // Leaving the image as large as it is
+    pub fn new() -> Self {
+        Self { width: None, height: None, quality: None, sampling: Sampling::Nearest, stretch: false, transforms: vec![] }
+    }
+
+    pub fn with_width(mut self, width: u32) -> Self {
+        self.width = Some(width);
+        self
+    }
+
+    pub fn with_height(mut self, height: u32) -> Self {
+        self.height = Some(height);
+        self
+    }
+
+    pub fn with_quality(mut self, quality: u8) -> Self {
+        self.quality = Some(quality);
+        self
+    }
+
+    pub fn with_sampling(mut self, sampling: Sampling) -> Self {
+        self.sampling = sampling;
+        self
+    }
+
+    pub fn stretched(mut self) -> Self {
+        self.stretch = true;
+        self
+    }
+
+    // ("sepia", "80"), for one of the transforms of `Settings`
+    pub fn with_transform(mut self, name: &str, value: &str) -> Self {
+        self.transforms.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    // "w=200&sampling=lanczos3", or "preset=thumbnail" for one of the
+    // presets of `settings`
+    pub fn from_query(query: &str, settings: &Settings) -> Result<Self, ImageServiceFailure> {
+        let parsed = serde_urlencoded::from_str::<ProcessingInstructions>(query)
+            .map_err(|e| ImageServiceFailure::InvalidParameter { parameter: None, message: format!("Invalid processing instructions: {}", e) })?
+            .with_transforms(query, &settings.transforms)?
+            .with_preset(&settings.presets, &settings.transforms)
+            .map_err(|message| ImageServiceFailure::invalid_parameter("preset", &message))?;
+        parsed.check_quality().map_err(|message| ImageServiceFailure::invalid_parameter("q", &message))?;
+
+        let (sampling, _) = parsed.sampling_method();
+        Ok(Self {
+            width: parsed.w,
+            height: parsed.h,
+            quality: parsed.q,
+            sampling: Sampling::from_name(sampling).unwrap_or(Sampling::Nearest),
+            stretch: parsed.stretch.unwrap_or(false),
+            transforms: parsed.transforms,
+        })
+    }
+
+    // Refusing transforms that `settings` doesn't have, or values they
+    // don't accept
+    fn resolved(&self, settings: &Settings) -> Result<ProcessingInstructions, ImageServiceFailure> {
+        for (name, value) in &self.transforms {
+            match settings.transforms.iter().find(|transform| transform.name() == name) {
+                Some(transform) => transform.check(value).map_err(|message| ImageServiceFailure::invalid_parameter(name, &message))?,
+                None => return Err(ImageServiceFailure::invalid_parameter(name, "There is no transform by this name")),
+            }
+        }
+
+        let resolved = ProcessingInstructions {
+            stretch: Some(self.stretch),
+            sampling: Some(self.sampling.name().to_string()),
+            w: self.width,
+            h: self.height,
+            q: self.quality,
+            preset: None,
+            transforms: self.transforms.clone(),
+            #[cfg(feature = "server")]
+            iiif: None,
+        };
+        resolved.check_quality().map_err(|message| ImageServiceFailure::invalid_parameter("q", &message))?;
+        Ok(resolved)
+    }
+}
+
+// An image once processed, in the format it was encoded in, along with
+// how long each stage took. The decode is counted towards the first of
+// several renditions processed at once
+#[derive(Clone, Debug)]
+pub struct Rendition {
+    pub bytes: Vec<u8>,
+    // "webp", "png" or "jpeg"
+    pub extension: String,
+    pub width: u32,
+    pub height: u32,
+    pub decode: Duration,
+    pub resize: Duration,
+    pub encode: Duration,
+}
+
+// Processing an image the way the server does, without starting one,
+// like from batch jobs and tests, and encoding it in the format named by
+// `extension`. Images may be PNGs, JPEGs or WebPs. They're encoded with
+// the defaults of `settings`, and refused if they claim more pixels than
+// it allows
+pub fn process(image: &[u8], instructions: &Instructions, extension: &str, settings: &Settings) -> Result<Rendition, ImageServiceFailure> {
+    process_many(image, &[(instructions.clone(), extension)], settings).map(|mut renditions| renditions.remove(0))
+}
+
+// Processing several renditions of an image, like
+// [(Instructions::new().with_width(400), "webp"), (Instructions::new(), "jpeg")],
+// decoding it only once
+pub fn process_many(image: &[u8], renditions: &[(Instructions, &str)], settings: &Settings) -> Result<Vec<Rendition>, ImageServiceFailure> {
+    let mut resolved = vec![];
+    for (instructions, extension) in renditions {
+        let required = FileDescription { filename: String::new(), extension: extension.to_string() };
+        resolved.push((instructions.resolved(settings)?, required));
+    }
+
+    let processed = try_processing_renditions(image, &resolved, settings, &MemoryBudget::unlimited(), &DecodedCache::disabled())?;
+
+    processed
+        .into_iter()
+        .zip(renditions)
+        .map(|(processed, (_, extension))| {
+            let (bytes, processing) = processed?;
+            let (width, height) = processing.output_dimensions;
+            Ok(Rendition {
+                bytes,
+                extension: extension.to_string(),
+                width,
+                height,
+                decode: processing.decode,
+                resize: processing.resize,
+                encode: processing.encode,
+            })
+        })
+        .collect()
+}
+
+// Converting an image to the WebP master that uploading it would store
+pub fn to_master(image: Vec<u8>, settings: &Settings) -> Result<Vec<u8>, ImageServiceFailure> {
+    encode_master(image, settings)
+}
@@ -0,0 +1,230 @@
+use image::{DynamicImage, Rgba, RgbaImage};
+use serde::Deserialize;
+use actix_web::{web, HttpRequest, HttpResponse};
+
+use crate::{
+    auth,
+    cache,
+    font,
+    Bytes,
+    Caches,
+    ImageServiceFailure,
+    ServerConfig,
+    encode_image,
+    image_buffer_as_http_response,
+    run_blocking,
+    try_streaming_preprocessed_file_from_disk,
+};
+
+
+// Placeholders larger than this in either dimension are refused,
+// since they would be expensive to render and almost never useful
+const MAX_PLACEHOLDER_DIMENSION: u32 = 4096;
+
+// Query string parameters that placeholders are drawn with. Any others are
+// refused, rather than each caching another copy of the same placeholder
+const PLACEHOLDER_PARAMS: [&str; 3] = ["bg", "fg", "text"];
+
+#[derive(Deserialize, Debug)]
+pub struct PlaceholderDescription {
+    width: u32,
+    height: u32,
+    extension: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PlaceholderInstructions {
+    bg: Option<String>,
+    fg: Option<String>,
+    text: Option<String>,
+}
+
+// What a placeholder is drawn with, once its instructions are understood
+struct Placeholder {
+    top: Rgba<u8>,
+    bottom: Rgba<u8>,
+    fg: Rgba<u8>,
+    text: String,
+}
+
+impl PlaceholderInstructions {
+    // Reading the colors and text to draw a `width` by `height`
+    // placeholder with, filling in the defaults
+    fn parse(&self, width: u32, height: u32) -> Result<Placeholder, ImageServiceFailure> {
+        let bg = self.bg.as_deref().unwrap_or("ccc");
+        let stops = bg.split(',').map(parse_hex_color).collect::<Option<Vec<Rgba<u8>>>>();
+        let (top, bottom) = match stops.as_deref() {
+            Some([solid]) => (*solid, *solid),
+            Some([top, bottom]) => (*top, *bottom),
+            _ => {
+                let message = "Colors must be hex values like `ccc` or `c0c0c0`, \
+                and `bg` may contain at most two colors for a gradient";
+                return Err(ImageServiceFailure::invalid_parameter("bg", message));
+            }
+        };
+
+        let fg = match parse_hex_color(self.fg.as_deref().unwrap_or("666")) {
+            Some(fg) => fg,
+            None => return Err(ImageServiceFailure::invalid_parameter("fg", "Colors must be hex values like `ccc` or `c0c0c0`")),
+        };
+
+        // Defaulting to the dimensions of the placeholder as its text
+        let text = match &self.text {
+            Some(text) => text.clone(),
+            None => format!("{}x{}", width, height),
+        };
+
+        Ok(Placeholder { top, bottom, fg, text })
+    }
+}
+
+// Rgba([204, 204, 204, 255]) -> "ccccccff"
+fn to_hex(color: Rgba<u8>) -> String {
+    color.0.iter().map(|channel| format!("{:02x}", channel)).collect()
+}
+
+// "c0c0c0" | "ccc" | "c0c0c080" | "ccc8" -> Rgba
+pub fn parse_hex_color(hex: &str) -> Option<Rgba<u8>> {
+    let hex = hex.trim_start_matches('#');
+    let digits: Vec<u8> = hex
+        .chars()
+        .map(|c| c.to_digit(16).map(|d| d as u8))
+        .collect::<Option<Vec<u8>>>()?;
+
+    let channels: Vec<u8> = match digits.len() {
+        3 | 4 => digits.iter().map(|d| d * 17).collect(),
+        6 | 8 => digits.chunks(2).map(|pair| pair[0] * 16 + pair[1]).collect(),
+        _ => return None,
+    };
+
+    let alpha = channels.get(3).copied().unwrap_or(255);
+    Some(Rgba([channels[0], channels[1], channels[2], alpha]))
+}
+
+// Interpolating between two colors, where `t` is between 0 and 1
+pub fn mix(from: Rgba<u8>, to: Rgba<u8>, t: f32) -> Rgba<u8> {
+    let mut mixed = [0; 4];
+    for i in 0..4 {
+        mixed[i] = (from[i] as f32 + (to[i] as f32 - from[i] as f32) * t).round() as u8;
+    }
+    Rgba(mixed)
+}
+
+// Drawing a solid (bg=ccc) or top-to-bottom gradient (bg=ccc,999)
+// background, with text centered on top of it
+fn render_placeholder(width: u32, height: u32, placeholder: &Placeholder) -> DynamicImage {
+    let Placeholder { top, bottom, fg, text } = placeholder;
+
+    let mut canvas = RgbaImage::new(width, height);
+    for (_, y, pixel) in canvas.enumerate_pixels_mut() {
+        let t = if height > 1 { y as f32 / (height - 1) as f32 } else { 0.0 };
+        *pixel = mix(*top, *bottom, t);
+    }
+
+    // Choosing the largest scale where the text fits comfortably
+    let unscaled_width = font::text_width(text, 1).max(1);
+    let scale = (width * 6 / 10 / unscaled_width)
+        .min(height * 3 / 10 / font::GLYPH_HEIGHT)
+        .max(1);
+
+    let x = (width as i64 - font::text_width(text, scale) as i64) / 2;
+    let y = (height as i64 - (font::GLYPH_HEIGHT * scale) as i64) / 2;
+    font::draw_text(&mut canvas, text, x, y, scale, *fg);
+
+    DynamicImage::ImageRgba8(canvas)
+}
+
+// 640x480 PNG drawn with bg=ccc -> "./images/.placeholder/9d2f…b41c.png"
+// Placeholders are named after a hash of their dimensions and what
+// they're drawn with, so that `bg=ccc` and `bg=cccccc` share one
+fn build_path_to_placeholder(
+    config: &ServerConfig,
+    desc: &PlaceholderDescription,
+    placeholder: &Placeholder,
+) -> String {
+    let drawn = serde_urlencoded::to_string([
+        ("bg", format!("{},{}", to_hex(placeholder.top), to_hex(placeholder.bottom))),
+        ("fg", to_hex(placeholder.fg)),
+        ("text", placeholder.text.clone()),
+    ]).unwrap_or_default();
+    let name = format!("{}x{}?{}.{}", desc.width, desc.height, drawn, desc.extension);
+    let dir = config.build_path_to_cache("placeholder");
+    format!("{}/{}{}.{}", dir, config.shard(&name), cache::hash_of(&name), desc.extension)
+}
+
+// Rendering and encoding a placeholder once a processing slot is free and
+// the memory budget has room for it, and caching it like any other variant
+fn try_rendering_placeholder(
+    config: &ServerConfig,
+    caches: &Caches,
+    desc: &PlaceholderDescription,
+    placeholder: &Placeholder,
+    filepath: &str,
+) -> Result<Bytes, ImageServiceFailure> {
+    let _slot = caches.processing.acquire().ok_or(ImageServiceFailure::Overloaded)?;
+    let _reservation = caches.budget.reserve(desc.width as usize * desc.height as usize * 4).ok_or(ImageServiceFailure::Overloaded)?;
+
+    let rendered = render_placeholder(desc.width, desc.height, placeholder);
+    let buffer = encode_image(&rendered, &desc.extension)?;
+    let _ = config.storage.put(filepath, &buffer);
+    Ok(buffer)
+}
+
+// Respond to a request for a synthesized placeholder image
+pub async fn serve_placeholder_via_http(
+    req: HttpRequest,
+    desc: web::Path<PlaceholderDescription>,
+    instructions: web::Query<PlaceholderInstructions>,
+    config: web::Data<ServerConfig>,
+    caches: web::Data<Caches>,
+) -> HttpResponse {
+    if let Err(failure) = auth::authorize(&req, &config, auth::Access::Read) {
+        return failure.as_http_response();
+    }
+
+    let desc = desc.into_inner();
+    let instructions = instructions.into_inner();
+
+    if !config.serves_format(&desc.extension) {
+        return config.unserved_format(&desc.extension).as_http_response(&req);
+    }
+
+    let dimensions = 1..=MAX_PLACEHOLDER_DIMENSION;
+    if !dimensions.contains(&desc.width) || !dimensions.contains(&desc.height) {
+        let message = format!(
+            "Placeholder dimensions must be between 1 and {}",
+            MAX_PLACEHOLDER_DIMENSION,
+        );
+        return ImageServiceFailure::InvalidParameter { parameter: None, message }.as_http_response(&req);
+    }
+
+    let unknown = req.query_string()
+        .split('&')
+        .filter_map(|pair| pair.split('=').next())
+        .find(|name| !name.is_empty() && !PLACEHOLDER_PARAMS.contains(name) && !auth::SIGNATURE_PARAMS.contains(name));
+    if let Some(name) = unknown {
+        let message = format!("Placeholders can't be drawn with `{}`. Try bg, fg or text", name);
+        return ImageServiceFailure::invalid_parameter(name, &message).as_http_response(&req);
+    }
+
+    let placeholder = match instructions.parse(desc.width, desc.height) {
+        Ok(placeholder) => placeholder,
+        Err(failure) => return failure.as_http_response(&req),
+    };
+
+    let filepath = build_path_to_placeholder(&config, &desc, &placeholder);
+    let (checked_config, checked_path) = (config.clone(), filepath.clone());
+    let _ = run_blocking(move || {
+        cache::discard_if_damaged(&checked_config, &checked_path);
+        Ok(())
+    }).await;
+    if let Some(response) = try_streaming_preprocessed_file_from_disk(&config, &filepath, &req).await {
+        return response;
+    }
+
+    let extension = desc.extension.clone();
+    match run_blocking(move || try_rendering_placeholder(&config, &caches, &desc, &placeholder, &filepath)).await {
+        Ok(buffer) => image_buffer_as_http_response(buffer, &extension),
+        Err(failure) => failure.as_http_response(&req),
+    }
+}
@@ -0,0 +1,98 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+
+#[derive(Default)]
+struct State {
+    running: usize,
+    waiting: usize,
+}
+
+// Limiting how many variants are processed at once altogether. Jobs
+// beyond the limit queue for a turn, for up to `wait`, and are refused
+// after that, or straight away once `queue` jobs are already waiting
+pub struct ProcessingLimit {
+    // Jobs at once, or no limit at all
+    limit: Option<usize>,
+    queue: usize,
+    wait: Duration,
+    state: Mutex<State>,
+    finished: Condvar,
+    refusals: AtomicU64,
+}
+
+// A turn at processing, which is handed on when dropped
+pub struct Slot<'a> {
+    limit: &'a ProcessingLimit,
+    counted: bool,
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        if !self.counted { return; }
+
+        self.limit.state.lock().unwrap().running -= 1;
+        self.limit.finished.notify_one();
+    }
+}
+
+impl ProcessingLimit {
+    pub fn new(limit: Option<usize>, queue: usize, wait: Duration) -> Self {
+        Self {
+            limit,
+            queue,
+            wait,
+            state: Mutex::new(State::default()),
+            finished: Condvar::new(),
+            refusals: AtomicU64::new(0),
+        }
+    }
+
+    // Taking a turn once one is free, or giving up when the queue is
+    // full or the wait runs out
+    pub fn acquire(&self) -> Option<Slot<'_>> {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return Some(Slot { limit: self, counted: false }),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        if state.running >= limit {
+            if state.waiting >= self.queue {
+                self.refusals.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+
+            state.waiting += 1;
+            let deadline = Instant::now() + self.wait;
+            while state.running >= limit {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    state.waiting -= 1;
+                    self.refusals.fetch_add(1, Ordering::Relaxed);
+                    return None;
+                }
+                state = self.finished.wait_timeout(state, remaining).unwrap().0;
+            }
+            state.waiting -= 1;
+        }
+
+        state.running += 1;
+        Some(Slot { limit: self, counted: true })
+    }
+
+    // How many jobs are being processed, and how many are queued
+    pub fn running(&self) -> usize {
+        self.state.lock().unwrap().running
+    }
+
+    pub fn waiting(&self) -> usize {
+        self.state.lock().unwrap().waiting
+    }
+
+    // How many jobs have been refused for want of a turn
+    pub fn refusals(&self) -> u64 {
+        self.refusals.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,104 @@
+use std::io::Read;
+use std::time::Duration;
+use image::{DynamicImage, GenericImageView};
+use image::imageops::FilterType;
+
+use crate::{encode_image, Transform};
+
+
+// Cutting images out of their backgrounds, as a transform asked for by
+// "?removebg=true", by POSTing each one as a PNG to a service that answers
+// with the same image, its background made transparent. Cutouts are
+// cached like any other variant, so a service is only asked once for each
+#[derive(Clone, Debug)]
+pub struct BackgroundRemovalConfig {
+    // "http://localhost:7000/api/remove"
+    pub url: String,
+    // Sent as the x-api-key header, for services that need one
+    pub api_key: Option<String>,
+    pub timeout: Duration,
+}
+
+impl BackgroundRemovalConfig {
+    pub fn new(url: &str) -> Self {
+        Self { url: url.to_string(), api_key: None, timeout: Duration::from_secs(30) }
+    }
+
+    pub fn with_api_key(mut self, key: &str) -> Self {
+        self.api_key = Some(key.to_string());
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+// The largest cutout that's read from a service
+const MAX_CUTOUT_BYTES: u64 = 64 * 1024 * 1024;
+
+pub struct RemoveBackground {
+    config: BackgroundRemovalConfig,
+    agent: ureq::Agent,
+}
+
+impl RemoveBackground {
+    pub fn new(config: BackgroundRemovalConfig) -> Self {
+        let agent = ureq::AgentBuilder::new().timeout(config.timeout).build();
+        Self { config, agent }
+    }
+
+    fn cut_out(&self, image: &DynamicImage) -> Result<DynamicImage, String> {
+        let png = encode_image(image, "png").map_err(|failure| failure.to_string())?;
+
+        let mut request = self.agent.post(&self.config.url).set("content-type", "image/png");
+        if let Some(key) = &self.config.api_key {
+            request = request.set("x-api-key", key);
+        }
+        let response = request.send_bytes(&png).map_err(|e| match e {
+            ureq::Error::Status(status, _) => format!("the background removal service responded {}", status),
+            ureq::Error::Transport(transport) => format!("the background removal service could not be reached: {}", transport),
+        })?;
+
+        let mut contents = vec![];
+        response.into_reader()
+            .take(MAX_CUTOUT_BYTES + 1)
+            .read_to_end(&mut contents)
+            .map_err(|e| format!("the background removal service's response could not be read: {}", e))?;
+        if contents.len() as u64 > MAX_CUTOUT_BYTES {
+            return Err("the background removal service responded with too large an image".to_string());
+        }
+
+        let cutout = image::load_from_memory(&contents)
+            .map_err(|_| "the background removal service responded with something other than an image".to_string())?;
+
+        // Keeping to the size the image was given at, for services that
+        // work at a size of their own
+        let (width, height) = image.dimensions();
+        match cutout.dimensions() == (width, height) {
+            true => Ok(DynamicImage::ImageRgba8(cutout.to_rgba8())),
+            false => Ok(DynamicImage::ImageRgba8(cutout.resize_exact(width, height, FilterType::Triangle).to_rgba8())),
+        }
+    }
+}
+
+impl Transform for RemoveBackground {
+    fn name(&self) -> &str {
+        "removebg"
+    }
+
+    fn check(&self, value: &str) -> Result<(), String> {
+        match value {
+            "true" | "false" => Ok(()),
+            _ => Err("removebg must be true or false".to_string()),
+        }
+    }
+
+    fn try_apply(&self, image: DynamicImage, value: &str) -> Result<DynamicImage, String> {
+        match value {
+            "true" => self.cut_out(&image),
+            _ => Ok(image),
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde::Serialize;
+
+use crate::storage::{is_partial_write, OpenedFile, Storage, StoredFile};
+
+
+enum Job {
+    Copy(String),
+    Delete(String),
+}
+
+// What reconciling a replica with its primary changed
+#[derive(Serialize, Debug, Default)]
+pub struct ReconcileResult {
+    pub copied: usize,
+    pub removed: usize,
+    pub failed: Vec<String>,
+}
+
+// Keeping every file in a primary backend, while mirroring masters (and
+// optionally everything derived from them) to a replica for disaster
+// recovery. Changes are copied in the background, so saving only waits
+// for the primary. Anything missed, like changes made while the replica
+// was unreachable, is caught up with by `reconcile`
+pub struct ReplicatedStorage<P: Storage, R: Storage> {
+    primary: Arc<P>,
+    replica: Arc<R>,
+    uploads_dir: String,
+    derivatives: bool,
+    jobs: Sender<Job>,
+    // How many jobs are queued or being copied
+    outstanding: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl<P: Storage + 'static, R: Storage + 'static> ReplicatedStorage<P, R> {
+    pub fn new(primary: P, replica: R, uploads_dir: &str) -> Self {
+        let primary = Arc::new(primary);
+        let replica = Arc::new(replica);
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let outstanding = Arc::new((Mutex::new(0), Condvar::new()));
+
+        let (source, destination) = (primary.clone(), replica.clone());
+        let finished = outstanding.clone();
+        thread::spawn(move || {
+            for job in queue {
+                if let Err((path, e)) = replicate(&*source, &*destination, job) {
+                    tracing::error!(%path, error = %e, "replication failed");
+                }
+                let (count, changed) = &*finished;
+                *count.lock().unwrap() -= 1;
+                changed.notify_all();
+            }
+        });
+
+        Self {
+            primary,
+            replica,
+            uploads_dir: uploads_dir.trim_end_matches('/').to_string(),
+            derivatives: false,
+            jobs,
+            outstanding,
+        }
+    }
+
+    // Mirroring cached variants, placeholders and analyses too, so that
+    // they don't all have to be produced again after a failover
+    pub fn with_derivatives(mut self) -> Self {
+        self.derivatives = true;
+        self
+    }
+
+    // "./images/cat.webp" -> true
+    // "./images/.variants/4a9c…7e58.png" -> false, unless mirroring derivatives
+    // Upload metadata, and the names of content addressed masters, are
+    // mirrored along with the masters themselves
+    fn is_replicated(&self, path: &str) -> bool {
+        if is_partial_write(path) { return false; }
+        if self.derivatives { return true; }
+
+        let relative = match path.strip_prefix(&self.uploads_dir).and_then(|rest| rest.strip_prefix('/')) {
+            Some(relative) => relative,
+            None => return false,
+        };
+
+        let mut components = Path::new(relative).components().map(|c| c.as_os_str().to_string_lossy().to_string());
+        match components.next() {
+            Some(first) if first == ".meta" || first == ".names" || first == ".blobs" => true,
+            Some(first) => !first.starts_with('.') && components.all(|c| !c.starts_with('.')),
+            None => false,
+        }
+    }
+
+    fn enqueue(&self, job: Job) {
+        let (count, _) = &*self.outstanding;
+        let mut count = count.lock().unwrap();
+
+        // The worker only stops along with the process
+        if self.jobs.send(job).is_ok() {
+            *count += 1;
+        }
+    }
+
+    // Copying whatever the replica is missing or has an outdated copy of,
+    // and removing whatever was deleted from the primary, within the
+    // uploads directory
+    pub fn reconcile(&self) -> ReconcileResult {
+        let mut result = ReconcileResult::default();
+
+        let originals: HashSet<String> = self.primary.list(&self.uploads_dir)
+            .into_iter()
+            .filter(|path| self.is_replicated(path))
+            .collect();
+
+        for path in &originals {
+            let original = self.primary.metadata(path);
+            let copy = self.replica.metadata(path);
+            let outdated = match (original, copy) {
+                (Some(original), Some(copy)) => {
+                    original.size != copy.size || matches!(
+                        (original.modified, copy.modified),
+                        (Some(original), Some(copy)) if original > copy
+                    )
+                }
+                (Some(_), None) => true,
+                (None, _) => false,
+            };
+            if !outdated { continue; }
+
+            match replicate(&*self.primary, &*self.replica, Job::Copy(path.clone())) {
+                Ok(_) => result.copied += 1,
+                Err((path, _)) => result.failed.push(path),
+            }
+        }
+
+        for path in self.replica.list(&self.uploads_dir) {
+            if originals.contains(&path) || !self.is_replicated(&path) { continue; }
+
+            match self.replica.delete(&path) {
+                Ok(_) => result.removed += 1,
+                Err(_) => result.failed.push(path),
+            }
+        }
+
+        result
+    }
+}
+
+// Copying the primary's current contents, rather than those given when
+// the job was queued, so that a burst of changes ends with the last one
+fn replicate<P: Storage + ?Sized, R: Storage + ?Sized>(
+    primary: &P,
+    replica: &R,
+    job: Job,
+) -> Result<(), (String, std::io::Error)> {
+    match job {
+        Job::Copy(path) => match primary.get(&path) {
+            Ok(contents) => replica.put(&path, &contents).map_err(|e| (path, e)),
+            // Deleted since, which a delete job follows
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err((path, e)),
+        },
+        Job::Delete(path) => match replica.delete(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err((path, e)),
+            _ => Ok(()),
+        },
+    }
+}
+
+impl<P: Storage + 'static, R: Storage + 'static> Storage for ReplicatedStorage<P, R> {
+    fn get(&self, path: &str) -> std::io::Result<Vec<u8>> {
+        self.primary.get(path)
+    }
+
+    fn put(&self, path: &str, contents: &[u8]) -> std::io::Result<()> {
+        self.primary.put(path, contents)?;
+        if self.is_replicated(path) {
+            self.enqueue(Job::Copy(path.to_string()));
+        }
+        Ok(())
+    }
+
+    fn put_new(&self, path: &str, contents: &[u8]) -> std::io::Result<()> {
+        self.primary.put_new(path, contents)?;
+        if self.is_replicated(path) {
+            self.enqueue(Job::Copy(path.to_string()));
+        }
+        Ok(())
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.primary.exists(path)
+    }
+
+    fn delete(&self, path: &str) -> std::io::Result<()> {
+        self.primary.delete(path)?;
+        if self.is_replicated(path) {
+            self.enqueue(Job::Delete(path.to_string()));
+        }
+        Ok(())
+    }
+
+    fn list(&self, dir: &str) -> Vec<String> {
+        self.primary.list(dir)
+    }
+
+    fn metadata(&self, path: &str) -> Option<StoredFile> {
+        self.primary.metadata(path)
+    }
+
+    fn open(&self, path: &str) -> Option<OpenedFile> {
+        self.primary.open(path)
+    }
+
+    fn streams_incrementally(&self) -> bool {
+        self.primary.streams_incrementally()
+    }
+
+    // Only the primary is needed to serve requests
+    fn check_writable(&self, dir: &str) -> std::io::Result<()> {
+        self.primary.check_writable(dir)
+    }
+
+    // Letting queued changes reach the replica before shutting down, so
+    // that there's less for `reconcile` to catch up with
+    fn flush(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let (count, changed) = &*self.outstanding;
+        let mut count = count.lock().unwrap();
+
+        while *count > 0 {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() { break; }
+            count = changed.wait_timeout(count, remaining).unwrap().0;
+        }
+        *count
+    }
+}
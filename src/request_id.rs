@@ -0,0 +1,63 @@
+use actix_web::{HttpMessage, HttpRequest};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use uuid::Uuid;
+
+
+// An identifier for a single request, shared with the client and
+// included in logs, so that reports of failures can be traced
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Accepting IDs assigned by upstream proxies, as long as they're
+// short and printable enough to be safely echoed and logged
+fn is_acceptable(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 128 && id.chars().all(|c| c.is_ascii_graphic())
+}
+
+// Propagating the incoming `X-Request-Id`, or generating a new one,
+// and making it available to handlers
+pub fn assign(req: &ServiceRequest) -> RequestId {
+    let id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_acceptable(id))
+        .map(|id| id.to_string())
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    let request_id = RequestId(id);
+    req.extensions_mut().insert(request_id.clone());
+    request_id
+}
+
+// Echoing the request ID to the client, and logging failures against it
+pub fn attach<B>(request_id: &RequestId, res: &mut ServiceResponse<B>) {
+    if let Ok(value) = HeaderValue::from_str(&request_id.0) {
+        res.headers_mut().insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+
+    let status = res.status();
+    let req = res.request();
+    match status.as_u16() {
+        500..=599 => tracing::error!(%request_id, method = %req.method(), path = req.path(), status = status.as_u16(), "request failed"),
+        400..=499 => tracing::info!(%request_id, method = %req.method(), path = req.path(), status = status.as_u16(), "request refused"),
+        _ => (),
+    }
+}
+
+// The ID assigned to a request by `assign`
+pub fn request_id(req: &HttpRequest) -> RequestId {
+    req.extensions()
+        .get::<RequestId>()
+        .cloned()
+        .unwrap_or_else(|| RequestId(Uuid::new_v4().to_string()))
+}
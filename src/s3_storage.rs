@@ -0,0 +1,251 @@
+use std::io::{Error, ErrorKind, Read};
+use std::time::SystemTime;
+use actix_web::http::header::HttpDate;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::clock::UtcTime;
+use crate::storage::{Storage, StoredFile};
+
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Where objects are kept, and the credentials used to sign requests
+#[derive(Clone, Debug)]
+pub struct S3Config {
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    // An S3 compatible service like MinIO, addressed with path-style URLs
+    pub endpoint: Option<String>,
+}
+
+impl S3Config {
+    pub fn new(bucket: &str, region: &str, access_key: &str, secret_key: &str) -> Self {
+        Self {
+            bucket: bucket.to_string(),
+            region: region.to_string(),
+            access_key: access_key.to_string(),
+            secret_key: secret_key.to_string(),
+            endpoint: None,
+        }
+    }
+
+    // "http://localhost:9000"
+    pub fn with_endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = Some(endpoint.trim_end_matches('/').to_string());
+        self
+    }
+}
+
+// Keeping every file as an object in an S3 bucket, named after its path
+pub struct S3Storage {
+    config: S3Config,
+    agent: ureq::Agent,
+}
+
+impl S3Storage {
+    pub fn new(config: S3Config) -> Self {
+        Self { config, agent: ureq::Agent::new() }
+    }
+
+    // "https://bucket.s3.us-east-1.amazonaws.com" -> "bucket.s3.us-east-1.amazonaws.com", ""
+    // The host requests are sent to, and the path the bucket is found at
+    fn host_and_prefix(&self) -> (String, String, String) {
+        match &self.config.endpoint {
+            Some(endpoint) => {
+                let (scheme, host) = endpoint.split_once("://").unwrap_or(("https", endpoint));
+                (scheme.to_string(), host.to_string(), format!("/{}", self.config.bucket))
+            }
+            None => {
+                let host = format!("{}.s3.{}.amazonaws.com", self.config.bucket, self.config.region);
+                ("https".to_string(), host, String::new())
+            }
+        }
+    }
+
+    // Sending a request signed with AWS Signature Version 4
+    fn send(
+        &self,
+        method: &str,
+        key: &str,
+        query: &[(&str, &str)],
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> std::io::Result<ureq::Response> {
+        let (scheme, host, prefix) = self.host_and_prefix();
+        let uri = format!("{}/{}", prefix, uri_encode(key, false));
+
+        let mut query: Vec<(String, String)> = query
+            .iter()
+            .map(|(name, value)| (uri_encode(name, true), uri_encode(value, true)))
+            .collect();
+        query.sort();
+        let query = query.iter().map(|(name, value)| format!("{}={}", name, value)).collect::<Vec<_>>().join("&");
+
+        let timestamp = amz_timestamp(SystemTime::now());
+        let date = &timestamp[..8];
+        let payload_hash = format!("{:x}", Sha256::digest(body));
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\nhost;x-amz-content-sha256;x-amz-date\n{}",
+            method, uri, query, host, payload_hash, timestamp, payload_hash,
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.config.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{:x}",
+            timestamp, scope, Sha256::digest(canonical_request.as_bytes()),
+        );
+
+        let signing_key = [self.config.region.as_str(), "s3", "aws4_request"]
+            .iter()
+            .fold(hmac(format!("AWS4{}", self.config.secret_key).as_bytes(), date), |key, part| hmac(&key, part));
+        let signature = hex(&hmac(&signing_key, &string_to_sign));
+
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+            self.config.access_key, scope, signature,
+        );
+
+        let url = match query.is_empty() {
+            true => format!("{}://{}{}", scheme, host, uri),
+            false => format!("{}://{}{}?{}", scheme, host, uri, query),
+        };
+
+        let request = self.agent.request(method, &url)
+            .set("x-amz-content-sha256", &payload_hash)
+            .set("x-amz-date", &timestamp)
+            .set("authorization", &authorization);
+        headers.iter()
+            .fold(request, |request, (name, value)| request.set(name, value))
+            .send_bytes(body)
+            .map_err(as_io_error)
+    }
+}
+
+// "./images/cat.webp" -> "images/cat.webp"
+fn object_key(path: &str) -> &str {
+    path.trim_start_matches("./").trim_start_matches('/')
+}
+
+fn hmac(key: &[u8], message: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// "images/a b.webp" -> "images/a%20b.webp"
+// Slashes are only encoded within query string values
+fn uri_encode(value: &str, encode_slash: bool) -> String {
+    value.bytes().map(|byte| match byte {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+        b'/' if !encode_slash => "/".to_string(),
+        _ => format!("%{:02X}", byte),
+    }).collect()
+}
+
+// 2024-03-05 14:07:09 UTC -> "20240305T140709Z"
+fn amz_timestamp(time: SystemTime) -> String {
+    let t = UtcTime::from_system_time(time);
+    format!("{:04}{:02}{:02}T{:02}{:02}{:02}Z", t.year, t.month, t.day, t.hour, t.minute, t.second)
+}
+
+// "<Key>a</Key><Key>b</Key>", "Key" -> ["a", "b"]
+fn xml_values<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    xml.split(open.as_str())
+        .skip(1)
+        .filter_map(|rest| rest.split(close.as_str()).next())
+        .collect()
+}
+
+fn xml_unescape(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+fn as_io_error(e: ureq::Error) -> Error {
+    match e {
+        ureq::Error::Status(404, _) => Error::new(ErrorKind::NotFound, "No such object"),
+        ureq::Error::Status(412, _) => Error::new(ErrorKind::AlreadyExists, "The object already exists"),
+        ureq::Error::Status(code, _) => Error::other(format!("S3 responded {}", code)),
+        ureq::Error::Transport(transport) => Error::other(transport.to_string()),
+    }
+}
+
+impl Storage for S3Storage {
+    fn get(&self, path: &str) -> std::io::Result<Vec<u8>> {
+        let _span = tracing::info_span!("storage.get", path, backend = "s3").entered();
+        let response = self.send("GET", object_key(path), &[], &[], b"")?;
+        let mut contents = Vec::new();
+        response.into_reader().read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    // Objects are replaced whole, so readers see either version in full
+    fn put(&self, path: &str, contents: &[u8]) -> std::io::Result<()> {
+        let _span = tracing::info_span!("storage.put", path, bytes = contents.len(), backend = "s3").entered();
+        self.send("PUT", object_key(path), &[], &[], contents).map(|_| ())
+    }
+
+    // Writing conditionally, which S3 refuses with 412 if the object exists
+    fn put_new(&self, path: &str, contents: &[u8]) -> std::io::Result<()> {
+        let _span = tracing::info_span!("storage.put_new", path, bytes = contents.len(), backend = "s3").entered();
+        self.send("PUT", object_key(path), &[], &[("if-none-match", "*")], contents).map(|_| ())
+    }
+
+    fn delete(&self, path: &str) -> std::io::Result<()> {
+        let _span = tracing::info_span!("storage.delete", path, backend = "s3").entered();
+        self.send("DELETE", object_key(path), &[], &[], b"").map(|_| ())
+    }
+
+    fn list(&self, dir: &str) -> Vec<String> {
+        let prefix = format!("{}/", object_key(dir).trim_end_matches('/'));
+        let leading = &dir[..dir.len() - dir.trim_start_matches("./").trim_start_matches('/').len()];
+
+        let mut paths = vec![];
+        let mut continuation: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", prefix.as_str())];
+            if let Some(token) = &continuation {
+                query.push(("continuation-token", token.as_str()));
+            }
+
+            let body = match self.send("GET", "", &query, &[], b"").map(|response| response.into_string()) {
+                Ok(Ok(body)) => body,
+                _ => return paths,
+            };
+
+            // Keys are turned back into paths like the ones they were saved with
+            paths.extend(xml_values(&body, "Key").into_iter().map(|key| format!("{}{}", leading, xml_unescape(key))));
+
+            continuation = match xml_values(&body, "IsTruncated").first() {
+                Some(&"true") => xml_values(&body, "NextContinuationToken").first().map(|token| xml_unescape(token)),
+                _ => None,
+            };
+            if continuation.is_none() {
+                return paths;
+            }
+        }
+    }
+
+    fn metadata(&self, path: &str) -> Option<StoredFile> {
+        let response = self.send("HEAD", object_key(path), &[], &[], b"").ok()?;
+        let size = response.header("content-length").and_then(|length| length.parse().ok())?;
+        let modified = response.header("last-modified")
+            .and_then(|date| date.parse::<HttpDate>().ok())
+            .map(SystemTime::from);
+
+        Some(StoredFile { size, modified, accessed: None })
+    }
+}
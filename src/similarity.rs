@@ -0,0 +1,108 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Serialize, Deserialize};
+
+use crate::{analysis, auth, run_blocking, ServerConfig};
+use crate::analysis::ImageName;
+use crate::warming::list_masters;
+
+
+// Finding uploads that look alike, by how many of the 64 bits of their
+// difference hashes differ. Copies that were only resized or recompressed
+// differ by a few bits at most, while unrelated images differ by about
+// half of them
+pub const DEFAULT_THRESHOLD: u32 = 10;
+const MAX_THRESHOLD: u32 = 32;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SimilarUpload {
+    pub filename: String,
+    // How many bits of their hashes differ, from 0 to 64
+    pub distance: u32,
+}
+
+// The difference hash of every upload, from the metadata index when there
+// is one, and by going through every master otherwise
+fn every_dhash(config: &ServerConfig) -> Vec<(String, u64)> {
+    #[cfg(feature = "sqlite")]
+    if let Some(index) = &config.metadata_index {
+        match index.dhashes() {
+            Ok(hashes) => return hashes,
+            Err(e) => tracing::warn!(error = %e, "failed to read hashes from the metadata index, so every master is hashed instead"),
+        }
+    }
+
+    list_masters(config)
+        .into_iter()
+        .filter_map(|filename| analysis::cached_dhash(config, &filename).ok().map(|hash| (filename, hash)))
+        .collect()
+}
+
+// The uploads besides `filename` whose hashes are within `threshold` bits
+// of `hash`, the most alike first
+pub fn similar_to(config: &ServerConfig, filename: &str, hash: u64, threshold: u32) -> Vec<SimilarUpload> {
+    let mut similar: Vec<SimilarUpload> = every_dhash(config)
+        .into_iter()
+        .filter(|(other, _)| other != filename)
+        .map(|(other, other_hash)| SimilarUpload { filename: other, distance: (hash ^ other_hash).count_ones() })
+        .filter(|upload| upload.distance <= threshold)
+        .collect();
+
+    similar.sort_by(|a, b| a.distance.cmp(&b.distance).then_with(|| a.filename.cmp(&b.filename)));
+    similar
+}
+
+// Near-duplicates of a new upload, which are only looked for when there's
+// a metadata index, rather than hashing every master on every upload
+pub fn near_duplicates_of(config: &ServerConfig, filename: &str) -> Vec<SimilarUpload> {
+    #[cfg(feature = "sqlite")]
+    if config.metadata_index.is_some() {
+        if let Ok(hash) = analysis::cached_dhash(config, filename) {
+            return similar_to(config, filename, hash, DEFAULT_THRESHOLD);
+        }
+    }
+    #[cfg(not(feature = "sqlite"))]
+    let _ = (config, filename);
+    vec![]
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SimilarInstructions {
+    threshold: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct Similar {
+    filename: String,
+    dhash: String,
+    similar: Vec<SimilarUpload>,
+}
+
+// Respond to a request for the uploads that look like an image, like
+// `GET /cat/similar?threshold=6`
+pub async fn serve_similar_via_http(
+    req: HttpRequest,
+    name: web::Path<ImageName>,
+    instructions: web::Query<SimilarInstructions>,
+    config: web::Data<ServerConfig>,
+) -> HttpResponse {
+    if let Err(failure) = auth::authorize_upload(&req, &config, auth::Access::Read, &name.filename) {
+        return failure.as_http_response();
+    }
+
+    let threshold = instructions.threshold.unwrap_or(DEFAULT_THRESHOLD);
+    if threshold > MAX_THRESHOLD {
+        return HttpResponse::BadRequest().body(format!("The threshold must be between 0 and {}", MAX_THRESHOLD));
+    }
+
+    let filename = name.into_inner().filename;
+    let found = run_blocking(move || {
+        let hash = analysis::cached_dhash(&config, &filename)?;
+        let similar = similar_to(&config, &filename, hash, threshold);
+        Ok(Similar { filename, dhash: format!("{:016x}", hash), similar })
+    }).await;
+
+    match found {
+        Ok(similar) => HttpResponse::Ok().json(similar),
+        Err(failure) => failure.as_http_response(&req),
+    }
+}
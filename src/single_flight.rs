@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+
+
+enum State<T> {
+    Running,
+    Done(T),
+    // The leader panicked before producing a result
+    Abandoned,
+}
+
+struct Flight<T> {
+    state: Mutex<State<T>>,
+    finished: Condvar,
+}
+
+// Finishing a flight even if its leader panics, so waiters aren't
+// left waiting forever
+struct Landing<'a, T> {
+    flights: &'a SingleFlight<T>,
+    key: &'a str,
+    flight: Arc<Flight<T>>,
+}
+
+impl<'a, T> Drop for Landing<'a, T> {
+    fn drop(&mut self) {
+        self.flights.in_flight.lock().unwrap().remove(self.key);
+
+        let mut state = self.flight.state.lock().unwrap();
+        if let State::Running = *state {
+            *state = State::Abandoned;
+        }
+        self.flight.finished.notify_all();
+    }
+}
+
+// Coalescing concurrent work with the same key, so that only the first
+// caller does it, while the rest wait for and share its result
+pub struct SingleFlight<T> {
+    in_flight: Mutex<HashMap<String, Arc<Flight<T>>>>,
+}
+
+impl<T: Clone> SingleFlight<T> {
+    pub fn new() -> Self {
+        Self { in_flight: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn run<F: FnOnce() -> T>(&self, key: &str, work: F) -> T {
+        let (flight, leading) = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(key) {
+                Some(flight) => (flight.clone(), false),
+                None => {
+                    let flight = Arc::new(Flight {
+                        state: Mutex::new(State::Running),
+                        finished: Condvar::new(),
+                    });
+                    in_flight.insert(key.to_string(), flight.clone());
+                    (flight, true)
+                }
+            }
+        };
+
+        if leading {
+            let landing = Landing { flights: self, key, flight };
+            let result = work();
+            *landing.flight.state.lock().unwrap() = State::Done(result.clone());
+            return result;
+        }
+
+        // Waiting for the leader, or doing the work after all if it failed
+        let mut state = flight.state.lock().unwrap();
+        loop {
+            match &*state {
+                State::Running => state = flight.finished.wait(state).unwrap(),
+                State::Done(result) => return result.clone(),
+                State::Abandoned => {
+                    drop(state);
+                    return work();
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use std::io::{Error, ErrorKind};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension};
+
+use crate::storage::{Storage, StoredFile};
+
+
+// Keeping every file as a blob in a single SQLite database, which is
+// easier to back up and copy around than thousands of loose files
+pub struct SqliteStorage {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    // "/var/lib/imogen/images.sqlite", created if it doesn't exist
+    pub fn open(path: &str) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            CREATE TABLE IF NOT EXISTS files (
+                path TEXT PRIMARY KEY,
+                contents BLOB NOT NULL,
+                modified INTEGER NOT NULL,
+                accessed INTEGER NOT NULL
+            );",
+        )?;
+
+        Ok(Self { connection: Mutex::new(connection) })
+    }
+}
+
+// Times are stored as nanoseconds since the epoch, since variants are
+// compared with masters replaced within the same second
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as i64)
+        .unwrap_or(0)
+}
+
+fn as_time(nanos: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_nanos(nanos.max(0) as u64)
+}
+
+fn as_io_error(e: rusqlite::Error) -> Error {
+    Error::other(e)
+}
+
+impl Storage for SqliteStorage {
+    fn get(&self, path: &str) -> std::io::Result<Vec<u8>> {
+        let _span = tracing::info_span!("storage.get", path, backend = "sqlite").entered();
+        let connection = self.connection.lock().unwrap();
+        let contents: Option<Vec<u8>> = connection
+            .query_row("SELECT contents FROM files WHERE path = ?1", params![path], |row| row.get(0))
+            .optional()
+            .map_err(as_io_error)?;
+
+        match contents {
+            Some(contents) => {
+                // Recording the read, so that recently used variants don't expire
+                connection
+                    .execute("UPDATE files SET accessed = ?1 WHERE path = ?2", params![now(), path])
+                    .map_err(as_io_error)?;
+                Ok(contents)
+            }
+            None => Err(Error::new(ErrorKind::NotFound, format!("{} is not stored", path))),
+        }
+    }
+
+    // Replacing a row is atomic, so readers see either version in full
+    fn put(&self, path: &str, contents: &[u8]) -> std::io::Result<()> {
+        let _span = tracing::info_span!("storage.put", path, bytes = contents.len(), backend = "sqlite").entered();
+        let time = now();
+        self.connection.lock().unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO files (path, contents, modified, accessed) VALUES (?1, ?2, ?3, ?4)",
+                params![path, contents, time, time],
+            )
+            .map(|_| ())
+            .map_err(as_io_error)
+    }
+
+    fn put_new(&self, path: &str, contents: &[u8]) -> std::io::Result<()> {
+        let _span = tracing::info_span!("storage.put_new", path, bytes = contents.len(), backend = "sqlite").entered();
+        let time = now();
+        let inserted = self.connection.lock().unwrap().execute(
+            "INSERT INTO files (path, contents, modified, accessed) VALUES (?1, ?2, ?3, ?4)",
+            params![path, contents, time, time],
+        );
+        match inserted {
+            Ok(_) => Ok(()),
+            Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == ErrorCode::ConstraintViolation => {
+                Err(Error::new(ErrorKind::AlreadyExists, "The file already exists"))
+            }
+            Err(e) => Err(as_io_error(e)),
+        }
+    }
+
+    fn delete(&self, path: &str) -> std::io::Result<()> {
+        let _span = tracing::info_span!("storage.delete", path, backend = "sqlite").entered();
+        let deleted = self.connection.lock().unwrap()
+            .execute("DELETE FROM files WHERE path = ?1", params![path])
+            .map_err(as_io_error)?;
+
+        match deleted {
+            0 => Err(Error::new(ErrorKind::NotFound, format!("{} is not stored", path))),
+            _ => Ok(()),
+        }
+    }
+
+    fn list(&self, dir: &str) -> Vec<String> {
+        let prefix = format!("{}/", dir.trim_end_matches('/'));
+        let connection = self.connection.lock().unwrap();
+        let mut statement = match connection.prepare("SELECT path FROM files WHERE substr(path, 1, ?1) = ?2") {
+            Ok(statement) => statement,
+            Err(_) => return vec![],
+        };
+
+        let paths = statement.query_map(params![prefix.len() as i64, prefix], |row| row.get(0));
+        match paths {
+            Ok(paths) => paths.filter_map(|path| path.ok()).collect(),
+            Err(_) => vec![],
+        }
+    }
+
+    fn metadata(&self, path: &str) -> Option<StoredFile> {
+        let connection = self.connection.lock().unwrap();
+        connection
+            .query_row(
+                "SELECT length(contents), modified, accessed FROM files WHERE path = ?1",
+                params![path],
+                |row| {
+                    Ok(StoredFile {
+                        size: row.get::<_, i64>(0)? as u64,
+                        modified: Some(as_time(row.get(1)?)),
+                        accessed: Some(as_time(row.get(2)?)),
+                    })
+                },
+            )
+            .ok()
+    }
+
+    // There are no directories to go missing, only the database itself
+    fn check_writable(&self, dir: &str) -> std::io::Result<()> {
+        let probe = format!("{}/.healthz", dir);
+        self.put(&probe, b"ok").and(self.delete(&probe))
+    }
+}
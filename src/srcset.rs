@@ -0,0 +1,168 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Serialize, Deserialize};
+
+use crate::{
+    auth,
+    build_path_to_master,
+    run_blocking,
+    Caches,
+    ImageServiceFailure,
+    ServerConfig,
+};
+use crate::analysis::ImageName;
+use crate::pipeline::Sampling;
+use crate::warming::{self, WarmingPlan};
+
+
+// The most widths one manifest may list
+const MAX_WIDTHS: usize = 16;
+
+#[derive(Deserialize, Debug)]
+pub struct SrcsetInstructions {
+    // "320,640,1280"
+    widths: String,
+    // A format like "webp", or "auto" to leave it to format negotiation
+    format: Option<String>,
+    q: Option<u8>,
+    sampling: Option<String>,
+    // "string" for the `srcset` attribute alone, rather than JSON
+    #[serde(rename = "as")]
+    output: Option<String>,
+    // Producing every variant in the background, so that they're cached
+    // by the time a browser asks for them
+    #[serde(default)]
+    pregenerate: bool,
+}
+
+#[derive(Serialize)]
+struct Variant {
+    width: u32,
+    url: String,
+}
+
+#[derive(Serialize)]
+struct Manifest {
+    filename: String,
+    srcset: String,
+    variants: Vec<Variant>,
+    pregenerating: bool,
+}
+
+// "1280,320,640,320" -> [320, 640, 1280]
+fn parse_widths(widths: &str) -> Result<Vec<u32>, String> {
+    let mut parsed = widths
+        .split(',')
+        .map(|width| width.trim().parse::<u32>().ok().filter(|width| *width > 0))
+        .collect::<Option<Vec<u32>>>()
+        .ok_or_else(|| format!("{:?} isn't a list of widths, like 320,640,1280", widths))?;
+
+    parsed.sort_unstable();
+    parsed.dedup();
+    match parsed.len() > MAX_WIDTHS {
+        true => Err(format!("At most {} widths can be listed", MAX_WIDTHS)),
+        false => Ok(parsed),
+    }
+}
+
+// The canonical query string of each variant, as it's cached
+// q=80, 320 -> "q=80&w=320"
+fn variant_query(instructions: &SrcsetInstructions, width: u32) -> String {
+    let mut pairs = vec![];
+    if let Some(q) = instructions.q { pairs.push(format!("q={}", q)); }
+    if let Some(sampling) = instructions.sampling.as_deref().filter(|sampling| *sampling != "nearest") {
+        pairs.push(format!("sampling={}", sampling));
+    }
+    pairs.push(format!("w={}", width));
+    pairs.join("&")
+}
+
+// Respond with the URL of an image at each of several widths, for
+// templates to put in an `<img srcset>` rather than building them
+pub async fn serve_srcset_via_http(
+    req: HttpRequest,
+    name: web::Path<ImageName>,
+    instructions: web::Query<SrcsetInstructions>,
+    config: web::Data<ServerConfig>,
+    caches: web::Data<Caches>,
+) -> HttpResponse {
+    if let Err(failure) = auth::authorize_upload(&req, &config, auth::Access::Read, &name.filename) {
+        return failure.as_http_response();
+    }
+
+    if config.presets_only {
+        let message = "Only presets can be requested, so widths can't be listed";
+        return ImageServiceFailure::invalid_parameter("widths", message).as_http_response(&req);
+    }
+
+    let widths = match parse_widths(&instructions.widths) {
+        Ok(widths) => widths,
+        Err(message) => return ImageServiceFailure::invalid_parameter("widths", &message).as_http_response(&req),
+    };
+    if let Some(q) = instructions.q.filter(|q| !(1..=100).contains(q)) {
+        let message = format!("q must be between 1 and 100, not {}", q);
+        return ImageServiceFailure::invalid_parameter("q", &message).as_http_response(&req);
+    }
+    if let Some(sampling) = instructions.sampling.as_deref().filter(|sampling| Sampling::from_name(sampling).is_none()) {
+        let message = format!("{:?} isn't a sampling method. Try nearest, triangle, catmullrom, gaussian or lanczos3", sampling);
+        return ImageServiceFailure::invalid_parameter("sampling", &message).as_http_response(&req);
+    }
+
+    // Variants of `auto` are negotiated when they're requested, so every
+    // format they might be negotiated to is pregenerated
+    let format = instructions.format.clone().unwrap_or_else(|| "webp".to_string());
+    let extensions: Vec<&str> = match format.as_str() {
+        "auto" if config.format_negotiation => config.output_formats.iter().map(|format| format.as_str()).collect(),
+        "auto" => {
+            let message = "Formats can't be negotiated on this server. Try a format like webp";
+            return ImageServiceFailure::invalid_parameter("format", message).as_http_response(&req);
+        }
+        format if config.serves_format(format) => vec![format],
+        format => return config.unserved_format(format).as_http_response(&req),
+    };
+
+    if config.origin.is_none() {
+        let (checking, master) = (config.clone(), build_path_to_master(&config, &name.filename));
+        match run_blocking(move || Ok(checking.storage.exists(&master))).await {
+            Ok(true) => {}
+            Ok(false) => return ImageServiceFailure::ImageDoesNotExist.as_http_response(&req),
+            Err(failure) => return failure.as_http_response(&req),
+        }
+    }
+
+    let variants: Vec<Variant> = widths
+        .iter()
+        .map(|width| Variant {
+            width: *width,
+            url: format!("{}/{}.{}?{}", config.base_path, name.filename, format, variant_query(&instructions, *width)),
+        })
+        .collect();
+    let srcset = variants
+        .iter()
+        .map(|variant| format!("{} {}w", variant.url, variant.width))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    if instructions.pregenerate {
+        let plan = widths.iter().fold(
+            WarmingPlan::new().with_filename(&name.filename).with_extensions(&extensions),
+            |plan, width| plan.with_transformation(&variant_query(&instructions, *width)),
+        );
+        let (config, budget) = (config.clone(), caches.budget.clone());
+        caches.background.spawn(async move {
+            let warmed = run_blocking(move || Ok(warming::warm_cache(&config, &plan, &budget))).await;
+            for error in warmed.map(|result| result.errors).unwrap_or_default() {
+                tracing::warn!(error = %error, "a srcset variant could not be pregenerated");
+            }
+        });
+    }
+
+    match instructions.output.as_deref() {
+        Some("string") => HttpResponse::Ok().content_type("text/plain; charset=utf-8").body(srcset),
+        _ => HttpResponse::Ok().json(Manifest {
+            filename: name.filename.clone(),
+            srcset,
+            variants,
+            pregenerating: instructions.pregenerate,
+        }),
+    }
+}
@@ -0,0 +1,210 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use actix_files::{file_extension_to_mime, NamedFile};
+use actix_web::{HttpRequest, HttpResponse};
+use uuid::Uuid;
+
+
+// What's known about a stored file without reading it
+pub struct StoredFile {
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    pub accessed: Option<SystemTime>,
+}
+
+// A stored file opened to be served. Opening may block, so it's done on
+// the blocking threadpool, while responding is left to the HTTP worker
+pub enum OpenedFile {
+    // Read a piece at a time, honoring conditional and range requests
+    Local(Box<NamedFile>),
+    Contents { contents: Vec<u8>, content_type: String },
+}
+
+impl OpenedFile {
+    pub fn into_response(self, req: &HttpRequest) -> Option<HttpResponse> {
+        match self {
+            OpenedFile::Local(file) => file.into_response(req).ok(),
+            OpenedFile::Contents { contents, content_type } => Some(
+                HttpResponse::Ok()
+                    .header("content-type", content_type)
+                    .body(contents)
+            ),
+        }
+    }
+
+    // Fetching a file whole, for backends that can't do better
+    pub(crate) fn fetched(path: &str, contents: Vec<u8>) -> Self {
+        let extension = Path::new(path).extension().and_then(|e| e.to_str()).unwrap_or("");
+        OpenedFile::Contents { contents, content_type: file_extension_to_mime(extension).to_string() }
+    }
+}
+
+// Where uploads, and everything derived from them, are kept. Files are
+// addressed by paths built from the configured directories, like
+// "./images/cat.webp" or "./images/.variants/4a9c…7e58.png"
+pub trait Storage: Send + Sync {
+    fn get(&self, path: &str) -> std::io::Result<Vec<u8>>;
+
+    // Saving a file in full or not at all, so that readers never see a
+    // partially written one
+    fn put(&self, path: &str, contents: &[u8]) -> std::io::Result<()>;
+
+    // Saving a file only if there isn't one already, failing with
+    // `ErrorKind::AlreadyExists` otherwise. Backends that can't check and
+    // save in one step should override this, since two callers could
+    // otherwise both see no file and both save one
+    fn put_new(&self, path: &str, contents: &[u8]) -> std::io::Result<()> {
+        if self.exists(path) {
+            return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, "The file already exists"));
+        }
+        self.put(path, contents)
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.metadata(path).is_some()
+    }
+
+    fn delete(&self, path: &str) -> std::io::Result<()>;
+
+    // "./images" -> ["./images/cat.webp", "./images/4a/9c/dog.webp"]
+    // The files within a directory, including those in subdirectories
+    fn list(&self, dir: &str) -> Vec<String>;
+
+    fn metadata(&self, path: &str) -> Option<StoredFile>;
+
+    // Opening a file to respond with, fetching it whole unless the backend
+    // can do better
+    fn open(&self, path: &str) -> Option<OpenedFile> {
+        self.get(path).ok().map(|contents| OpenedFile::fetched(path, contents))
+    }
+
+    // Whether `open` reads files a piece at a time, rather than
+    // fetching them whole first
+    fn streams_incrementally(&self) -> bool {
+        false
+    }
+
+    // Writing and removing a scratch file, to prove files can be saved in `dir`
+    fn check_writable(&self, dir: &str) -> std::io::Result<()> {
+        let probe = format!("{}/.healthz-{}", dir, Uuid::new_v4());
+
+        let written = self.put(&probe, b"ok");
+        let removed = self.delete(&probe);
+        written.and(removed)
+    }
+
+    // Waiting up to `timeout` for writes the backend finishes in the
+    // background, returning how many didn't finish
+    fn flush(&self, _timeout: std::time::Duration) -> usize {
+        0
+    }
+}
+
+// "./images/cat.webp.4a9c…7e58.tmp" -> true
+// Whether a file is one `LocalStorage` is still writing, or one left
+// behind by a write that never finished, like when the server crashed
+pub fn is_partial_write(path: &str) -> bool {
+    path.ends_with(".tmp")
+}
+
+// Keeping everything on the local filesystem, which is the default
+pub struct LocalStorage;
+
+impl Storage for LocalStorage {
+    fn get(&self, path: &str) -> std::io::Result<Vec<u8>> {
+        let _span = tracing::info_span!("storage.get", path, backend = "local").entered();
+        // Allocating the whole file at once, rather than growing to fit it
+        fs::read(path)
+    }
+
+    // Writing to a temporary file that's renamed into place once complete
+    fn put(&self, path: &str, contents: &[u8]) -> std::io::Result<()> {
+        let _span = tracing::info_span!("storage.put", path, bytes = contents.len(), backend = "local").entered();
+        if let Some(dir) = Path::new(path).parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let temporary = format!("{}.{}.tmp", path, Uuid::new_v4());
+        let written = File::create(&temporary)
+            .and_then(|mut file| file.write_all(contents))
+            .and_then(|_| fs::rename(&temporary, path));
+
+        if written.is_err() {
+            let _ = fs::remove_file(&temporary);
+        }
+        written
+    }
+
+    // Linking a complete temporary file into place, which fails if there's
+    // a file there already
+    fn put_new(&self, path: &str, contents: &[u8]) -> std::io::Result<()> {
+        let _span = tracing::info_span!("storage.put_new", path, bytes = contents.len(), backend = "local").entered();
+        if let Some(dir) = Path::new(path).parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let temporary = format!("{}.{}.tmp", path, Uuid::new_v4());
+        let written = File::create(&temporary)
+            .and_then(|mut file| file.write_all(contents))
+            .and_then(|_| fs::hard_link(&temporary, path));
+
+        let _ = fs::remove_file(&temporary);
+        written
+    }
+
+    fn delete(&self, path: &str) -> std::io::Result<()> {
+        let _span = tracing::info_span!("storage.delete", path, backend = "local").entered();
+        fs::remove_file(path)
+    }
+
+    fn list(&self, dir: &str) -> Vec<String> {
+        let mut files = vec![];
+        let mut dirs = vec![PathBuf::from(dir)];
+
+        while let Some(dir) = dirs.pop() {
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+
+            for entry in entries.filter_map(|entry| entry.ok()) {
+                match entry.file_type() {
+                    Ok(t) if t.is_dir() => dirs.push(entry.path()),
+                    Ok(t) if t.is_file() => files.push(entry.path().to_string_lossy().to_string()),
+                    _ => {},
+                }
+            }
+        }
+
+        files
+    }
+
+    fn metadata(&self, path: &str) -> Option<StoredFile> {
+        let metadata = fs::metadata(path).ok()?;
+        Some(StoredFile {
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+            accessed: metadata.accessed().ok(),
+        })
+    }
+
+    fn open(&self, path: &str) -> Option<OpenedFile> {
+        NamedFile::open(path).ok().map(|file| OpenedFile::Local(Box::new(file)))
+    }
+
+    fn streams_incrementally(&self) -> bool {
+        true
+    }
+
+    fn check_writable(&self, dir: &str) -> std::io::Result<()> {
+        // Saving would create a missing directory, rather than reporting it
+        if !Path::new(dir).is_dir() {
+            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "No such directory"));
+        }
+
+        let probe = format!("{}/.healthz-{}", dir, Uuid::new_v4());
+        self.put(&probe, b"ok").and(self.delete(&probe))
+    }
+}
@@ -0,0 +1,82 @@
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+
+
+// A socket systemd opened on imogen's behalf, from a `.socket` unit
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+// The sockets passed by systemd socket activation, if any. They're
+// numbered from 3, and only meant for the process named by LISTEN_PID
+#[cfg(unix)]
+pub fn listeners() -> Vec<Listener> {
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+
+    let intended = std::env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+    let count = std::env::var("LISTEN_FDS").ok().and_then(|count| count.parse::<i32>().ok());
+
+    // Not passing them on to anything imogen starts
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    let count = match (intended, count) {
+        (Some(pid), Some(count)) if pid == std::process::id() => count,
+        _ => return vec![],
+    };
+
+    (3..3 + count)
+        .map(|fd| {
+            // Telling TCP sockets from unix ones by their address
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            match listener.local_addr() {
+                Ok(_) => Listener::Tcp(listener),
+                Err(_) => Listener::Unix(unsafe { UnixListener::from_raw_fd(listener.into_raw_fd()) }),
+            }
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+pub fn listeners() -> Vec<Listener> {
+    vec![]
+}
+
+// Telling systemd about the service's state, like "READY=1", when it
+// was started by a `Type=notify` unit. Otherwise there's no one to tell
+#[cfg(unix)]
+pub fn notify(state: &str) {
+    use std::os::unix::net::UnixDatagram;
+
+    let path = match std::env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+
+    let socket = match UnixDatagram::unbound() {
+        Ok(socket) => socket,
+        Err(_) => return,
+    };
+
+    // "@/org/freedesktop/systemd1/notify" names an abstract socket
+    let sent = match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            std::os::unix::net::SocketAddr::from_abstract_name(name)
+                .and_then(|address| socket.send_to_addr(state.as_bytes(), &address))
+        }
+        _ => socket.send_to(state.as_bytes(), &path),
+    };
+
+    if let Err(e) = sent {
+        tracing::warn!(state, error = %e, "failed to notify systemd");
+    }
+}
+
+#[cfg(not(unix))]
+pub fn notify(_state: &str) {}
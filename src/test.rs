@@ -0,0 +1,121 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use actix_web::http::header::HeaderMap;
+use actix_web::http::StatusCode;
+use actix_web::{test, App};
+
+use crate::{routes, ImageRoutes, ServerConfig};
+
+
+// Telling apart the directories of servers started by the same process
+static STARTED: AtomicUsize = AtomicUsize::new(0);
+
+// Every route of a server, called in-process rather than over a socket,
+// with its uploads kept in a temporary directory that's removed once the
+// server is dropped. Requests need an actix runtime, like the one
+// `#[actix_rt::test]` starts
+pub struct TestServer {
+    routes: ImageRoutes,
+    base_path: String,
+    dir: PathBuf,
+}
+
+// What a route responded with, once its body was read
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl TestResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+
+    pub fn json(&self) -> Result<serde_json::Value, String> {
+        serde_json::from_slice(&self.body).map_err(|e| format!("The response isn't JSON: {}", e))
+    }
+}
+
+impl TestServer {
+    // A server with the default settings
+    pub fn new() -> Result<Self, String> {
+        Self::with_config(|config| config)
+    }
+
+    // A server whose settings are changed by `configure`, like
+    // |config| config.with_preset("thumbnail", "w=200"). It's handed a
+    // config whose uploads are kept in the temporary directory
+    pub fn with_config<F: FnOnce(ServerConfig) -> ServerConfig>(configure: F) -> Result<Self, String> {
+        let name = format!("imogen-test-{}-{}", std::process::id(), STARTED.fetch_add(1, Ordering::SeqCst));
+        let dir = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let uploads_dir = dir.join("uploads");
+        let config = configure(ServerConfig::new(&uploads_dir.to_string_lossy()));
+        let base_path = config.base_path.clone();
+        match routes(config) {
+            Ok(routes) => Ok(Self { routes, base_path, dir }),
+            Err(e) => {
+                let _ = std::fs::remove_dir_all(&dir);
+                Err(e)
+            }
+        }
+    }
+
+    // The temporary directory, for keeping a cache directory of its own in
+    pub fn dir(&self) -> &std::path::Path {
+        &self.dir
+    }
+
+    // "/cat.webp?w=200"
+    pub async fn get(&self, path: &str) -> TestResponse {
+        self.send(test::TestRequest::get().uri(path)).await
+    }
+
+    // Uploading `contents` as a file named `filename`, like "cat.png"
+    pub async fn upload(&self, filename: &str, contents: &[u8]) -> TestResponse {
+        let uri = format!("{}/upload", self.base_path);
+        self.send(upload_request(filename, contents).uri(&uri)).await
+    }
+
+    // Sending a request of any other kind, like one with an API key
+    pub async fn send(&self, request: test::TestRequest) -> TestResponse {
+        let mut app = test::init_service(App::new().service(self.routes.clone())).await;
+        let response = test::call_service(&mut app, request.to_request()).await;
+
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = test::read_body(response).await.to_vec();
+        TestResponse { status, headers, body }
+    }
+
+    // Waiting for cached variants still being saved, before looking for
+    // them on disk
+    pub async fn finish(&self) {
+        self.routes.finish().await;
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+// A POST of `contents` as a multipart form, the way a browser uploads a
+// file named `filename`, to `/upload` unless given another path with `.uri()`
+pub fn upload_request(filename: &str, contents: &[u8]) -> test::TestRequest {
+    let boundary = "imogen-test-boundary";
+    let mut body = format!(
+        "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+        boundary, filename,
+    ).into_bytes();
+    body.extend_from_slice(contents);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+    test::TestRequest::post()
+        .uri("/upload")
+        .header("content-type", format!("multipart/form-data; boundary={}", boundary))
+        .set_payload(body)
+}
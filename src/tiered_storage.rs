@@ -0,0 +1,225 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::cache;
+use crate::storage::{is_partial_write, LocalStorage, OpenedFile, Storage, StoredFile};
+
+
+struct Entry {
+    size: u64,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<String, Entry>,
+    // Local copies ordered from least to most recently used
+    recency: BTreeMap<u64, String>,
+    used: u64,
+    clock: u64,
+}
+
+impl State {
+    fn touch(&mut self, local: &str) -> bool {
+        self.clock += 1;
+        let clock = self.clock;
+        match self.entries.get_mut(local) {
+            Some(entry) => {
+                self.recency.remove(&entry.last_used);
+                self.recency.insert(clock, local.to_string());
+                entry.last_used = clock;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn insert(&mut self, local: &str, size: u64) {
+        self.remove(local);
+        self.clock += 1;
+        self.used += size;
+        self.recency.insert(self.clock, local.to_string());
+        self.entries.insert(local.to_string(), Entry { size, last_used: self.clock });
+    }
+
+    fn remove(&mut self, local: &str) {
+        if let Some(entry) = self.entries.remove(local) {
+            self.recency.remove(&entry.last_used);
+            self.used -= entry.size;
+        }
+    }
+}
+
+// Keeping every file in durable (but slow) remote storage, like an S3
+// bucket, while copies of recently used masters and variants are kept
+// on local disk. Once the local budget is used up, the least recently
+// used copies are deleted to make room. They're still in remote storage
+pub struct TieredStorage<R: Storage> {
+    remote: R,
+    local: LocalStorage,
+    local_dir: String,
+    budget: u64,
+    state: Mutex<State>,
+}
+
+impl<R: Storage> TieredStorage<R> {
+    // Keeping up to `megabytes` of local copies in `local_dir`, including
+    // any left there by a previous run
+    pub fn new(remote: R, local_dir: &str, megabytes: u64) -> Self {
+        let local_dir = local_dir.trim_end_matches('/').to_string();
+        let local = LocalStorage;
+
+        // Resuming with the copies already on disk, oldest first
+        let mut existing: Vec<(String, u64, SystemTime)> = local.list(&local_dir)
+            .into_iter()
+            .filter(|path| !is_partial_write(path))
+            .filter_map(|path| {
+                let metadata = local.metadata(&path)?;
+                let last_used = metadata.accessed.or(metadata.modified)?;
+                Some((path, metadata.size, last_used))
+            })
+            .collect();
+        existing.sort_by_key(|(_, _, last_used)| *last_used);
+
+        let mut state = State::default();
+        for (path, size, _) in existing {
+            state.insert(&path, size);
+        }
+
+        let storage = Self {
+            remote,
+            local,
+            local_dir,
+            budget: megabytes * 1024 * 1024,
+            state: Mutex::new(state),
+        };
+        storage.evict();
+        storage
+    }
+
+    // "./images/cat.webp" -> "/var/cache/imogen/4a9c…7e58.webp"
+    fn build_path_to_copy(&self, path: &str) -> String {
+        match Path::new(path).extension().and_then(|e| e.to_str()) {
+            Some(extension) => format!("{}/{}.{}", self.local_dir, cache::hash_of(path), extension),
+            None => format!("{}/{}", self.local_dir, cache::hash_of(path)),
+        }
+    }
+
+    fn evict(&self) {
+        let mut state = self.state.lock().unwrap();
+        while state.used > self.budget {
+            let oldest = match state.recency.values().next() {
+                Some(oldest) => oldest.clone(),
+                None => break,
+            };
+            state.remove(&oldest);
+            let _ = self.local.delete(&oldest);
+        }
+    }
+
+    // Keeping a local copy, unless it wouldn't fit in the budget on its own.
+    // Copies are dated like the original, so that masters and variants are
+    // still compared by when they were saved, not when they were copied
+    fn keep_copy(&self, path: &str, contents: &[u8], modified: Option<SystemTime>) {
+        if contents.len() as u64 > self.budget { return; }
+
+        let copy = self.build_path_to_copy(path);
+        if self.local.put(&copy, contents).is_err() { return; }
+
+        if let Some(modified) = modified {
+            let _ = File::options().write(true).open(&copy).and_then(|file| file.set_modified(modified));
+        }
+
+        self.state.lock().unwrap().insert(&copy, contents.len() as u64);
+        self.evict();
+    }
+
+    // The local copy of a file, if there is one
+    fn local_copy(&self, path: &str) -> Option<String> {
+        let copy = self.build_path_to_copy(path);
+        let touched = self.state.lock().unwrap().touch(&copy);
+        match touched && self.local.exists(&copy) {
+            true => Some(copy),
+            false => None,
+        }
+    }
+}
+
+impl<R: Storage> Storage for TieredStorage<R> {
+    fn get(&self, path: &str) -> std::io::Result<Vec<u8>> {
+        if let Some(contents) = self.local_copy(path).and_then(|copy| self.local.get(&copy).ok()) {
+            return Ok(contents);
+        }
+
+        let contents = self.remote.get(path)?;
+        let modified = self.remote.metadata(path).and_then(|metadata| metadata.modified);
+        self.keep_copy(path, &contents, modified);
+        Ok(contents)
+    }
+
+    // Saving to remote storage first, so that nothing is only kept locally
+    fn put(&self, path: &str, contents: &[u8]) -> std::io::Result<()> {
+        self.remote.put(path, contents)?;
+        self.keep_copy(path, contents, None);
+        Ok(())
+    }
+
+    fn put_new(&self, path: &str, contents: &[u8]) -> std::io::Result<()> {
+        self.remote.put_new(path, contents)?;
+        self.keep_copy(path, contents, None);
+        Ok(())
+    }
+
+    fn delete(&self, path: &str) -> std::io::Result<()> {
+        let copy = self.build_path_to_copy(path);
+        self.state.lock().unwrap().remove(&copy);
+        let _ = self.local.delete(&copy);
+        self.remote.delete(path)
+    }
+
+    fn list(&self, dir: &str) -> Vec<String> {
+        self.remote.list(dir)
+    }
+
+    // Answering from the local copy where there is one, since this is
+    // asked of every variant that's requested
+    fn metadata(&self, path: &str) -> Option<StoredFile> {
+        let copy = self.build_path_to_copy(path);
+        match self.local.metadata(&copy) {
+            Some(metadata) => Some(metadata),
+            None => self.remote.metadata(path),
+        }
+    }
+
+    fn open(&self, path: &str) -> Option<OpenedFile> {
+        if let Some(file) = self.local_copy(path).and_then(|copy| self.local.open(&copy)) {
+            return Some(file);
+        }
+
+        // Streaming the copy that fetching leaves behind, unless the file
+        // was too large to keep one
+        let contents = self.get(path).ok()?;
+        match self.local_copy(path).and_then(|copy| self.local.open(&copy)) {
+            Some(file) => Some(file),
+            None => Some(OpenedFile::fetched(path, contents)),
+        }
+    }
+
+    // Files fetched from the remote are streamed from their local copies
+    fn streams_incrementally(&self) -> bool {
+        true
+    }
+
+    fn check_writable(&self, dir: &str) -> std::io::Result<()> {
+        fs::create_dir_all(&self.local_dir)?;
+        self.local.check_writable(&self.local_dir)?;
+        self.remote.check_writable(dir)
+    }
+
+    fn flush(&self, timeout: std::time::Duration) -> usize {
+        self.remote.flush(timeout)
+    }
+}
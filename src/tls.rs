@@ -0,0 +1,41 @@
+// Paths to the PEM encoded certificate chain and private key that
+// the server presents when serving HTTPS
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+#[cfg(feature = "tls")]
+pub fn load_rustls_config(tls: &TlsConfig) -> Result<rustls::ServerConfig, String> {
+    use std::fs::File;
+    use std::io::BufReader;
+    use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+
+    let open = |path: &str| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| format!("Could not open {}: {}", path, e))
+    };
+
+    let cert_chain = certs(&mut open(&tls.cert_path)?)
+        .map_err(|_| format!("Could not parse certificates in {}", tls.cert_path))?;
+
+    // Accepting keys in either PKCS #8 or the older RSA format
+    let mut keys = pkcs8_private_keys(&mut open(&tls.key_path)?).unwrap_or_default();
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open(&tls.key_path)?).unwrap_or_default();
+    }
+
+    let key = match keys.into_iter().next() {
+        Some(key) => key,
+        None => return Err(format!("Could not find a private key in {}", tls.key_path)),
+    };
+
+    let mut config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+    config
+        .set_single_cert(cert_chain, key)
+        .map_err(|e| format!("Invalid certificate or key: {}", e))?;
+
+    Ok(config)
+}
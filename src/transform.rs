@@ -0,0 +1,93 @@
+use std::sync::Arc;
+use image::{DynamicImage, GenericImageView};
+
+use crate::ImageServiceFailure;
+
+
+// The query parameters that processing already understands, which
+// transforms can't be named after. Nor can the parameters of signed URLs
+#[cfg(feature = "server")]
+const RESERVED: [&str; 8] = ["stretch", "sampling", "w", "h", "q", "preset", "expires", "sig"];
+
+// An effect of an application's own, applied to images after they're
+// resized and before they're encoded. It's asked for by naming it in the
+// query string, like "?w=400&sepia=80" for a transform named "sepia",
+// and told the value it was given
+pub trait Transform: Send + Sync {
+    // "sepia", the query parameter that asks for it
+    fn name(&self) -> &str;
+
+    // Refusing values that the transform doesn't accept, before an image
+    // is read. The message is told to the client
+    fn check(&self, _value: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    // Refusing images that the transform can't be applied to, such as ones
+    // too large for it, once they're resized. The message is told to the
+    // client
+    fn check_dimensions(&self, _value: &str, _dimensions: (u32, u32)) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn apply(&self, image: DynamicImage, _value: &str) -> DynamicImage {
+        image
+    }
+
+    // Applying a transform that can fail, such as by calling out to another
+    // service, rather than `apply`. Variants it fails on aren't cached, and
+    // the message is told to the client
+    fn try_apply(&self, image: DynamicImage, value: &str) -> Result<DynamicImage, String> {
+        Ok(self.apply(image, value))
+    }
+}
+
+// Refusing transforms named after a parameter that's already understood,
+// or after another transform
+#[cfg(feature = "server")]
+pub fn check_names(transforms: &[Arc<dyn Transform>]) -> Result<(), String> {
+    for (i, transform) in transforms.iter().enumerate() {
+        let name = transform.name();
+        if name.is_empty() || RESERVED.contains(&name) {
+            return Err(format!("A transform can't be named {:?}", name));
+        }
+        if transforms[..i].iter().any(|earlier| earlier.name() == name) {
+            return Err(format!("More than one transform is named {:?}", name));
+        }
+    }
+    Ok(())
+}
+
+// "w=400&sepia=80&grain=" with a "sepia" and a "grain" transform
+// -> [("sepia", "80")]
+// The transforms asked for by a query string, in the order they were
+// registered, along with the values they were given. Empty values are
+// left out, as they are for every other parameter
+pub fn requested(query: &str, transforms: &[Arc<dyn Transform>]) -> Result<Vec<(String, String)>, (String, String)> {
+    let pairs = serde_urlencoded::from_str::<Vec<(String, String)>>(query).unwrap_or_default();
+
+    let mut requested = vec![];
+    for transform in transforms {
+        let name = transform.name();
+        let value = match pairs.iter().find(|(given, value)| given == name && !value.is_empty()) {
+            Some((_, value)) => value,
+            None => continue,
+        };
+        transform.check(value).map_err(|message| (name.to_string(), message))?;
+        requested.push((name.to_string(), value.clone()));
+    }
+    Ok(requested)
+}
+
+// Applying each of the `requested` transforms in turn
+pub fn apply(mut image: DynamicImage, requested: &[(String, String)], transforms: &[Arc<dyn Transform>]) -> Result<DynamicImage, ImageServiceFailure> {
+    for (name, value) in requested {
+        if let Some(transform) = transforms.iter().find(|transform| transform.name() == name) {
+            transform.check_dimensions(value, image.dimensions())
+                .map_err(|message| ImageServiceFailure::invalid_parameter(name, &message))?;
+            image = transform.try_apply(image, value)
+                .map_err(|message| ImageServiceFailure::TransformFailed { transform: name.clone(), message })?;
+        }
+    }
+    Ok(image)
+}
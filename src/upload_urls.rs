@@ -0,0 +1,198 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::collections::HashMap;
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+use crate::{auth, build_path_to_master, valid_new_name, ServerConfig};
+use crate::auth::{AuthFailure, Identity};
+use crate::storage::is_partial_write;
+
+
+// Signed URLs that let whoever holds one upload a single file, like a
+// browser given one by an application that holds an API key itself.
+// Each is bound to the name the file is saved as, and to a size limit,
+// and is refused once it's been used or has expired
+
+// How long upload URLs last unless asked otherwise, and at most, in seconds
+const DEFAULT_EXPIRY: u64 = 15 * 60;
+const MAX_EXPIRY: u64 = 24 * 60 * 60;
+
+// How often used URLs that have since expired are forgotten
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UploadUrlInstructions {
+    // "cat", the name the upload will be saved as
+    filename: String,
+    max_bytes: Option<usize>,
+    // Seconds until the URL expires
+    expires_in: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct UploadUrl {
+    url: String,
+    filename: String,
+    max_bytes: usize,
+    expires_at: u64,
+}
+
+// What an upload URL allows, once its signature has been checked
+#[derive(Clone)]
+pub struct UploadGrant {
+    pub filename: String,
+    pub max_bytes: usize,
+    // Whoever the URL was signed for, who's recorded as the uploader
+    pub uploaded_by: Option<Identity>,
+    nonce: String,
+    expires: u64,
+}
+
+#[derive(Deserialize)]
+struct GrantQuery {
+    filename: String,
+    max_bytes: usize,
+    by: Option<String>,
+    nonce: String,
+    expires: u64,
+}
+
+// "./images/.upload-urls/{nonce}"
+fn build_path_to_redemption(config: &ServerConfig, nonce: &str) -> String {
+    format!("{}/.upload-urls/{}", config.uploads_dir, nonce)
+}
+
+// Forgetting which URLs were used, once they've expired anyway,
+// returning how many were forgotten
+fn forget_expired_redemptions(config: &ServerConfig) -> usize {
+    let dir = format!("{}/.upload-urls", config.uploads_dir);
+    config.storage.list(&dir)
+        .into_iter()
+        .filter(|path| !is_partial_write(path))
+        .filter(|path| {
+            let expires_at = config.storage.get(path)
+                .ok()
+                .and_then(|contents| String::from_utf8(contents).ok())
+                .and_then(|expires_at| expires_at.parse::<u64>().ok());
+            expires_at.is_none_or(|expires_at| expires_at < now())
+        })
+        .filter(|path| config.storage.delete(path).is_ok())
+        .count()
+}
+
+// Periodically forgetting used URLs that have expired, for as long as
+// the server runs
+pub async fn sweep_expired_redemptions(config: web::Data<ServerConfig>) {
+    let mut interval = actix_web::rt::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let config = config.clone();
+        match web::block(move || Ok::<_, ()>(forget_expired_redemptions(&config))).await {
+            Ok(0) => {},
+            Ok(forgotten) => tracing::info!(forgotten, "forgot expired upload URLs"),
+            Err(_) => tracing::error!("failed to forget expired upload URLs"),
+        }
+    }
+}
+
+// The grant of a request made to an upload URL, or none for uploads
+// authorized some other way
+pub fn grant_of(req: &HttpRequest, config: &ServerConfig) -> Option<Result<UploadGrant, AuthFailure>> {
+    let query = web::Query::<HashMap<String, String>>::from_query(req.query_string()).ok()?;
+    if !query.contains_key("nonce") {
+        return None;
+    }
+
+    if let Err(failure) = auth::verify_signed_url(req, config) {
+        return Some(Err(failure));
+    }
+    let grant = match web::Query::<GrantQuery>::from_query(req.query_string()) {
+        Ok(grant) => grant.into_inner(),
+        Err(_) => return Some(Err(AuthFailure::InvalidSignature)),
+    };
+
+    Some(Ok(UploadGrant {
+        filename: grant.filename,
+        max_bytes: grant.max_bytes,
+        uploaded_by: grant.by.map(|name| Identity { name, metadata: HashMap::new() }),
+        nonce: grant.nonce,
+        expires: grant.expires,
+    }))
+}
+
+// Using up a URL before its upload is read, failing with
+// `ErrorKind::AlreadyExists` if it was used already. Kept until the URL
+// expires, since it's refused after that anyway
+pub fn claim(config: &ServerConfig, grant: &UploadGrant) -> std::io::Result<()> {
+    config.storage.put_new(&build_path_to_redemption(config, &grant.nonce), grant.expires.to_string().as_bytes())
+}
+
+// Giving a URL back when nothing was saved with it, so that the upload
+// can be tried again
+pub fn release(config: &ServerConfig, grant: &UploadGrant) -> std::io::Result<()> {
+    config.storage.delete(&build_path_to_redemption(config, &grant.nonce))
+}
+
+// Respond to a request for an upload URL, like
+// `POST /upload/sign?filename=cat&max_bytes=5000000&expires_in=600`
+pub async fn serve_upload_url_via_http(
+    req: HttpRequest,
+    instructions: web::Query<UploadUrlInstructions>,
+    config: web::Data<ServerConfig>,
+) -> HttpResponse {
+    // Signing for uploads that the signer could make themselves
+    let signer = match auth::authorize_upload(&req, &config, auth::Access::Write, &instructions.filename) {
+        Ok(signer) => signer,
+        Err(failure) => return failure.as_http_response(),
+    };
+    if config.origin.is_some() {
+        return HttpResponse::NotFound().body("Uploads are disabled while proxying an origin");
+    }
+    let secret = match &config.url_signing_secret {
+        Some(secret) => secret.clone(),
+        None => return HttpResponse::NotImplemented().body("Upload URLs can't be signed without a URL signing secret"),
+    };
+
+    let UploadUrlInstructions { filename, max_bytes, expires_in } = instructions.into_inner();
+    if !valid_new_name(&filename) {
+        return HttpResponse::BadRequest().body("The filename is not a valid filename");
+    }
+    let max_bytes = max_bytes.unwrap_or(config.max_upload_size);
+    if max_bytes == 0 || max_bytes > config.max_upload_size {
+        return HttpResponse::BadRequest().body(format!("max_bytes must be between 1 and {}", config.max_upload_size));
+    }
+    let expires_in = expires_in.unwrap_or(DEFAULT_EXPIRY);
+    if expires_in == 0 || expires_in > MAX_EXPIRY {
+        return HttpResponse::BadRequest().body(format!("expires_in must be between 1 and {} seconds", MAX_EXPIRY));
+    }
+
+    let (checking, target) = (config.clone(), filename.clone());
+    let exists = web::block(move || Ok::<_, ()>(checking.storage.exists(&build_path_to_master(&checking, &target)))).await;
+    match exists {
+        Ok(false) => {}
+        Ok(true) => return HttpResponse::Conflict().body("Another file with this name already exists."),
+        Err(_) => return HttpResponse::InternalServerError().body("The upload URL could not be signed"),
+    }
+
+    let mut pairs = vec![("filename", filename.clone()), ("max_bytes", max_bytes.to_string())];
+    if let Some(signer) = signer {
+        pairs.push(("by", signer.name));
+    }
+    pairs.push(("nonce", Uuid::new_v4().simple().to_string()));
+    let query = serde_urlencoded::to_string(&pairs).unwrap_or_default();
+
+    let expires_at = now() + expires_in;
+    let path = auth::sign_url(&format!("{}/upload?{}", config.base_path, query), &secret, expires_at);
+    let connection = req.connection_info();
+    let url = format!("{}://{}{}", connection.scheme(), connection.host(), path);
+
+    HttpResponse::Ok().json(UploadUrl { url, filename, max_bytes, expires_at })
+}
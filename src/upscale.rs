@@ -0,0 +1,145 @@
+use std::io::Read;
+use std::time::Duration;
+use image::{DynamicImage, GenericImageView};
+use image::imageops::FilterType;
+
+use crate::{encode_image, Transform};
+
+
+// Enlarging images with a super-resolution model, as a transform asked for
+// by "?upscale=2x", by POSTing each one as a PNG to a service that answers
+// with it enlarged by that factor. It's applied after resizing, so
+// "?w=400&upscale=2x" is 800 pixels wide. Upscaled variants are cached
+// like any other, so a service is only asked once for each
+#[derive(Clone, Debug)]
+pub struct UpscalingConfig {
+    // "http://localhost:7001/api/upscale", told the factor as "?scale=2"
+    pub url: String,
+    // Sent as the x-api-key header, for services that need one
+    pub api_key: Option<String>,
+    pub timeout: Duration,
+    // The largest factor that can be asked for, up to 4
+    pub max_factor: u32,
+    // The most pixels an image can have before it's upscaled
+    pub max_pixels: u64,
+}
+
+impl UpscalingConfig {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            api_key: None,
+            timeout: Duration::from_secs(60),
+            max_factor: 4,
+            max_pixels: 1024 * 1024,
+        }
+    }
+
+    pub fn with_api_key(mut self, key: &str) -> Self {
+        self.api_key = Some(key.to_string());
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_max_factor(mut self, factor: u32) -> Self {
+        self.max_factor = factor.clamp(2, MAX_FACTOR);
+        self
+    }
+
+    pub fn with_max_pixels(mut self, pixels: u64) -> Self {
+        self.max_pixels = pixels;
+        self
+    }
+}
+
+// The largest factor any service is asked for
+const MAX_FACTOR: u32 = 4;
+
+// The largest upscaled image that's read from a service
+const MAX_UPSCALED_BYTES: u64 = 128 * 1024 * 1024;
+
+pub struct Upscale {
+    config: UpscalingConfig,
+    agent: ureq::Agent,
+}
+
+// "2x" -> Some(2)
+fn factor(value: &str) -> Option<u32> {
+    value.strip_suffix('x').and_then(|factor| factor.parse().ok())
+}
+
+impl Upscale {
+    pub fn new(config: UpscalingConfig) -> Self {
+        let agent = ureq::AgentBuilder::new().timeout(config.timeout).build();
+        Self { config, agent }
+    }
+
+    fn upscale(&self, image: &DynamicImage, factor: u32) -> Result<DynamicImage, String> {
+        let png = encode_image(image, "png").map_err(|failure| failure.to_string())?;
+
+        let mut request = self.agent.post(&self.config.url)
+            .query("scale", &factor.to_string())
+            .set("content-type", "image/png");
+        if let Some(key) = &self.config.api_key {
+            request = request.set("x-api-key", key);
+        }
+        let response = request.send_bytes(&png).map_err(|e| match e {
+            ureq::Error::Status(status, _) => format!("the upscaling service responded {}", status),
+            ureq::Error::Transport(transport) => format!("the upscaling service could not be reached: {}", transport),
+        })?;
+
+        let mut contents = vec![];
+        response.into_reader()
+            .take(MAX_UPSCALED_BYTES + 1)
+            .read_to_end(&mut contents)
+            .map_err(|e| format!("the upscaling service's response could not be read: {}", e))?;
+        if contents.len() as u64 > MAX_UPSCALED_BYTES {
+            return Err("the upscaling service responded with too large an image".to_string());
+        }
+
+        let upscaled = image::load_from_memory(&contents)
+            .map_err(|_| "the upscaling service responded with something other than an image".to_string())?;
+
+        // Keeping to exactly the factor asked for, for models that pad or
+        // round the images they're given
+        let (width, height) = (image.width() * factor, image.height() * factor);
+        match upscaled.dimensions() == (width, height) {
+            true => Ok(upscaled),
+            false => Ok(upscaled.resize_exact(width, height, FilterType::Lanczos3)),
+        }
+    }
+}
+
+impl Transform for Upscale {
+    fn name(&self) -> &str {
+        "upscale"
+    }
+
+    fn check(&self, value: &str) -> Result<(), String> {
+        match factor(value) {
+            Some(factor) if (1..=self.config.max_factor).contains(&factor) => Ok(()),
+            _ => Err(format!("upscale must be one of 1x to {}x", self.config.max_factor)),
+        }
+    }
+
+    fn check_dimensions(&self, value: &str, (width, height): (u32, u32)) -> Result<(), String> {
+        match factor(value) <= Some(1) || width as u64 * height as u64 <= self.config.max_pixels {
+            true => Ok(()),
+            false => Err(format!(
+                "Images of {}x{} pixels are too large to upscale. The limit is {} pixels, so ask for a smaller w or h",
+                width, height, self.config.max_pixels,
+            )),
+        }
+    }
+
+    fn try_apply(&self, image: DynamicImage, value: &str) -> Result<DynamicImage, String> {
+        match factor(value) {
+            Some(factor) if factor > 1 => self.upscale(&image, factor),
+            _ => Ok(image),
+        }
+    }
+}
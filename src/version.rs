@@ -0,0 +1,49 @@
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+
+use crate::{ServerConfig, INPUT_FORMATS, OUTPUT_FORMATS};
+
+
+#[derive(Serialize)]
+struct Version {
+    version: &'static str,
+    commit: Option<&'static str>,
+    features: Vec<&'static str>,
+    input_formats: Vec<&'static str>,
+    output_formats: Vec<&'static str>,
+}
+
+// The optional cargo features this binary was compiled with
+fn enabled_features() -> Vec<&'static str> {
+    let mut features = vec![];
+    if cfg!(feature = "tls") { features.push("tls"); }
+    if cfg!(feature = "sqlite") { features.push("sqlite"); }
+    if cfg!(feature = "s3") { features.push("s3"); }
+    if cfg!(feature = "proxy") { features.push("proxy"); }
+    if cfg!(feature = "otlp") { features.push("otlp"); }
+    if cfg!(feature = "graphql") { features.push("graphql"); }
+    if cfg!(feature = "grpc") { features.push("grpc"); }
+    if cfg!(feature = "webhooks") { features.push("webhooks"); }
+    if cfg!(feature = "removebg") { features.push("removebg"); }
+    if cfg!(feature = "upscale") { features.push("upscale"); }
+    features
+}
+
+// Respond to fleet tooling verifying what's deployed
+pub fn serve_version_via_http(config: web::Data<ServerConfig>) -> HttpResponse {
+    let commit = env!("IMOGEN_GIT_COMMIT");
+
+    let mut output_formats: Vec<&str> = OUTPUT_FORMATS.iter()
+        .filter(|format| config.serves_format(format))
+        .copied()
+        .collect();
+    if config.format_negotiation { output_formats.push("auto"); }
+
+    HttpResponse::Ok().json(Version {
+        version: env!("CARGO_PKG_VERSION"),
+        commit: if commit.is_empty() { None } else { Some(commit) },
+        features: enabled_features(),
+        input_formats: INPUT_FORMATS.to_vec(),
+        output_formats,
+    })
+}
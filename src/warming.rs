@@ -0,0 +1,218 @@
+use std::path::Path;
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Serialize, Deserialize};
+
+use crate::{
+    auth,
+    Caches,
+    cache,
+    content_store,
+    ServerConfig,
+    FileDescription,
+    ProcessingInstructions,
+    build_path_to_master,
+    try_loading_unprocessed_image,
+    try_processing_renditions,
+};
+use crate::decoded_cache::DecodedCache;
+use crate::hooks::VariantEvent;
+use crate::memory_budget::MemoryBudget;
+
+
+fn default_extensions() -> Vec<String> {
+    vec!["webp".to_string()]
+}
+
+// The variants to produce ahead of time, like before a new deployment
+// takes traffic, or after the cache was flushed
+#[derive(Deserialize, Clone, Debug)]
+pub struct WarmingPlan {
+    // Uploads to warm, or every upload when omitted
+    #[serde(default)]
+    pub filenames: Option<Vec<String>>,
+    // Query strings like "w=200&h=200", or every preset when omitted
+    #[serde(default)]
+    pub transformations: Option<Vec<String>>,
+    // Formats to encode each variant in
+    #[serde(default = "default_extensions")]
+    pub extensions: Vec<String>,
+}
+
+impl WarmingPlan {
+    // Every configured preset, for every upload, as WebP
+    pub fn new() -> Self {
+        Self { filenames: None, transformations: None, extensions: default_extensions() }
+    }
+
+    pub fn with_filename(mut self, filename: &str) -> Self {
+        self.filenames.get_or_insert_with(Vec::new).push(filename.to_string());
+        self
+    }
+
+    pub fn with_transformation(mut self, query: &str) -> Self {
+        self.transformations.get_or_insert_with(Vec::new).push(query.to_string());
+        self
+    }
+
+    pub fn with_extensions(mut self, extensions: &[&str]) -> Self {
+        self.extensions = extensions.iter().map(|extension| extension.to_string()).collect();
+        self
+    }
+}
+
+impl Default for WarmingPlan {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct WarmingResult {
+    pub warmed: usize,
+    pub skipped: usize,
+    pub errors: Vec<String>,
+}
+
+impl WarmingResult {
+    fn with_error(mut self, message: String) -> Self {
+        self.errors.push(message);
+        self
+    }
+}
+
+// "./images" -> ["cat", "dog"]
+pub fn list_masters(config: &ServerConfig) -> Vec<String> {
+    let mut masters: Vec<String> = config.storage.list(&config.uploads_dir)
+        .iter()
+        .filter_map(|path| Path::new(path).strip_prefix(&config.uploads_dir).ok())
+        // Skipping hidden directories like ".variants"
+        .filter(|path| !path.components().any(|part| part.as_os_str().to_string_lossy().starts_with('.')))
+        .filter_map(|path| path.file_name().and_then(|name| name.to_str()))
+        .filter(|name| !name.contains('?'))
+        .filter_map(|name| name.strip_suffix(".webp").map(|name| name.to_string()))
+        .collect();
+
+    if config.content_addressed {
+        masters.extend(content_store::list_names(config));
+    }
+
+    masters.sort();
+    masters.dedup();
+    masters
+}
+
+// Producing and caching every variant in the plan that isn't cached yet,
+// taking turns with requests for memory to process them in
+pub fn warm_cache(config: &ServerConfig, plan: &WarmingPlan, budget: &MemoryBudget) -> WarmingResult {
+    let mut result = WarmingResult { warmed: 0, skipped: 0, errors: vec![] };
+
+    if let Some(extension) = plan.extensions.iter().find(|e| !config.serves_format(e)) {
+        return result.with_error(format!("Unsupported output format {:?}", extension));
+    }
+
+    let transformations = match &plan.transformations {
+        Some(transformations) => transformations.clone(),
+        None => config.presets.iter().map(|preset| format!("preset={}", preset.name)).collect(),
+    };
+
+    // Resolving every transformation up front, to report mistakes
+    // before doing any work
+    let mut instructions = vec![];
+    for query in &transformations {
+        let parsed = web::Query::<ProcessingInstructions>::from_query(query)
+            .map_err(|_| format!("Transformation {:?} could not be parsed", query))
+            .and_then(|parsed| parsed.into_inner().with_transforms(query, &config.transforms).map_err(|failure| failure.to_string()))
+            .and_then(|parsed| parsed.with_preset(&config.presets, &config.transforms));
+
+        match parsed {
+            Ok(parsed) => instructions.push(parsed),
+            Err(message) => return result.with_error(message),
+        }
+    }
+
+    let settings = crate::pipeline::Settings::from(config);
+    let filenames = match &plan.filenames {
+        Some(filenames) => filenames.clone(),
+        None => list_masters(config),
+    };
+
+    for filename in filenames {
+        let master = match try_loading_unprocessed_image(config, &build_path_to_master(config, &filename)) {
+            Ok(master) => master,
+            Err(failure) => {
+                result.errors.push(format!("{}: {}", filename, failure));
+                continue;
+            }
+        };
+
+        let mut renditions = vec![];
+        for optional in &instructions {
+            for extension in &plan.extensions {
+                let variant_name = cache::variant_name(&filename, &optional.cache_key(), extension);
+
+                // Masters are served as they are, and needn't be cached again
+                let is_master = optional.cache_key().is_empty() && extension == "webp";
+                let filepath = cache::build_path_to_variant(config, &variant_name, extension);
+                if is_master || config.storage.exists(&filepath) {
+                    result.skipped += 1;
+                    continue;
+                }
+
+                let required = FileDescription { filename: filename.clone(), extension: extension.clone() };
+                renditions.push((optional.clone(), required));
+            }
+        }
+
+        // Decoding each master once for all of its variants, which leaves
+        // nothing for the cache of decoded masters to save
+        let processed = match try_processing_renditions(&master, &renditions, &settings, budget, &DecodedCache::disabled()) {
+            Ok(processed) => processed,
+            Err(failure) => {
+                result.errors.push(format!("{}: {}", filename, failure));
+                continue;
+            }
+        };
+
+        for ((optional, required), processed) in renditions.iter().zip(processed) {
+            let cache_key = optional.cache_key();
+            let stored = processed
+                .map_err(|failure| failure.to_string())
+                .and_then(|(buffer, processing)| {
+                    cache::store_variant(config, &filename, &cache_key, &required.extension, &buffer)
+                        .map_err(|e| e.to_string())?;
+                    config.hooks.variant_generated(VariantEvent::of(&filename, &cache_key, &required.extension, &processing));
+                    Ok(())
+                });
+
+            match stored {
+                Ok(_) => result.warmed += 1,
+                Err(message) => {
+                    let variant_name = cache::variant_name(&filename, &cache_key, &required.extension);
+                    result.errors.push(format!("{}: {}", variant_name, message));
+                }
+            }
+        }
+    }
+
+    result
+}
+
+// Respond to a request to warm the cache, described by a JSON body like
+// {"filenames": ["cat"], "transformations": ["w=200"], "extensions": ["jpeg"]}
+pub async fn serve_warming_via_http(
+    req: HttpRequest,
+    plan: web::Json<WarmingPlan>,
+    config: web::Data<ServerConfig>,
+    caches: web::Data<Caches>,
+) -> HttpResponse {
+    if let Err(failure) = auth::authorize(&req, &config, auth::Access::Write) {
+        return failure.as_http_response();
+    }
+
+    let plan = plan.into_inner();
+    let budget = caches.budget.clone();
+    match web::block(move || Ok::<_, ()>(warm_cache(&config, &plan, &budget))).await {
+        Ok(result) => HttpResponse::Ok().json(result),
+        Err(_) => HttpResponse::InternalServerError().body("The cache could not be warmed"),
+    }
+}
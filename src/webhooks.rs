@@ -0,0 +1,196 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::auth;
+use crate::hooks::{Hooks, UploadEvent, DeleteEvent};
+
+
+// Telling other systems of uploads, replacements and deletions, by POSTing
+// JSON describing them to each of `urls`, so that they stay in sync
+#[derive(Clone, Debug)]
+pub struct WebhookConfig {
+    pub urls: Vec<String>,
+    // Signing every delivery, so receivers can tell it came from imogen
+    pub secret: Option<String>,
+    // How many times a delivery is tried before it's given up on, waiting
+    // `retry_delay` after the first attempt, then twice as long each time
+    pub max_attempts: u32,
+    pub retry_delay: Duration,
+    // Where deliveries that were given up on are written, one JSON object
+    // per line, so they can be sent again by hand
+    pub dead_letter_log: Option<String>,
+}
+
+impl WebhookConfig {
+    pub fn new(url: &str) -> Self {
+        Self {
+            urls: vec![url.to_string()],
+            secret: None,
+            max_attempts: 5,
+            retry_delay: Duration::from_secs(1),
+            dead_letter_log: None,
+        }
+    }
+
+    pub fn with_url(mut self, url: &str) -> Self {
+        self.urls.push(url.to_string());
+        self
+    }
+
+    pub fn with_secret(mut self, secret: &str) -> Self {
+        self.secret = Some(secret.to_string());
+        self
+    }
+
+    pub fn with_max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = attempts.max(1);
+        self
+    }
+
+    pub fn with_retry_delay(mut self, delay: Duration) -> Self {
+        self.retry_delay = delay;
+        self
+    }
+
+    pub fn with_dead_letter_log(mut self, path: &str) -> Self {
+        self.dead_letter_log = Some(path.to_string());
+        self
+    }
+}
+
+// How long each attempt may take, and the longest wait between them
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Connection failures, server errors, and responses asking to slow down
+// are tried again, while other refusals are given up on
+enum Failure {
+    Retryable(String),
+    Refused(String),
+}
+
+// Delivering events as hooks, which are told of them one at a time in
+// the order they happened. Every URL is sent each event at once, and the
+// next event waits until they've all been delivered or given up on
+pub struct Webhooks {
+    config: WebhookConfig,
+    agent: ureq::Agent,
+}
+
+impl Webhooks {
+    pub fn new(config: WebhookConfig) -> Self {
+        let agent = ureq::AgentBuilder::new().timeout(DELIVERY_TIMEOUT).build();
+        Self { config, agent }
+    }
+
+    fn deliver(&self, event: &str, details: Value) {
+        let id = Uuid::new_v4().simple().to_string();
+        let body = json!({ "id": id, "event": event, "occurred_at": now(), "image": details }).to_string();
+
+        let (id, body) = (&id, &body);
+        std::thread::scope(|threads| {
+            for url in &self.config.urls {
+                threads.spawn(move || self.deliver_to(url, id, event, body));
+            }
+        });
+    }
+
+    fn deliver_to(&self, url: &str, id: &str, event: &str, body: &str) {
+        let mut delay = self.config.retry_delay;
+        let mut attempt = 1;
+
+        loop {
+            let failure = match self.send(url, id, event, body) {
+                Ok(()) => return,
+                Err(Failure::Refused(failure)) => return self.give_up(url, attempt, &failure, body),
+                Err(Failure::Retryable(failure)) => failure,
+            };
+
+            if attempt >= self.config.max_attempts {
+                return self.give_up(url, attempt, &failure, body);
+            }
+            tracing::debug!(%url, event, attempt, error = %failure, "retrying a webhook");
+            std::thread::sleep(delay);
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+            attempt += 1;
+        }
+    }
+
+    // Signing "{timestamp}.{body}", so that old deliveries can't be replayed
+    // under a fresh timestamp
+    fn send(&self, url: &str, id: &str, event: &str, body: &str) -> Result<(), Failure> {
+        let timestamp = now().to_string();
+        let mut request = self.agent.post(url)
+            .set("content-type", "application/json")
+            .set("x-imogen-event", event)
+            .set("x-imogen-delivery", id)
+            .set("x-imogen-timestamp", &timestamp);
+        if let Some(secret) = &self.config.secret {
+            let signature = auth::hmac_hex(secret, &format!("{}.{}", timestamp, body));
+            request = request.set("x-imogen-signature", &format!("sha256={}", signature));
+        }
+
+        match request.send_string(body) {
+            Ok(_) => Ok(()),
+            Err(ureq::Error::Status(status, _)) if status >= 500 || status == 408 || status == 429 => {
+                Err(Failure::Retryable(format!("responded {}", status)))
+            }
+            Err(ureq::Error::Status(status, _)) => Err(Failure::Refused(format!("responded {}", status))),
+            Err(ureq::Error::Transport(transport)) => Err(Failure::Retryable(transport.to_string())),
+        }
+    }
+
+    fn give_up(&self, url: &str, attempts: u32, failure: &str, body: &str) {
+        tracing::error!(%url, attempts, error = %failure, "gave up on a webhook");
+
+        let path = match &self.config.dead_letter_log {
+            Some(path) => path,
+            None => return,
+        };
+        let delivery = serde_json::from_str::<Value>(body).unwrap_or(Value::Null);
+        let line = json!({ "url": url, "attempts": attempts, "error": failure, "failed_at": now(), "delivery": delivery });
+
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut log| writeln!(log, "{}", line));
+        if let Err(e) = written {
+            tracing::error!(%path, error = %e, "could not write to the webhook dead letter log");
+        }
+    }
+}
+
+fn upload_details(upload: &UploadEvent) -> Value {
+    json!({
+        "filename": upload.filename,
+        "bytes": upload.bytes,
+        "width": upload.width,
+        "height": upload.height,
+        "uploaded_by": upload.uploaded_by,
+    })
+}
+
+impl Hooks for Webhooks {
+    fn on_upload(&self, upload: &UploadEvent) {
+        self.deliver("upload", upload_details(upload));
+    }
+
+    fn on_replace(&self, replacement: &UploadEvent) {
+        self.deliver("replace", upload_details(replacement));
+    }
+
+    fn on_delete(&self, deletion: &DeleteEvent) {
+        self.deliver("delete", json!({ "filename": deletion.filename, "renamed_to": deletion.renamed_to }));
+    }
+}